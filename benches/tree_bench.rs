@@ -1,4 +1,4 @@
-use bao_tree::{blake3, BaoTree, BlockSize, ByteNum, ChunkRanges};
+use bao_tree::{blake3, BaoTree, BlockSize, ByteNum, ChunkNum, ChunkRanges};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 fn offset_benches(c: &mut Criterion) {
@@ -44,6 +44,22 @@ fn iter_benches(c: &mut Criterion) {
     });
 }
 
+fn split_benches(c: &mut Criterion) {
+    // every other chunk of a 1 MiB blob, so the traversal has to split at every node
+    let ranges = ChunkRanges::new_unchecked((0..1024).map(ChunkNum).collect());
+    c.bench_function("split", |b| {
+        b.iter(|| black_box(&ranges).split(black_box(ChunkNum(511))))
+    });
+    let tree = BaoTree::new(ByteNum(1024 * 1024), BlockSize::ZERO);
+    c.bench_function("ranges_pre_order_chunks_iter_ref_sparse", |b| {
+        b.iter(|| {
+            for item in tree.ranges_pre_order_chunks_iter_ref(&ranges, 0) {
+                black_box(item);
+            }
+        })
+    });
+}
+
 fn hash_benches_large(c: &mut Criterion) {
     let data = (0..1024 * 16).map(|i| i as u8).collect::<Vec<_>>();
     c.bench_function("hash_blake3", |b| {
@@ -65,5 +81,11 @@ fn hash_benches_large(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, offset_benches, iter_benches, hash_benches_large,);
+criterion_group!(
+    benches,
+    offset_benches,
+    iter_benches,
+    split_benches,
+    hash_benches_large,
+);
 criterion_main!(benches);
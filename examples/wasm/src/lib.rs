@@ -0,0 +1,60 @@
+//! Hashing and verified streaming in the browser
+//!
+//! This uses only the in memory functions of bao-tree, with default features
+//! disabled, so it builds for wasm32-unknown-unknown:
+//!
+//! ```text
+//! cargo build --target wasm32-unknown-unknown
+//! ```
+//!
+//! Use e.g. `wasm-pack build --target web` to get the javascript bindings. Byte
+//! arrays are passed as `Uint8Array`.
+use bao_tree::{
+    blake3,
+    io::sync::{decode_ranges_with, encode_ranges_from_data},
+    BlockSize, ByteNum, ChunkRanges,
+};
+use wasm_bindgen::prelude::*;
+
+/// Chunk groups of 16 KiB
+const BLOCK_SIZE: BlockSize = BlockSize(4);
+
+/// The chunks that cover the bytes `start..end`
+fn chunk_ranges(start: u64, end: u64) -> ChunkRanges {
+    ChunkRanges::from(ByteNum(start).full_chunks()..ByteNum(end).chunks())
+}
+
+/// Compute the 32 byte blake3 hash of `data`
+#[wasm_bindgen]
+pub fn hash(data: &[u8]) -> Vec<u8> {
+    blake3::hash(data).as_bytes().to_vec()
+}
+
+/// Encode the bytes `start..end` of `data` as a verifiable slice
+#[wasm_bindgen]
+pub fn encode(data: &[u8], start: u64, end: u64) -> Vec<u8> {
+    let (encoded, _) = encode_ranges_from_data(data, &chunk_ranges(start, end), BLOCK_SIZE);
+    encoded
+}
+
+/// Verify a slice for the bytes `start..end` against the hash of the blob
+///
+/// Returns the verified data of the chunks that cover the range. Fails if the slice
+/// does not match the hash.
+#[wasm_bindgen]
+pub fn verify(hash: &[u8], encoded: &[u8], start: u64, end: u64) -> Result<Vec<u8>, JsError> {
+    let hash = <[u8; 32]>::try_from(hash).map_err(|_| JsError::new("hash must be 32 bytes"))?;
+    let mut data = Vec::new();
+    decode_ranges_with(
+        blake3::Hash::from(hash),
+        BLOCK_SIZE,
+        &chunk_ranges(start, end),
+        encoded,
+        |_, leaf| {
+            data.extend_from_slice(leaf);
+            Ok(())
+        },
+    )
+    .map_err(|e| JsError::new(&e.to_string()))?;
+    Ok(data)
+}
@@ -0,0 +1,75 @@
+//! Pluggable hashing backends
+//!
+//! The tree only needs two primitives from blake3: hashing a single chunk, and
+//! combining two chaining values into a parent. The [Hasher] trait captures just
+//! these, so the outboard and decode code can be used with a different
+//! implementation, e.g. a hardware accelerated one.
+//!
+//! All functions that take a hasher are generic over it, so using the default
+//! [Blake3Hasher] has no overhead compared to calling blake3 directly.
+use crate::blake3::{
+    self,
+    guts::{parent_cv, ChunkState, CHUNK_LEN},
+};
+
+/// The hashing primitives needed to compute a bao tree
+pub trait Hasher {
+    /// Hash a single chunk of at most 1024 bytes, with the given chunk number
+    fn hash_chunk(&self, chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash;
+
+    /// Compute the hash of a parent node from the hashes of its children
+    fn parent(&self, left: &blake3::Hash, right: &blake3::Hash, is_root: bool) -> blake3::Hash;
+
+    /// Hash a subtree of data, starting at chunk `start_chunk`
+    ///
+    /// The default implementation splits the data into chunks and combines them
+    /// using [Hasher::hash_chunk] and [Hasher::parent]. Implementations can
+    /// override this if they have a faster way to hash many chunks at once.
+    fn hash_subtree(&self, start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        if data.len() <= CHUNK_LEN {
+            self.hash_chunk(start_chunk, data, is_root)
+        } else {
+            let chunks = data.len() / CHUNK_LEN + (data.len() % CHUNK_LEN != 0) as usize;
+            let chunks = chunks.next_power_of_two();
+            let mid = chunks / 2;
+            let mid_bytes = mid * CHUNK_LEN;
+            let left = self.hash_subtree(start_chunk, &data[..mid_bytes], false);
+            let right = self.hash_subtree(start_chunk + mid as u64, &data[mid_bytes..], false);
+            self.parent(&left, &right, is_root)
+        }
+    }
+}
+
+impl<H: Hasher + ?Sized> Hasher for &H {
+    fn hash_chunk(&self, chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        (**self).hash_chunk(chunk, data, is_root)
+    }
+
+    fn parent(&self, left: &blake3::Hash, right: &blake3::Hash, is_root: bool) -> blake3::Hash {
+        (**self).parent(left, right, is_root)
+    }
+
+    fn hash_subtree(&self, start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        (**self).hash_subtree(start_chunk, data, is_root)
+    }
+}
+
+/// The default hasher, using the blake3 crate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn hash_chunk(&self, chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        let mut hasher = ChunkState::new(chunk);
+        hasher.update(data);
+        hasher.finalize(is_root)
+    }
+
+    fn parent(&self, left: &blake3::Hash, right: &blake3::Hash, is_root: bool) -> blake3::Hash {
+        parent_cv(left, right, is_root)
+    }
+
+    fn hash_subtree(&self, start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        crate::hash_subtree(start_chunk, data, is_root)
+    }
+}
@@ -1,21 +1,155 @@
 //! Errors when encoding or decoding
 //!
 //! These erros contain more specific information about e.g. where a hash mismatch occured
-use crate::{ChunkNum, TreeNode};
-use std::{fmt, io};
+//!
+//! The decoders yield [DecodeError] or [AnyDecodeError], so corruption can be told
+//! apart from a dropped connection by matching on the variant, instead of on the
+//! [io::ErrorKind] and the message. The not found variants carry the [TreeNode] or
+//! [ChunkNum] of the item that failed, the hash mismatch variants carry a
+//! [ParentMismatch] or [LeafMismatch] with the expected and actual hashes. All
+//! errors convert into [io::Error] for callers that only care about that.
+//!
+//! The error enums and the mismatch details are `#[non_exhaustive]`, so new variants
+//! and fields can be added without a breaking change.
+use crate::{blake3, BlockNum, BlockSize, ByteNum, ChunkNum, TreeNode};
+use std::{fmt, io, ops::Range};
+
+/// The location of a node in error messages
+///
+/// This includes the level and the chunk range, so a mismatch in the tree geometry,
+/// e.g. a different block size on the other side, can be told apart from corrupt data.
+struct NodeLocation(TreeNode);
+
+impl fmt::Display for NodeLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chunks = self.0.chunk_range();
+        write!(
+            f,
+            "node {} (level {}, chunks {}..{})",
+            self.0,
+            self.0.level(),
+            chunks.start,
+            chunks.end
+        )
+    }
+}
+
+/// The location of a leaf in error messages
+struct ChunkLocation(ChunkNum);
+
+impl fmt::Display for ChunkLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chunk {} (offset {})", self.0, self.0.to_bytes())
+    }
+}
+
+/// The blocks that a chunk range overlaps, for error messages
+fn block_range(chunks: &Range<ChunkNum>, block_size: BlockSize) -> Range<u64> {
+    let shift = block_size.0;
+    let start = chunks.start.0 >> shift;
+    let end = chunks.end.0.saturating_add((1 << shift) - 1) >> shift;
+    start..end
+}
+
+/// A hash pair that does not match the hash recorded for its node
+///
+/// The level and the blocks of the node tell whether the other side uses the same
+/// tree geometry, e.g. the same block size, or actually sent corrupt data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ParentMismatch {
+    /// The node of the hash pair
+    pub node: TreeNode,
+    /// The block size of the tree
+    pub block_size: BlockSize,
+    /// The hash recorded for the node, in its parent or as the root hash
+    pub expected: blake3::Hash,
+    /// The hash computed from the hash pair
+    pub actual: blake3::Hash,
+}
+
+impl fmt::Display for ParentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks = block_range(&self.node.chunk_range(), self.block_size);
+        write!(
+            f,
+            "node {} (level {}, blocks {}..{}): expected {}, actual {}",
+            self.node,
+            self.node.level(),
+            blocks.start,
+            blocks.end,
+            self.expected.to_hex(),
+            self.actual.to_hex()
+        )
+    }
+}
+
+/// Leaf data that does not match the hash recorded for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LeafMismatch {
+    /// The chunks of the leaf
+    pub chunks: Range<ChunkNum>,
+    /// True if the leaf is the left child of its parent
+    ///
+    /// A leaf that is the entire tree counts as a left child.
+    pub left: bool,
+    /// The block size of the tree
+    pub block_size: BlockSize,
+    /// The hash recorded for the leaf in its parent, or the root hash
+    pub expected: blake3::Hash,
+    /// The hash computed from the data
+    pub actual: blake3::Hash,
+}
+
+impl fmt::Display for LeafMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks = block_range(&self.chunks, self.block_size);
+        write!(
+            f,
+            "chunks {}..{} (blocks {}..{}, {} child): expected {}, actual {}",
+            self.chunks.start,
+            self.chunks.end,
+            blocks.start,
+            blocks.end,
+            if self.left { "left" } else { "right" },
+            self.expected.to_hex(),
+            self.actual.to_hex()
+        )
+    }
+}
 
 /// Error when starting to decode from a reader
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StartDecodeError {
     /// We got an EOF when reading the size, indicating that the remote end does not have the blob
     NotFound,
+    /// The size in the header is larger than the maximum size the decoder accepts
+    SizeTooLarge(ByteNum),
+    /// The block size announced by the encoder is not the block size of the decoder
+    BlockSizeMismatch {
+        /// The block size of the decoder
+        expected: BlockSize,
+        /// The block size announced in the stream
+        actual: BlockSize,
+    },
     /// A generic io error
     Io(io::Error),
 }
 
 impl fmt::Display for StartDecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            Self::NotFound => write!(f, "not found"),
+            Self::SizeTooLarge(size) => write!(f, "size {size} is too large"),
+            Self::BlockSizeMismatch { expected, actual } => write!(
+                f,
+                "block size mismatch: expected {}, got {}",
+                expected.0, actual.0
+            ),
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
     }
 }
 
@@ -34,6 +168,9 @@ impl From<StartDecodeError> for io::Error {
         match e {
             Io(e) => e,
             NotFound => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            SizeTooLarge(_) | BlockSizeMismatch { .. } => {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            }
         }
     }
 }
@@ -52,17 +189,27 @@ impl StartDecodeError {
 ///
 /// This is an union of [`StartDecodeError`] and [`DecodeError`] for convenience.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum AnyDecodeError {
     /// We got an EOF when reading the size, indicating that the remote end does not have the blob
     NotFound,
+    /// The size in the header is larger than the maximum size the decoder accepts
+    SizeTooLarge(ByteNum),
+    /// The block size announced by the encoder is not the block size of the decoder
+    BlockSizeMismatch {
+        /// The block size of the decoder
+        expected: BlockSize,
+        /// The block size announced in the stream
+        actual: BlockSize,
+    },
     /// We got an EOF while reading a parent hash pair, indicating that the remote end does not have the outboard
     ParentNotFound(TreeNode),
     /// We got an EOF while reading a chunk, indicating that the remote end does not have the data
     LeafNotFound(ChunkNum),
     /// The hash of a parent did not match the expected hash
-    ParentHashMismatch(TreeNode),
+    ParentHashMismatch(ParentMismatch),
     /// The hash of a leaf did not match the expected hash
-    LeafHashMismatch(ChunkNum),
+    LeafHashMismatch(LeafMismatch),
     /// There was an error reading from the underlying io
     Io(io::Error),
 }
@@ -71,8 +218,8 @@ impl From<DecodeError> for AnyDecodeError {
     fn from(e: DecodeError) -> Self {
         match e {
             DecodeError::Io(e) => Self::Io(e),
-            DecodeError::ParentHashMismatch(node) => Self::ParentHashMismatch(node),
-            DecodeError::LeafHashMismatch(chunk) => Self::LeafHashMismatch(chunk),
+            DecodeError::ParentHashMismatch(m) => Self::ParentHashMismatch(m),
+            DecodeError::LeafHashMismatch(m) => Self::LeafHashMismatch(m),
             DecodeError::LeafNotFound(chunk) => Self::LeafNotFound(chunk),
             DecodeError::ParentNotFound(node) => Self::ParentNotFound(node),
         }
@@ -84,13 +231,30 @@ impl From<StartDecodeError> for AnyDecodeError {
         match e {
             StartDecodeError::Io(e) => Self::Io(e),
             StartDecodeError::NotFound => Self::NotFound,
+            StartDecodeError::SizeTooLarge(size) => Self::SizeTooLarge(size),
+            StartDecodeError::BlockSizeMismatch { expected, actual } => {
+                Self::BlockSizeMismatch { expected, actual }
+            }
         }
     }
 }
 
 impl fmt::Display for AnyDecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            Self::NotFound => write!(f, "not found"),
+            Self::SizeTooLarge(size) => write!(f, "size {size} is too large"),
+            Self::BlockSizeMismatch { expected, actual } => write!(
+                f,
+                "block size mismatch: expected {}, got {}",
+                expected.0, actual.0
+            ),
+            Self::ParentNotFound(node) => write!(f, "parent not found at {}", NodeLocation(*node)),
+            Self::LeafNotFound(chunk) => write!(f, "leaf not found at {}", ChunkLocation(*chunk)),
+            Self::ParentHashMismatch(m) => write!(f, "parent hash mismatch at {m}"),
+            Self::LeafHashMismatch(m) => write!(f, "leaf hash mismatch at {m}"),
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
     }
 }
 
@@ -107,18 +271,12 @@ impl From<AnyDecodeError> for io::Error {
     fn from(e: AnyDecodeError) -> Self {
         match e {
             AnyDecodeError::Io(e) => e,
-            AnyDecodeError::ParentHashMismatch(node) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "parent hash mismatch (level {}, block {})",
-                    node.level(),
-                    node.mid().0
-                ),
-            ),
-            AnyDecodeError::LeafHashMismatch(chunk) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("leaf hash mismatch (offset {})", chunk.to_bytes().0),
-            ),
+            AnyDecodeError::ParentHashMismatch(_)
+            | AnyDecodeError::LeafHashMismatch(_)
+            | AnyDecodeError::SizeTooLarge(_)
+            | AnyDecodeError::BlockSizeMismatch { .. } => {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            }
             AnyDecodeError::LeafNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
             AnyDecodeError::ParentNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
             AnyDecodeError::NotFound => io::Error::new(io::ErrorKind::UnexpectedEof, e),
@@ -128,22 +286,29 @@ impl From<AnyDecodeError> for io::Error {
 
 /// Error when decoding from a reader, after the size has been read
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DecodeError {
     /// We got an EOF while reading a parent hash pair, indicating that the remote end does not have the outboard
     ParentNotFound(TreeNode),
     /// We got an EOF while reading a chunk, indicating that the remote end does not have the data
     LeafNotFound(ChunkNum),
     /// The hash of a parent did not match the expected hash
-    ParentHashMismatch(TreeNode),
+    ParentHashMismatch(ParentMismatch),
     /// The hash of a leaf did not match the expected hash
-    LeafHashMismatch(ChunkNum),
+    LeafHashMismatch(LeafMismatch),
     /// There was an error reading from the underlying io
     Io(io::Error),
 }
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            Self::ParentNotFound(node) => write!(f, "parent not found at {}", NodeLocation(*node)),
+            Self::LeafNotFound(chunk) => write!(f, "leaf not found at {}", ChunkLocation(*chunk)),
+            Self::ParentHashMismatch(m) => write!(f, "parent hash mismatch at {m}"),
+            Self::LeafHashMismatch(m) => write!(f, "leaf hash mismatch at {m}"),
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
     }
 }
 
@@ -160,18 +325,9 @@ impl From<DecodeError> for io::Error {
     fn from(e: DecodeError) -> Self {
         match e {
             DecodeError::Io(e) => e,
-            DecodeError::ParentHashMismatch(node) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "parent hash mismatch (level {}, block {})",
-                    node.level(),
-                    node.mid().0
-                ),
-            ),
-            DecodeError::LeafHashMismatch(chunk) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("leaf hash mismatch (offset {})", chunk.to_bytes().0),
-            ),
+            DecodeError::ParentHashMismatch(_) | DecodeError::LeafHashMismatch(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            }
             DecodeError::LeafNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
             DecodeError::ParentNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
         }
@@ -196,30 +352,98 @@ impl DecodeError {
     }
 }
 
+/// Error when decoding a combined encoding of an entire blob
+///
+/// Data is written in order and only after it has been verified, so everything
+/// before `written` is valid.
+#[derive(Debug)]
+pub struct DecodeCombinedError {
+    /// The number of verified bytes that were written before the error
+    pub written: u64,
+    /// The underlying error
+    pub cause: AnyDecodeError,
+}
+
+impl fmt::Display for DecodeCombinedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {} bytes", self.cause, self.written)
+    }
+}
+
+impl std::error::Error for DecodeCombinedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl From<DecodeCombinedError> for io::Error {
+    fn from(e: DecodeCombinedError) -> Self {
+        e.cause.into()
+    }
+}
+
 /// Error when encoding from outboard and data
 ///
 /// This can either be a io error or a more specific error like a hash mismatch
 /// or a size mismatch. If the remote end stops listening while we are writing,
 /// the error will indicate which parent or chunk we were writing at the time.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EncodeError {
     /// The hash of a parent did not match the expected hash
-    ParentHashMismatch(TreeNode),
+    ParentHashMismatch(ParentMismatch),
     /// The hash of a leaf did not match the expected hash
-    LeafHashMismatch(ChunkNum),
+    LeafHashMismatch(LeafMismatch),
     /// We got a ConnectionReset while writing a parent hash pair, indicating that the remote end stopped listening
     ParentWrite(TreeNode),
     /// We got a ConnectionReset while writing a chunk, indicating that the remote end stopped listening
     LeafWrite(ChunkNum),
     /// File size does not match size in outboard
     SizeMismatch,
+    /// The hash pair of a node does not match the hash recorded in its parent
+    ///
+    /// This is the first inconsistent node. The most likely cause is an outboard
+    /// in the wrong layout (pre order vs post order) or with a different block size.
+    InconsistentOutboard(TreeNode),
+    /// The outboard does not have the size implied by its tree
+    ///
+    /// The outboard is truncated or was computed with a different block size.
+    OutboardSizeMismatch {
+        /// The expected size of the outboard, including the 8 byte length
+        expected: u64,
+        /// The actual size of the outboard, including the 8 byte length
+        actual: u64,
+    },
+    /// The outboard does not have the hash pair of a node that is part of the response
+    ///
+    /// This happens for a partial outboard that does not cover the query.
+    MissingHashPair(TreeNode),
     /// There was an error reading from the underlying io
     Io(io::Error),
 }
 
 impl fmt::Display for EncodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            Self::ParentHashMismatch(m) => write!(f, "parent hash mismatch at {m}"),
+            Self::LeafHashMismatch(m) => write!(f, "leaf hash mismatch at {m}"),
+            Self::ParentWrite(node) => write!(f, "parent write failed at {}", NodeLocation(*node)),
+            Self::LeafWrite(chunk) => write!(f, "leaf write failed at {}", ChunkLocation(*chunk)),
+            Self::SizeMismatch => write!(f, "size mismatch"),
+            Self::InconsistentOutboard(node) => write!(
+                f,
+                "inconsistent outboard at {}, likely a layout or block size mismatch",
+                NodeLocation(*node)
+            ),
+            Self::OutboardSizeMismatch { expected, actual } => write!(
+                f,
+                "outboard size mismatch: expected {expected} bytes, got {actual}"
+            ),
+            Self::MissingHashPair(node) => {
+                write!(f, "missing hash pair at {}", NodeLocation(*node))
+            }
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
     }
 }
 
@@ -234,36 +458,19 @@ impl std::error::Error for EncodeError {
 
 impl From<EncodeError> for io::Error {
     fn from(e: EncodeError) -> Self {
-        match e {
-            EncodeError::Io(e) => e,
-            EncodeError::ParentHashMismatch(node) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "parent hash mismatch (level {}, block {})",
-                    node.level(),
-                    node.mid().0
-                ),
-            ),
-            EncodeError::LeafHashMismatch(chunk) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("leaf hash mismatch at {}", chunk.to_bytes().0),
-            ),
-            EncodeError::ParentWrite(node) => io::Error::new(
-                io::ErrorKind::ConnectionReset,
-                format!(
-                    "parent write failed (level {}, block {})",
-                    node.level(),
-                    node.mid().0
-                ),
-            ),
-            EncodeError::LeafWrite(chunk) => io::Error::new(
-                io::ErrorKind::ConnectionReset,
-                format!("leaf write failed at {}", chunk.to_bytes().0),
-            ),
-            EncodeError::SizeMismatch => {
-                io::Error::new(io::ErrorKind::InvalidData, "size mismatch")
+        let kind = match e {
+            EncodeError::Io(e) => return e,
+            EncodeError::ParentWrite(_) | EncodeError::LeafWrite(_) => {
+                io::ErrorKind::ConnectionReset
             }
-        }
+            EncodeError::ParentHashMismatch(_)
+            | EncodeError::LeafHashMismatch(_)
+            | EncodeError::SizeMismatch
+            | EncodeError::InconsistentOutboard(_)
+            | EncodeError::OutboardSizeMismatch { .. } => io::ErrorKind::InvalidData,
+            EncodeError::MissingHashPair(_) => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, e.to_string())
     }
 }
 
@@ -274,6 +481,16 @@ impl From<io::Error> for EncodeError {
 }
 
 impl EncodeError {
+    /// Convert an error from validating the outboard while encoding
+    pub(crate) fn from_verify(e: DecodeError) -> Self {
+        match e {
+            DecodeError::ParentHashMismatch(m) => Self::ParentHashMismatch(m),
+            DecodeError::LeafHashMismatch(m) => Self::LeafHashMismatch(m),
+            e => Self::Io(e.into()),
+        }
+    }
+
+    #[cfg(feature = "tokio_fsm")]
     pub(crate) fn maybe_parent_write(e: io::Error, node: TreeNode) -> Self {
         if e.kind() == io::ErrorKind::ConnectionReset {
             Self::ParentWrite(node)
@@ -282,6 +499,7 @@ impl EncodeError {
         }
     }
 
+    #[cfg(feature = "tokio_fsm")]
     pub(crate) fn maybe_leaf_write(e: io::Error, chunk: ChunkNum) -> Self {
         if e.kind() == io::ErrorKind::ConnectionReset {
             Self::LeafWrite(chunk)
@@ -290,3 +508,82 @@ impl EncodeError {
         }
     }
 }
+
+/// Error when verifying a single block against an outboard
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BlockVerifyError {
+    /// The block is not within the tree
+    BlockOutOfRange(BlockNum),
+    /// The data does not have the size of the block
+    SizeMismatch(BlockNum),
+    /// The hash of the block did not match the hash in the outboard
+    HashMismatch(BlockNum),
+    /// There was an error reading from the outboard
+    Io(io::Error),
+}
+
+impl fmt::Display for BlockVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlockOutOfRange(block) => write!(f, "block {} is out of range", block.0),
+            Self::SizeMismatch(block) => write!(f, "size mismatch for block {}", block.0),
+            Self::HashMismatch(block) => write!(f, "block hash mismatch (block {})", block.0),
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BlockVerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlockVerifyError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<BlockVerifyError> for io::Error {
+    fn from(e: BlockVerifyError) -> Self {
+        match e {
+            BlockVerifyError::Io(e) => e,
+            BlockVerifyError::BlockOutOfRange(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            BlockVerifyError::SizeMismatch(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            BlockVerifyError::HashMismatch(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// The operation was cancelled by a progress callback
+///
+/// Functions that return an [io::Error] return this as the inner error of an error
+/// of kind [io::ErrorKind::Other]. Use [Cancelled::is_cancelled] to check for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// True if the io error is a [Cancelled] error
+    pub fn is_cancelled(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(e: Cancelled) -> Self {
+        io::Error::other(e)
+    }
+}
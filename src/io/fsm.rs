@@ -5,25 +5,30 @@
 //!
 //! This makes them occasionally a bit verbose to use, but allows being generic
 //! without having to box the futures.
-use std::{io, result};
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    result,
+};
 
 use crate::{
-    blake3, hash_subtree,
+    blake3,
+    hasher::{Blake3Hasher, Hasher},
     iter::ResponseIter,
-    rec::{encode_selected_rec, truncate_ranges, truncate_ranges_owned},
-    ChunkRanges, ChunkRangesRef,
+    ranges::{as_chunk_ranges_ref, ChunkRangeSet},
+    rec::{encode_selected_rec_with_hasher, truncate_ranges, truncate_ranges_owned},
+    ChunkRanges,
 };
 use blake3::guts::parent_cv;
 use bytes::{Bytes, BytesMut};
-use futures::{future::LocalBoxFuture, Future, FutureExt};
+use futures::{future::LocalBoxFuture, Future, FutureExt, Stream, StreamExt};
 use iroh_io::AsyncStreamWriter;
-use smallvec::SmallVec;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     io::{
         error::EncodeError,
-        outboard::{PostOrderOutboard, PreOrderOutboard},
+        outboard::{combine_hash_pair, PostOrderOutboard, PreOrderOutboard},
         Leaf, Parent,
     },
     iter::BaoChunk,
@@ -31,7 +36,10 @@ use crate::{
 };
 pub use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
 
-use super::{DecodeError, StartDecodeError};
+use super::{
+    check_block_size, check_size, check_stream_block_size, encode_leaf, AnyDecodeError,
+    DecodeError, StartDecodeError, VerifyStack,
+};
 
 /// An item of bao content
 ///
@@ -94,6 +102,13 @@ pub trait Outboard {
     /// This takes a &mut self not because it mutates the outboard (it doesn't),
     /// but to ensure that there is only one outstanding load at a time.
     fn load(&mut self, node: TreeNode) -> Self::LoadFuture<'_>;
+    /// The size of the outboard in bytes, including the 8 byte length, if known
+    ///
+    /// This is used to validate the outboard before encoding. The default
+    /// implementation returns None, which skips the check.
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
 }
 
 /// A mutable outboard.
@@ -140,6 +155,10 @@ impl<'b, O: Outboard> Outboard for &'b mut O {
     fn load(&mut self, node: TreeNode) -> Self::LoadFuture<'_> {
         (**self).load(node)
     }
+
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        (**self).outboard_size()
+    }
 }
 
 impl<R: AsyncSliceReader> Outboard for PreOrderOutboard<R> {
@@ -260,36 +279,58 @@ pub(crate) fn parse_hash_pair(buf: Bytes) -> io::Result<(blake3::Hash, blake3::H
     Ok((l_hash, r_hash))
 }
 
-pub(crate) fn combine_hash_pair(l: &blake3::Hash, r: &blake3::Hash) -> [u8; 64] {
-    let mut res = [0u8; 64];
-    let lb: &mut [u8; 32] = (&mut res[0..32]).try_into().unwrap();
-    *lb = *l.as_bytes();
-    let rb: &mut [u8; 32] = (&mut res[32..]).try_into().unwrap();
-    *rb = *r.as_bytes();
-    res
-}
-
 /// Response decoder state machine, at the start of a stream
+///
+/// Hashes are computed with `H`, which defaults to blake3, see [Hasher].
 #[derive(Debug)]
-pub struct ResponseDecoderStart<R> {
+pub struct ResponseDecoderStart<R, H = Blake3Hasher> {
     ranges: ChunkRanges,
     block_size: BlockSize,
     hash: blake3::Hash,
     encoded: R,
+    max_size: ByteNum,
+    hasher: H,
 }
 
-impl<'a, R: AsyncRead + Unpin> ResponseDecoderStart<R> {
+impl<R: AsyncRead + Unpin> ResponseDecoderStart<R> {
     /// Create a new response decoder state machine, at the start of a stream
     /// where you don't yet know the size.
     pub fn new(hash: blake3::Hash, ranges: ChunkRanges, block_size: BlockSize, encoded: R) -> Self {
+        Self::new_with_hasher(hash, ranges, block_size, encoded, Blake3Hasher)
+    }
+}
+
+impl<R: AsyncRead + Unpin, H: Hasher> ResponseDecoderStart<R, H> {
+    /// Create a new response decoder state machine, using a custom [Hasher]
+    ///
+    /// This is the same as [ResponseDecoderStart::new], but computes all hashes with
+    /// `hasher`.
+    pub fn new_with_hasher(
+        hash: blake3::Hash,
+        ranges: ChunkRanges,
+        block_size: BlockSize,
+        encoded: R,
+        hasher: H,
+    ) -> Self {
         Self {
             ranges,
             block_size,
             hash,
             encoded,
+            max_size: super::MAX_SIZE,
+            hasher,
         }
     }
 
+    /// Set the largest size that is accepted in the header
+    ///
+    /// A larger size fails with [StartDecodeError::SizeTooLarge] before anything is
+    /// allocated for it. The default is [super::MAX_SIZE].
+    pub fn with_max_size(mut self, max_size: ByteNum) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
     /// Immediately finish decoding the stream, returning the underlying reader
     pub fn finish(self) -> R {
         self.encoded
@@ -297,25 +338,30 @@ impl<'a, R: AsyncRead + Unpin> ResponseDecoderStart<R> {
 
     /// Read the size and go into the next state
     ///
-    /// The only thing that can go wrong here is an io error when reading the size.
+    /// The only things that can go wrong here are an io error when reading the size,
+    /// and a size that is too large.
     pub async fn next(
         self,
-    ) -> std::result::Result<(ResponseDecoderReading<R>, u64), StartDecodeError> {
+    ) -> std::result::Result<(ResponseDecoderReading<R, H>, u64), StartDecodeError> {
         let Self {
             ranges,
             block_size,
             hash,
             mut encoded,
+            max_size,
+            hasher,
         } = self;
+        check_block_size(block_size).map_err(StartDecodeError::Io)?;
         let size = ByteNum(
             encoded
                 .read_u64_le()
                 .await
                 .map_err(StartDecodeError::maybe_not_found)?,
         );
+        check_size(size, max_size)?;
         let tree = BaoTree::new(size, block_size);
         let state = ResponseDecoderReading(Box::new(ResponseDecoderReadingInner::new(
-            tree, hash, ranges, encoded,
+            tree, hash, ranges, encoded, hasher,
         )));
         Ok((state, size.0))
     }
@@ -332,39 +378,45 @@ impl<'a, R: AsyncRead + Unpin> ResponseDecoderStart<R> {
 }
 
 #[derive(Debug)]
-struct ResponseDecoderReadingInner<R> {
+struct ResponseDecoderReadingInner<R, H> {
     iter: ResponseIter,
-    stack: SmallVec<[blake3::Hash; 10]>,
+    root: blake3::Hash,
+    stack: VerifyStack<H>,
     encoded: R,
     buf: BytesMut,
+    verified: ChunkRanges,
 }
 
-impl<R> ResponseDecoderReadingInner<R> {
-    fn new(tree: BaoTree, hash: blake3::Hash, ranges: ChunkRanges, encoded: R) -> Self {
+impl<R, H: Hasher> ResponseDecoderReadingInner<R, H> {
+    fn new(tree: BaoTree, hash: blake3::Hash, ranges: ChunkRanges, encoded: R, hasher: H) -> Self {
         // now that we know the size, we can canonicalize the ranges
         let ranges = truncate_ranges_owned(ranges, tree.size());
-        let mut res = Self {
+        Self {
             iter: ResponseIter::new(tree, ranges),
-            stack: SmallVec::new(),
+            root: hash,
+            stack: VerifyStack::new(hash, tree.block_size, hasher),
             encoded,
             buf: BytesMut::with_capacity(tree.chunk_group_bytes().to_usize()),
-        };
-        res.stack.push(hash);
-        res
+            verified: ChunkRanges::empty(),
+        }
     }
 }
 
 /// Response decoder state machine, after reading the size
+///
+/// Like [super::sync::DecodeResponseIter], this yields the data of leaves as [Bytes]
+/// split off a buffer of the chunk group size, which is reused if the data of a leaf is
+/// dropped before the next leaf is read.
 #[derive(Debug)]
-pub struct ResponseDecoderReading<R>(Box<ResponseDecoderReadingInner<R>>);
+pub struct ResponseDecoderReading<R, H = Blake3Hasher>(Box<ResponseDecoderReadingInner<R, H>>);
 
 /// Next type for ResponseDecoderReading.
 #[derive(Debug)]
-pub enum ResponseDecoderReadingNext<R> {
+pub enum ResponseDecoderReadingNext<R, H = Blake3Hasher> {
     /// One more item, and you get back the state machine in the next state
     More(
         (
-            ResponseDecoderReading<R>,
+            ResponseDecoderReading<R, H>,
             std::result::Result<BaoContentItem, DecodeError>,
         ),
     ),
@@ -375,20 +427,35 @@ pub enum ResponseDecoderReadingNext<R> {
 impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
     /// Create a new response decoder state machine, when you have already read the size.
     ///
-    /// The size as well as the chunk size is given in the `tree` parameter.
+    /// The size as well as the chunk size is given in the `tree` parameter. This can also
+    /// be used to decode a stream without the size prefix, as written by
+    /// [super::sync::encode_ranges_headerless], if the size is known from somewhere else.
+    /// The ranges are canonicalized for the size, the same way as after reading the size.
     pub fn new(hash: blake3::Hash, ranges: ChunkRanges, tree: BaoTree, encoded: R) -> Self {
-        let mut stack = SmallVec::new();
-        stack.push(hash);
-        Self(Box::new(ResponseDecoderReadingInner {
-            iter: ResponseIter::new(tree, ranges),
-            stack,
-            encoded,
-            buf: BytesMut::new(),
-        }))
+        Self::new_with_hasher(hash, ranges, tree, encoded, Blake3Hasher)
+    }
+}
+
+impl<R: AsyncRead + Unpin, H: Hasher> ResponseDecoderReading<R, H> {
+    /// Create a new response decoder state machine, when you have already read the size,
+    /// using a custom [Hasher]
+    ///
+    /// This is the same as [ResponseDecoderReading::new], but computes all hashes with
+    /// `hasher`.
+    pub fn new_with_hasher(
+        hash: blake3::Hash,
+        ranges: ChunkRanges,
+        tree: BaoTree,
+        encoded: R,
+        hasher: H,
+    ) -> Self {
+        Self(Box::new(ResponseDecoderReadingInner::new(
+            tree, hash, ranges, encoded, hasher,
+        )))
     }
 
     /// Proceed to the next state by reading the next chunk from the stream.
-    pub async fn next(mut self) -> ResponseDecoderReadingNext<R> {
+    pub async fn next(mut self) -> ResponseDecoderReadingNext<R, H> {
         if let Some(chunk) = self.0.iter.next() {
             let item = self.next0(chunk).await;
             ResponseDecoderReadingNext::More((self, item))
@@ -409,7 +476,14 @@ impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
 
     /// Hash of the blob we are currently getting
     pub fn hash(&self) -> &blake3::Hash {
-        &self.0.stack[0]
+        &self.0.root
+    }
+
+    /// The chunk ranges of the leaves that have been verified so far
+    ///
+    /// See [super::sync::DecodeResponseIter::verified_ranges].
+    pub fn verified_ranges(&self) -> &ChunkRanges {
+        &self.0.verified
     }
 
     async fn next0(&mut self, chunk: BaoChunk) -> std::result::Result<BaoContentItem, DecodeError> {
@@ -427,22 +501,9 @@ impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
                     .read_exact(&mut buf)
                     .await
                     .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
-                let pair @ (l_hash, r_hash) = read_parent(&buf);
-                let parent_hash = this.stack.pop().unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
-                // Push the children in reverse order so they are popped in the correct order
-                // only push right if the range intersects with the right child
-                if right {
-                    this.stack.push(r_hash);
-                }
-                // only push left if the range intersects with the left child
-                if left {
-                    this.stack.push(l_hash);
-                }
-                // Validate after pushing the children so that we could in principle continue
-                if parent_hash != actual {
-                    return Err(DecodeError::ParentHashMismatch(node));
-                }
+                let pair = read_parent(&buf);
+                this.stack
+                    .verify_parent(node, is_root, left, right, &pair)?;
                 Parent { pair, node }.into()
             }
             BaoChunk::Leaf {
@@ -458,11 +519,11 @@ impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
                     .read_exact(&mut this.buf)
                     .await
                     .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
-                let leaf_hash = this.stack.pop().unwrap();
-                let actual = hash_subtree(start_chunk.0, &this.buf, is_root);
-                if leaf_hash != actual {
-                    return Err(DecodeError::LeafHashMismatch(start_chunk));
-                }
+                let tree_size = this.iter.tree().size;
+                this.stack
+                    .verify_leaf(start_chunk, &this.buf, is_root, tree_size)?;
+                let end = start_chunk + ByteNum(size as u64).chunks();
+                this.verified |= crate::ranges::chunk_range(start_chunk, end);
                 Leaf {
                     offset: start_chunk.to_bytes(),
                     data: self.0.buf.split().freeze(),
@@ -473,6 +534,37 @@ impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
     }
 }
 
+/// Announce the block size of the encoder in front of an encoded stream
+///
+/// This is the async version of [super::sync::write_block_size].
+pub async fn write_block_size<W: AsyncStreamWriter>(
+    block_size: BlockSize,
+    encoded: &mut W,
+) -> io::Result<()> {
+    check_block_size(block_size)?;
+    encoded.write(&[block_size.0]).await
+}
+
+/// Read the block size written by [write_block_size] and check it against the block
+/// size of the decoder
+///
+/// This is the async version of [super::sync::read_block_size].
+pub async fn read_block_size<R: AsyncRead + Unpin>(
+    encoded: &mut R,
+    block_size: BlockSize,
+) -> result::Result<(), StartDecodeError> {
+    let actual = encoded
+        .read_u8()
+        .await
+        .map_err(StartDecodeError::maybe_not_found)?;
+    check_stream_block_size(actual, block_size)
+}
+
+/// Check that the outboard has the size implied by its tree, if the size is known
+fn check_outboard_size(outboard: &impl Outboard) -> result::Result<(), EncodeError> {
+    super::check_outboard_size(outboard.tree(), outboard.outboard_size()?)
+}
+
 /// Encode ranges relevant to a query from a reader and outboard to a writer
 ///
 /// This will not validate on writing, so data corruption will be detected on reading
@@ -480,25 +572,60 @@ impl<R: AsyncRead + Unpin> ResponseDecoderReading<R> {
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
-pub async fn encode_ranges<D, O, W>(
+///
+/// The traversal of the tree is synchronous. Only the hash pair loads from the
+/// [Outboard], the data reads and the writes are awaited, one at a time, so the
+/// outboard can live in a remote store and be fetched lazily per node.
+pub async fn encode_ranges<D, O, W, R>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+    R: ChunkRangeSet + ?Sized,
+{
+    encode_ranges_with_min_level(data, outboard, ranges, 0, encoded).await
+}
+
+/// Encode ranges relevant to a query, sending fully requested subtrees below
+/// `min_level` as data
+///
+/// This is the async version of [super::sync::encode_ranges_with_min_level]. The
+/// result is the same as encoding with an outboard for the block size
+/// `max(block_size, min_level)`, so it is decoded by passing that block size to the
+/// decoders. With a `min_level` of 0, this is the same as [encode_ranges].
+pub async fn encode_ranges_with_min_level<D, O, W, R>(
     mut data: D,
     mut outboard: O,
-    ranges: &ChunkRangesRef,
+    ranges: &R,
+    min_level: u8,
     encoded: W,
 ) -> result::Result<(), EncodeError>
 where
     D: AsyncSliceReader,
     O: Outboard,
     W: AsyncStreamWriter,
+    R: ChunkRangeSet + ?Sized,
 {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
     let mut encoded = encoded;
+    let mut out_buf = Vec::new();
     let tree = outboard.tree();
+    let ranges = truncate_ranges(ranges, tree.size());
     // write header
     encoded.write(tree.size.0.to_le_bytes().as_slice()).await?;
-    for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
+    for item in tree.ranges_pre_order_chunks_iter_ref(ranges, min_level) {
         match item {
             BaoChunk::Parent { node, .. } => {
-                let (l_hash, r_hash) = outboard.load(node).await?.unwrap();
+                let (l_hash, r_hash) = outboard
+                    .load(node)
+                    .await?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
                 let pair = combine_hash_pair(&l_hash, &r_hash);
                 encoded
                     .write(&pair)
@@ -506,17 +633,106 @@ where
                     .map_err(|e| EncodeError::maybe_parent_write(e, node))?;
             }
             BaoChunk::Leaf {
-                start_chunk, size, ..
+                start_chunk,
+                size,
+                ranges,
+                ..
             } => {
                 let start = start_chunk.to_bytes();
                 let bytes = data.read_at(start.0, size).await?;
+                let to_write =
+                    encode_leaf(start_chunk, &bytes, ranges, tree.block_size, &mut out_buf);
+                encoded
+                    .write(to_write)
+                    .await
+                    .map_err(|e| EncodeError::maybe_leaf_write(e, start_chunk))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encode ranges relevant to a query from a tokio reader and outboard to a tokio writer
+///
+/// This is the same as [encode_ranges], but reads the data from an [AsyncRead] +
+/// [AsyncSeek] source and writes to an [AsyncWrite]. Only the leaves that are part
+/// of the query are read. To not starve other tasks when encoding large ranges from
+/// sources that are always ready, this yields to the executor regularly.
+///
+/// Like the sync encoders, this fails with [EncodeError::OutboardSizeMismatch] before
+/// writing anything if the outboard has the wrong size, and with
+/// [EncodeError::MissingHashPair] if the outboard does not have a needed hash pair.
+///
+/// Dropping the future before completion is safe. All state is local to the
+/// future, and the reader is always seeked before the first read, so the inputs
+/// can be reused. The writer will contain a partial encoding.
+pub async fn encode_ranges_tokio<D, O, W, R>(
+    mut data: D,
+    mut outboard: O,
+    ranges: &R,
+    mut encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncRead + AsyncSeek + Unpin,
+    O: Outboard,
+    W: AsyncWrite + Unpin,
+    R: ChunkRangeSet + ?Sized,
+{
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    // number of bytes to write before yielding to the executor
+    const YIELD_BYTES: u64 = 1024 * 1024;
+    let tree = outboard.tree();
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    // current position of the reader, if known
+    let mut position = None;
+    let mut since_yield = 0u64;
+    let ranges = truncate_ranges(ranges, tree.size());
+    // write header
+    encoded
+        .write_all(tree.size.0.to_le_bytes().as_slice())
+        .await?;
+    for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
+        match item {
+            BaoChunk::Parent { node, .. } => {
+                let (l_hash, r_hash) = outboard
+                    .load(node)
+                    .await?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                let pair = combine_hash_pair(&l_hash, &r_hash);
                 encoded
-                    .write(&bytes)
+                    .write_all(&pair)
+                    .await
+                    .map_err(|e| EncodeError::maybe_parent_write(e, node))?;
+            }
+            BaoChunk::Leaf {
+                start_chunk,
+                size,
+                ranges,
+                ..
+            } => {
+                let start = start_chunk.to_bytes();
+                if position != Some(start.0) {
+                    data.seek(SeekFrom::Start(start.0)).await?;
+                }
+                let buf = &mut buffer[..size];
+                data.read_exact(buf).await?;
+                position = Some(start.0 + size as u64);
+                let to_write = encode_leaf(start_chunk, buf, ranges, tree.block_size, &mut out_buf);
+                encoded
+                    .write_all(to_write)
                     .await
                     .map_err(|e| EncodeError::maybe_leaf_write(e, start_chunk))?;
+                since_yield += size as u64;
+                if since_yield >= YIELD_BYTES {
+                    since_yield = 0;
+                    tokio::task::yield_now().await;
+                }
             }
         }
     }
+    encoded.flush().await?;
     Ok(())
 }
 
@@ -527,24 +743,47 @@ where
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
-pub async fn encode_ranges_validated<D, O, W>(
+pub async fn encode_ranges_validated<D, O, W, R>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+    R: ChunkRangeSet + ?Sized,
+{
+    encode_ranges_validated_with_hasher(data, outboard, ranges, encoded, Blake3Hasher).await
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer, using a
+/// custom [Hasher]
+///
+/// This is the same as [encode_ranges_validated], but computes all hashes with
+/// `hasher`, like [super::sync::encode_ranges_validated_with_hasher].
+pub async fn encode_ranges_validated_with_hasher<D, O, W, R>(
     mut data: D,
     mut outboard: O,
-    ranges: &ChunkRangesRef,
+    ranges: &R,
     encoded: W,
+    hasher: impl Hasher,
 ) -> result::Result<(), EncodeError>
 where
     D: AsyncSliceReader,
     O: Outboard,
     W: AsyncStreamWriter,
+    R: ChunkRangeSet + ?Sized,
 {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
     // buffer for writing incomplete subtrees.
     // for queries that don't have incomplete subtrees, this will never be used.
     let mut out_buf = Vec::new();
-    let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
-    stack.push(outboard.root());
-    let mut encoded = encoded;
     let tree = outboard.tree();
+    let mut stack = VerifyStack::new(outboard.root(), tree.block_size, &hasher);
+    let mut encoded = encoded;
     let ranges = truncate_ranges(ranges, tree.size());
     // write header
     encoded.write(tree.size.0.to_le_bytes().as_slice()).await?;
@@ -557,18 +796,13 @@ where
                 node,
                 ..
             } => {
-                let (l_hash, r_hash) = outboard.load(node).await?.unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
-                let expected = stack.pop().unwrap();
-                if actual != expected {
-                    return Err(EncodeError::ParentHashMismatch(node));
-                }
-                if right {
-                    stack.push(r_hash);
-                }
-                if left {
-                    stack.push(l_hash);
-                }
+                let pair @ (l_hash, r_hash) = outboard
+                    .load(node)
+                    .await?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                stack
+                    .verify_parent(node, is_root, left, right, &pair)
+                    .map_err(EncodeError::from_verify)?;
                 let pair = combine_hash_pair(&l_hash, &r_hash);
                 encoded
                     .write(&pair)
@@ -582,7 +816,6 @@ where
                 ranges,
                 ..
             } => {
-                let expected = stack.pop().unwrap();
                 let start = start_chunk.to_bytes();
                 let bytes = data.read_at(start.0, size).await?;
                 let (actual, to_write) = if !ranges.is_all() {
@@ -591,7 +824,8 @@ where
                     // write into an out buffer to ensure we detect mismatches
                     // before writing to the output.
                     out_buf.clear();
-                    let actual = encode_selected_rec(
+                    let actual = encode_selected_rec_with_hasher(
+                        &hasher,
                         start_chunk,
                         &bytes,
                         is_root,
@@ -602,12 +836,12 @@ where
                     );
                     (actual, &out_buf[..])
                 } else {
-                    let actual = hash_subtree(start_chunk.0, &bytes, is_root);
+                    let actual = hasher.hash_subtree(start_chunk.0, &bytes, is_root);
                     (actual, &bytes[..])
                 };
-                if actual != expected {
-                    return Err(EncodeError::LeafHashMismatch(start_chunk));
-                }
+                stack
+                    .verify_leaf_hash(start_chunk, size, is_root, tree.size, actual)
+                    .map_err(EncodeError::from_verify)?;
                 encoded
                     .write(to_write)
                     .await
@@ -622,6 +856,9 @@ where
 ///
 /// If you do not want to update an outboard, use [super::outboard::EmptyOutboard] as
 /// the outboard.
+///
+/// Returns the size of the blob as given in the encoded stream, and the outboard if
+/// any hash pairs were received, like [super::sync::decode_response_into].
 pub async fn decode_response_into<R, O, W, F, Fut>(
     root: blake3::Hash,
     block_size: BlockSize,
@@ -629,7 +866,7 @@ pub async fn decode_response_into<R, O, W, F, Fut>(
     encoded: R,
     create: F,
     mut target: W,
-) -> io::Result<Option<O>>
+) -> io::Result<(ByteNum, Option<O>)>
 where
     O: OutboardMut,
     R: AsyncRead + Unpin,
@@ -638,7 +875,7 @@ where
     Fut: Future<Output = io::Result<O>>,
 {
     let start = ResponseDecoderStart::new(root, ranges, block_size, encoded);
-    let (mut reading, _size) = start.next().await?;
+    let (mut reading, size) = start.next().await?;
     let mut outboard = None;
     let mut create = Some(create);
     loop {
@@ -667,8 +904,119 @@ where
             }
         }
     }
-    Ok(outboard)
+    Ok((ByteNum(size), outboard))
 }
+
+/// Decode a response into a seekable writer, as a stream of the byte ranges written.
+///
+/// This writes the leaves of [decode_ranges_tokio] to `target`, and yields each range
+/// after its data has been written.
+/// Hash pairs are verified, but not stored. Like the sync decoder, this stops at the
+/// first error, so the stream ends after yielding an error.
+///
+/// The target is only seeked if a range does not start where the previous one ended,
+/// and it is flushed after the last range, so e.g. a [tokio::fs::File] can be used
+/// directly.
+pub fn decode_ranges_stream<R, W>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: ChunkRanges,
+    encoded: R,
+    target: W,
+) -> impl Stream<Item = result::Result<Range<ByteNum>, AnyDecodeError>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + AsyncSeek + Unpin,
+{
+    let leaves = Box::pin(decode_ranges_tokio(root, block_size, ranges, encoded));
+    // the leaves, the target and the position of the target if known, or None when done
+    futures::stream::unfold(Some((leaves, target, None)), |state| async move {
+        let (mut leaves, mut target, position) = state?;
+        let (offset, data) = match leaves.next().await {
+            Some(Ok(leaf)) => leaf,
+            Some(Err(cause)) => return Some((Err(cause), None)),
+            None => {
+                return match target.flush().await {
+                    Ok(()) => None,
+                    Err(cause) => Some((Err(AnyDecodeError::Io(cause)), None)),
+                };
+            }
+        };
+        if position != Some(offset) {
+            if let Err(cause) = target.seek(SeekFrom::Start(offset.0)).await {
+                return Some((Err(AnyDecodeError::Io(cause)), None));
+            }
+        }
+        if let Err(cause) = target.write_all(&data).await {
+            return Some((Err(AnyDecodeError::Io(cause)), None));
+        }
+        let range = offset..offset + ByteNum(data.len() as u64);
+        let position = Some(range.end);
+        Some((Ok(range), Some((leaves, target, position))))
+    })
+}
+
+/// Decode a response as a stream of verified leaves.
+///
+/// Each item is the offset and the data of a leaf, yielded as soon as it has been
+/// read and verified, without a target to write to. Hash pairs are verified, but not
+/// yielded. Like [decode_ranges_stream], the stream ends after yielding an error.
+///
+/// Only the bytes of the response are read from `encoded`, so it is fine if the
+/// reader does not end after the response. All state is owned by the stream, so
+/// dropping it at any point just stops decoding.
+pub fn decode_ranges_tokio<R>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: ChunkRanges,
+    encoded: R,
+) -> impl Stream<Item = result::Result<(ByteNum, Bytes), AnyDecodeError>>
+where
+    R: AsyncRead + Unpin,
+{
+    enum State<R> {
+        Start(ResponseDecoderStart<R>),
+        Reading(ResponseDecoderReading<R>),
+        Done,
+    }
+
+    async fn next_leaf<R>(
+        mut reading: ResponseDecoderReading<R>,
+    ) -> Option<(result::Result<(ByteNum, Bytes), AnyDecodeError>, State<R>)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            let item = match reading.next().await {
+                ResponseDecoderReadingNext::Done(_reader) => return None,
+                ResponseDecoderReadingNext::More((next, item)) => {
+                    reading = next;
+                    item
+                }
+            };
+            match item {
+                Ok(BaoContentItem::Parent(_)) => {}
+                Ok(BaoContentItem::Leaf(Leaf { offset, data })) => {
+                    return Some((Ok((offset, data)), State::Reading(reading)));
+                }
+                Err(cause) => return Some((Err(cause.into()), State::Done)),
+            }
+        }
+    }
+
+    let start = ResponseDecoderStart::new(root, ranges, block_size, encoded);
+    futures::stream::unfold(State::Start(start), |state| async move {
+        match state {
+            State::Start(start) => match start.next().await {
+                Ok((reading, _size)) => next_leaf(reading).await,
+                Err(cause) => Some((Err(cause.into()), State::Done)),
+            },
+            State::Reading(reading) => next_leaf(reading).await,
+            State::Done => None,
+        }
+    })
+}
+
 fn read_parent(buf: &[u8]) -> (blake3::Hash, blake3::Hash) {
     let l_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[..32]).unwrap());
     let r_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[32..64]).unwrap());
@@ -0,0 +1,226 @@
+//! An outboard for a blob that is still being appended to
+//!
+//! [GrowableBlob] incrementally hashes data as it is appended, and can at any time
+//! produce a [GrowableBlobSnapshot], an immutable view of the tree for all data
+//! appended up to that point.
+//!
+//! Hash pairs of nodes that can no longer change are stored once and shared between
+//! the blob and all its snapshots. Only the hash pairs along the right edge of the
+//! tree, which change on every append, are computed for each snapshot.
+use std::{
+    io::{self, Write},
+    result,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use blake3::guts::parent_cv;
+use smallvec::SmallVec;
+
+use super::{
+    outboard::parse_hash_pair,
+    sync::{encode_ranges_validated, Outboard, ReadAt, Size},
+    EncodeError,
+};
+use crate::{blake3, hash_subtree, BaoTree, BlockSize, ByteNum, ChunkRangesRef, TreeNode};
+
+/// A blob that is still being appended to, together with its outboard.
+///
+/// The blob does not store the data, except for the last block. It is up to the
+/// caller to store the data, and to make sure that it is available before it is
+/// appended here.
+///
+/// Appending and taking snapshots can be done concurrently from different threads.
+#[derive(Debug)]
+pub struct GrowableBlob {
+    block_size: BlockSize,
+    /// hash pairs of all nodes that do not contain the last block, in post order
+    ///
+    /// These never change once written, so they are shared with all snapshots.
+    stable: Arc<RwLock<Vec<u8>>>,
+    /// state of the incremental hasher
+    state: Mutex<HasherState>,
+}
+
+#[derive(Debug, Default)]
+struct HasherState {
+    /// total number of bytes appended
+    size: u64,
+    /// hashes of complete subtrees left of the last block, with their size in blocks
+    stack: SmallVec<[(blake3::Hash, u64); 10]>,
+    /// the last block
+    ///
+    /// This only gets hashed once more data arrives, since we don't know if it is
+    /// the root before that.
+    tail: Vec<u8>,
+}
+
+impl GrowableBlob {
+    /// Create a new, empty blob with the given block size.
+    pub fn new(block_size: BlockSize) -> Self {
+        Self {
+            block_size,
+            stable: Default::default(),
+            state: Default::default(),
+        }
+    }
+
+    /// The block size of the tree
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// The number of bytes appended so far
+    pub fn size(&self) -> ByteNum {
+        ByteNum(self.state.lock().unwrap().size)
+    }
+
+    /// Append data to the blob.
+    pub fn append(&self, mut data: &[u8]) {
+        let block_bytes = self.block_size.bytes();
+        let mut state = self.state.lock().unwrap();
+        while !data.is_empty() {
+            if state.tail.len() == block_bytes {
+                // there is more data, so the tail is not the last block
+                self.push_tail(&mut state);
+            }
+            let n = (block_bytes - state.tail.len()).min(data.len());
+            state.tail.extend_from_slice(&data[..n]);
+            state.size += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    /// Hash the full tail block and merge it with complete subtrees of the same size.
+    ///
+    /// Every merge produces a hash pair for a node that will never change again.
+    fn push_tail(&self, state: &mut HasherState) {
+        let start = ByteNum(state.size - state.tail.len() as u64);
+        let hash = hash_subtree(start.full_chunks().0, &state.tail, false);
+        state.tail.clear();
+        let mut stable = self.stable.write().unwrap();
+        let (mut right, mut blocks) = (hash, 1);
+        while let Some(&(left, count)) = state.stack.last() {
+            if count != blocks {
+                break;
+            }
+            state.stack.pop();
+            stable.extend_from_slice(left.as_bytes());
+            stable.extend_from_slice(right.as_bytes());
+            right = parent_cv(&left, &right, false);
+            blocks *= 2;
+        }
+        state.stack.push((right, blocks));
+    }
+
+    /// Take a snapshot of the blob.
+    ///
+    /// The snapshot covers all data that was appended before this call, and will not
+    /// change when more data is appended.
+    pub fn snapshot(&self) -> GrowableBlobSnapshot {
+        let state = self.state.lock().unwrap();
+        let tree = BaoTree::new(ByteNum(state.size), self.block_size);
+        // no need to lock for the entire function, since appends need the state lock
+        let stable_len = self.stable.read().unwrap().len();
+        // combine the last block with the complete subtrees to the left of it,
+        // from right to left. This produces the nodes that contain the last block,
+        // which come last in post order.
+        let start = ByteNum(state.size - state.tail.len() as u64);
+        let mut right = hash_subtree(start.full_chunks().0, &state.tail, state.stack.is_empty());
+        let mut unstable = Vec::with_capacity(state.stack.len() * 64);
+        for (i, (left, _)) in state.stack.iter().enumerate().rev() {
+            unstable.extend_from_slice(left.as_bytes());
+            unstable.extend_from_slice(right.as_bytes());
+            right = parent_cv(left, &right, i == 0);
+        }
+        GrowableBlobSnapshot {
+            tree,
+            root: right,
+            stable: self.stable.clone(),
+            stable_len,
+            unstable,
+        }
+    }
+}
+
+/// An immutable view of a [GrowableBlob] at a point in time.
+///
+/// This implements [Outboard], so it can be used for all sync operations that need an
+/// outboard.
+#[derive(Debug, Clone)]
+pub struct GrowableBlobSnapshot {
+    tree: BaoTree,
+    root: blake3::Hash,
+    /// shared stable hash pairs. Only the first `stable_len` bytes belong to this snapshot.
+    stable: Arc<RwLock<Vec<u8>>>,
+    stable_len: usize,
+    /// hash pairs of the nodes that contain the last block, in post order
+    unstable: Vec<u8>,
+}
+
+impl GrowableBlobSnapshot {
+    /// Encode ranges relevant to a query from the data and this snapshot.
+    ///
+    /// `data` may have grown since the snapshot was taken. This will never read
+    /// beyond the size of the snapshot.
+    pub fn encode_ranges<D: ReadAt, W: Write>(
+        &self,
+        data: D,
+        ranges: &ChunkRangesRef,
+        encoded: W,
+    ) -> result::Result<(), EncodeError> {
+        let data = SizeLimited {
+            inner: data,
+            size: self.tree.size.0,
+        };
+        encode_ranges_validated(data, self, ranges, encoded)
+    }
+}
+
+impl Outboard for GrowableBlobSnapshot {
+    fn root(&self) -> blake3::Hash {
+        self.root
+    }
+
+    fn tree(&self) -> BaoTree {
+        self.tree
+    }
+
+    fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+        let Some(offset) = self.tree.post_order_offset(node) else {
+            return Ok(None);
+        };
+        let offset = usize::try_from(offset.value() * 64).unwrap();
+        let mut content = [0u8; 64];
+        if offset < self.stable_len {
+            let stable = self.stable.read().unwrap();
+            content.copy_from_slice(&stable[offset..offset + 64]);
+        } else {
+            let offset = offset - self.stable_len;
+            content.copy_from_slice(&self.unstable[offset..offset + 64]);
+        }
+        Ok(Some(parse_hash_pair(content)))
+    }
+}
+
+/// A reader that ends at `size`, even if the underlying data is larger
+#[derive(Debug)]
+struct SizeLimited<D> {
+    inner: D,
+    size: u64,
+}
+
+impl<D: ReadAt> ReadAt for SizeLimited<D> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(pos);
+        let n = usize::try_from(remaining)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        self.inner.read_at(pos, &mut buf[..n])
+    }
+}
+
+impl<D> Size for SizeLimited<D> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.size))
+    }
+}
@@ -0,0 +1,133 @@
+//! Batch migration of pre order outboard files to post order
+//!
+//! [migrate_outboards] flips outboard files one by one, using a constant amount of
+//! memory per file, and stops when a time budget is used up. Completed files are
+//! recorded in a journal, so a large migration can be done in many smaller runs.
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use blake3::guts::parent_cv;
+
+use super::{
+    outboard::{flip_pre_order_outboard, parse_hash_pair},
+    sync::ReadAt,
+};
+use crate::{blake3, BlockSize};
+
+/// The result of a call to [migrate_outboards]
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Number of files that were migrated
+    pub migrated: u64,
+    /// Total size of the migrated outboards, in bytes
+    pub bytes: u64,
+    /// Files that could not be migrated, together with the reason
+    ///
+    /// These files are left unchanged.
+    pub failed: Vec<(PathBuf, io::Error)>,
+    /// True if the migration stopped because the budget was used up
+    pub budget_exhausted: bool,
+}
+
+/// Migrate pre order outboard files to post order, in place.
+///
+/// Each file is flipped into a temporary file next to it using [flip_pre_order_outboard],
+/// which uses a constant amount of memory regardless of the size of the outboard. The
+/// temporary file is then renamed over the original, so a file is never left half
+/// migrated.
+///
+/// If `root` returns a hash for a file, the root hash pair of the migrated outboard is
+/// checked against it before the rename. Outboards for blobs that consist of a single
+/// block contain no hash pairs, so for them there is nothing to check.
+///
+/// The path of every migrated file is written to `journal` as a line, after the rename.
+/// To resume an interrupted migration, skip the paths that are in the journal.
+///
+/// No new file is started once `budget` has elapsed. Paths are only taken from `paths`
+/// when they are migrated, so pass `iter.by_ref()` to continue with the remaining paths
+/// in a later call.
+pub fn migrate_outboards(
+    mut paths: impl Iterator<Item = PathBuf>,
+    block_size: BlockSize,
+    budget: Duration,
+    root: impl Fn(&Path) -> Option<blake3::Hash>,
+    mut journal: impl Write,
+) -> io::Result<MigrationReport> {
+    let start = Instant::now();
+    let mut report = MigrationReport::default();
+    loop {
+        if start.elapsed() >= budget {
+            report.budget_exhausted = true;
+            break;
+        }
+        let Some(path) = paths.next() else {
+            break;
+        };
+        match migrate_one(&path, block_size, root(&path)) {
+            Ok(size) => {
+                writeln!(journal, "{}", path.display())?;
+                journal.flush()?;
+                report.migrated += 1;
+                report.bytes += size;
+            }
+            Err(cause) => report.failed.push((path, cause)),
+        }
+    }
+    Ok(report)
+}
+
+/// Migrate a single file, returning the size of the outboard
+fn migrate_one(path: &Path, block_size: BlockSize, root: Option<blake3::Hash>) -> io::Result<u64> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".migrating");
+    let tmp = PathBuf::from(tmp);
+    let res = flip_to(path, &tmp, block_size, root);
+    if res.is_err() {
+        // leave the original untouched, and don't leave garbage behind
+        fs::remove_file(&tmp).ok();
+        return res;
+    }
+    fs::rename(&tmp, path)?;
+    res
+}
+
+fn flip_to(
+    path: &Path,
+    tmp: &Path,
+    block_size: BlockSize,
+    root: Option<blake3::Hash>,
+) -> io::Result<u64> {
+    let source = fs::File::open(path)?;
+    // open for reading as well, to check the root pair afterwards
+    let target = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(tmp)?;
+    let mut target = BufWriter::new(target);
+    let tree = flip_pre_order_outboard(source, block_size, &mut target)?;
+    let target = target.into_inner().map_err(|e| e.into_error())?;
+    target.sync_all()?;
+    let size = target.metadata()?.len();
+    if let Some(root) = root {
+        if tree.outboard_hash_pairs() > 0 {
+            // the root is the last node in post order, just before the length suffix
+            let mut pair = [0u8; 64];
+            target.read_exact_at(size - 72, &mut pair)?;
+            let (l_hash, r_hash) = parse_hash_pair(pair);
+            if parent_cv(&l_hash, &r_hash, true) != root {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "root hash mismatch",
+                ));
+            }
+        }
+    }
+    Ok(size)
+}
@@ -1,17 +1,345 @@
 //! Implementation of bao streaming for std io and tokio io
-use crate::{blake3, BaoTree, BlockSize, ByteNum, ChunkRanges, TreeNode};
+//!
+//! # Wire format
+//!
+//! An encoded stream consists of the 8 byte little endian size, followed by the
+//! hash pairs and leaf data that are relevant for the requested ranges, in pre order.
+//! Leaves are chunk groups of the block size. A chunk group that is only partially
+//! requested is sent as its requested chunks, with the hash pairs below the block
+//! size. So apart from the block size, the stream is fully determined by the size and
+//! the ranges, and a decoder does not need to know anything else about the encoder.
+//! [sync::EncodedSlice] reads the individual items of a stream, without verifying them.
+//!
+//! [sync::encode_ranges_with_min_level] and [fsm::encode_ranges_with_min_level] send
+//! fully requested subtrees below `min_level` as a single leaf. This does not change
+//! the format: the stream is the same as for the block size `max(block_size,
+//! min_level)`, so it is decoded by passing that block size to the decoder. All other
+//! encoders use a `min_level` of 0.
+//!
+//! The block size is not part of the stream. If encoder and decoder use different
+//! block sizes, decoding either produces the same, correct data, e.g. for blobs that
+//! fit into a single block, or fails with a hash mismatch. It never yields
+//! unverified data.
+//!
+//! Peers that do not agree on the block size out of band can announce it with a
+//! single byte in front of the stream, using [sync::write_block_size] and
+//! [sync::read_block_size], or their async counterparts in [fsm]. A decoder with a
+//! different block size then fails with [StartDecodeError::BlockSizeMismatch] before
+//! reading the size, instead of with a hash mismatch.
+//!
+//! # Compatibility with bao
+//!
+//! With [BlockSize::ZERO], the format is the slice format of the
+//! [bao](https://crates.io/crates/bao) crate. For a single chunk range that starts
+//! within the data, [sync::encode_ranges] produces exactly the bytes of a bao
+//! `SliceExtractor` for the same range, and bao slices can be decoded with
+//! [sync::DecodeResponseIter]. This is checked by tests against the bao crate.
+//!
+//! There are two differences. Bao slices are a single range, while this crate
+//! supports arbitrary range sets. And for a query that lies entirely after the end of
+//! the data, this crate encodes the last chunk, so the query can be used to get the
+//! size of a blob.
+use crate::{
+    blake3,
+    hasher::{Blake3Hasher, Hasher},
+    iter::{BaoChunk, ResponseIterRef},
+    ranges::{as_chunk_ranges_ref, ChunkRangeSet},
+    rec::{encode_selected_rec, truncate_ranges},
+    BaoTree, BlockSize, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode,
+};
 use bytes::Bytes;
+use smallvec::SmallVec;
 
 mod error;
 pub use error::*;
+#[cfg(feature = "range-collections")]
 use range_collections::{range_set::RangeSetRange, RangeSetRef};
 
 use self::outboard::PostOrderMemOutboard;
 #[cfg(feature = "tokio_fsm")]
 pub mod fsm;
+pub mod growable;
+#[cfg(feature = "fs")]
+pub mod migrate;
 pub mod outboard;
+pub mod push;
 pub mod sync;
 
+/// Check that a block size passed to a decoder is supported
+///
+/// Decoders call this before reading anything, so an invalid block size is reported
+/// as an error right away instead of causing a panic once the size is known.
+pub(crate) fn check_block_size(block_size: BlockSize) -> std::io::Result<()> {
+    if block_size > BlockSize::MAX {
+        return Err(block_size_error(block_size));
+    }
+    Ok(())
+}
+
+/// Create a tree for a size and block size that come from outside, e.g. from an
+/// outboard that is being loaded
+///
+/// Unlike [BaoTree::new], this returns an error instead of panicking if the block
+/// size is larger than [BlockSize::MAX].
+pub(crate) fn try_tree(size: ByteNum, block_size: BlockSize) -> std::io::Result<BaoTree> {
+    BaoTree::try_new(size, block_size).ok_or_else(|| block_size_error(block_size))
+}
+
+fn block_size_error(block_size: BlockSize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "block size {} is larger than the maximum of {}",
+            block_size.0,
+            BlockSize::MAX.0
+        ),
+    )
+}
+
+/// The largest size that decoders accept in the header, 2^63 bytes
+///
+/// For larger sizes, the byte offsets of the nodes near the root do not fit into an
+/// u64. The size is not verified until the first hash pair or leaf is, so without a
+/// limit a peer could make a decoder compute with absurd sizes, or make the caller
+/// allocate an outboard for them. Decoders can be given a lower limit.
+pub const MAX_SIZE: ByteNum = ByteNum(1 << 63);
+
+/// Check the block size announced in a stream against the block size of the decoder
+pub(crate) fn check_stream_block_size(
+    actual: u8,
+    expected: BlockSize,
+) -> Result<(), StartDecodeError> {
+    if actual != expected.0 {
+        return Err(StartDecodeError::BlockSizeMismatch {
+            expected,
+            actual: BlockSize(actual),
+        });
+    }
+    Ok(())
+}
+
+/// Check the size from the header of a response against the maximum size
+pub(crate) fn check_size(size: ByteNum, max_size: ByteNum) -> Result<(), StartDecodeError> {
+    if size > max_size.min(MAX_SIZE) {
+        return Err(StartDecodeError::SizeTooLarge(size));
+    }
+    Ok(())
+}
+
+/// Check the size of an outboard against the size implied by its tree, if it is known
+///
+/// A wrong size means that the outboard is truncated or was computed with a
+/// different block size.
+pub(crate) fn check_outboard_size(tree: BaoTree, actual: Option<u64>) -> Result<(), EncodeError> {
+    if let Some(actual) = actual {
+        let expected = outboard_size(tree.size.0, tree.block_size);
+        if actual != expected {
+            return Err(EncodeError::OutboardSizeMismatch { expected, actual });
+        }
+    }
+    Ok(())
+}
+
+/// The bytes to send for a leaf of the chunk group iterator
+///
+/// If the leaf is fully requested, this is just the data. Otherwise the chunk group
+/// is only partially requested, and is sent as the requested chunks together with
+/// the hash pairs below the block size, which are computed from the data.
+pub(crate) fn encode_leaf<'a>(
+    start_chunk: ChunkNum,
+    data: &'a [u8],
+    ranges: &ChunkRangesRef,
+    block_size: BlockSize,
+    out_buf: &'a mut Vec<u8>,
+) -> &'a [u8] {
+    if ranges.is_all() {
+        return data;
+    }
+    out_buf.clear();
+    encode_selected_rec(
+        start_chunk,
+        data,
+        false,
+        ranges,
+        block_size.to_u32(),
+        true,
+        out_buf,
+    );
+    out_buf
+}
+
+/// The hashes that the rest of a response is verified against
+///
+/// This is the verification core of all decoders, and of the encoders that validate
+/// an outboard while encoding. It starts with the root hash. The items of a response
+/// are verified in traversal order: each hash pair or leaf is checked against the
+/// hash on top of the stack, and a hash pair replaces it with the hashes of its
+/// children that are part of the response.
+///
+/// A hash pair that fails verification does not push its children. A caller that
+/// continues after a failure must skip the items below it, otherwise they fail too.
+#[derive(Debug, Clone)]
+pub(crate) struct VerifyStack<H = Blake3Hasher> {
+    stack: SmallVec<[blake3::Hash; 10]>,
+    block_size: BlockSize,
+    hasher: H,
+}
+
+impl<H: Hasher> VerifyStack<H> {
+    /// Start verifying a response for the blob with hash `root`
+    pub(crate) fn new(root: blake3::Hash, block_size: BlockSize, hasher: H) -> Self {
+        let mut stack = SmallVec::new();
+        stack.push(root);
+        Self {
+            stack,
+            block_size,
+            hasher,
+        }
+    }
+
+    /// Continue verifying with hashes that were saved from [VerifyStack::hashes]
+    ///
+    /// The caller must make sure that these are the hashes that the rest of the
+    /// traversal needs.
+    pub(crate) fn from_hashes(
+        stack: SmallVec<[blake3::Hash; 10]>,
+        block_size: BlockSize,
+        hasher: H,
+    ) -> Self {
+        Self {
+            stack,
+            block_size,
+            hasher,
+        }
+    }
+
+    /// The hashes that the rest of the response is verified against, from the bottom
+    /// to the top
+    pub(crate) fn hashes(&self) -> &[blake3::Hash] {
+        &self.stack
+    }
+
+    /// Verify the hash pair of `node`
+    ///
+    /// If it matches, the hashes of the children that are part of the response are
+    /// pushed, so the items below `node` can be verified.
+    pub(crate) fn verify_parent(
+        &mut self,
+        node: TreeNode,
+        is_root: bool,
+        left: bool,
+        right: bool,
+        pair: &(blake3::Hash, blake3::Hash),
+    ) -> Result<(), DecodeError> {
+        let expected = self.pop();
+        let actual = self.hasher.parent(&pair.0, &pair.1, is_root);
+        if expected != actual {
+            return Err(self.parent_mismatch(node, expected, actual));
+        }
+        self.push_children(left, right, pair);
+        Ok(())
+    }
+
+    /// Verify the hash pair of `node` by comparing it to `known`, a pair for the same
+    /// node that has already been verified against the same root
+    pub(crate) fn verify_known_parent(
+        &mut self,
+        node: TreeNode,
+        is_root: bool,
+        left: bool,
+        right: bool,
+        pair: &(blake3::Hash, blake3::Hash),
+        known: &(blake3::Hash, blake3::Hash),
+    ) -> Result<(), DecodeError> {
+        let expected = self.pop();
+        if pair != known {
+            let actual = self.hasher.parent(&pair.0, &pair.1, is_root);
+            return Err(self.parent_mismatch(node, expected, actual));
+        }
+        self.push_children(left, right, pair);
+        Ok(())
+    }
+
+    /// Verify the data of the leaf that starts at `start_chunk`, in a tree of `size`
+    /// bytes
+    pub(crate) fn verify_leaf(
+        &mut self,
+        start_chunk: ChunkNum,
+        data: &[u8],
+        is_root: bool,
+        size: ByteNum,
+    ) -> Result<(), DecodeError> {
+        let actual = self.hasher.hash_subtree(start_chunk.0, data, is_root);
+        self.verify_leaf_hash(start_chunk, data.len(), is_root, size, actual)
+    }
+
+    /// Verify the hash of the leaf that starts at `start_chunk` and is `len` bytes
+    /// long, if it has already been computed
+    pub(crate) fn verify_leaf_hash(
+        &mut self,
+        start_chunk: ChunkNum,
+        len: usize,
+        is_root: bool,
+        size: ByteNum,
+        actual: blake3::Hash,
+    ) -> Result<(), DecodeError> {
+        let expected = self.pop();
+        if expected != actual {
+            let end = start_chunk + ByteNum(len as u64).chunks();
+            // a leaf is the right child if it is the second block of a pair, or if it
+            // is the last block and has no sibling, so it was moved up the tree
+            let block = start_chunk.0 >> self.block_size.0;
+            let has_sibling = ChunkNum((block + 1) << self.block_size.0).to_bytes() < size;
+            let left = is_root || (block % 2 == 0 && has_sibling);
+            return Err(DecodeError::LeafHashMismatch(LeafMismatch {
+                chunks: start_chunk..end,
+                left,
+                block_size: self.block_size,
+                expected,
+                actual,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Skip the hash of a leaf that is not verified
+    pub(crate) fn skip_leaf(&mut self) {
+        self.pop();
+    }
+
+    /// The hash on top of the stack
+    ///
+    /// The number of hashes depends only on the tree and the ranges, not on the
+    /// data, so running out of hashes is a bug in the traversal.
+    fn pop(&mut self) -> blake3::Hash {
+        self.stack.pop().expect("verify stack is empty")
+    }
+
+    fn parent_mismatch(
+        &self,
+        node: TreeNode,
+        expected: blake3::Hash,
+        actual: blake3::Hash,
+    ) -> DecodeError {
+        DecodeError::ParentHashMismatch(ParentMismatch {
+            node,
+            block_size: self.block_size,
+            expected,
+            actual,
+        })
+    }
+
+    fn push_children(&mut self, left: bool, right: bool, pair: &(blake3::Hash, blake3::Hash)) {
+        // push the right hash first, so the left subtree is verified first
+        if right {
+            self.stack.push(pair.1);
+        }
+        if left {
+            self.stack.push(pair.0);
+        }
+    }
+}
+
 /// A bao header, containing the size of the file.
 #[derive(Debug)]
 pub struct Header {
@@ -41,10 +369,22 @@ pub struct Leaf {
 }
 
 /// The outboard size of a file of size `size` with a block size of `block_size`
+///
+/// This is the size of a post order outboard, with the size as an 8 byte suffix.
 pub fn outboard_size(size: u64, block_size: BlockSize) -> u64 {
     BaoTree::outboard_size(ByteNum(size), block_size).0
 }
 
+/// The pre order outboard size of a file of size `size` with a block size of `block_size`
+///
+/// A pre order outboard stores the size as an 8 byte prefix instead of a suffix, and
+/// the hash pairs in pre order instead of post order. The number of hash pairs does not
+/// depend on the order, so this is always the same as [outboard_size]. It is provided so
+/// code that deals with pre order outboards does not have to rely on that.
+pub fn outboard_size_pre_order(size: u64, block_size: BlockSize) -> u64 {
+    BaoTree::outboard_size(ByteNum(size), block_size).0
+}
+
 /// The encoded size of a file of size `size` with a block size of `block_size`
 pub fn encoded_size(size: u64, block_size: BlockSize) -> u64 {
     outboard_size(size, block_size) + size
@@ -52,14 +392,26 @@ pub fn encoded_size(size: u64, block_size: BlockSize) -> u64 {
 
 /// Computes the pre order outboard of a file in memory.
 pub fn outboard(input: impl AsRef<[u8]>, block_size: BlockSize) -> (Vec<u8>, blake3::Hash) {
-    let outboard = PostOrderMemOutboard::create(input, block_size).flip();
+    // a created outboard always has the right size for its tree, so this can not fail
+    let outboard = PostOrderMemOutboard::create(input, block_size)
+        .flip()
+        .unwrap();
     let hash = *outboard.hash();
     (outboard.into_inner_with_prefix(), hash)
 }
 
+/// Computes the combined encoding of a file in memory.
+///
+/// This is the size, followed by the hash pairs interleaved with the data. See
+/// [sync::encode_combined] for details.
+pub fn encode(input: impl AsRef<[u8]>, block_size: BlockSize) -> (Vec<u8>, blake3::Hash) {
+    sync::encode_ranges_from_data(input.as_ref(), &ChunkRanges::all(), block_size)
+}
+
 /// Given a range set of byte ranges, round it up to full chunks.
 ///
 /// E.g. a byte range from 1..3 will be converted into the chunk range 0..1 (0..1024 bytes).
+#[cfg(feature = "range-collections")]
 pub fn round_up_to_chunks(ranges: &RangeSetRef<u64>) -> ChunkRanges {
     let mut res = ChunkRanges::empty();
     // we don't know if the ranges are overlapping, so we just compute the union
@@ -78,3 +430,33 @@ pub fn round_up_to_chunks(ranges: &RangeSetRef<u64>) -> ChunkRanges {
     }
     res
 }
+
+/// Compute the chunk ranges that a response to a query will contain.
+///
+/// `size` is the size of the blob, and `ranges` are the requested ranges. The ranges are
+/// canonicalized the same way as when encoding and decoding, so a request for anything
+/// beyond the end of the blob becomes a request for the last chunk. Chunk groups that are
+/// only partially requested are sent as individual chunks.
+///
+/// This does not touch any data, so it can be used to know up front which ranges a
+/// response will deliver, e.g. to reconcile requested and received ranges.
+pub fn covered_chunk_ranges<R: ChunkRangeSet + ?Sized>(
+    size: u64,
+    ranges: &R,
+    block_size: BlockSize,
+) -> ChunkRanges {
+    let ranges = as_chunk_ranges_ref(ranges);
+    let tree = BaoTree::new(ByteNum(size), block_size);
+    let ranges = truncate_ranges(ranges, tree.size());
+    let mut res = ChunkRanges::empty();
+    for item in ResponseIterRef::new(tree, ranges) {
+        if let BaoChunk::Leaf {
+            start_chunk, size, ..
+        } = item
+        {
+            let end = start_chunk + ByteNum(size as u64).chunks();
+            res |= ChunkRanges::from(start_chunk..end);
+        }
+    }
+    res
+}
@@ -4,9 +4,18 @@
 //! Implementations for in-memory outboards, for outboards where the data resides on disk,
 //! and a special implementation [EmptyOutboard] that just ignores all writes.
 
-use super::{sync::write_outboard_from_mem, TreeNode};
-use crate::{blake3, BaoTree, BlockSize, ByteNum};
-use std::{fmt, io};
+use super::{
+    sync::{write_outboard_from_mem, Outboard, ReadAt, Size},
+    try_tree, Parent, TreeNode,
+};
+use crate::{blake3, hash_subtree, iter::BaoChunk, BaoTree, BlockSize, ByteNum};
+use blake3::guts::parent_cv;
+use smallvec::SmallVec;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    ops::Range,
+};
 
 /// An empty outboard, that just returns 0 hashes for all nodes.
 ///
@@ -41,6 +50,7 @@ impl crate::io::sync::Outboard for EmptyOutboard {
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl crate::io::fsm::Outboard for EmptyOutboard {
     fn root(&self) -> blake3::Hash {
         self.root
@@ -72,6 +82,7 @@ impl crate::io::sync::OutboardMut for EmptyOutboard {
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl crate::io::fsm::OutboardMut for EmptyOutboard {
     fn save(
         &mut self,
@@ -99,6 +110,9 @@ impl crate::io::fsm::OutboardMut for EmptyOutboard {
 }
 
 /// A generic outboard in pre order
+///
+/// All offsets are computed as u64, so this works for blobs of any size, even if
+/// the outboard does not fit into memory.
 #[derive(Debug, Clone)]
 pub struct PreOrderOutboard<R> {
     /// root hash
@@ -117,13 +131,16 @@ impl<R> PreOrderOutboard<R> {
 }
 
 /// A generic outboard in post order
+///
+/// All offsets are computed as u64, so this works for blobs of any size, even if
+/// the outboard does not fit into memory.
 #[derive(Debug, Clone)]
 pub struct PostOrderOutboard<R> {
     /// root hash
     pub(crate) root: blake3::Hash,
     /// tree defining the data
     pub(crate) tree: BaoTree,
-    /// hashes with length prefix
+    /// hashes with length suffix
     pub(crate) data: R,
 }
 
@@ -135,6 +152,9 @@ impl<R> PostOrderOutboard<R> {
 }
 
 /// A post order outboard that is optimized for memory storage.
+///
+/// For very large blobs, where the outboard might not fit into memory, use
+/// [PostOrderOutboard] instead.
 #[derive(Clone, PartialEq, Eq)]
 pub struct PostOrderMemOutboard<T = Vec<u8>> {
     /// root hash
@@ -168,7 +188,7 @@ impl PostOrderMemOutboard {
     pub fn create(data: impl AsRef<[u8]>, block_size: BlockSize) -> Self {
         let data = data.as_ref();
         let tree = BaoTree::new(ByteNum(data.len() as u64), block_size);
-        let outboard_len: usize = (tree.outboard_hash_pairs() * 64).try_into().unwrap();
+        let outboard_len = mem_outboard_len(&tree).unwrap();
         let outboard_data = vec![0; outboard_len];
         let root = blake3::Hash::from_bytes([0; 32]);
         let mut outboard = Self::new(root, tree, outboard_data).unwrap();
@@ -236,9 +256,76 @@ impl<T: AsRef<[u8]>> PostOrderMemOutboard<T> {
     }
 
     /// Flip the outboard to pre order.
-    pub fn flip(&self) -> PreOrderMemOutboard {
+    ///
+    /// Fails if the outboard data is too short for the tree.
+    pub fn flip(&self) -> io::Result<PreOrderMemOutboard> {
         flip_post(self.root, self.tree, self.data.as_ref())
     }
+
+    /// The hash pairs of the outboard as [Parent]s, split into stable and unstable pairs
+    ///
+    /// Fails if the outboard data is too short for the tree.
+    pub fn pairs(&self) -> io::Result<OutboardPairs> {
+        let mut nodes = self
+            .tree
+            .post_order_nodes_iter()
+            .filter_map(|node| Some((self.tree.post_order_offset(node)?.value(), node)))
+            .collect::<Vec<_>>();
+        nodes.sort_unstable();
+        let mut pairs = nodes
+            .into_iter()
+            .map(|(offset, node)| {
+                let pair = load_raw_mem(self.data.as_ref(), offset)?;
+                Ok(Parent {
+                    node,
+                    pair: parse_hash_pair(pair),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        // the stable pairs are a prefix of the pairs, so this is at most pairs.len()
+        let stable = usize::try_from(self.tree.stable_pair_count()).unwrap_or(usize::MAX);
+        let unstable = pairs.split_off(stable.min(pairs.len()));
+        Ok(OutboardPairs {
+            stable: pairs,
+            unstable,
+        })
+    }
+}
+
+/// The hash pairs of a post order outboard, as typed values
+///
+/// The stable pairs are the first [BaoTree::stable_pair_count] pairs. They are for
+/// the nodes that are entirely within the data, and do not change when data is
+/// appended. The unstable pairs are for the nodes on the right edge of the tree.
+/// Both are in post order, which is the order of the pairs in the outboard.
+#[derive(Debug, Clone)]
+pub struct OutboardPairs {
+    /// The stable pairs
+    pub stable: Vec<Parent>,
+    /// The unstable pairs
+    pub unstable: Vec<Parent>,
+}
+
+impl OutboardPairs {
+    /// The total number of pairs
+    pub fn len(&self) -> usize {
+        self.stable.len() + self.unstable.len()
+    }
+
+    /// True if there are no pairs, i.e. the tree has only one block
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Flatten the pairs into the data of a post order outboard, without the size
+    pub fn to_post_order_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.len() * 64);
+        for Parent { pair: (l, r), .. } in self.stable.iter().chain(&self.unstable) {
+            res.extend_from_slice(l.as_bytes());
+            res.extend_from_slice(r.as_bytes());
+        }
+        res
+    }
 }
 
 impl<T: AsRef<[u8]>> crate::io::sync::Outboard for PostOrderMemOutboard<T> {
@@ -249,10 +336,14 @@ impl<T: AsRef<[u8]>> crate::io::sync::Outboard for PostOrderMemOutboard<T> {
         self.tree
     }
     fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
-        Ok(load_post(&self.tree, self.data.as_ref(), node))
+        load_post(&self.tree, self.data.as_ref(), node)
+    }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.data.as_ref().len() as u64 + 8))
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl<T: AsRef<[u8]>> crate::io::fsm::Outboard for PostOrderMemOutboard<T> {
     fn root(&self) -> blake3::Hash {
         self.root
@@ -263,20 +354,17 @@ impl<T: AsRef<[u8]>> crate::io::fsm::Outboard for PostOrderMemOutboard<T> {
     type LoadFuture<'a> = futures::future::Ready<io::Result<Option<(blake3::Hash, blake3::Hash)>>>
         where T: 'a;
     fn load(&mut self, node: TreeNode) -> Self::LoadFuture<'_> {
-        futures::future::ok(load_post(&self.tree, self.data.as_ref(), node))
+        futures::future::ready(load_post(&self.tree, self.data.as_ref(), node))
+    }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.data.as_ref().len() as u64 + 8))
     }
 }
 
 impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PostOrderMemOutboard<T> {
     fn save(&mut self, node: TreeNode, pair: &(blake3::Hash, blake3::Hash)) -> io::Result<()> {
         match self.tree.post_order_offset(node) {
-            Some(offset) => {
-                let offset = usize::try_from(offset.value() * 64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
+            Some(offset) => save_raw_mem(self.data.as_mut(), offset.value(), pair),
             None => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "invalid node for this outboard",
@@ -285,6 +373,7 @@ impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PostOrderMemOutboard<T> {
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PostOrderMemOutboard<T> {
     type SaveFuture<'a> = futures::future::Ready<io::Result<()>> where T: 'a;
 
@@ -294,13 +383,7 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PostOrderMemOutboard<T> {
         pair: &(blake3::Hash, blake3::Hash),
     ) -> Self::SaveFuture<'_> {
         let res = match self.tree.post_order_offset(node) {
-            Some(offset) => {
-                let offset = usize::try_from(offset.value() * 64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
+            Some(offset) => save_raw_mem(self.data.as_mut(), offset.value(), pair),
             None => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "invalid node for this outboard",
@@ -316,35 +399,336 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PostOrderMemOutboard<T> {
     }
 }
 
-fn load_raw_post_mem(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<[u8; 64]> {
-    let offset = tree.post_order_offset(node)?.value();
-    let offset = usize::try_from(offset * 64).unwrap();
-    let slice = &data[offset..offset + 64];
-    Some(slice.try_into().unwrap())
+/// The size of the hash pairs of an outboard in memory, without the length
+///
+/// Offsets and sizes of file based outboards are computed as u64, so they work for
+/// blobs of any size. In memory outboards need the pairs to fit in a usize, which
+/// might not be the case on 32 bit platforms.
+pub(crate) fn mem_outboard_len(tree: &BaoTree) -> io::Result<usize> {
+    usize::try_from(tree.outboard_hash_pairs() * 64).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "outboard does not fit into memory",
+        )
+    })
+}
+
+/// The byte range of the hash pair with index `offset` in in memory outboard data
+///
+/// The data of an in memory outboard is public, so it can not be assumed to have the
+/// size that the tree requires.
+fn mem_pair_range(data: &[u8], offset: u64) -> io::Result<Range<usize>> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|offset| offset.checked_mul(64))
+        .and_then(|start| Some(start..start.checked_add(64)?))
+        .filter(|range| range.end <= data.len())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hash pair {offset} is outside of the outboard data"),
+            )
+        })
+}
+
+fn load_raw_mem(data: &[u8], offset: u64) -> io::Result<[u8; 64]> {
+    let range = mem_pair_range(data, offset)?;
+    Ok(data[range].try_into().unwrap())
 }
 
-fn load_post(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<(blake3::Hash, blake3::Hash)> {
-    load_raw_post_mem(tree, data, node).map(parse_hash_pair)
+fn save_raw_mem(
+    data: &mut [u8],
+    offset: u64,
+    pair: &(blake3::Hash, blake3::Hash),
+) -> io::Result<()> {
+    let range = mem_pair_range(data, offset)?;
+    let slot = &mut data[range];
+    slot[..32].copy_from_slice(pair.0.as_bytes());
+    slot[32..].copy_from_slice(pair.1.as_bytes());
+    Ok(())
 }
 
-fn flip_post(root: blake3::Hash, tree: BaoTree, data: &[u8]) -> PreOrderMemOutboard {
+fn load_raw_post_mem(tree: &BaoTree, data: &[u8], node: TreeNode) -> io::Result<Option<[u8; 64]>> {
+    match tree.post_order_offset(node) {
+        Some(offset) => load_raw_mem(data, offset.value()).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn load_post(
+    tree: &BaoTree,
+    data: &[u8],
+    node: TreeNode,
+) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+    Ok(load_raw_post_mem(tree, data, node)?.map(parse_hash_pair))
+}
+
+/// Downsample a post order outboard to a larger block size, without access to the data.
+///
+/// Every hash pair of an outboard with a larger block size is also present in an
+/// outboard with a smaller block size, just at a higher level of the tree. So the
+/// coarser outboard can be computed by just selecting and reordering hash pairs.
+///
+/// `fine` is the post order outboard for a blob of size `size` at block size `from`,
+/// without the length suffix. The result is the post order outboard at block size `to`,
+/// also without the length suffix. It is identical to what [PostOrderMemOutboard::create]
+/// would produce for the data at block size `to`.
+pub fn downsample_outboard(
+    fine: &[u8],
+    size: u64,
+    from: BlockSize,
+    to: BlockSize,
+) -> io::Result<Vec<u8>> {
+    if to <= from {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "target block size must be larger than source block size",
+        ));
+    }
+    let fine_tree = try_tree(ByteNum(size), from)?;
+    if fine.len() as u64 != fine_tree.outboard_hash_pairs() * 64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid outboard data size",
+        ));
+    }
+    let coarse_tree = try_tree(ByteNum(size), to)?;
+    let mut res = vec![0u8; mem_outboard_len(&coarse_tree)?];
+    for node in coarse_tree.post_order_nodes_iter() {
+        // the half leaf is not persisted in the coarse outboard
+        let Some(offset) = coarse_tree.post_order_offset(node) else {
+            continue;
+        };
+        // all nodes of the coarse tree are above the leaf level of the fine tree,
+        // so they are always persisted in the fine outboard
+        let pair = load_raw_post_mem(&fine_tree, fine, node)?.unwrap();
+        let range = mem_pair_range(&res, offset.value())?;
+        res[range].copy_from_slice(&pair);
+    }
+    Ok(res)
+}
+
+/// Refine a post order outboard to a smaller block size, given the data.
+///
+/// This is the opposite of [downsample_outboard]. The hash pairs at or above the leaf
+/// level of the coarse outboard are copied from it. The hash pairs below are not
+/// available, so they are computed from `data`. Each copied pair is checked against
+/// the hashes of its children, so a corrupt coarse outboard or data that does not
+/// match it is detected. Such a mismatch is reported as an [io::ErrorKind::InvalidData]
+/// error naming the chunk range of the affected node.
+///
+/// `coarse` is the post order outboard for a blob of size `size` at block size `from`,
+/// without the length suffix. `data` must provide exactly `size` bytes, otherwise an
+/// [io::ErrorKind::UnexpectedEof] or [io::ErrorKind::InvalidInput] error is returned.
+/// The result is the post order outboard at block size `to`, also without the length
+/// suffix.
+pub fn refine_outboard(
+    coarse: &[u8],
+    mut data: impl Read,
+    size: u64,
+    from: BlockSize,
+    to: BlockSize,
+) -> io::Result<Vec<u8>> {
+    if to >= from {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "target block size must be smaller than source block size",
+        ));
+    }
+    let coarse_tree = try_tree(ByteNum(size), from)?;
+    if coarse.len() as u64 != coarse_tree.outboard_hash_pairs() * 64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid outboard data size",
+        ));
+    }
+    let fine_tree = try_tree(ByteNum(size), to)?;
+    let mut res = vec![0u8; mem_outboard_len(&fine_tree)?];
+    let mut buffer = vec![0u8; fine_tree.chunk_group_bytes().to_usize()];
+    // do not allocate for small trees
+    let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
+    for item in fine_tree.post_order_chunks_iter() {
+        match item {
+            BaoChunk::Parent { node, is_root, .. } => {
+                let right_hash = stack.pop().unwrap();
+                let left_hash = stack.pop().unwrap();
+                // nodes at or above the leaf level of the coarse tree are also in the
+                // coarse outboard, except for the half leaf. Take the pair from there,
+                // after checking that it matches the hashes of the children.
+                let pair = match load_raw_post_mem(&coarse_tree, coarse, node)? {
+                    Some(stored) => {
+                        if parse_hash_pair(stored) != (left_hash, right_hash) {
+                            let range = fine_tree.byte_range(node);
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "outboard hash mismatch for chunks {}..{}",
+                                    range.start.full_chunks(),
+                                    range.end.chunks()
+                                ),
+                            ));
+                        }
+                        stored
+                    }
+                    None => {
+                        let mut pair = [0u8; 64];
+                        pair[..32].copy_from_slice(left_hash.as_bytes());
+                        pair[32..].copy_from_slice(right_hash.as_bytes());
+                        pair
+                    }
+                };
+                // all parents in the post order traversal are persisted
+                let offset = fine_tree.post_order_offset(node).unwrap().value();
+                let range = mem_pair_range(&res, offset)?;
+                res[range].copy_from_slice(&pair);
+                stack.push(parent_cv(&left_hash, &right_hash, is_root));
+            }
+            BaoChunk::Leaf {
+                size,
+                is_root,
+                start_chunk,
+                ..
+            } => {
+                let buf = &mut buffer[..size];
+                data.read_exact(buf)?;
+                stack.push(hash_subtree(start_chunk.0, buf, is_root));
+            }
+        }
+    }
+    if data.read(&mut [0u8; 1])? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "data is longer than size",
+        ));
+    }
+    Ok(res)
+}
+
+/// Merge two partial outboards for the same blob into one.
+///
+/// This is useful when different ranges of a blob were downloaded from different
+/// peers, and each download saved its verified hash pairs into its own outboard.
+///
+/// The tree is traversed from the root. A hash pair is taken from whichever outboard
+/// has a pair that matches the hash of its parent, so only hash pairs that can be
+/// verified from the root end up in the result. Pairs that are missing in both
+/// outboards, or that do not verify, are left as zeros, just like in an outboard that
+/// has been partially written by a decoder. If one outboard has a verified pair and the
+/// other has a different pair that is not all zeros, the outboards disagree, and this
+/// is reported as an [io::ErrorKind::InvalidData] error naming the chunk range of the
+/// node.
+///
+/// Fails with [io::ErrorKind::InvalidInput] if the outboards do not have the same root
+/// and tree.
+pub fn merge_outboards<A: Outboard, B: Outboard>(a: &A, b: &B) -> io::Result<PostOrderMemOutboard> {
+    let tree = a.tree();
+    let root = a.root();
+    if b.tree() != tree || b.root() != root {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "outboards are not for the same blob",
+        ));
+    }
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    let mut merger = OutboardMerger {
+        a,
+        b,
+        tree,
+        shifted_filled_size,
+        res: vec![0u8; mem_outboard_len(&tree)?],
+    };
+    merger.merge_rec(&root, shifted_root, true)?;
+    Ok(PostOrderMemOutboard {
+        root,
+        tree,
+        data: merger.res,
+    })
+}
+
+struct OutboardMerger<'a, A, B> {
+    a: &'a A,
+    b: &'a B,
+    tree: BaoTree,
+    shifted_filled_size: TreeNode,
+    res: Vec<u8>,
+}
+
+impl<'a, A: Outboard, B: Outboard> OutboardMerger<'a, A, B> {
+    fn merge_rec(
+        &mut self,
+        parent_hash: &blake3::Hash,
+        shifted: TreeNode,
+        is_root: bool,
+    ) -> io::Result<()> {
+        let node = shifted.subtract_block_size(self.tree.block_size.0);
+        let candidates = [self.a.load(node)?, self.b.load(node)?];
+        let verified = candidates
+            .iter()
+            .flatten()
+            .find(|(l, r)| parent_cv(l, r, is_root) == *parent_hash)
+            .copied();
+        let (l_hash, r_hash) = match verified {
+            Some(pair) => {
+                let disagree = candidates
+                    .iter()
+                    .flatten()
+                    .any(|other| *other != pair && !is_zero_pair(other));
+                if disagree {
+                    let range = self.tree.byte_range(node);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "outboards disagree for chunks {}..{}",
+                            range.start.full_chunks(),
+                            range.end.chunks()
+                        ),
+                    ));
+                }
+                if let Some(offset) = self.tree.post_order_offset(node) {
+                    save_raw_mem(&mut self.res, offset.value(), &pair)?;
+                }
+                pair
+            }
+            // the node is not persisted, so the hash is just passed through
+            None if candidates.iter().all(Option::is_none) => {
+                (*parent_hash, blake3::Hash::from([0; 32]))
+            }
+            // neither outboard has a valid pair, so the subtree can not be verified
+            None => return Ok(()),
+        };
+        if !shifted.is_leaf() {
+            let left = shifted.left_child().unwrap();
+            self.merge_rec(&l_hash, left, false)?;
+            let right = shifted.right_descendant(self.shifted_filled_size).unwrap();
+            self.merge_rec(&r_hash, right, false)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_zero_pair(pair: &(blake3::Hash, blake3::Hash)) -> bool {
+    pair.0.as_bytes() == &[0; 32] && pair.1.as_bytes() == &[0; 32]
+}
+
+fn flip_post(root: blake3::Hash, tree: BaoTree, data: &[u8]) -> io::Result<PreOrderMemOutboard> {
     let mut out = vec![0; data.len()];
     for node in tree.post_order_nodes_iter() {
-        if let Some((l, r)) = load_post(&tree, data, node) {
+        if let Some(pair) = load_post(&tree, data, node)? {
             let offset = tree.pre_order_offset(node).unwrap();
-            let offset = (offset as usize) * 64;
-            out[offset..offset + 32].copy_from_slice(l.as_bytes());
-            out[offset + 32..offset + 64].copy_from_slice(r.as_bytes());
+            save_raw_mem(&mut out, offset, &pair)?;
         }
     }
-    PreOrderMemOutboard {
+    Ok(PreOrderMemOutboard {
         root,
         tree,
         data: out,
-    }
+    })
 }
 
 /// A pre order outboard that is optimized for memory storage.
+///
+/// For very large blobs, where the outboard might not fit into memory, use
+/// [PreOrderOutboard] instead.
 #[derive(Clone, PartialEq, Eq)]
 pub struct PreOrderMemOutboard<T = Vec<u8>> {
     /// root hash
@@ -385,7 +769,7 @@ impl PreOrderMemOutboard {
     pub fn create(data: impl AsRef<[u8]>, block_size: BlockSize) -> Self {
         let data = data.as_ref();
         let tree = BaoTree::new(ByteNum(data.len() as u64), block_size);
-        let outboard_len: usize = (tree.outboard_hash_pairs() * 64).try_into().unwrap();
+        let outboard_len = mem_outboard_len(&tree).unwrap();
         let outboard_data = vec![0u8; outboard_len];
         let root = blake3::Hash::from_bytes([0; 32]);
         let mut outboard = Self::new(root, tree, outboard_data).unwrap();
@@ -453,7 +837,9 @@ impl<T: AsRef<[u8]>> PreOrderMemOutboard<T> {
     }
 
     /// Flip the outboard to a post order outboard.
-    pub fn flip(&self) -> PostOrderMemOutboard {
+    ///
+    /// Fails if the outboard data is too short for the tree.
+    pub fn flip(&self) -> io::Result<PostOrderMemOutboard> {
         flip_pre(self.root, self.tree, self.data.as_ref())
     }
 }
@@ -466,21 +852,17 @@ impl<T: AsRef<[u8]>> crate::io::sync::Outboard for PreOrderMemOutboard<T> {
         self.tree
     }
     fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
-        Ok(load_pre(&self.tree, self.data.as_ref(), node))
+        load_pre(&self.tree, self.data.as_ref(), node)
+    }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.data.as_ref().len() as u64 + 8))
     }
 }
 
 impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PreOrderMemOutboard<T> {
     fn save(&mut self, node: TreeNode, pair: &(blake3::Hash, blake3::Hash)) -> io::Result<()> {
         match self.tree.pre_order_offset(node) {
-            Some(offset) => {
-                let offset_u64 = offset * 64;
-                let offset = usize::try_from(offset_u64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
+            Some(offset) => save_raw_mem(self.data.as_mut(), offset, pair),
             None => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "invalid node for this outboard",
@@ -489,6 +871,7 @@ impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PreOrderMemOutboard<T> {
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl<T: AsRef<[u8]> + 'static> crate::io::fsm::Outboard for PreOrderMemOutboard<T> {
     fn root(&self) -> blake3::Hash {
         self.root
@@ -498,11 +881,14 @@ impl<T: AsRef<[u8]> + 'static> crate::io::fsm::Outboard for PreOrderMemOutboard<
     }
     type LoadFuture<'a> = futures::future::Ready<io::Result<Option<(blake3::Hash, blake3::Hash)>>>;
     fn load(&mut self, node: TreeNode) -> Self::LoadFuture<'_> {
-        let res = load_raw_pre_mem(&self.tree, self.data.as_ref(), node).map(parse_hash_pair);
-        futures::future::ok(res)
+        futures::future::ready(load_pre(&self.tree, self.data.as_ref(), node))
+    }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.data.as_ref().len() as u64 + 8))
     }
 }
 
+#[cfg(feature = "tokio_fsm")]
 impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PreOrderMemOutboard<T> {
     type SaveFuture<'a> = futures::future::Ready<io::Result<()>> where T: 'a;
 
@@ -512,14 +898,7 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PreOrderMemOutboard<T> {
         pair: &(blake3::Hash, blake3::Hash),
     ) -> Self::SaveFuture<'_> {
         let res = match self.tree.pre_order_offset(node) {
-            Some(offset) => {
-                let offset_u64 = offset * 64;
-                let offset = usize::try_from(offset_u64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
+            Some(offset) => save_raw_mem(self.data.as_mut(), offset, pair),
             None => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "invalid node for this outboard",
@@ -535,36 +914,68 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PreOrderMemOutboard<T> {
     }
 }
 
-fn load_raw_pre_mem(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<[u8; 64]> {
+fn load_raw_pre_mem(tree: &BaoTree, data: &[u8], node: TreeNode) -> io::Result<Option<[u8; 64]>> {
     // this is a bit slow because pre_order_offset uses a loop.
     // pretty sure there is a way to write it as a single expression if you spend the time.
     // but profiling still has this in the nanosecond range, so this is unlikely to be a
     // bottleneck.
-    let offset = tree.pre_order_offset(node)?;
-    let offset = usize::try_from(offset * 64).unwrap();
-    let slice = &data[offset..offset + 64];
-    Some(slice.try_into().unwrap())
+    match tree.pre_order_offset(node) {
+        Some(offset) => load_raw_mem(data, offset).map(Some),
+        None => Ok(None),
+    }
 }
 
-fn load_pre(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<(blake3::Hash, blake3::Hash)> {
-    load_raw_pre_mem(tree, data, node).map(parse_hash_pair)
+fn load_pre(
+    tree: &BaoTree,
+    data: &[u8],
+    node: TreeNode,
+) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+    Ok(load_raw_pre_mem(tree, data, node)?.map(parse_hash_pair))
 }
 
-fn flip_pre(root: blake3::Hash, tree: BaoTree, data: &[u8]) -> PostOrderMemOutboard {
+fn flip_pre(root: blake3::Hash, tree: BaoTree, data: &[u8]) -> io::Result<PostOrderMemOutboard> {
     let mut out = vec![0; data.len()];
     for node in tree.post_order_nodes_iter() {
-        if let Some((l, r)) = load_pre(&tree, data, node) {
+        if let Some(pair) = load_pre(&tree, data, node)? {
             let offset = tree.post_order_offset(node).unwrap().value();
-            let offset = usize::try_from(offset * 64).unwrap();
-            out[offset..offset + 32].copy_from_slice(l.as_bytes());
-            out[offset + 32..offset + 64].copy_from_slice(r.as_bytes());
+            save_raw_mem(&mut out, offset, &pair)?;
         }
     }
-    PostOrderMemOutboard {
+    Ok(PostOrderMemOutboard {
         root,
         tree,
         data: out,
+    })
+}
+
+/// Flip a pre order outboard to a post order outboard, using a constant amount of memory.
+///
+/// `pre` is a pre order outboard including the length prefix. The post order outboard,
+/// including the length suffix, is written sequentially to `post`.
+///
+/// Unlike [PreOrderMemOutboard::flip], this never loads the outboard into memory, so it
+/// can be used for outboards of any size. The hash pairs are read from `pre` in post
+/// order, so `pre` should support cheap random access. Since writes are just 32 bytes
+/// each, `post` should be buffered.
+///
+/// Returns the tree described by the outboard.
+pub fn flip_pre_order_outboard(
+    pre: impl ReadAt + Size,
+    block_size: BlockSize,
+    mut post: impl Write,
+) -> io::Result<BaoTree> {
+    // the root hash is not needed for loading hash pairs
+    let pre = PreOrderOutboard::new(blake3::Hash::from([0; 32]), block_size, pre)?;
+    let tree = pre.tree;
+    for node in tree.post_order_nodes_iter() {
+        // this skips the half leaf, which is not persisted in either order
+        if let Some((l_hash, r_hash)) = pre.load(node)? {
+            post.write_all(l_hash.as_bytes())?;
+            post.write_all(r_hash.as_bytes())?;
+        }
     }
+    post.write_all(&tree.size.0.to_le_bytes())?;
+    Ok(tree)
 }
 
 pub(crate) fn parse_hash_pair(buf: [u8; 64]) -> (blake3::Hash, blake3::Hash) {
@@ -572,3 +983,12 @@ pub(crate) fn parse_hash_pair(buf: [u8; 64]) -> (blake3::Hash, blake3::Hash) {
     let r_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[32..]).unwrap());
     (l_hash, r_hash)
 }
+
+pub(crate) fn combine_hash_pair(l: &blake3::Hash, r: &blake3::Hash) -> [u8; 64] {
+    let mut res = [0u8; 64];
+    let lb: &mut [u8; 32] = (&mut res[0..32]).try_into().unwrap();
+    *lb = *l.as_bytes();
+    let rb: &mut [u8; 32] = (&mut res[32..]).try_into().unwrap();
+    *rb = *r.as_bytes();
+    res
+}
@@ -0,0 +1,405 @@
+//! A push based decoder for responses that arrive in frames
+//!
+//! [SliceDecoder] does not do any io itself. Bytes are pushed into it as they arrive,
+//! in frames of any size, and it returns the items that could be verified with them.
+//! It only buffers the item that is currently incomplete, which is at most one hash
+//! pair or one chunk group, and [SliceDecoder::needed] tells how many more bytes that
+//! item needs, so the transport can size its reads.
+//!
+//! The items and errors are the same as those of [DecodeResponseIter], which is the
+//! equivalent decoder for a [std::io::Read].
+//!
+//! The verified progress of a decoder can be saved as a [DecoderState], and a decoder
+//! can be resumed from it with [SliceDecoder::from_state], e.g. after a connection was
+//! interrupted. With the `serde` feature, [DecoderState] can be serialized.
+//!
+//! [DecodeResponseIter]: super::sync::DecodeResponseIter
+use std::io;
+
+use bytes::BytesMut;
+use smallvec::SmallVec;
+
+use super::{
+    check_block_size, check_size, outboard::parse_hash_pair, sync::DecodeResponseItem,
+    AnyDecodeError, Header, Leaf, Parent, VerifyStack, MAX_SIZE,
+};
+use crate::{
+    blake3,
+    hasher::{Blake3Hasher, Hasher},
+    iter::{BaoChunk, ResponseIter},
+    ranges::SimpleChunkRanges,
+    rec::truncate_ranges_owned,
+    BaoTree, BlockSize, ByteNum, ChunkNum, ChunkRanges,
+};
+
+/// Where the decoder is in the response
+#[derive(Debug)]
+enum State {
+    /// waiting for the 8 byte size
+    Header,
+    /// waiting for the bytes of `current`
+    Content {
+        iter: ResponseIter,
+        current: BaoChunk,
+    },
+    /// the response is complete
+    Done,
+    /// there was an error, the decoder can not be used anymore
+    Failed,
+}
+
+/// A decoder for a response, where bytes are pushed in as they arrive
+///
+/// Each call to [SliceDecoder::push] returns the items that were completed and
+/// verified with the pushed bytes, in stream order. Once [SliceDecoder::is_done]
+/// returns true, the response is complete. After an error, the decoder is failed, and
+/// [SliceDecoder::push], [SliceDecoder::needed], [SliceDecoder::finish] and
+/// [SliceDecoder::state] return an error.
+///
+/// Hashes are computed with `H`, which defaults to blake3, see [Hasher].
+#[derive(Debug)]
+pub struct SliceDecoder<H = Blake3Hasher> {
+    ranges: ChunkRanges,
+    block_size: BlockSize,
+    /// the size of the blob, once the header has been read
+    size: Option<ByteNum>,
+    state: State,
+    stack: VerifyStack<H>,
+    /// the number of items after the header that have been completed
+    items: u64,
+    /// the number of bytes of the completed items, including the header
+    offset: u64,
+    /// the bytes of the current item that have arrived so far
+    buf: BytesMut,
+}
+
+/// The verified progress of a [SliceDecoder]
+///
+/// This contains everything that is needed to continue decoding a response after the
+/// last completed item: the size, once it is known, the hashes that the rest of the
+/// response will be verified against, and the position in the response. Bytes of an
+/// item that was only partially received are not part of the state, so the rest of the
+/// response must be sent again starting at [DecoderState::offset].
+///
+/// Hashes are stored as bytes, so the state can be serialized with the `serde`
+/// feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecoderState {
+    /// The requested ranges, as the boundaries of the chunk ranges
+    pub ranges: Vec<u64>,
+    /// The block size
+    pub block_size: u8,
+    /// The size of the blob, or None if the header has not been read yet
+    pub size: Option<u64>,
+    /// The stack of expected hashes, from the bottom to the top
+    pub stack: Vec<[u8; 32]>,
+    /// The number of hash pairs and leaves that have been completed
+    pub items: u64,
+    /// The number of bytes of the response that have been completed, including the
+    /// 8 byte header
+    pub offset: u64,
+}
+
+impl SliceDecoder {
+    /// Create a new decoder for a response to a query for `ranges`
+    pub fn new(root: blake3::Hash, block_size: BlockSize, ranges: ChunkRanges) -> Self {
+        Self::new_with_hasher(root, block_size, ranges, Blake3Hasher)
+    }
+
+    /// Resume decoding from a state that was saved with [SliceDecoder::state]
+    ///
+    /// The decoder expects the bytes of the response starting at [DecoderState::offset].
+    /// Fails with [io::ErrorKind::InvalidInput] if the state is not consistent, e.g. if
+    /// the stack does not have the number of hashes that the completed items leave.
+    pub fn from_state(saved: DecoderState) -> io::Result<Self> {
+        Self::from_state_with_hasher(saved, Blake3Hasher)
+    }
+}
+
+impl<H: Hasher> SliceDecoder<H> {
+    /// Create a new decoder for a response to a query for `ranges`, using a custom
+    /// [Hasher]
+    pub fn new_with_hasher(
+        root: blake3::Hash,
+        block_size: BlockSize,
+        ranges: ChunkRanges,
+        hasher: H,
+    ) -> Self {
+        Self {
+            ranges,
+            block_size,
+            size: None,
+            state: State::Header,
+            stack: VerifyStack::new(root, block_size, hasher),
+            items: 0,
+            offset: 0,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Resume decoding from a state that was saved with [SliceDecoder::state], using
+    /// a custom [Hasher]
+    ///
+    /// The hasher must be the same as the one of the decoder that saved the state.
+    pub fn from_state_with_hasher(saved: DecoderState, hasher: H) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+        let block_size = BlockSize(saved.block_size);
+        check_block_size(block_size)?;
+        let boundaries = saved.ranges.iter().map(|x| ChunkNum(*x)).collect();
+        let ranges: ChunkRanges = SimpleChunkRanges::new(boundaries)
+            .ok_or_else(|| invalid("ranges are not strictly increasing"))?
+            .into();
+        let stack = saved
+            .stack
+            .iter()
+            .map(|hash| blake3::Hash::from(*hash))
+            .collect::<SmallVec<_>>();
+        let size = saved.size.map(ByteNum);
+        let state = match size {
+            None => {
+                if stack.len() != 1 || saved.items != 0 || saved.offset != 0 {
+                    return Err(invalid("inconsistent state before the header"));
+                }
+                State::Header
+            }
+            Some(size) => {
+                if size > MAX_SIZE {
+                    return Err(invalid("size too large"));
+                }
+                let tree = BaoTree::new(size, block_size);
+                let mut iter = ResponseIter::new(tree, truncate_ranges_owned(ranges.clone(), size));
+                // skip the items that have already been completed, keeping track of the
+                // number of hashes they leave on the stack, so the rest of the response
+                // can not run out of hashes
+                let mut depth = 1usize;
+                for _ in 0..saved.items {
+                    match iter.next() {
+                        Some(BaoChunk::Parent { left, right, .. }) => {
+                            depth = depth - 1 + usize::from(left) + usize::from(right);
+                        }
+                        Some(BaoChunk::Leaf { .. }) => depth -= 1,
+                        None => return Err(invalid("number of items too large")),
+                    }
+                }
+                if stack.len() != depth {
+                    return Err(invalid("stack does not match the number of items"));
+                }
+                match iter.next() {
+                    Some(current) => State::Content { iter, current },
+                    None => State::Done,
+                }
+            }
+        };
+        Ok(Self {
+            ranges,
+            block_size,
+            size,
+            state,
+            stack: VerifyStack::from_hashes(stack, block_size, hasher),
+            items: saved.items,
+            offset: saved.offset,
+            buf: BytesMut::new(),
+        })
+    }
+
+    /// The verified progress of the decoder, to resume decoding later
+    ///
+    /// This does not include the bytes of an item that was only partially received.
+    /// Fails for a decoder that failed, since it can not be resumed.
+    pub fn state(&self) -> io::Result<DecoderState> {
+        if self.is_failed() {
+            return Err(failed());
+        }
+        Ok(DecoderState {
+            ranges: self.ranges.boundaries().iter().map(|x| x.0).collect(),
+            block_size: self.block_size.0,
+            size: self.size.map(|size| size.0),
+            stack: self
+                .stack
+                .hashes()
+                .iter()
+                .map(|hash| *hash.as_bytes())
+                .collect(),
+            items: self.items,
+            offset: self.offset,
+        })
+    }
+
+    /// The tree of the response, once the size has been read
+    pub fn tree(&self) -> Option<BaoTree> {
+        self.size.map(|size| BaoTree::new(size, self.block_size))
+    }
+
+    /// True if the response is complete, or there was an error
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done | State::Failed)
+    }
+
+    /// True if there was an error
+    pub fn is_failed(&self) -> bool {
+        matches!(self.state, State::Failed)
+    }
+
+    /// The number of bytes that are needed to complete the next item
+    ///
+    /// This is 0 once the response is complete, and an error once the decoder has
+    /// failed. The response may need more bytes after that item.
+    pub fn needed(&self) -> io::Result<usize> {
+        if self.is_failed() {
+            return Err(failed());
+        }
+        Ok(self.needed0())
+    }
+
+    fn needed0(&self) -> usize {
+        let total = match &self.state {
+            State::Header => 8,
+            State::Content { current, .. } => item_size(current),
+            State::Done | State::Failed => 0,
+        };
+        total - self.buf.len()
+    }
+
+    /// Push bytes of the response into the decoder
+    ///
+    /// Returns the items that were completed and verified. Bytes after the end of the
+    /// response are an error.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<DecodeResponseItem>, AnyDecodeError> {
+        if self.is_failed() {
+            return Err(AnyDecodeError::Io(failed()));
+        }
+        let res = self.push0(bytes);
+        if res.is_err() {
+            self.state = State::Failed;
+        }
+        res
+    }
+
+    /// Finish decoding, checking that the response is complete
+    ///
+    /// If it is not, this returns the same error as [DecodeResponseIter] for a
+    /// response that ends early. If the decoder has failed, this returns an error
+    /// as well.
+    ///
+    /// [DecodeResponseIter]: super::sync::DecodeResponseIter
+    pub fn finish(self) -> Result<(), AnyDecodeError> {
+        match self.state {
+            State::Header => Err(AnyDecodeError::NotFound),
+            State::Content {
+                current: BaoChunk::Parent { node, .. },
+                ..
+            } => Err(AnyDecodeError::ParentNotFound(node)),
+            State::Content {
+                current: BaoChunk::Leaf { start_chunk, .. },
+                ..
+            } => Err(AnyDecodeError::LeafNotFound(start_chunk)),
+            State::Done => Ok(()),
+            State::Failed => Err(AnyDecodeError::Io(failed())),
+        }
+    }
+
+    fn push0(&mut self, mut bytes: &[u8]) -> Result<Vec<DecodeResponseItem>, AnyDecodeError> {
+        let mut res = Vec::new();
+        loop {
+            if self.is_done() {
+                if !bytes.is_empty() {
+                    return Err(AnyDecodeError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "data after the end of the response",
+                    )));
+                }
+                break;
+            }
+            let n = self.needed0().min(bytes.len());
+            self.buf.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            // items of size 0, such as the leaf of an empty blob, complete right away
+            if self.needed0() > 0 {
+                break;
+            }
+            res.push(self.complete()?);
+        }
+        Ok(res)
+    }
+
+    /// Verify the current item, which is complete, and move on to the next one
+    fn complete(&mut self) -> Result<DecodeResponseItem, AnyDecodeError> {
+        let item = match &self.state {
+            State::Header => {
+                check_block_size(self.block_size).map_err(AnyDecodeError::Io)?;
+                let size = ByteNum(u64::from_le_bytes(self.buf[..].try_into().unwrap()));
+                check_size(size, MAX_SIZE)?;
+                self.buf.clear();
+                self.size = Some(size);
+                self.offset += 8;
+                let tree = BaoTree::new(size, self.block_size);
+                // now we know the size, so we can canonicalize the ranges
+                let ranges = truncate_ranges_owned(self.ranges.clone(), size);
+                let mut iter = ResponseIter::new(tree, ranges);
+                self.state = match iter.next() {
+                    Some(current) => State::Content { iter, current },
+                    None => State::Done,
+                };
+                return Ok(Header { size }.into());
+            }
+            State::Content { current, .. } => match *current {
+                BaoChunk::Parent {
+                    node,
+                    is_root,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let pair = parse_hash_pair(self.buf[..].try_into().unwrap());
+                    self.buf.clear();
+                    self.stack
+                        .verify_parent(node, is_root, left, right, &pair)?;
+                    self.offset += 64;
+                    Parent { node, pair }.into()
+                }
+                BaoChunk::Leaf {
+                    start_chunk,
+                    is_root,
+                    ..
+                } => {
+                    // the size is known once the header has been read
+                    let tree_size = self.size.unwrap_or_default();
+                    self.stack
+                        .verify_leaf(start_chunk, &self.buf, is_root, tree_size)?;
+                    self.offset += self.buf.len() as u64;
+                    Leaf {
+                        offset: start_chunk.to_bytes(),
+                        data: self.buf.split().freeze(),
+                    }
+                    .into()
+                }
+            },
+            State::Done | State::Failed => unreachable!(),
+        };
+        self.items += 1;
+        if let State::Content { iter, current } = &mut self.state {
+            match iter.next() {
+                Some(next) => *current = next,
+                None => self.state = State::Done,
+            }
+        }
+        Ok(item)
+    }
+}
+
+/// The error for using a decoder that has failed
+fn failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "the decoder failed on an earlier error",
+    )
+}
+
+/// The number of bytes of an item in the response
+fn item_size(item: &BaoChunk) -> usize {
+    match item {
+        BaoChunk::Parent { .. } => 64,
+        BaoChunk::Leaf { size, .. } => *size,
+    }
+}
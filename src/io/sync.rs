@@ -1,7 +1,11 @@
 //! Syncronous IO
+#[cfg(feature = "fs")]
+use std::fs::File;
 use std::{
-    io::{self, Read, Write},
-    ops::Range,
+    collections::BTreeMap,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::{ControlFlow, Range},
     result,
 };
 
@@ -12,18 +16,29 @@ use crate::{
         outboard::{parse_hash_pair, PostOrderMemOutboard, PostOrderOutboard, PreOrderOutboard},
         Header, Leaf, Parent,
     },
-    iter::BaoChunk,
-    rec::{encode_selected_rec, truncate_ranges},
-    BaoTree, BlockSize, ByteNum, ChunkRanges, ChunkRangesRef, TreeNode,
+    iter::{BaoChunk, PreOrderPartialChunkIterRef},
+    ranges::{as_chunk_ranges_ref, ChunkRangeSet},
+    rec::{encode_selected_rec, encode_selected_rec_with_hasher, truncate_ranges},
+    BaoTree, BlockNum, BlockSize, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode,
 };
 use blake3::guts::parent_cv;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 pub use positioned_io::{ReadAt, Size, WriteAt};
+#[cfg(feature = "range-collections")]
 use range_collections::{range_set::RangeSetRange, RangeSetRef};
 use smallvec::SmallVec;
 
-use super::{fsm::combine_hash_pair, outboard::PreOrderMemOutboard, DecodeError, StartDecodeError};
-use crate::{hash_subtree, iter::ResponseIterRef};
+use super::{
+    check_block_size, check_size, check_stream_block_size, encode_leaf,
+    outboard::{combine_hash_pair, mem_outboard_len, PreOrderMemOutboard},
+    try_tree, BlockVerifyError, Cancelled, DecodeCombinedError, DecodeError, ParentMismatch,
+    StartDecodeError, VerifyStack,
+};
+use crate::{
+    hash_subtree,
+    hasher::{Blake3Hasher, Hasher},
+    iter::ResponseIterRef,
+};
 
 macro_rules! io_error {
     ($($arg:tt)*) => {
@@ -32,6 +47,16 @@ macro_rules! io_error {
 }
 
 /// An item of a decode response
+///
+/// [DecodeResponseIter] yields these in stream order, each one after it has been
+/// verified. Parents carry the node and the hash pair, so they can be stored with
+/// [OutboardMut::save] as they arrive, e.g. to resume a download later. Leaves carry
+/// the offset and the data. [decode_response_into] does exactly this.
+///
+/// This is the extension point for custom sinks. [decode_response_into],
+/// [decode_ranges_with_outboard], [decode_exact_bytes] and [DecodeMultiIter] are all
+/// thin consumers of the iterator, so a sink that e.g. stores the pairs in a database
+/// or forwards the leaves to a socket can be written the same way.
 #[derive(Debug)]
 pub enum DecodeResponseItem {
     /// We got the header and now know how big the overall size is
@@ -87,6 +112,13 @@ pub trait Outboard {
     fn tree(&self) -> BaoTree;
     /// load the hash pair for a node
     fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>>;
+    /// The size of the outboard in bytes, including the 8 byte length, if known
+    ///
+    /// This is used to validate the outboard before encoding. The default
+    /// implementation returns None, which skips the check.
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
 }
 
 /// A mutable outboard.
@@ -118,6 +150,9 @@ impl<O: Outboard> Outboard for &O {
     fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
         (**self).load(node)
     }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        (**self).outboard_size()
+    }
 }
 
 impl<O: Outboard> Outboard for &mut O {
@@ -130,6 +165,9 @@ impl<O: Outboard> Outboard for &mut O {
     fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
         (**self).load(node)
     }
+    fn outboard_size(&self) -> io::Result<Option<u64>> {
+        (**self).outboard_size()
+    }
 }
 
 impl<R: ReadAt + Size> PreOrderOutboard<R> {
@@ -138,7 +176,7 @@ impl<R: ReadAt + Size> PreOrderOutboard<R> {
         let mut content = [0u8; 8];
         data.read_exact_at(0, &mut content)?;
         let len = ByteNum(u64::from_le_bytes(content[0..8].try_into().unwrap()));
-        let tree = BaoTree::new(len, block_size);
+        let tree = try_tree(len, block_size)?;
         let expected_outboard_size = super::outboard_size(len.0, block_size);
         let size = data.size()?;
         if size != Some(expected_outboard_size) {
@@ -200,6 +238,7 @@ impl<R: ReadAt + Size> PostOrderOutboard<R> {
         let mut suffix = [0u8; 8];
         data.read_exact_at(outboard_size - 8, &mut suffix)?;
         let len = u64::from_le_bytes(suffix);
+        let tree = try_tree(ByteNum(len), block_size)?;
         let expected_outboard_size = super::outboard_size(len, block_size);
         if outboard_size != expected_outboard_size {
             io_error!(
@@ -208,7 +247,6 @@ impl<R: ReadAt + Size> PostOrderOutboard<R> {
                 outboard_size
             );
         }
-        let tree = BaoTree::new(ByteNum(len), block_size);
         Ok(Self { root, tree, data })
     }
 }
@@ -226,7 +264,8 @@ impl<R: ReadAt> Outboard for PostOrderOutboard<R> {
         let Some(offset) = self.tree.post_order_offset(node) else {
             return Ok(None);
         };
-        let offset = offset.value() * 64 + 8;
+        // the length is a suffix, so the pairs start at 0
+        let offset = offset.value() * 64;
         let mut content = [0u8; 64];
         self.data.read_exact_at(offset, &mut content)?;
         Ok(Some(parse_hash_pair(content)))
@@ -254,6 +293,7 @@ impl PreOrderMemOutboard {
         };
         let prefix = &outboard[..8];
         let len = u64::from_le_bytes(prefix.try_into().unwrap());
+        let tree = try_tree(ByteNum(len), block_size)?;
         let expected_outboard_size = super::outboard_size(len, block_size);
         let outboard_size = outboard.len() as u64;
         if outboard_size != expected_outboard_size {
@@ -261,7 +301,6 @@ impl PreOrderMemOutboard {
                 "outboard length does not match expected outboard length: {outboard_size} != {expected_outboard_size}"
             );
         }
-        let tree = BaoTree::new(ByteNum(len), block_size);
         outboard.splice(..8, []);
         Ok(Self {
             root,
@@ -292,6 +331,7 @@ impl PostOrderMemOutboard {
         };
         let suffix = &outboard[outboard.len() - 8..];
         let len = u64::from_le_bytes(suffix.try_into().unwrap());
+        let tree = try_tree(ByteNum(len), block_size)?;
         let expected_outboard_size = super::outboard_size(len, block_size);
         let outboard_size = outboard.len() as u64;
         if outboard_size != expected_outboard_size {
@@ -299,7 +339,6 @@ impl PostOrderMemOutboard {
                 "outboard length does not match expected outboard length: {outboard_size} != {expected_outboard_size}"
             );
         }
-        let tree = BaoTree::new(ByteNum(len), block_size);
         outboard.truncate(outboard.len() - 8);
         Ok(Self {
             root,
@@ -377,18 +416,56 @@ enum Position<'a> {
     Header {
         ranges: &'a ChunkRangesRef,
         block_size: BlockSize,
+        max_size: ByteNum,
     },
     /// currently reading the tree, all the info we need is in the iter
     Content { iter: ResponseIterRef<'a> },
 }
 
 /// Iterator that can be used to decode a response to a range request
+///
+/// The iterator is lazy. Each call to `next` reads a single item, the header, a hash
+/// pair or a leaf, from the reader and verifies it before returning it. So memory use
+/// is bounded by the block size, and each leaf is available as soon as it has arrived.
+///
+/// The data of a leaf is read into the buffer of the iterator, and yielded as [Bytes]
+/// that are split off the buffer, so it is never copied. If the data of a leaf is
+/// dropped before the next leaf is read, the allocation of the buffer is reused, so
+/// the number of allocations does not depend on the size of the response.
+///
+/// Hashes are computed with `H`, which defaults to blake3, see [Hasher].
+///
+/// This is the one decoder that all other sync decoders are built on, such as
+/// [decode_ranges_with], [decode_ranges_into_slice] and [decode_combined].
 #[derive(Debug)]
-pub struct DecodeResponseIter<'a, R> {
+pub struct DecodeResponseIter<'a, R, H = Blake3Hasher> {
     inner: Position<'a>,
-    stack: SmallVec<[blake3::Hash; 10]>,
+    stack: VerifyStack<H>,
     encoded: R,
     buf: BytesMut,
+    bytes_read: u64,
+    verified: ChunkRanges,
+    stats: DecodeStats,
+    /// continue after a hash mismatch, see [DecodeResponseIter::continue_on_mismatch]
+    continue_on_mismatch: bool,
+    /// a node whose hash pair did not match, the rest of its subtree is skipped
+    skip: Option<TreeNode>,
+    /// the chunk ranges of the leaves that failed or were skipped
+    failed: ChunkRanges,
+}
+
+/// Statistics of a decode, see [DecodeResponseIter::stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// The number of bytes of verified leaf data
+    pub payload_bytes: u64,
+    /// The number of verified hash pairs
+    pub parent_pairs: u64,
+    /// The number of bytes read from the wire, including the header and the bytes of
+    /// an item that failed
+    pub wire_bytes: u64,
+    /// The number of verified leaves
+    pub leaf_hashes: u64,
 }
 
 impl<'a, R: Read> DecodeResponseIter<'a, R> {
@@ -396,11 +473,11 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
     ///
     /// For decoding you need to know the root hash, block size, and the ranges that were requested.
     /// Additionally you need to provide a reader that can be used to read the encoded data.
-    pub fn new(
+    pub fn new<Q: ChunkRangeSet + ?Sized>(
         root: blake3::Hash,
         block_size: BlockSize,
         encoded: R,
-        ranges: &'a ChunkRangesRef,
+        ranges: &'a Q,
     ) -> Self {
         let buf = BytesMut::with_capacity(block_size.bytes());
         Self::new_with_buffer(root, block_size, encoded, ranges, buf)
@@ -410,21 +487,75 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
     ///
     /// This is the same as [Self::new], but allows you to provide a buffer to use for decoding.
     /// The buffer will be resized as needed, but it's capacity should be the [BlockSize::bytes].
-    pub fn new_with_buffer(
+    pub fn new_with_buffer<Q: ChunkRangeSet + ?Sized>(
         root: blake3::Hash,
         block_size: BlockSize,
         encoded: R,
-        ranges: &'a ChunkRangesRef,
+        ranges: &'a Q,
+        buf: BytesMut,
+    ) -> Self {
+        DecodeResponseIter::new_with_hasher(root, block_size, encoded, ranges, buf, Blake3Hasher)
+    }
+}
+
+impl<'a, R: Read, H: Hasher> DecodeResponseIter<'a, R, H> {
+    /// Create a new iterator to decode a response, using a custom [Hasher].
+    ///
+    /// This is the same as [DecodeResponseIter::new_with_buffer], but computes all
+    /// hashes with `hasher`.
+    pub fn new_with_hasher<Q: ChunkRangeSet + ?Sized>(
+        root: blake3::Hash,
+        block_size: BlockSize,
+        encoded: R,
+        ranges: &'a Q,
         buf: BytesMut,
+        hasher: H,
     ) -> Self {
-        let mut stack = SmallVec::new();
-        stack.push(root);
+        let ranges = as_chunk_ranges_ref(ranges);
         Self {
-            stack,
-            inner: Position::Header { ranges, block_size },
+            stack: VerifyStack::new(root, block_size, hasher),
+            inner: Position::Header {
+                ranges,
+                block_size,
+                max_size: super::MAX_SIZE,
+            },
             encoded,
             buf,
+            bytes_read: 0,
+            verified: ChunkRanges::empty(),
+            stats: DecodeStats::default(),
+            continue_on_mismatch: false,
+            skip: None,
+            failed: ChunkRanges::empty(),
+        }
+    }
+
+    /// Set the largest size that is accepted in the header
+    ///
+    /// A larger size fails with [AnyDecodeError::SizeTooLarge] before anything is
+    /// allocated for it. The default is [super::MAX_SIZE]. This has no effect once the
+    /// header has been read.
+    pub fn with_max_size(mut self, max_size: ByteNum) -> Self {
+        if let Position::Header { max_size: m, .. } = &mut self.inner {
+            *m = max_size;
         }
+        self
+    }
+
+    /// Continue decoding after a hash mismatch
+    ///
+    /// By default, the decode has to be stopped at the first error. In this mode, a
+    /// [AnyDecodeError::LeafHashMismatch] or [AnyDecodeError::ParentHashMismatch] is
+    /// yielded, and the next call continues with the next item that can still be
+    /// verified. For a hash pair that does not match, the rest of its subtree can not
+    /// be verified, so it is read and discarded. All other items are verified through
+    /// different hash pairs, so e.g. the intact parts of a damaged file can be
+    /// recovered. See [Self::failed_ranges] for what was lost.
+    ///
+    /// Other errors, e.g. a response that ends early, still end the decode.
+    pub fn continue_on_mismatch(mut self) -> Self {
+        self.continue_on_mismatch = true;
+        self
     }
 
     /// Get a reference to the buffer used for decoding.
@@ -442,12 +573,84 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
         }
     }
 
-    fn next0(&mut self) -> result::Result<Option<DecodeResponseItem>, AnyDecodeError> {
+    /// Get back the reader
+    ///
+    /// Once the iterator is done, the reader is positioned right after the response,
+    /// so it can be used to read whatever follows.
+    pub fn into_inner(self) -> R {
+        self.encoded
+    }
+
+    /// The number of bytes that have been read from the reader so far
+    ///
+    /// This includes the 8 byte header, and the bytes of an item that could only be
+    /// read partially before an error. So after an error, this is the position in
+    /// the response where the error happened, e.g. to account for the bandwidth used
+    /// or to decide where to restart.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The chunk ranges of the leaves that have been verified so far
+    ///
+    /// The requested ranges are canonicalized for the size, so this can differ from
+    /// them, e.g. a query past the end of the blob delivers the last chunk. Once the
+    /// iterator is done, this is the same as [super::covered_chunk_ranges], and
+    /// together with the size from [Self::tree] tells what was actually delivered.
+    /// After an error, it contains everything before the error.
+    pub fn verified_ranges(&self) -> &ChunkRanges {
+        &self.verified
+    }
+
+    /// The chunk ranges of the leaves that failed verification so far
+    ///
+    /// This includes the leaves that were skipped because a hash pair above them did
+    /// not match, see [Self::continue_on_mismatch].
+    pub fn failed_ranges(&self) -> &ChunkRanges {
+        &self.failed
+    }
+
+    /// Statistics of the decode so far
+    ///
+    /// Only items that have been verified are counted, except for
+    /// [DecodeStats::wire_bytes], which is the same as [Self::bytes_read].
+    pub fn stats(&self) -> DecodeStats {
+        DecodeStats {
+            wire_bytes: self.bytes_read,
+            ..self.stats
+        }
+    }
+
+    /// Read and verify the next item, reading the data of a leaf into `target` at its
+    /// offset in the blob
+    ///
+    /// The data of a returned leaf is empty, since it is in `target`. `target` must be
+    /// at least as large as the size of the blob, see [Self::with_max_size].
+    pub(crate) fn next_into(
+        &mut self,
+        target: &mut [u8],
+    ) -> Option<result::Result<DecodeResponseItem, AnyDecodeError>> {
+        self.next0(Some(target)).transpose()
+    }
+
+    fn next0(
+        &mut self,
+        target: Option<&mut [u8]>,
+    ) -> result::Result<Option<DecodeResponseItem>, AnyDecodeError> {
+        let mut encoded = CountingRead {
+            inner: &mut self.encoded,
+            count: &mut self.bytes_read,
+        };
         let inner = match &mut self.inner {
             Position::Content { ref mut iter } => iter,
-            Position::Header { block_size, ranges } => {
-                let size =
-                    read_len(&mut self.encoded).map_err(StartDecodeError::maybe_not_found)?;
+            Position::Header {
+                block_size,
+                ranges,
+                max_size,
+            } => {
+                check_block_size(*block_size).map_err(AnyDecodeError::Io)?;
+                let size = read_len(&mut encoded).map_err(StartDecodeError::maybe_not_found)?;
+                check_size(size, *max_size)?;
                 let tree = BaoTree::new(size, *block_size);
                 // now we know the size, so we can canonicalize the ranges
                 let ranges = truncate_ranges(ranges, tree.size());
@@ -457,7 +660,34 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 return Ok(Some(Header { size }.into()));
             }
         };
-        match inner.next() {
+        let mut item = inner.next();
+        // discard the items below a hash pair that did not match
+        while let Some(skip) = self.skip {
+            match item {
+                Some(BaoChunk::Parent { node, .. })
+                    if skip.chunk_range().contains(&node.chunk_range().start) =>
+                {
+                    read_parent(&mut encoded)
+                        .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
+                }
+                Some(BaoChunk::Leaf {
+                    size, start_chunk, ..
+                }) if skip.chunk_range().contains(&start_chunk) => {
+                    self.buf.resize(size, 0);
+                    encoded
+                        .read_exact(&mut self.buf)
+                        .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
+                    let end = start_chunk + ByteNum(size as u64).chunks();
+                    self.failed |= crate::ranges::chunk_range(start_chunk, end);
+                }
+                _ => {
+                    self.skip = None;
+                    break;
+                }
+            }
+            item = inner.next();
+        }
+        match item {
             Some(BaoChunk::Parent {
                 is_root,
                 left,
@@ -465,19 +695,15 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 node,
                 ..
             }) => {
-                let pair @ (l_hash, r_hash) = read_parent(&mut self.encoded)
+                let pair @ (l_hash, r_hash) = read_parent(&mut encoded)
                     .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
-                let parent_hash = self.stack.pop().unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
-                if parent_hash != actual {
-                    return Err(AnyDecodeError::ParentHashMismatch(node));
-                }
-                if right {
-                    self.stack.push(r_hash);
-                }
-                if left {
-                    self.stack.push(l_hash);
+                if let Err(e) = self.stack.verify_parent(node, is_root, left, right, &pair) {
+                    if self.continue_on_mismatch {
+                        self.skip = Some(node);
+                    }
+                    return Err(e.into());
                 }
+                self.stats.parent_pairs += 1;
                 Ok(Some(Parent { node, pair }.into()))
             }
             Some(BaoChunk::Leaf {
@@ -486,19 +712,38 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 start_chunk,
                 ..
             }) => {
-                self.buf.resize(size, 0);
-                self.encoded
-                    .read_exact(&mut self.buf)
+                let in_target = target.is_some();
+                let buf = match target {
+                    Some(target) => {
+                        let start = start_chunk.to_bytes().to_usize();
+                        &mut target[start..start + size]
+                    }
+                    None => {
+                        self.buf.resize(size, 0);
+                        &mut self.buf[..]
+                    }
+                };
+                encoded
+                    .read_exact(buf)
                     .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
-                let actual = hash_subtree(start_chunk.0, &self.buf, is_root);
-                let leaf_hash = self.stack.pop().unwrap();
-                if leaf_hash != actual {
-                    return Err(AnyDecodeError::LeafHashMismatch(start_chunk));
+                let end = start_chunk + ByteNum(size as u64).chunks();
+                let tree_size = inner.tree().size;
+                if let Err(e) = self.stack.verify_leaf(start_chunk, buf, is_root, tree_size) {
+                    self.failed |= crate::ranges::chunk_range(start_chunk, end);
+                    return Err(e.into());
                 }
+                self.verified |= crate::ranges::chunk_range(start_chunk, end);
+                self.stats.leaf_hashes += 1;
+                self.stats.payload_bytes += size as u64;
+                let data = if in_target {
+                    Bytes::new()
+                } else {
+                    self.buf.split().freeze()
+                };
                 Ok(Some(
                     Leaf {
                         offset: start_chunk.to_bytes(),
-                        data: self.buf.split().freeze(),
+                        data,
                     }
                     .into(),
                 ))
@@ -508,244 +753,2288 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
     }
 }
 
-impl<'a, R: Read> Iterator for DecodeResponseIter<'a, R> {
+/// A reader that counts the bytes read, including those of a failed read_exact
+struct CountingRead<'a, R> {
+    inner: &'a mut R,
+    count: &'a mut u64,
+}
+
+impl<'a, R: Read> Read for CountingRead<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        *self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, R: Read, H: Hasher> Iterator for DecodeResponseIter<'a, R, H> {
     type Item = result::Result<DecodeResponseItem, AnyDecodeError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next0().transpose()
+        self.next0(None).transpose()
     }
 }
 
-/// Encode ranges relevant to a query from a reader and outboard to a writer
+/// Decode a response without the size header, for a blob of a known size
 ///
-/// This will not validate on writing, so data corruption will be detected on reading
+/// This is the counterpart of [encode_ranges_headerless]. The geometry of the tree
+/// comes from `size`, which must come from a trusted source, and no size is read
+/// from `encoded`. The iterator never yields a [DecodeResponseItem::Header].
 ///
-/// It is possible to encode ranges from a partial file and outboard.
-/// This will either succeed if the requested ranges are all present, or fail
-/// as soon as a range is missing.
-pub fn encode_ranges<D: ReadAt + Size, O: Outboard, W: Write>(
-    data: D,
-    outboard: O,
-    ranges: &ChunkRangesRef,
-    encoded: W,
-) -> result::Result<(), EncodeError> {
-    let data = data;
-    let mut encoded = encoded;
-    let tree = outboard.tree();
-    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
-    // write header
-    encoded.write_all(tree.size.0.to_le_bytes().as_slice())?;
-    for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
-        match item {
-            BaoChunk::Parent { node, .. } => {
-                let (l_hash, r_hash) = outboard.load(node)?.unwrap();
-                let pair = combine_hash_pair(&l_hash, &r_hash);
-                encoded.write_all(&pair)?;
-            }
-            BaoChunk::Leaf {
-                start_chunk, size, ..
-            } => {
-                let start = start_chunk.to_bytes();
-                let buf = &mut buffer[..size];
-                data.read_exact_at(start.0, buf)?;
-                encoded.write_all(buf)?;
-            }
-        }
-    }
-    Ok(())
+/// Fails if the block size is invalid.
+pub fn decode_ranges_headerless<'a, R: Read, Q: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
+    size: ByteNum,
+    block_size: BlockSize,
+    encoded: R,
+    ranges: &'a Q,
+) -> io::Result<DecodeResponseIter<'a, R>> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_block_size(block_size)?;
+    let tree = BaoTree::new(size, block_size);
+    let mut res = DecodeResponseIter::new(root, block_size, encoded, ranges);
+    res.inner = Position::Content {
+        iter: ResponseIterRef::new(tree, truncate_ranges(ranges, size)),
+    };
+    Ok(res)
 }
 
-/// Encode ranges relevant to a query from a reader and outboard to a writer
+/// Iterator to decode a stream of segments written by [encode_ranges_multi]
 ///
-/// This function validates the data before writing.
+/// Yields the items of each segment together with the index of its query in
+/// `queries`. Each segment starts with a [DecodeResponseItem::Header]. After an
+/// error, the iterator is done, since the position in the stream is then unknown.
+/// The items of the segments before the error have been verified, so they remain
+/// valid.
 ///
-/// It is possible to encode ranges from a partial file and outboard.
-/// This will either succeed if the requested ranges are all present, or fail
-/// as soon as a range is missing.
-pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
-    data: D,
-    outboard: O,
-    ranges: &ChunkRangesRef,
-    encoded: W,
-) -> result::Result<(), EncodeError> {
-    let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
-    stack.push(outboard.root());
-    let data = data;
-    let mut encoded = encoded;
-    let tree = outboard.tree();
-    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
-    let mut out_buf = Vec::new();
-    // canonicalize ranges
-    let ranges = truncate_ranges(ranges, tree.size());
-    // write header
-    encoded.write_all(tree.size.0.to_le_bytes().as_slice())?;
-    for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
-        match item {
-            BaoChunk::Parent {
-                is_root,
-                left,
-                right,
-                node,
-                ..
-            } => {
-                let (l_hash, r_hash) = outboard.load(node)?.unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
-                let expected = stack.pop().unwrap();
-                if actual != expected {
-                    return Err(EncodeError::ParentHashMismatch(node));
-                }
-                if right {
-                    stack.push(r_hash);
+/// All segments are for the same blob, so the size in the header of each segment
+/// must be the same as in the first one. Otherwise the iterator fails with an
+/// [io::ErrorKind::InvalidData] error.
+#[derive(Debug)]
+pub struct DecodeMultiIter<'a, R> {
+    root: blake3::Hash,
+    block_size: BlockSize,
+    queries: &'a [ChunkRanges],
+    index: usize,
+    inner: Option<DecodeResponseIter<'a, R>>,
+    /// the size from the header of the first segment
+    size: Option<ByteNum>,
+}
+
+impl<'a, R: Read> DecodeMultiIter<'a, R> {
+    /// Create a new iterator to decode the segments for `queries`
+    pub fn new(
+        root: blake3::Hash,
+        block_size: BlockSize,
+        encoded: R,
+        queries: &'a [ChunkRanges],
+    ) -> Self {
+        let inner = queries
+            .first()
+            .map(|query| DecodeResponseIter::new(root, block_size, encoded, query));
+        Self {
+            root,
+            block_size,
+            queries,
+            index: 0,
+            inner,
+            size: None,
+        }
+    }
+}
+
+impl<'a, R: Read> Iterator for DecodeMultiIter<'a, R> {
+    type Item = (usize, result::Result<DecodeResponseItem, AnyDecodeError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.as_mut()?.next() {
+                Some(Ok(DecodeResponseItem::Header(Header { size }))) => {
+                    let expected = *self.size.get_or_insert(size);
+                    if size != expected {
+                        self.inner = None;
+                        let cause = io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "size {size} of segment {} does not match size {expected}",
+                                self.index
+                            ),
+                        );
+                        return Some((self.index, Err(AnyDecodeError::Io(cause))));
+                    }
+                    return Some((self.index, Ok(Header { size }.into())));
                 }
-                if left {
-                    stack.push(l_hash);
+                Some(Ok(item)) => return Some((self.index, Ok(item))),
+                Some(Err(cause)) => {
+                    self.inner = None;
+                    return Some((self.index, Err(cause)));
                 }
-                let pair = combine_hash_pair(&l_hash, &r_hash);
-                encoded.write_all(&pair)?;
-            }
-            BaoChunk::Leaf {
-                start_chunk,
-                size,
-                is_root,
-                ranges,
-                ..
-            } => {
-                let expected = stack.pop().unwrap();
-                let start = start_chunk.to_bytes();
-                let buf = &mut buffer[..size];
-                data.read_exact_at(start.0, buf)?;
-                let (actual, to_write) = if !ranges.is_all() {
-                    // we need to encode just a part of the data
-                    //
-                    // write into an out buffer to ensure we detect mismatches
-                    // before writing to the output.
-                    out_buf.clear();
-                    let actual = encode_selected_rec(
-                        start_chunk,
-                        buf,
-                        is_root,
-                        ranges,
-                        tree.block_size.to_u32(),
-                        true,
-                        &mut out_buf,
-                    );
-                    (actual, &out_buf[..])
-                } else {
-                    let actual = hash_subtree(start_chunk.0, buf, is_root);
-                    #[allow(clippy::redundant_slicing)]
-                    (actual, &buf[..])
-                };
-                if actual != expected {
-                    return Err(EncodeError::LeafHashMismatch(start_chunk));
+                None => {
+                    // the segment is done, continue with the next query
+                    let encoded = self.inner.take()?.into_inner();
+                    self.index += 1;
+                    let query = self.queries.get(self.index)?;
+                    self.inner = Some(DecodeResponseIter::new(
+                        self.root,
+                        self.block_size,
+                        encoded,
+                        query,
+                    ));
                 }
-                encoded.write_all(to_write)?;
             }
         }
     }
-    Ok(())
 }
 
-/// Decode a response into a file while updating an outboard.
+/// Verify an encoded response against a root hash, without keeping the data.
 ///
-/// If you do not want to update an outboard, use [super::outboard::EmptyOutboard] as
-/// the outboard.
-pub fn decode_response_into<R, O, W>(
+/// This performs the same checks as [DecodeResponseIter], but reuses a single buffer
+/// for all leaves instead of handing out the data, so it is cheaper than decoding
+/// and discarding the result. It returns on the first mismatch.
+///
+/// Like for decoding, ranges that are beyond the end of the data are treated as a
+/// request for the last chunk.
+pub fn verify_ranges<R: ChunkRangeSet + ?Sized>(
     root: blake3::Hash,
     block_size: BlockSize,
-    ranges: &ChunkRangesRef,
-    encoded: R,
-    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
-    mut target: W,
-) -> io::Result<Option<O>>
-where
-    O: OutboardMut,
-    R: Read,
-    W: WriteAt,
-{
-    let iter = DecodeResponseIter::new(root, block_size, encoded, ranges);
-    let mut outboard = None;
-    let mut tree = None;
-    let mut create = Some(create);
-    for item in iter {
-        match item? {
-            DecodeResponseItem::Header(Header { size }) => {
-                tree = Some(BaoTree::new(size, block_size));
-            }
-            DecodeResponseItem::Parent(Parent { node, pair }) => {
-                let outboard = if let Some(outboard) = outboard.as_mut() {
-                    outboard
-                } else {
-                    let create = create.take().unwrap();
-                    outboard = Some(create(tree.take().unwrap(), root)?);
-                    outboard.as_mut().unwrap()
-                };
-                outboard.save(node, &pair)?;
-            }
-            DecodeResponseItem::Leaf(Leaf { offset, data }) => {
-                target.write_all_at(offset.0, &data)?;
-            }
-        }
-    }
-    Ok(outboard)
+    ranges: &R,
+    encoded: impl Read,
+) -> result::Result<(), AnyDecodeError> {
+    validate_slice(root, block_size, ranges, encoded).map(|_| ())
 }
 
-/// Write ranges from memory to disk
+/// Validate an encoded response against a root hash, and return the proven ranges.
 ///
-/// This is useful for writing changes to outboards.
-/// Note that it is up to you to call flush.
-pub fn write_ranges(
-    from: impl AsRef<[u8]>,
-    mut to: impl WriteAt,
-    ranges: &RangeSetRef<u64>,
-) -> io::Result<()> {
-    let from = from.as_ref();
-    let end = from.len() as u64;
-    for range in ranges.iter() {
-        let range = match range {
-            RangeSetRange::RangeFrom(x) => *x.start..end,
-            RangeSetRange::Range(x) => *x.start..*x.end,
-        };
-        let start = usize::try_from(range.start).unwrap();
-        let end = usize::try_from(range.end).unwrap();
-        to.write_all_at(range.start, &from[start..end])?;
-    }
-    Ok(())
+/// This is [verify_ranges], but returns the chunk ranges of the data that was
+/// verified. These are the same as [super::covered_chunk_ranges] for the size in the
+/// header. Nothing is written and the data is not copied anywhere, so this can be
+/// used to check a slice before forwarding it, or to check a locally stored slice.
+pub fn validate_slice<R: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &R,
+    encoded: impl Read,
+) -> result::Result<ChunkRanges, AnyDecodeError> {
+    validate_slice_with_hasher(root, block_size, ranges, encoded, Blake3Hasher)
 }
 
-/// Compute the post order outboard for the given data, writing into a io::Write
-pub fn outboard_post_order(
-    data: impl Read,
-    size: u64,
+/// Validate an encoded response against a root hash, using a custom [Hasher]
+///
+/// This is the same as [validate_slice], but computes all hashes with `hasher`.
+pub fn validate_slice_with_hasher<R: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
     block_size: BlockSize,
-    mut outboard: impl Write,
-) -> io::Result<blake3::Hash> {
-    let tree = BaoTree::new(ByteNum(size), block_size);
-    let mut buffer = vec![0; tree.chunk_group_bytes().to_usize()];
-    let hash = outboard_post_order_impl(tree, data, &mut outboard, &mut buffer)?;
-    outboard.write_all(&size.to_le_bytes())?;
-    Ok(hash)
+    ranges: &R,
+    encoded: impl Read,
+    hasher: impl Hasher,
+) -> result::Result<ChunkRanges, AnyDecodeError> {
+    let buf = BytesMut::with_capacity(block_size.bytes());
+    let mut iter =
+        DecodeResponseIter::new_with_hasher(root, block_size, encoded, ranges, buf, hasher);
+    for item in &mut iter {
+        item?;
+    }
+    Ok(iter.verified_ranges().clone())
 }
 
-/// Compute the post order outboard for the given data
+/// Verify an encoded response while forwarding it unchanged to `output`
 ///
-/// This is the internal version that takes a start chunk and does not append the size!
-pub(crate) fn outboard_post_order_impl(
-    tree: BaoTree,
-    mut data: impl Read,
-    mut outboard: impl Write,
-    buffer: &mut [u8],
-) -> io::Result<blake3::Hash> {
+/// This is for relays that pass a response on without decoding and encoding it
+/// again. Each item is written to `output` as soon as it has been verified, so the
+/// output is a byte for byte copy of the verified prefix of the input. On the first
+/// error nothing more is written, so the output never contains anything past a
+/// corrupted item.
+///
+/// The size header is held back until the first hash pair or leaf has been verified,
+/// since that proves the size. A response without any items, e.g. for an empty query,
+/// is forwarded at the end. Returns the statistics of the decode, see
+/// [DecodeResponseIter::stats].
+pub fn validate_passthrough<R: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &R,
+    encoded: impl Read,
+    mut output: impl Write,
+) -> result::Result<DecodeStats, AnyDecodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    let mut iter = DecodeResponseIter::new(root, block_size, encoded, ranges);
+    let mut header = None;
+    for item in iter.by_ref() {
+        let item = item?;
+        if let DecodeResponseItem::Header(Header { size }) = item {
+            header = Some(size.0.to_le_bytes());
+            continue;
+        }
+        if let Some(header) = header.take() {
+            output.write_all(&header).map_err(AnyDecodeError::Io)?;
+        }
+        match item {
+            DecodeResponseItem::Parent(Parent { pair: (l, r), .. }) => {
+                output.write_all(&combine_hash_pair(&l, &r))
+            }
+            DecodeResponseItem::Leaf(Leaf { data, .. }) => output.write_all(&data),
+            DecodeResponseItem::Header(_) => Ok(()),
+        }
+        .map_err(AnyDecodeError::Io)?;
+    }
+    if let Some(header) = header {
+        output.write_all(&header).map_err(AnyDecodeError::Io)?;
+    }
+    output.flush().map_err(AnyDecodeError::Io)?;
+    Ok(iter.stats())
+}
+
+/// Decode an encoded response, calling `on_leaf` with each verified leaf.
+///
+/// `on_leaf` gets the byte offset of the leaf in the blob and its data. The data is
+/// borrowed from a buffer of the chunk group size that is reused for all leaves, so it
+/// is only valid for the duration of the call. Apart from this buffer, nothing is
+/// allocated per leaf. If `on_leaf` returns an error, decoding stops and the error is
+/// returned as [AnyDecodeError::Io].
+///
+/// Returns the size of the blob from the header.
+pub fn decode_ranges_with<R: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &R,
+    encoded: impl Read,
+    mut on_leaf: impl FnMut(ByteNum, &[u8]) -> io::Result<()>,
+) -> result::Result<ByteNum, AnyDecodeError> {
+    let mut size = ByteNum(0);
+    for item in DecodeResponseIter::new(root, block_size, encoded, ranges) {
+        match item? {
+            DecodeResponseItem::Header(header) => size = header.size,
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(leaf) => {
+                on_leaf(leaf.offset, &leaf.data).map_err(AnyDecodeError::Io)?
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Decode an encoded response directly into a slice of memory.
+///
+/// This is like [validate_slice], but the data of each leaf is read directly into
+/// `target`, at its offset in the blob, and verified there. So there is no allocation
+/// or copy per leaf. Returns the chunk ranges that were written and verified.
+///
+/// `target` must be at least as large as the size in the header, otherwise this
+/// fails with [AnyDecodeError::SizeTooLarge] before anything is written. If decoding
+/// fails later, the part of `target` for the leaf that failed contains unverified
+/// data.
+pub fn decode_ranges_into_slice<R: ChunkRangeSet + ?Sized>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &R,
+    encoded: impl Read,
+    target: &mut [u8],
+) -> result::Result<ChunkRanges, AnyDecodeError> {
+    let mut iter = DecodeResponseIter::new(root, block_size, encoded, ranges)
+        .with_max_size(ByteNum(target.len() as u64));
+    while let Some(item) = iter.next_into(target) {
+        item?;
+    }
+    Ok(iter.verified_ranges().clone())
+}
+
+/// Incremental verification of a blob that arrives in multiple slices
+///
+/// Each slice is an encoded response, as written by [encode_ranges], that is
+/// verified like in [verify_ranges]. The verifier keeps the hash pairs of all
+/// completely verified slices. If a later slice contains a hash pair that is already
+/// known, it is just compared to the known pair instead of being hashed again.
+///
+/// The size is taken from the header of the first slice that is completely
+/// verified. Later slices must have the same size.
+///
+/// Hashes are computed with `H`, which defaults to blake3, see [Hasher].
+#[derive(Debug, Clone)]
+pub struct Verifier<H = Blake3Hasher> {
+    root: blake3::Hash,
+    block_size: BlockSize,
+    tree: Option<BaoTree>,
+    pairs: BTreeMap<TreeNode, (blake3::Hash, blake3::Hash)>,
+    hasher: H,
+}
+
+impl Verifier {
+    /// Create a new verifier for the blob with the given root hash
+    pub fn new(root: blake3::Hash, block_size: BlockSize) -> Self {
+        Self::new_with_hasher(root, block_size, Blake3Hasher)
+    }
+}
+
+impl<H: Hasher> Verifier<H> {
+    /// Create a new verifier for the blob with the given root hash, using a custom
+    /// [Hasher]
+    pub fn new_with_hasher(root: blake3::Hash, block_size: BlockSize, hasher: H) -> Self {
+        Self {
+            root,
+            block_size,
+            tree: None,
+            pairs: BTreeMap::new(),
+            hasher,
+        }
+    }
+
+    /// The root hash
+    pub fn root(&self) -> blake3::Hash {
+        self.root
+    }
+
+    /// The tree, once a slice has been verified
+    pub fn tree(&self) -> Option<BaoTree> {
+        self.tree
+    }
+
+    /// Verify a slice that is a response to a query for `ranges`
+    ///
+    /// Returns the byte ranges of the data in the slice, which have been verified.
+    /// If the slice fails to verify, the state of the verifier is not changed.
+    pub fn feed_slice<R: ChunkRangeSet + ?Sized>(
+        &mut self,
+        mut encoded: impl Read,
+        ranges: &R,
+    ) -> result::Result<Vec<Range<ByteNum>>, AnyDecodeError> {
+        let ranges = as_chunk_ranges_ref(ranges);
+        check_block_size(self.block_size).map_err(AnyDecodeError::Io)?;
+        let size = read_len(&mut encoded).map_err(StartDecodeError::maybe_not_found)?;
+        check_size(size, super::MAX_SIZE)?;
+        let tree = BaoTree::new(size, self.block_size);
+        if matches!(self.tree, Some(known) if known != tree) {
+            return Err(AnyDecodeError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "size does not match the size of previous slices",
+            )));
+        }
+        // now we know the size, so we can canonicalize the ranges
+        let ranges = truncate_ranges(ranges, tree.size());
+        let mut stack = VerifyStack::new(self.root, self.block_size, &self.hasher);
+        let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+        let mut new_pairs = Vec::new();
+        let mut res = Vec::new();
+        for item in ResponseIterRef::new(tree, ranges) {
+            match item {
+                BaoChunk::Parent {
+                    is_root,
+                    left,
+                    right,
+                    node,
+                    ..
+                } => {
+                    let pair = read_parent(&mut encoded)
+                        .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
+                    if let Some(known) = self.pairs.get(&node) {
+                        // the known pair has been verified against the same root
+                        stack.verify_known_parent(node, is_root, left, right, &pair, known)?;
+                    } else {
+                        stack.verify_parent(node, is_root, left, right, &pair)?;
+                        new_pairs.push((node, pair));
+                    }
+                }
+                BaoChunk::Leaf {
+                    size,
+                    is_root,
+                    start_chunk,
+                    ..
+                } => {
+                    let buf = &mut buffer[..size];
+                    encoded
+                        .read_exact(buf)
+                        .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
+                    stack.verify_leaf(start_chunk, buf, is_root, tree.size)?;
+                    let start = start_chunk.to_bytes();
+                    res.push(start..start + size as u64);
+                }
+            }
+        }
+        self.tree = Some(tree);
+        self.pairs.extend(new_pairs);
+        Ok(res)
+    }
+}
+
+/// The query for a size proof
+///
+/// A query for anything beyond the end of the data is canonicalized to the last
+/// chunk, so this works without knowing the size.
+fn size_proof_ranges() -> ChunkRanges {
+    ChunkRanges::from(ChunkNum(u64::MAX)..)
+}
+
+/// Encode a proof of the size of a blob
+///
+/// The proof is the encoding of the last chunk, i.e. the size, the hash pairs from
+/// the root to the last leaf, and the data of the last chunk. The hash of the last
+/// chunk depends on its length and position, so this pins down the size. The data
+/// is validated against the outboard before writing.
+///
+/// Use [verify_size] to check the proof on the receiving side.
+pub fn encode_size_proof<D: ReadAt + Size, O: Outboard>(
+    data: D,
+    outboard: O,
+) -> result::Result<Vec<u8>, EncodeError> {
+    let ranges = size_proof_ranges();
+    let mut res = Vec::with_capacity(encoded_capacity(outboard.tree(), &ranges));
+    encode_ranges_validated(data, outboard, &ranges, &mut res)?;
+    Ok(res)
+}
+
+/// The capacity for a buffer for the encoding of `ranges`, 0 if it does not fit
+///
+/// This is only used for encodings of local data, so the size is trusted.
+fn encoded_capacity(tree: BaoTree, ranges: &ChunkRangesRef) -> usize {
+    tree.encoded_size(ranges).try_to_usize().unwrap_or(0)
+}
+
+/// Verify a size proof from [encode_size_proof], and return the verified size
+///
+/// This allows learning the size of a blob from an untrusted peer before deciding
+/// which ranges to fetch. A proof with a size header that is inconsistent with the
+/// hash chain fails with an [io::ErrorKind::InvalidData] error.
+pub fn verify_size(
+    root: blake3::Hash,
+    proof: impl Read,
+    block_size: BlockSize,
+) -> io::Result<ByteNum> {
+    let ranges = size_proof_ranges();
+    let mut size = None;
+    for item in DecodeResponseIter::new(root, block_size, proof, &ranges) {
+        if let DecodeResponseItem::Header(header) = item? {
+            size = Some(header.size);
+        }
+    }
+    size.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "missing size"))
+}
+
+/// Decode a combined encoding of an entire blob into memory
+///
+/// This is the counterpart of [encode_all] and [encode_combined]. The data is only
+/// returned if the entire stream was verified against the root hash.
+pub fn decode_all(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    encoded: impl Read,
+) -> result::Result<Vec<u8>, AnyDecodeError> {
+    let mut res = Vec::new();
+    decode_combined(root, block_size, encoded, &mut res).map_err(|e| e.cause)?;
+    Ok(res)
+}
+
+/// Decode a combined encoding of an entire blob, writing the data to `out`.
+///
+/// This is the counterpart of [encode_combined]. It verifies the stream against the
+/// root hash and writes the data of each leaf as soon as it is verified, reusing a
+/// single buffer for all leaves.
+///
+/// Returns the size of the blob. On failure, the error contains the number of bytes
+/// that were verified and written to `out` before the failure.
+pub fn decode_combined(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    encoded: impl Read,
+    out: impl Write,
+) -> result::Result<u64, DecodeCombinedError> {
+    decode_combined_with_hasher(root, block_size, encoded, out, Blake3Hasher)
+}
+
+/// Decode a combined encoding of an entire blob, using a custom [Hasher]
+///
+/// This is the same as [decode_combined], but computes all hashes with `hasher`.
+pub fn decode_combined_with_hasher(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    encoded: impl Read,
+    mut out: impl Write,
+    hasher: impl Hasher,
+) -> result::Result<u64, DecodeCombinedError> {
+    let ranges = ChunkRanges::all();
+    let buf = BytesMut::with_capacity(block_size.bytes());
+    let mut iter =
+        DecodeResponseIter::new_with_hasher(root, block_size, encoded, &ranges, buf, hasher);
+    let mut written = 0u64;
+    let mut decode = || -> result::Result<u64, AnyDecodeError> {
+        let mut size = 0;
+        for item in &mut iter {
+            match item? {
+                DecodeResponseItem::Header(header) => size = header.size.0,
+                DecodeResponseItem::Parent(_) => {}
+                DecodeResponseItem::Leaf(leaf) => {
+                    out.write_all(&leaf.data).map_err(AnyDecodeError::Io)?;
+                    written += leaf.data.len() as u64;
+                }
+            }
+        }
+        Ok(size)
+    };
+    decode().map_err(|cause| DecodeCombinedError { written, cause })
+}
+
+/// Verify a single block against an outboard.
+///
+/// This is a cheap predicate for storage engines that store blocks individually
+/// and want to check a block after reading it, without going through encoding and
+/// decoding. It does not allocate.
+///
+/// The outboard is trusted, so this only checks that `data` matches the hash stored
+/// in the outboard. To check the outboard itself, use [valid_ranges].
+///
+/// For most blocks the hash is stored in the hash pair of the leaf containing the
+/// block. The last block of a tree has no leaf pair if it is the left half of its
+/// leaf. Its hash is then taken from the nearest persisted ancestor, or from the
+/// root hash if the tree consists of just this block.
+pub fn verify_block(
+    tree: &BaoTree,
+    outboard: &impl Outboard,
+    block: BlockNum,
+    data: &[u8],
+) -> result::Result<(), BlockVerifyError> {
+    check_block_data(tree, block, data)?;
+    let is_root = tree.blocks() == 1;
+    let actual = hash_subtree(block.to_chunks(tree.block_size).0, data, is_root);
+    // the shifted leaf containing the block
+    let leaf = TreeNode(block.0 & !1);
+    let expected = match outboard.load(leaf.subtract_block_size(tree.block_size.0))? {
+        Some((l_hash, r_hash)) => {
+            if block.0 & 1 == 0 {
+                l_hash
+            } else {
+                r_hash
+            }
+        }
+        // the last leaf is not persisted, so we have to go up to the nearest ancestor
+        None => stored_hash(tree, outboard, leaf)?,
+    };
+    if actual != expected {
+        return Err(BlockVerifyError::HashMismatch(block));
+    }
+    Ok(())
+}
+
+/// Verify a block together with its sibling block against an outboard.
+///
+/// Unlike [verify_block], this does not use the hash pair of the leaf containing
+/// both blocks. Instead the hash of the leaf is computed from the data of both
+/// blocks and checked against the hash stored in the nearest persisted ancestor,
+/// or against the root hash. This is useful if the leaf pair is not available or
+/// not trusted.
+///
+/// The last block of a tree has no sibling if it is the left half of its leaf.
+/// In that case `sibling` must be empty.
+///
+/// A mismatch is reported for `block`, since it is impossible to tell which of
+/// the two blocks is corrupt.
+pub fn verify_block_with_sibling(
+    tree: &BaoTree,
+    outboard: &impl Outboard,
+    block: BlockNum,
+    data: &[u8],
+    sibling: &[u8],
+) -> result::Result<(), BlockVerifyError> {
+    check_block_data(tree, block, data)?;
+    let sibling_block = BlockNum(block.0 ^ 1);
+    // the shifted leaf containing the block
+    let leaf = TreeNode(block.0 & !1);
+    let (shifted_root, _) = tree.shifted();
+    let is_root = leaf == shifted_root;
+    let actual = if sibling_block >= tree.blocks() {
+        if !sibling.is_empty() {
+            return Err(BlockVerifyError::SizeMismatch(sibling_block));
+        }
+        hash_subtree(block.to_chunks(tree.block_size).0, data, is_root)
+    } else {
+        check_block_data(tree, sibling_block, sibling)?;
+        let (l_data, r_data) = if block.0 & 1 == 0 {
+            (data, sibling)
+        } else {
+            (sibling, data)
+        };
+        let l_start = BlockNum(leaf.0).to_chunks(tree.block_size);
+        let r_start = l_start + tree.chunk_group_chunks();
+        let l_hash = hash_subtree(l_start.0, l_data, false);
+        let r_hash = hash_subtree(r_start.0, r_data, false);
+        parent_cv(&l_hash, &r_hash, is_root)
+    };
+    let expected = stored_hash(tree, outboard, leaf)?;
+    if actual != expected {
+        return Err(BlockVerifyError::HashMismatch(block));
+    }
+    Ok(())
+}
+
+/// Check that the block is within the tree and that the data has the size of the block
+fn check_block_data(
+    tree: &BaoTree,
+    block: BlockNum,
+    data: &[u8],
+) -> result::Result<(), BlockVerifyError> {
+    if block >= tree.blocks() {
+        return Err(BlockVerifyError::BlockOutOfRange(block));
+    }
+    let start = block.to_bytes(tree.block_size);
+    let end = (start + tree.chunk_group_bytes()).min(tree.size);
+    if data.len() as u64 != (end - start).0 {
+        return Err(BlockVerifyError::SizeMismatch(block));
+    }
+    Ok(())
+}
+
+/// Get the hash of a shifted node from the hash pair of its parent, or the root hash
+fn stored_hash(
+    tree: &BaoTree,
+    outboard: &impl Outboard,
+    shifted: TreeNode,
+) -> io::Result<blake3::Hash> {
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    if shifted == shifted_root {
+        return Ok(outboard.root());
+    }
+    // the parent might be more than one level up if we are on the right edge of the tree
+    let parent = shifted.restricted_parent(shifted_filled_size).unwrap();
+    let node = parent.subtract_block_size(tree.block_size.0);
+    let Some((l_hash, r_hash)) = outboard.load(node)? else {
+        io_error!("missing hash pair for node {}", node);
+    };
+    Ok(if shifted < parent { l_hash } else { r_hash })
+}
+
+/// Check that the outboard has the size implied by its tree, if the size is known
+///
+/// A wrong size means that the outboard is truncated or was computed with a
+/// different block size.
+fn check_outboard_size(outboard: &impl Outboard) -> result::Result<(), EncodeError> {
+    super::check_outboard_size(outboard.tree(), outboard.outboard_size()?)
+}
+
+/// Compute the hash of a subtree from its data
+///
+/// `node` is a node of `tree`, e.g. one of the subtrees returned by
+/// [BaoTree::split_at_level], and `data` is the data below it. The chunks are hashed
+/// with their position in the entire blob, so the result is the hash of the node in
+/// the tree, not the hash of `data` as a blob of its own. For the root of the tree,
+/// this is the root hash.
+///
+/// Fails with [io::ErrorKind::InvalidInput] if `data` does not have the size of the
+/// data below `node`.
+pub fn subtree_hash(tree: &BaoTree, node: TreeNode, data: &[u8]) -> io::Result<blake3::Hash> {
+    let range = tree.byte_range(node);
+    if data.len() as u64 != (range.end - range.start).0 {
+        io_error!(
+            "expected {} bytes for node {}, got {}",
+            range.end - range.start,
+            node,
+            data.len()
+        );
+    }
+    let is_root = node == tree.root();
+    Ok(hash_subtree(range.start.full_chunks().0, data, is_root))
+}
+
+/// Compute the root hash from the hashes of the subtrees of a split
+///
+/// `hashes` are the hashes of all subtrees returned by [BaoTree::split_at_level], in
+/// order, as computed by [subtree_hash]. Returns `None` if `hashes` is empty.
+pub fn combine_subtree_hashes(hashes: &[blake3::Hash]) -> Option<blake3::Hash> {
+    fn combine_rec(hashes: &[blake3::Hash], is_root: bool) -> blake3::Hash {
+        if hashes.len() == 1 {
+            return hashes[0];
+        }
+        // all subtrees but the last are full, so the split is at a power of two
+        let mid = hashes.len().next_power_of_two() / 2;
+        let left = combine_rec(&hashes[..mid], false);
+        let right = combine_rec(&hashes[mid..], false);
+        parent_cv(&left, &right, is_root)
+    }
+    if hashes.is_empty() {
+        return None;
+    }
+    Some(combine_rec(hashes, true))
+}
+
+/// Encode ranges relevant to a query from in memory data, without an outboard
+///
+/// All hash pairs are computed from the data during the traversal, so this always
+/// hashes the entire blob, no matter how small the query is. For small blobs or
+/// queries for the entire blob this is about as fast as encoding with an outboard,
+/// and avoids having to store one.
+///
+/// The result includes the 8 byte size prefix and is the same as the output of
+/// [encode_ranges] for the same ranges. Also returns the root hash.
+pub fn encode_ranges_from_data<R: ChunkRangeSet + ?Sized>(
+    data: &[u8],
+    ranges: &R,
+    block_size: BlockSize,
+) -> (Vec<u8>, blake3::Hash) {
+    let ranges = as_chunk_ranges_ref(ranges);
+    let tree = BaoTree::new(ByteNum(data.len() as u64), block_size);
+    let ranges = truncate_ranges(ranges, tree.size());
+    let mut res = Vec::with_capacity(encoded_capacity(tree, ranges));
+    res.extend_from_slice(&tree.size.0.to_le_bytes());
+    let hash = encode_selected_rec(
+        ChunkNum(0),
+        data,
+        true,
+        ranges,
+        block_size.to_u32(),
+        true,
+        &mut res,
+    );
+    (res, hash)
+}
+
+/// Encode everything except the chunks in `have`
+///
+/// This is for sending a peer the chunks it does not have yet. The query is all
+/// chunks of the blob minus `have`, clamped to the size of the blob, so `have` may
+/// contain chunks past the end. Returns the encoding and the chunks that were sent,
+/// so the caller can update what the peer has.
+///
+/// If the peer has everything, no chunks are sent and the encoding is just the
+/// 8 byte size. This is also the case for a blob of size 0, which has no chunks.
+pub fn encode_ranges_excluding<D: ReadAt + Size, O: Outboard, R: ChunkRangeSet + ?Sized>(
+    data: D,
+    outboard: O,
+    have: &R,
+) -> result::Result<(Vec<u8>, ChunkRanges), EncodeError> {
+    let have = as_chunk_ranges_ref(have);
+    let tree = outboard.tree();
+    let mut missing = crate::ranges::chunk_range(ChunkNum(0), tree.chunks());
+    let mut have = ChunkRanges::new_unchecked(SmallVec::from_slice(have.boundaries()));
+    have &= missing.clone();
+    missing ^= have;
+    let mut encoded = Vec::new();
+    encode_ranges(data, outboard, &missing, &mut encoded)?;
+    Ok((encoded, missing))
+}
+
+/// Encode the entire data in the combined format, writing to `encoded`
+///
+/// The combined format is the size, followed by the hash pairs interleaved with
+/// the data in pre order, so the result carries its own proof and can be decoded
+/// without a separate outboard. This is [encode_ranges_from_data] for all chunks, so
+/// no outboard is computed. With [BlockSize::ZERO], the output is identical to the
+/// combined encoding of the bao crate.
+///
+/// Returns the root hash.
+pub fn encode_combined(
+    data: &[u8],
+    block_size: BlockSize,
+    mut encoded: impl Write,
+) -> result::Result<blake3::Hash, EncodeError> {
+    let (res, hash) = encode_ranges_from_data(data, &ChunkRanges::all(), block_size);
+    encoded.write_all(&res)?;
+    Ok(hash)
+}
+
+/// Encode an entire blob in the combined format, using an existing outboard
+///
+/// This is [encode_ranges] for all chunks, so the result is the same as
+/// [encode_combined], without having to hash the data again. Use [decode_all] to
+/// get the data back.
+pub fn encode_all<D: ReadAt + Size, O: Outboard>(
+    data: D,
+    outboard: O,
+) -> result::Result<Vec<u8>, EncodeError> {
+    let tree = outboard.tree();
+    let size = super::encoded_size(tree.size.0, tree.block_size);
+    let mut res = Vec::with_capacity(usize::try_from(size).unwrap_or(0));
+    encode_ranges(data, outboard, &ChunkRanges::all(), &mut res)?;
+    Ok(res)
+}
+
+/// An item of a response to a query, without any hashes or data
+///
+/// This is what [EncodeResponseIter] yields, the encoding side of [DecodeResponseItem].
+/// A transport can send the hash pairs from wherever they are stored, and the data of
+/// complete leaves directly from a file, e.g. with `sendfile`, instead of copying
+/// everything through a buffer.
+#[derive(Debug, Clone)]
+pub enum EncodeResponseItem<'a> {
+    /// The size of the blob, sent as 8 bytes little endian
+    Header(ByteNum),
+    /// The hash pair of a node, sent as 64 bytes, see [Outboard::load]
+    Parent {
+        /// The node of the hash pair
+        node: TreeNode,
+        /// This is the root, to be passed to parent_cv
+        is_root: bool,
+        /// The left child is part of the response
+        left: bool,
+        /// The right child is part of the response
+        right: bool,
+    },
+    /// The data of a leaf
+    Leaf {
+        /// The first chunk of the leaf
+        start_chunk: ChunkNum,
+        /// This is the root, to be passed to hash_subtree
+        is_root: bool,
+        /// The bytes of the blob that make up the leaf
+        byte_range: Range<ByteNum>,
+        /// The requested part of the leaf
+        ///
+        /// If this is not all of the leaf, the leaf is not sent as is, see
+        /// [EncodeResponseIter::leaf_bytes].
+        ranges: &'a ChunkRangesRef,
+    },
+}
+
+/// Iterator over the items of a response to a query, in stream order
+///
+/// This does not touch any data or outboard, so it is the building block for
+/// encoders that do their own io. [encode_ranges] and the other encoders that read
+/// from an outboard or a combined encoding are implemented on top of it.
+#[derive(Debug)]
+pub struct EncodeResponseIter<'a> {
+    tree: BaoTree,
+    /// true if the header still has to be yielded
+    header: bool,
+    inner: PreOrderPartialChunkIterRef<'a>,
+}
+
+impl<'a> EncodeResponseIter<'a> {
+    /// Create a new iterator for a response to a query for `ranges`
+    pub fn new<Q: ChunkRangeSet + ?Sized>(tree: BaoTree, ranges: &'a Q) -> Self {
+        Self::with_min_level(tree, ranges, 0)
+    }
+
+    /// Create a new iterator that sends fully requested subtrees below `min_level` as
+    /// a single leaf, see [encode_ranges_with_min_level]
+    pub fn with_min_level<Q: ChunkRangeSet + ?Sized>(
+        tree: BaoTree,
+        ranges: &'a Q,
+        min_level: u8,
+    ) -> Self {
+        let ranges = as_chunk_ranges_ref(ranges);
+        // canonicalize ranges
+        let ranges = truncate_ranges(ranges, tree.size());
+        Self {
+            tree,
+            header: true,
+            inner: tree.ranges_pre_order_chunks_iter_ref(ranges, min_level),
+        }
+    }
+
+    /// Do not yield the header, for a response without it, see [encode_ranges_headerless]
+    pub fn headerless(mut self) -> Self {
+        self.header = false;
+        self
+    }
+
+    /// The tree of the response
+    pub fn tree(&self) -> BaoTree {
+        self.tree
+    }
+
+    /// The bytes to send for a leaf, given the data in its `byte_range`
+    ///
+    /// If the leaf is fully requested, this is just `data`. Otherwise the requested
+    /// chunks are sent together with the hash pairs below the block size, which are
+    /// computed from the data into `out_buf`.
+    pub fn leaf_bytes<'b>(
+        &self,
+        start_chunk: ChunkNum,
+        data: &'b [u8],
+        ranges: &ChunkRangesRef,
+        out_buf: &'b mut Vec<u8>,
+    ) -> &'b [u8] {
+        encode_leaf(start_chunk, data, ranges, self.tree.block_size, out_buf)
+    }
+}
+
+impl<'a> Iterator for EncodeResponseIter<'a> {
+    type Item = EncodeResponseItem<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.header {
+            self.header = false;
+            return Some(EncodeResponseItem::Header(self.tree.size));
+        }
+        Some(match self.inner.next()? {
+            BaoChunk::Parent {
+                node,
+                is_root,
+                left,
+                right,
+                ..
+            } => EncodeResponseItem::Parent {
+                node,
+                is_root,
+                left,
+                right,
+            },
+            BaoChunk::Leaf {
+                start_chunk,
+                size,
+                is_root,
+                ranges,
+            } => {
+                let start = start_chunk.to_bytes();
+                EncodeResponseItem::Leaf {
+                    start_chunk,
+                    is_root,
+                    byte_range: start..start + size as u64,
+                    ranges,
+                }
+            }
+        })
+    }
+}
+
+/// Announce the block size of the encoder in front of an encoded stream
+///
+/// The block size is not part of the stream, see the [wire format](crate::io#wire-format).
+/// Peers that do not agree on it out of band can write it with this function before
+/// the encoded stream, and the decoder checks it with [read_block_size].
+pub fn write_block_size(block_size: BlockSize, mut writer: impl Write) -> io::Result<()> {
+    check_block_size(block_size)?;
+    writer.write_all(&[block_size.0])
+}
+
+/// Read the block size written by [write_block_size] and check it against the block
+/// size of the decoder
+///
+/// A different block size fails with [StartDecodeError::BlockSizeMismatch], so the
+/// decoder never has to interpret a stream with a different tree geometry.
+pub fn read_block_size(
+    mut reader: impl Read,
+    block_size: BlockSize,
+) -> result::Result<(), StartDecodeError> {
+    let mut buf = [0u8; 1];
+    reader
+        .read_exact(&mut buf)
+        .map_err(StartDecodeError::maybe_not_found)?;
+    check_stream_block_size(buf[0], block_size)
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer
+///
+/// This will not validate on writing, so data corruption will be detected on reading.
+/// Use [encode_ranges_validated] to check the data against the outboard while encoding,
+/// e.g. for a server whose files might be corrupted on disk.
+///
+/// It is possible to encode ranges from a partial file and outboard.
+/// This will either succeed if the requested ranges are all present, or fail
+/// as soon as a range is missing.
+///
+/// Chunk groups that are only partially requested are sent as individual chunks.
+/// The hash pairs below the block size are not in the outboard, so for these groups
+/// they are computed from the data.
+///
+/// The data is only read for the chunk groups that are part of the response, with one
+/// [ReadAt::read_at] call per group, and the outboard only for the hash pairs that are
+/// sent. So both can be large files, or slices of memory mapped files, and only the
+/// parts needed for the query are touched.
+pub fn encode_ranges<D: ReadAt + Size, O: Outboard, W: Write, R: ChunkRangeSet + ?Sized>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    encode_ranges_to(data, outboard, ranges, encoded)?;
+    Ok(())
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer,
+/// returning the number of bytes written
+///
+/// This is the same as [encode_ranges]. The header, hash pairs and leaf data are
+/// written to `encoded` as they are produced, in stream order, so a reader on the
+/// other side can start verifying immediately. The writer is flushed at the end.
+pub fn encode_ranges_to<D: ReadAt + Size, O: Outboard, W: Write, R: ChunkRangeSet + ?Sized>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<u64, EncodeError> {
+    encode_ranges_with_min_level(data, outboard, ranges, 0, encoded)
+}
+
+/// Encode ranges relevant to a query, sending fully requested subtrees below
+/// `min_level` as data, returning the number of bytes written
+///
+/// Subtrees of up to `2^min_level` chunks that are entirely requested are sent as a
+/// single leaf, without the hash pairs below them. This trades fewer hash pairs for
+/// more data per leaf, since a leaf can only be verified once all of it has arrived.
+/// A `min_level` at or below the block size of the outboard has no effect, so with
+/// 0 this is the same as [encode_ranges_to].
+///
+/// The result is the same as encoding with an outboard for the block size
+/// `max(block_size, min_level)`, so it is decoded by passing that block size to the
+/// decoders, e.g. [DecodeResponseIter::new]. The decoders do not support block sizes
+/// above [BlockSize::MAX].
+pub fn encode_ranges_with_min_level<
+    D: ReadAt + Size,
+    O: Outboard,
+    W: Write,
+    R: ChunkRangeSet + ?Sized,
+>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    min_level: u8,
+    encoded: W,
+) -> result::Result<u64, EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    let mut encoded = encoded;
+    let tree = outboard.tree();
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    let written = encode_ranges_impl(
+        &data,
+        &outboard,
+        EncodeResponseIter::with_min_level(tree, ranges, min_level),
+        &mut encoded,
+        &mut buffer,
+        &mut out_buf,
+    )?;
+    encoded.flush()?;
+    Ok(written)
+}
+
+/// Encode ranges relevant to a query without the size header
+///
+/// This is the same as [encode_ranges], but the 8 byte size is not written. It is
+/// for protocols where the receiver already knows the size from a trusted source,
+/// and decodes with [decode_ranges_headerless].
+pub fn encode_ranges_headerless<
+    D: ReadAt + Size,
+    O: Outboard,
+    W: Write,
+    R: ChunkRangeSet + ?Sized,
+>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    let mut encoded = encoded;
+    let tree = outboard.tree();
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    encode_ranges_impl(
+        &data,
+        &outboard,
+        EncodeResponseIter::new(tree, ranges).headerless(),
+        &mut encoded,
+        &mut buffer,
+        &mut out_buf,
+    )?;
+    encoded.flush()?;
+    Ok(())
+}
+
+/// Encode the items of a response, returning the number of bytes written
+///
+/// `buffer` is grown as needed for leaves larger than a chunk group, and `out_buf`
+/// is scratch space for partially requested chunk groups. Both can be reused for
+/// multiple queries.
+fn encode_ranges_impl<D: ReadAt, O: Outboard, W: Write>(
+    data: &D,
+    outboard: &O,
+    items: EncodeResponseIter<'_>,
+    encoded: &mut W,
+    buffer: &mut Vec<u8>,
+    out_buf: &mut Vec<u8>,
+) -> result::Result<u64, EncodeError> {
+    let block_size = items.tree().block_size;
+    let mut written = 0;
+    for item in items {
+        match item {
+            EncodeResponseItem::Header(size) => {
+                encoded.write_all(size.0.to_le_bytes().as_slice())?;
+                written += 8;
+            }
+            EncodeResponseItem::Parent { node, .. } => {
+                let (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                let pair = combine_hash_pair(&l_hash, &r_hash);
+                encoded.write_all(&pair)?;
+                written += 64;
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                byte_range,
+                ranges,
+                ..
+            } => {
+                let size = (byte_range.end - byte_range.start).to_usize();
+                if buffer.len() < size {
+                    // a subtree above the block size, only with min_level
+                    buffer.resize(size, 0);
+                }
+                let buf = &mut buffer[..size];
+                data.read_exact_at(byte_range.start.0, buf)?;
+                let to_write = encode_leaf(start_chunk, buf, ranges, block_size, out_buf);
+                encoded.write_all(to_write)?;
+                written += to_write.len() as u64;
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Encode multiple queries as segments of a single stream
+///
+/// Each query is encoded as an independent response, including its own size header,
+/// and the responses are written back to back in the order of `queries`. So the
+/// most important ranges can be sent first, and each segment can be verified on its
+/// own as soon as it is received. Hash pairs that are needed by more than one query
+/// are sent again for each of them. Use [DecodeMultiIter] to decode the stream.
+pub fn encode_ranges_multi<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    queries: &[ChunkRanges],
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    check_outboard_size(&outboard)?;
+    let mut encoded = encoded;
+    let tree = outboard.tree();
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    for query in queries {
+        encode_ranges_impl(
+            &data,
+            &outboard,
+            EncodeResponseIter::new(tree, query),
+            &mut encoded,
+            &mut buffer,
+            &mut out_buf,
+        )?;
+    }
+    encoded.flush()?;
+    Ok(())
+}
+
+/// Encode ranges relevant to a query from a seekable reader and outboard to a writer
+///
+/// This is the same as [encode_ranges], but reads the data from a [Read] + [Seek]
+/// source. Only the leaves that are part of the query are read, and the reader is
+/// only seeked when the next leaf does not start where the previous one ended.
+///
+/// The size and block size are taken from the outboard. If the data is shorter than
+/// the size given in the outboard, this will fail with an io error.
+pub fn encode_ranges_io<D: Read + Seek, O: Outboard, W: Write, R: ChunkRangeSet + ?Sized>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    let mut data = data;
+    let mut encoded = encoded;
+    let tree = outboard.tree();
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    // current position of the reader, if known
+    let mut position = None;
+    for item in EncodeResponseIter::new(tree, ranges) {
+        match item {
+            EncodeResponseItem::Header(size) => {
+                encoded.write_all(size.0.to_le_bytes().as_slice())?;
+            }
+            EncodeResponseItem::Parent { node, .. } => {
+                let (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                let pair = combine_hash_pair(&l_hash, &r_hash);
+                encoded.write_all(&pair)?;
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                byte_range,
+                ranges,
+                ..
+            } => {
+                let start = byte_range.start;
+                if position != Some(start.0) {
+                    data.seek(SeekFrom::Start(start.0))?;
+                }
+                let buf = &mut buffer[..(byte_range.end - start).to_usize()];
+                data.read_exact(buf)?;
+                position = Some(byte_range.end.0);
+                encoded.write_all(encode_leaf(
+                    start_chunk,
+                    buf,
+                    ranges,
+                    tree.block_size,
+                    &mut out_buf,
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract the encoding of `ranges` from a combined encoding
+///
+/// `combined` is a combined encoding as produced by [encode_combined], e.g. of an
+/// archived blob. The output is the same as [encode_ranges] with the data and the
+/// outboard of the blob, but no separate outboard is needed. Only the hash pairs and
+/// leaves that are part of the response are read, at the offsets given by
+/// [BaoTree::combined_pair_offset] and [BaoTree::combined_data_offset].
+///
+/// The combined encoding is not validated, so data corruption will be detected on
+/// reading. If the combined encoding is shorter than the size in its header says,
+/// this will fail with an io error.
+pub fn extract_ranges_from_combined<R: ChunkRangeSet + ?Sized>(
+    combined: impl Read + Seek,
+    ranges: &R,
+    block_size: BlockSize,
+    encoded: impl Write,
+) -> result::Result<(), EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_block_size(block_size)?;
+    let mut combined = combined;
+    let mut encoded = encoded;
+    combined.seek(SeekFrom::Start(0))?;
+    let size = read_len(&mut combined)?;
+    let tree = BaoTree::new(size, block_size);
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    // current position of the reader
+    let mut position = 8;
+    for item in EncodeResponseIter::new(tree, ranges) {
+        match item {
+            EncodeResponseItem::Header(size) => {
+                encoded.write_all(size.0.to_le_bytes().as_slice())?;
+            }
+            EncodeResponseItem::Parent { node, .. } => {
+                let offset = tree.combined_pair_offset(node).unwrap();
+                if position != offset {
+                    combined.seek(SeekFrom::Start(offset))?;
+                }
+                let mut pair = [0u8; 64];
+                combined.read_exact(&mut pair)?;
+                position = offset + 64;
+                encoded.write_all(&pair)?;
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                byte_range,
+                ranges,
+                ..
+            } => {
+                let offset = tree.combined_data_offset(byte_range.start).unwrap();
+                if position != offset {
+                    combined.seek(SeekFrom::Start(offset))?;
+                }
+                let size = (byte_range.end - byte_range.start).to_usize();
+                let buf = &mut buffer[..size];
+                combined.read_exact(buf)?;
+                position = offset + size as u64;
+                encoded.write_all(encode_leaf(
+                    start_chunk,
+                    buf,
+                    ranges,
+                    tree.block_size,
+                    &mut out_buf,
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract the encoding of `inner_ranges` from an encoding of `outer_ranges`
+///
+/// `slice` is an encoded response for `outer_ranges`, as produced by [encode_ranges].
+/// The result is the response for `inner_ranges`, the same bytes that [encode_ranges]
+/// would produce with the data and the outboard of the blob. This allows a node that
+/// holds a slice to serve narrower queries without decoding it into data and outboard.
+///
+/// The slice is read in a single pass, and only the data of one leaf of the slice is
+/// kept at a time. Nothing is verified, that is left to the recipient of the result.
+/// Hash pairs below the block size that are not in `slice`, because a chunk group was
+/// fully requested in the outer query but only partially in the inner one, are
+/// computed from the data.
+///
+/// Both ranges are compared as they are encoded for the size in the header, so e.g. a
+/// query for anything past the end is the same as a query for the last chunk. Fails
+/// with [io::ErrorKind::InvalidInput] if `inner_ranges` is not a subset of
+/// `outer_ranges`, and with an io error if `slice` is too short.
+pub fn extract_slice<R1: ChunkRangeSet + ?Sized, R2: ChunkRangeSet + ?Sized>(
+    slice: impl Read,
+    outer_ranges: &R1,
+    inner_ranges: &R2,
+    block_size: BlockSize,
+) -> io::Result<Vec<u8>> {
+    let outer_ranges = as_chunk_ranges_ref(outer_ranges);
+    let inner_ranges = as_chunk_ranges_ref(inner_ranges);
+    check_block_size(block_size)?;
+    let mut slice = slice;
+    let size = read_len(&mut slice)?;
+    let tree = BaoTree::new(size, block_size);
+    // canonicalize both ranges for the size before comparing them
+    let outer_ranges = truncate_ranges(outer_ranges, size);
+    let inner_ranges = truncate_ranges(inner_ranges, size);
+    if !outer_ranges.is_superset(inner_ranges) {
+        io_error!("inner ranges must be a subset of the outer ranges");
+    }
+    // both responses are in pre order, so the items of the outer slice that the inner
+    // items need come in the same order, and the rest can be skipped
+    let mut outer = ResponseIterRef::new(tree, outer_ranges);
+    // the start of the current leaf of the outer slice, and its data
+    let mut leaf_start = None;
+    let mut leaf = Vec::with_capacity(tree.chunk_group_bytes().to_usize());
+    let mut res = size.0.to_le_bytes().to_vec();
+    'inner: for item in ResponseIterRef::new(tree, inner_ranges) {
+        let (node, range) = match item {
+            BaoChunk::Parent { node, .. } => {
+                let range = node.chunk_range();
+                (
+                    Some(node),
+                    range.start.to_bytes()..range.end.to_bytes().min(size),
+                )
+            }
+            BaoChunk::Leaf {
+                start_chunk, size, ..
+            } => {
+                let start = start_chunk.to_bytes();
+                (None, start..start + size as u64)
+            }
+        };
+        // find the item of the outer slice that contains the item
+        let offset = loop {
+            if let Some(start) = leaf_start.filter(|start| *start <= range.start) {
+                let offset = (range.start - start).to_usize();
+                if offset + (range.end - range.start).to_usize() <= leaf.len() {
+                    break offset;
+                }
+            }
+            match outer.next() {
+                Some(BaoChunk::Parent {
+                    node: outer_node, ..
+                }) => {
+                    let mut pair = [0u8; 64];
+                    slice.read_exact(&mut pair)?;
+                    if Some(outer_node) == node {
+                        res.extend_from_slice(&pair);
+                        continue 'inner;
+                    }
+                }
+                Some(BaoChunk::Leaf {
+                    start_chunk, size, ..
+                }) => {
+                    leaf.resize(size, 0);
+                    slice.read_exact(&mut leaf)?;
+                    leaf_start = Some(start_chunk.to_bytes());
+                }
+                None => io_error!("data for {:?} is not in the slice", range),
+            }
+        };
+        let data = &leaf[offset..offset + (range.end - range.start).to_usize()];
+        match node {
+            Some(node) => {
+                // a node below the block size within a leaf of the slice
+                let mid = node.mid();
+                let (left, right) = data.split_at((mid.to_bytes() - range.start).to_usize());
+                let l_hash = hash_subtree(node.chunk_range().start.0, left, false);
+                let r_hash = hash_subtree(mid.0, right, false);
+                res.extend_from_slice(l_hash.as_bytes());
+                res.extend_from_slice(r_hash.as_bytes());
+            }
+            None => res.extend_from_slice(data),
+        }
+    }
+    Ok(res)
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer
+///
+/// This is the same as [encode_ranges], but additionally checks that the outboard
+/// is consistent with itself: each emitted hash pair must hash to the hash that is
+/// recorded for its node in the pair of its parent, or to the root hash. This costs
+/// one extra hash per parent, but no hashing of the data beyond what [encode_ranges]
+/// does.
+///
+/// An outboard in the wrong layout or computed with a different block size will
+/// fail this check within the first few nodes, with
+/// [EncodeError::InconsistentOutboard].
+pub fn encode_ranges_checked<D: ReadAt + Size, O: Outboard, W: Write, R: ChunkRangeSet + ?Sized>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    let tree = outboard.tree();
+    let mut stack = VerifyStack::new(outboard.root(), tree.block_size, Blake3Hasher);
+    let data = data;
+    let mut encoded = encoded;
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    for item in EncodeResponseIter::new(tree, ranges) {
+        match item {
+            EncodeResponseItem::Header(size) => {
+                encoded.write_all(size.0.to_le_bytes().as_slice())?;
+            }
+            EncodeResponseItem::Parent {
+                node,
+                is_root,
+                left,
+                right,
+            } => {
+                let pair @ (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                stack
+                    .verify_parent(node, is_root, left, right, &pair)
+                    .map_err(|_| EncodeError::InconsistentOutboard(node))?;
+                let pair = combine_hash_pair(&l_hash, &r_hash);
+                encoded.write_all(&pair)?;
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                byte_range,
+                ranges,
+                ..
+            } => {
+                // the leaf data is not checked
+                stack.skip_leaf();
+                let buf = &mut buffer[..(byte_range.end - byte_range.start).to_usize()];
+                data.read_exact_at(byte_range.start.0, buf)?;
+                encoded.write_all(encode_leaf(
+                    start_chunk,
+                    buf,
+                    ranges,
+                    tree.block_size,
+                    &mut out_buf,
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer
+///
+/// This function validates the data before writing. Each leaf is hashed and checked
+/// against the outboard, and each hash pair is checked against its parent, starting
+/// from the root hash. A mismatch is returned as [EncodeError::LeafHashMismatch] or
+/// [EncodeError::ParentHashMismatch] before the corrupted part is written, so local
+/// corruption is detected here instead of by the receiver. [encode_ranges] does none
+/// of these checks and has no hashing overhead.
+///
+/// It is possible to encode ranges from a partial file and outboard.
+/// This will either succeed if the requested ranges are all present, or fail
+/// as soon as a range is missing.
+pub fn encode_ranges_validated<
+    D: ReadAt + Size,
+    O: Outboard,
+    W: Write,
+    R: ChunkRangeSet + ?Sized,
+>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    encode_ranges_validated_with_hasher(data, outboard, ranges, encoded, Blake3Hasher)
+}
+
+/// Encode ranges relevant to a query from a reader and outboard to a writer, using a
+/// custom [Hasher]
+///
+/// This is the same as [encode_ranges_validated], but computes all hashes with
+/// `hasher`, both for validating and for the hash pairs below the block size.
+pub fn encode_ranges_validated_with_hasher<
+    D: ReadAt + Size,
+    O: Outboard,
+    W: Write,
+    R: ChunkRangeSet + ?Sized,
+>(
+    data: D,
+    outboard: O,
+    ranges: &R,
+    encoded: W,
+    hasher: impl Hasher,
+) -> result::Result<(), EncodeError> {
+    let ranges = as_chunk_ranges_ref(ranges);
+    check_outboard_size(&outboard)?;
+    let tree = outboard.tree();
+    let mut stack = VerifyStack::new(outboard.root(), tree.block_size, &hasher);
+    let data = data;
+    let mut encoded = encoded;
+    let mut buffer = vec![0u8; tree.chunk_group_bytes().to_usize()];
+    let mut out_buf = Vec::new();
+    for item in EncodeResponseIter::new(tree, ranges) {
+        match item {
+            EncodeResponseItem::Header(size) => {
+                encoded.write_all(size.0.to_le_bytes().as_slice())?;
+            }
+            EncodeResponseItem::Parent {
+                node,
+                is_root,
+                left,
+                right,
+            } => {
+                let pair @ (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::MissingHashPair(node))?;
+                stack
+                    .verify_parent(node, is_root, left, right, &pair)
+                    .map_err(EncodeError::from_verify)?;
+                let pair = combine_hash_pair(&l_hash, &r_hash);
+                encoded.write_all(&pair)?;
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                is_root,
+                byte_range,
+                ranges,
+            } => {
+                let size = (byte_range.end - byte_range.start).to_usize();
+                let buf = &mut buffer[..size];
+                data.read_exact_at(byte_range.start.0, buf)?;
+                let (actual, to_write) = if !ranges.is_all() {
+                    // we need to encode just a part of the data
+                    //
+                    // write into an out buffer to ensure we detect mismatches
+                    // before writing to the output.
+                    out_buf.clear();
+                    let actual = encode_selected_rec_with_hasher(
+                        &hasher,
+                        start_chunk,
+                        buf,
+                        is_root,
+                        ranges,
+                        tree.block_size.to_u32(),
+                        true,
+                        &mut out_buf,
+                    );
+                    (actual, &out_buf[..])
+                } else {
+                    let actual = hasher.hash_subtree(start_chunk.0, buf, is_root);
+                    #[allow(clippy::redundant_slicing)]
+                    (actual, &buf[..])
+                };
+                stack
+                    .verify_leaf_hash(start_chunk, size, is_root, tree.size, actual)
+                    .map_err(EncodeError::from_verify)?;
+                encoded.write_all(to_write)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decode a response into a file while updating an outboard.
+///
+/// If you do not want to update an outboard, use [super::outboard::EmptyOutboard] as
+/// the outboard. To decode into a target that implements [Write] and [Seek] instead
+/// of [WriteAt], wrap it in a [SeekWriter]. To save the hash pairs into an existing
+/// outboard, use [decode_ranges_with_outboard].
+///
+/// Returns the size of the blob as given in the encoded stream, and the outboard if
+/// any hash pairs were received. The size is verified for all chunks that were
+/// received, so e.g. a response for the last chunk proves the size of the blob.
+pub fn decode_response_into<R, O, W, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
+    mut target: W,
+) -> io::Result<(ByteNum, Option<O>)>
+where
+    O: OutboardMut,
+    R: Read,
+    W: WriteAt,
+    Q: ChunkRangeSet + ?Sized,
+{
+    decode_response_into_with_progress(root, block_size, ranges, encoded, create, target, |_, _| {
+        ControlFlow::Continue(())
+    })
+}
+
+/// Decode a response into a file while updating an outboard, reporting progress.
+///
+/// This is the same as [decode_response_into], but calls `progress` after each leaf
+/// with the number of data bytes that have been verified and written so far, and the
+/// total number of data bytes in the response, which is known once the size has been
+/// read. If `progress` returns [ControlFlow::Break], decoding stops with a
+/// [Cancelled] error.
+pub fn decode_response_into_with_progress<R, O, W, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
+    mut target: W,
+    mut progress: impl FnMut(ByteNum, ByteNum) -> ControlFlow<()>,
+) -> io::Result<(ByteNum, Option<O>)>
+where
+    O: OutboardMut,
+    R: Read,
+    W: WriteAt,
+    Q: ChunkRangeSet + ?Sized,
+{
+    let ranges = as_chunk_ranges_ref(ranges);
+    let mut done = 0u64;
+    let mut total = ByteNum(0);
+    decode_response_into_impl(root, block_size, ranges, encoded, create, |tree, item| {
+        match item {
+            DecodeResponseItem::Header(Header { size }) => {
+                total = ResponseIterRef::new(tree, truncate_ranges(ranges, size))
+                    .map(|item| match item {
+                        BaoChunk::Leaf { size, .. } => ByteNum(size as u64),
+                        BaoChunk::Parent { .. } => ByteNum(0),
+                    })
+                    .fold(ByteNum(0), |a, b| a + b);
+            }
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(Leaf { offset, data }) => {
+                target.write_all_at(offset.0, &data)?;
+                done += data.len() as u64;
+                if progress(ByteNum(done), total).is_break() {
+                    return Err(Cancelled.into());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Decode a response, saving the hash pairs into an outboard that is created with
+/// `create` on the first hash pair
+///
+/// Each item is passed to `on_item` together with the tree, after a hash pair has been
+/// saved. This is the common part of [decode_response_into] and its variants. Returns
+/// the size of the blob and the outboard, if any hash pairs were received.
+fn decode_response_into_impl<R, O, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
+    mut on_item: impl FnMut(BaoTree, DecodeResponseItem) -> io::Result<()>,
+) -> io::Result<(ByteNum, Option<O>)>
+where
+    O: OutboardMut,
+    R: Read,
+    Q: ChunkRangeSet + ?Sized,
+{
+    const HEADER_FIRST: &str = "the header is always the first item";
+    let mut iter = DecodeResponseIter::new(root, block_size, encoded, ranges);
+    let mut outboard = None;
+    let mut create = Some(create);
+    while let Some(item) = iter.next() {
+        let item = item?;
+        let tree = iter.tree().expect(HEADER_FIRST);
+        if let DecodeResponseItem::Parent(Parent { node, pair }) = &item {
+            let outboard = match &mut outboard {
+                Some(outboard) => outboard,
+                slot @ None => {
+                    let create = create.take().expect("the outboard is only created once");
+                    slot.insert(create(tree, root)?)
+                }
+            };
+            outboard.save(*node, pair)?;
+        }
+        on_item(tree, item)?;
+    }
+    let size = iter.tree().expect(HEADER_FIRST).size;
+    Ok((size, outboard))
+}
+
+/// The ranges of a decode into existing data, see [decode_response_into_existing]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodedRanges {
+    /// Verified leaves that were already present in the target, and were not written
+    pub present: ChunkRanges,
+    /// Verified leaves that were written to the target
+    pub written: ChunkRanges,
+}
+
+/// Decode a response into a target that may already contain some of the data
+///
+/// This is the same as [decode_response_into], but before a verified leaf is written,
+/// the bytes at its offset are read from `target`, and the write is skipped if they
+/// are the same. So resuming a download does not rewrite the data that is already
+/// there. Reading costs at most one chunk group per leaf. If the target ends before
+/// the end of the leaf, the leaf is written. Other errors reading the target are
+/// returned.
+///
+/// Returns the size of the blob, the outboard if any hash pairs were received, and
+/// which of the verified leaves were already present and which were written.
+pub fn decode_response_into_existing<R, O, W, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
+    mut target: W,
+) -> io::Result<(ByteNum, Option<O>, DecodedRanges)>
+where
+    O: OutboardMut,
+    R: Read,
+    W: ReadAt + WriteAt,
+    Q: ChunkRangeSet + ?Sized,
+{
+    let mut res = DecodedRanges::default();
+    let mut existing = Vec::new();
+    let (size, outboard) =
+        decode_response_into_impl(root, block_size, ranges, encoded, create, |_, item| {
+            if let DecodeResponseItem::Leaf(Leaf { offset, data }) = item {
+                let start = offset.full_chunks();
+                let end = start + ByteNum(data.len() as u64).chunks();
+                let range = crate::ranges::chunk_range(start, end);
+                existing.resize(data.len(), 0);
+                let present = match target.read_exact_at(offset.0, &mut existing) {
+                    Ok(()) => existing[..] == data[..],
+                    // the target is shorter, so the leaf is not there yet
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+                    Err(e) => return Err(e),
+                };
+                if present {
+                    res.present |= range;
+                } else {
+                    target.write_all_at(offset.0, &data)?;
+                    res.written |= range;
+                }
+            }
+            Ok(())
+        })?;
+    Ok((size, outboard, res))
+}
+
+/// Decode a response into a file while updating an outboard, optionally setting the
+/// length of the file to the size of the blob.
+///
+/// This is the same as [decode_response_into], but if `set_len` is true, the length of
+/// `target` is set to the size of the blob once the first item after the header has
+/// been verified, before any data is written. So decoding sparse ranges into a new file
+/// gives a file of the size of the blob, without growing it with every write. Set
+/// `set_len` to false to decode into an existing file that should not be resized.
+///
+/// Returns the size of the blob and the outboard, if one was created.
+#[cfg(feature = "fs")]
+pub fn decode_response_into_file<R, O, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    create: impl FnOnce(BaoTree, blake3::Hash) -> io::Result<O>,
+    target: &mut File,
+    set_len: bool,
+) -> io::Result<(ByteNum, Option<O>)>
+where
+    O: OutboardMut,
+    R: Read,
+    Q: ChunkRangeSet + ?Sized,
+{
+    // the size to set the length to, once the first item has been verified
+    let mut pending_len = None;
+    decode_response_into_impl(root, block_size, ranges, encoded, create, |_, item| {
+        match item {
+            DecodeResponseItem::Header(Header { size }) => {
+                if set_len {
+                    pending_len = Some(size.0);
+                }
+            }
+            item => {
+                if let Some(size) = pending_len.take() {
+                    target.set_len(size)?;
+                }
+                if let DecodeResponseItem::Leaf(Leaf { offset, data }) = item {
+                    target.write_all_at(offset.0, &data)?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Decode a response into a file while saving the hash pairs into an existing outboard.
+///
+/// This is like [decode_response_into], but the outboard is provided by the caller
+/// instead of being created on the first hash pair, so a partial outboard can
+/// accumulate over multiple downloads, alongside the partial data. The outboard must
+/// be for the same blob, so it must have the size of the blob.
+///
+/// A hash pair is only saved after it has been verified against the root, so the
+/// outboard never contains unverified data, even if decoding fails later on. Hash pairs
+/// below the block size are verified, but not saved, since they are not part of an
+/// outboard.
+///
+/// Returns the size of the blob as given in the encoded stream.
+pub fn decode_ranges_with_outboard<R, O, W, Q>(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    ranges: &Q,
+    encoded: R,
+    mut outboard: O,
+    mut target: W,
+) -> io::Result<ByteNum>
+where
+    O: OutboardMut,
+    R: Read,
+    W: WriteAt,
+    Q: ChunkRangeSet + ?Sized,
+{
+    let ranges = as_chunk_ranges_ref(ranges);
+    let iter = DecodeResponseIter::new(root, block_size, encoded, ranges);
+    let mut tree = None;
+    for item in iter {
+        match item? {
+            DecodeResponseItem::Header(Header { size }) => {
+                tree = Some(BaoTree::new(size, block_size));
+            }
+            DecodeResponseItem::Parent(Parent { node, pair }) => {
+                // the header is always the first item
+                if tree.unwrap().post_order_offset(node).is_some() {
+                    outboard.save(node, &pair)?;
+                }
+            }
+            DecodeResponseItem::Leaf(Leaf { offset, data }) => {
+                target.write_all_at(offset.0, &data)?;
+            }
+        }
+    }
+    Ok(tree.unwrap().size)
+}
+
+/// Adapter to use any [Write] + [Seek] target as a [WriteAt]
+///
+/// This allows [decode_response_into] to decode into e.g. a [std::io::Cursor] or a
+/// writer that is not backed by a file. Each positioned write seeks to the position
+/// first, so the position of the inner writer after a write is undefined.
+#[derive(Debug)]
+pub struct SeekWriter<W>(pub W);
+
+impl<W: Write + Seek> WriteAt for SeekWriter<W> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.seek(SeekFrom::Start(pos))?;
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Summary of a call to [decode_exact_bytes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeSummary {
+    /// The size of the blob, as given in the encoded stream
+    pub size: ByteNum,
+    /// The byte range that was written to the sink
+    ///
+    /// This is the requested range, clamped to the size of the blob. It is empty if
+    /// the requested range is entirely past the end of the blob.
+    pub written: Range<ByteNum>,
+}
+
+/// Decode an encoded response, writing exactly the bytes of `byte_range` to `sink`.
+///
+/// Verification works on whole chunks, so the response must contain the chunks that
+/// cover the byte range, as computed by [crate::ranges::chunks_for_bytes]. Only the bytes
+/// within `byte_range` are written, in order, even if the range starts or ends in the
+/// middle of a chunk or block.
+///
+/// If `byte_range` extends past the end of the blob and `strict` is false, it is
+/// clamped to the size, and [DecodeSummary::written] tells what was actually
+/// written. If `strict` is true, this fails with an [io::ErrorKind::InvalidInput]
+/// error before anything is written.
+pub fn decode_exact_bytes(
+    root: blake3::Hash,
+    block_size: BlockSize,
+    encoded: impl Read,
+    byte_range: Range<ByteNum>,
+    strict: bool,
+    mut sink: impl Write,
+) -> result::Result<DecodeSummary, AnyDecodeError> {
+    let Range { start, end } = byte_range;
+    let end = end.max(start);
+    let ranges = crate::ranges::chunks_for_bytes(start..end);
+    let mut summary = None;
+    for item in DecodeResponseIter::new(root, block_size, encoded, &ranges) {
+        match item? {
+            DecodeResponseItem::Header(Header { size }) => {
+                if strict && end > size {
+                    return Err(AnyDecodeError::Io(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "byte range {}..{} extends past the end of the blob ({} bytes)",
+                            start.0, end.0, size.0
+                        ),
+                    )));
+                }
+                let end = end.min(size);
+                let start = start.min(end);
+                summary = Some(DecodeSummary {
+                    size,
+                    written: start..end,
+                });
+            }
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(Leaf { offset, data }) => {
+                // the header always comes first
+                let written = &summary.as_ref().unwrap().written;
+                let leaf_end = offset + ByteNum(data.len() as u64);
+                let start = written.start.max(offset).min(leaf_end);
+                let end = written.end.min(leaf_end).max(start);
+                let slice = &data[(start - offset).to_usize()..(end - offset).to_usize()];
+                sink.write_all(slice).map_err(AnyDecodeError::Io)?;
+            }
+        }
+    }
+    // the header is read even for an empty range
+    Ok(summary.unwrap())
+}
+
+/// Write ranges from memory to disk
+///
+/// This is useful for writing changes to outboards.
+/// Note that it is up to you to call flush.
+#[cfg(feature = "range-collections")]
+pub fn write_ranges(
+    from: impl AsRef<[u8]>,
+    mut to: impl WriteAt,
+    ranges: &RangeSetRef<u64>,
+) -> io::Result<()> {
+    let from = from.as_ref();
+    let end = from.len() as u64;
+    for range in ranges.iter() {
+        let range = match range {
+            RangeSetRange::RangeFrom(x) => *x.start..end,
+            RangeSetRange::Range(x) => *x.start..*x.end,
+        };
+        let start = usize::try_from(range.start).unwrap();
+        let end = usize::try_from(range.end).unwrap();
+        to.write_all_at(range.start, &from[start..end])?;
+    }
+    Ok(())
+}
+
+/// Compute the post order outboard for the given data, writing into a io::Write
+///
+/// The data is read sequentially, one chunk group at a time, so it does not have to
+/// be in a single contiguous buffer. Data that is stored in segments, e.g. a list of
+/// [bytes::Bytes], can be passed as a chain of readers without copying it together.
+///
+/// ```
+/// use std::io::Read;
+/// use bao_tree::{io::sync::outboard_post_order, BlockSize};
+///
+/// let segments = [vec![1u8; 5000], vec![2u8; 3000], vec![3u8; 100000]];
+/// let size: u64 = segments.iter().map(|s| s.len() as u64).sum();
+/// let data = segments[0]
+///     .as_slice()
+///     .chain(segments[1].as_slice())
+///     .chain(segments[2].as_slice());
+/// let mut outboard = Vec::new();
+/// let hash = outboard_post_order(data, size, BlockSize(4), &mut outboard).unwrap();
+/// assert_eq!(hash, bao_tree::blake3::hash(&segments.concat()));
+/// ```
+pub fn outboard_post_order(
+    data: impl Read,
+    size: u64,
+    block_size: BlockSize,
+    outboard: impl Write,
+) -> io::Result<blake3::Hash> {
+    OutboardBuilder::new(block_size).write(data, size, outboard)
+}
+
+/// A progress hook, called with the number of bytes hashed so far and the total size
+type ProgressFn<'a> = Box<dyn FnMut(ByteNum, ByteNum) -> ControlFlow<()> + 'a>;
+
+/// A hook that is called with each node that has a hash pair, and its hash
+type SubtreeFn<'a> = Box<dyn FnMut(TreeNode, blake3::Hash) + 'a>;
+
+/// Options for computing a post order outboard
+///
+/// [outboard_post_order] covers the common case. This allows to use a custom
+/// [Hasher], to reuse buffers, to report progress and to get the hashes of subtrees,
+/// in any combination, and to write the outboard to a [Write], a [Vec] or a file.
+///
+/// ```
+/// use std::ops::ControlFlow;
+/// use bao_tree::{io::sync::OutboardBuilder, BlockSize};
+///
+/// let data = vec![1u8; 100000];
+/// let mut outboard = Vec::new();
+/// let mut done = 0;
+/// let hash = OutboardBuilder::new(BlockSize(4))
+///     .progress(|bytes, _total| {
+///         done = bytes.0;
+///         ControlFlow::Continue(())
+///     })
+///     .write_into(data.as_slice(), data.len() as u64, &mut outboard)
+///     .unwrap();
+/// assert_eq!(hash, bao_tree::blake3::hash(&data));
+/// assert_eq!(done, 100000);
+/// ```
+pub struct OutboardBuilder<'a, H = Blake3Hasher> {
+    block_size: BlockSize,
+    hasher: H,
+    buffer: Option<&'a mut Vec<u8>>,
+    progress: Option<ProgressFn<'a>>,
+    on_subtree: Option<SubtreeFn<'a>>,
+}
+
+impl<H: fmt::Debug> fmt::Debug for OutboardBuilder<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboardBuilder")
+            .field("block_size", &self.block_size)
+            .field("hasher", &self.hasher)
+            .field("progress", &self.progress.is_some())
+            .field("on_subtree", &self.on_subtree.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutboardBuilder<'_> {
+    /// Options for computing an outboard with the given block size
+    pub fn new(block_size: BlockSize) -> Self {
+        Self {
+            block_size,
+            hasher: Blake3Hasher,
+            buffer: None,
+            progress: None,
+            on_subtree: None,
+        }
+    }
+}
+
+impl<'a, H: Hasher> OutboardBuilder<'a, H> {
+    /// Compute all hashes with `hasher`
+    pub fn hasher<H2: Hasher>(self, hasher: H2) -> OutboardBuilder<'a, H2> {
+        OutboardBuilder {
+            block_size: self.block_size,
+            hasher,
+            buffer: self.buffer,
+            progress: self.progress,
+            on_subtree: self.on_subtree,
+        }
+    }
+
+    /// Use `buffer` as scratch space for reading chunk groups
+    ///
+    /// It will be resized as needed, so the same buffer can be reused when computing
+    /// outboards for many blobs. By default, a temporary buffer is allocated.
+    pub fn buffer(mut self, buffer: &'a mut Vec<u8>) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Report progress
+    ///
+    /// `progress` is called with the number of bytes hashed so far and the total size
+    /// after each chunk group, so e.g. a UI can show the progress for large files. If
+    /// it returns [ControlFlow::Break], the computation stops with a [Cancelled] error.
+    pub fn progress(
+        mut self,
+        progress: impl FnMut(ByteNum, ByteNum) -> ControlFlow<()> + 'a,
+    ) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Report the hash of each subtree
+    ///
+    /// `on_subtree` is called with each node that has a hash pair and its hash, in
+    /// post order, as soon as the hash is computed. So e.g. the hashes of all aligned
+    /// 1 MiB subtrees are available without a second pass over the data.
+    ///
+    /// The hash of the root is the root hash. The hashes of the other nodes are blake3
+    /// chaining values, which depend on the position of the data in the blob. Nodes
+    /// that extend past the end of the data, see [crate::PostOrderOffset::Unstable],
+    /// get a different hash when data is appended.
+    pub fn on_subtree(mut self, on_subtree: impl FnMut(TreeNode, blake3::Hash) + 'a) -> Self {
+        self.on_subtree = Some(Box::new(on_subtree));
+        self
+    }
+
+    /// Compute the outboard for `size` bytes of `data`, writing it including the
+    /// length suffix to `outboard`
+    pub fn write(
+        self,
+        data: impl Read,
+        size: u64,
+        mut outboard: impl Write,
+    ) -> io::Result<blake3::Hash> {
+        let hash = self.write_impl(data, size, &mut outboard)?;
+        outboard.write_all(&size.to_le_bytes())?;
+        Ok(hash)
+    }
+
+    /// Compute the outboard for `size` bytes of `data` into `out`
+    ///
+    /// `out` is cleared and then filled with the outboard, including the length suffix,
+    /// so the same buffer can be reused when computing outboards for many blobs.
+    pub fn write_into(
+        self,
+        data: impl Read,
+        size: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<blake3::Hash> {
+        let tree = BaoTree::new(ByteNum(size), self.block_size);
+        out.clear();
+        out.reserve(mem_outboard_len(&tree)? + 8);
+        let hash = self.write_impl(data, size, &mut *out)?;
+        out.extend_from_slice(&size.to_le_bytes());
+        Ok(hash)
+    }
+
+    /// Compute the outboard for a file, writing it into another file
+    ///
+    /// The data is read from the start of `data` in a single pass, using a read
+    /// buffer of `buffer_size` bytes, and the outboard including the length suffix is
+    /// written to the start of `outboard`, which is truncated to the outboard size.
+    #[cfg(feature = "fs")]
+    pub fn write_file(
+        self,
+        data: &File,
+        outboard: &mut File,
+        buffer_size: usize,
+    ) -> io::Result<blake3::Hash> {
+        let block_size = self.block_size;
+        let size = data.metadata()?.len();
+        let mut data = data;
+        data.seek(SeekFrom::Start(0))?;
+        let data = io::BufReader::with_capacity(buffer_size, data);
+        outboard.seek(SeekFrom::Start(0))?;
+        let mut writer = io::BufWriter::with_capacity(buffer_size, &mut *outboard);
+        let hash = self.write(data, size, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        outboard.set_len(super::outboard_size(size, block_size))?;
+        Ok(hash)
+    }
+
+    fn write_impl(
+        self,
+        data: impl Read,
+        size: u64,
+        outboard: impl Write,
+    ) -> io::Result<blake3::Hash> {
+        let tree = BaoTree::new(ByteNum(size), self.block_size);
+        let mut tmp = Vec::new();
+        let buffer = self.buffer.unwrap_or(&mut tmp);
+        buffer.resize(tree.chunk_group_bytes().to_usize(), 0);
+        let mut progress = self.progress;
+        let mut on_subtree = self.on_subtree;
+        outboard_post_order_impl(
+            tree,
+            data,
+            outboard,
+            buffer,
+            self.hasher,
+            |done, total| match &mut progress {
+                Some(progress) => progress(done, total),
+                None => ControlFlow::Continue(()),
+            },
+            |node, hash| {
+                if let Some(on_subtree) = &mut on_subtree {
+                    on_subtree(node, hash);
+                }
+            },
+        )
+    }
+}
+
+/// Validate a post order outboard against the data it was computed from
+///
+/// `outboard` is the outboard including the length suffix. All hash pairs are
+/// recomputed from the data and compared with the stored pairs, so this detects
+/// bit rot in a stored outboard. Returns the root hash if everything matches,
+/// [DecodeError::ParentHashMismatch] with the first node in post order whose stored
+/// pair does not match, or an [io::ErrorKind::InvalidData] error if the length
+/// suffix or the outboard size does not match the data.
+pub fn validate_outboard(
+    data: &[u8],
+    outboard: &[u8],
+    block_size: BlockSize,
+) -> result::Result<blake3::Hash, DecodeError> {
+    let invalid = |msg: &str| DecodeError::Io(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let size = data.len() as u64;
+    if outboard.len() < 8 {
+        return Err(invalid("outboard is too short"));
+    }
+    let (pairs, suffix) = outboard.split_at(outboard.len() - 8);
+    if u64::from_le_bytes(suffix.try_into().unwrap()) != size {
+        return Err(invalid("outboard size does not match data size"));
+    }
+    let tree = BaoTree::new(ByteNum(size), block_size);
+    if pairs.len() as u64 != tree.outboard_hash_pairs() * 64 {
+        return Err(invalid("invalid outboard data size"));
+    }
+    let mut pairs = pairs.chunks_exact(64);
+    // do not allocate for small trees
+    let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
+    for item in tree.post_order_chunks_iter() {
+        match item {
+            BaoChunk::Parent { is_root, node, .. } => {
+                let right_hash = stack.pop().unwrap();
+                let left_hash = stack.pop().unwrap();
+                // the pairs are stored in post order, so they are in iteration order
+                let pair = pairs.next().unwrap();
+                let expected = parent_cv(&left_hash, &right_hash, is_root);
+                if pair[..32] != *left_hash.as_bytes() || pair[32..] != *right_hash.as_bytes() {
+                    let (l, r) = parse_hash_pair(pair.try_into().unwrap());
+                    return Err(DecodeError::ParentHashMismatch(ParentMismatch {
+                        node,
+                        block_size,
+                        expected,
+                        actual: parent_cv(&l, &r, is_root),
+                    }));
+                }
+                stack.push(expected);
+            }
+            BaoChunk::Leaf {
+                size,
+                is_root,
+                start_chunk,
+                ..
+            } => {
+                let start = start_chunk.to_bytes().to_usize();
+                let buf = &data[start..start + size];
+                stack.push(hash_subtree(start_chunk.0, buf, is_root));
+            }
+        }
+    }
+    debug_assert_eq!(stack.len(), 1);
+    Ok(stack.pop().unwrap())
+}
+
+/// Compute the post order outboard for the given data
+///
+/// This is the internal version that takes a start chunk and does not append the size!
+///
+/// `progress` is called with the number of bytes hashed so far and the size after
+/// each leaf, and can cancel the computation. `on_subtree` is called with each node
+/// that has a hash pair, and its hash.
+pub(crate) fn outboard_post_order_impl(
+    tree: BaoTree,
+    mut data: impl Read,
+    mut outboard: impl Write,
+    buffer: &mut [u8],
+    hasher: impl Hasher,
+    mut progress: impl FnMut(ByteNum, ByteNum) -> ControlFlow<()>,
+    mut on_subtree: impl FnMut(TreeNode, blake3::Hash),
+) -> io::Result<blake3::Hash> {
     // do not allocate for small trees
     let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
     debug_assert!(buffer.len() == tree.chunk_group_bytes().to_usize());
+    let mut done = 0u64;
     for item in tree.post_order_chunks_iter() {
         match item {
-            BaoChunk::Parent { is_root, .. } => {
+            BaoChunk::Parent { is_root, node, .. } => {
                 let right_hash = stack.pop().unwrap();
                 let left_hash = stack.pop().unwrap();
                 outboard.write_all(left_hash.as_bytes())?;
                 outboard.write_all(right_hash.as_bytes())?;
-                let parent = parent_cv(&left_hash, &right_hash, is_root);
+                let parent = hasher.parent(&left_hash, &right_hash, is_root);
+                on_subtree(node, parent);
                 stack.push(parent);
             }
             BaoChunk::Leaf {
@@ -756,8 +3045,12 @@ pub(crate) fn outboard_post_order_impl(
             } => {
                 let buf = &mut buffer[..size];
                 data.read_exact(buf)?;
-                let hash = hash_subtree(start_chunk.0, buf, is_root);
+                let hash = hasher.hash_subtree(start_chunk.0, buf, is_root);
                 stack.push(hash);
+                done += size as u64;
+                if progress(ByteNum(done), tree.size).is_break() {
+                    return Err(Cancelled.into());
+                }
             }
         }
     }
@@ -825,6 +3118,48 @@ fn read_parent(mut from: impl Read) -> std::io::Result<(blake3::Hash, blake3::Ha
     Ok((l_hash, r_hash))
 }
 
+/// A reader for the items of an encoded response
+///
+/// This reads the wire format described in [super], without verifying anything: the
+/// 8 byte little endian size, 64 byte hash pairs as the left and the right hash, and
+/// the data of leaves. The order and the sizes of the items after the header are given
+/// by [crate::iter::ResponseIterRef] for the size and the ranges canonicalized with
+/// [crate::ranges::canonicalize_ranges]. This can be used to inspect or relay a
+/// response without verifying it. To verify, use [DecodeResponseIter].
+///
+/// Reading past the end of the stream fails with [io::ErrorKind::UnexpectedEof].
+#[derive(Debug)]
+pub struct EncodedSlice<R> {
+    inner: R,
+}
+
+impl<R: Read> EncodedSlice<R> {
+    /// Create a new reader at the start of an encoded response
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Read the size header
+    pub fn read_header(&mut self) -> io::Result<ByteNum> {
+        read_len(&mut self.inner)
+    }
+
+    /// Read a hash pair, as the left and the right hash
+    pub fn read_parent(&mut self) -> io::Result<(blake3::Hash, blake3::Hash)> {
+        read_parent(&mut self.inner)
+    }
+
+    /// Read the data of a leaf into `buf`, which must have the size of the leaf
+    pub fn read_leaf(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)
+    }
+
+    /// Get back the reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
 /// seeks read the bytes for the range from the source
 fn read_range(from: impl ReadAt, range: Range<ByteNum>, buf: &mut [u8]) -> std::io::Result<&[u8]> {
     let len = (range.end - range.start).to_usize();
@@ -35,6 +35,26 @@ pub struct NodeInfo<'a> {
     pub is_half_leaf: bool,
 }
 
+impl NodeInfo<'_> {
+    /// True if the query touches the left child of the node
+    pub fn touches_left(&self) -> bool {
+        !self.l_ranges.is_empty()
+    }
+
+    /// True if the query touches the right child of the node
+    ///
+    /// For the half leaf, this is the same as [NodeInfo::touches_left], since the
+    /// ranges are not split.
+    pub fn touches_right(&self) -> bool {
+        !self.r_ranges.is_empty()
+    }
+
+    /// True if the node is fully included in the query, see [NodeInfo::full]
+    pub fn is_included(&self) -> bool {
+        self.full
+    }
+}
+
 /// Iterator over all nodes in a BaoTree in pre-order that overlap with a given chunk range.
 ///
 /// This is mostly used internally
@@ -75,6 +95,12 @@ impl<'a> PreOrderPartialIterRef<'a> {
     pub fn tree(&self) -> &BaoTree {
         &self.tree
     }
+
+    /// The number of nodes that are waiting to be visited
+    #[cfg(test)]
+    pub(crate) fn pending(&self) -> usize {
+        self.stack.len()
+    }
 }
 
 impl<'a> Iterator for PreOrderPartialIterRef<'a> {
@@ -102,13 +128,19 @@ impl<'a> Iterator for PreOrderPartialIterRef<'a> {
             // we can't recurse if the node is a leaf
             // we don't want to recurse if the node is full and below the minimum level
             let query_leaf = shifted.is_leaf() || (full && node.level() < self.min_level as u32);
-            // recursion is just pushing the children onto the stack
+            // recursion is just pushing the children onto the stack. Children that do
+            // not intersect the query are never visited, so the number of nodes is
+            // bounded by the depth of the tree times the number of ranges.
             if !query_leaf {
                 let l = shifted.left_child().unwrap();
                 let r = shifted.right_descendant(self.shifted_filled_size).unwrap();
                 // push right first so we pop left first
-                self.stack.push((r, r_ranges));
-                self.stack.push((l, l_ranges));
+                if !r_ranges.is_empty() {
+                    self.stack.push((r, r_ranges));
+                }
+                if !l_ranges.is_empty() {
+                    self.stack.push((l, l_ranges));
+                }
             }
             // the first node is the root, so just set the flag to false afterwards
             let is_root = shifted == self.shifted_root;
@@ -0,0 +1,121 @@
+//! The layout of an encoded response, as a slow reference implementation
+//!
+//! An encoded response for a blob of size `size`, a block size and a set of chunk
+//! ranges consists of the following items, in this order:
+//!
+//! 1. A header with the 8 byte little endian size of the blob.
+//! 2. The requested ranges are canonicalized. Chunks past the end of the blob are
+//!    dropped, and if any of them were requested, the last chunk is added. A blob of
+//!    size 0 has a single empty chunk.
+//! 3. The tree is traversed recursively in pre order, starting at the root. A subtree
+//!    that contains no requested chunk is skipped entirely. A subtree of at most one
+//!    chunk is emitted as data. A subtree below the level of the block size whose
+//!    chunks are all requested is emitted as data. Any other subtree is emitted as the
+//!    64 byte hash pair of its node, followed by its left and its right child.
+//!
+//! A subtree of more than one chunk is split at the largest power of two number of
+//! chunks that is smaller than the number of chunks in the subtree.
+//!
+//! [expected_layout] computes this sequence in the most direct way, without any of
+//! the node arithmetic of the iterators in [crate::iter]. It is used as the oracle in
+//! tests of the encoders and decoders. Changes to the traversal must change this
+//! module deliberately.
+use std::ops::Range;
+
+use smallvec::SmallVec;
+
+use crate::{BlockSize, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode};
+
+/// An item of an encoded response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireItem {
+    /// The 8 byte little endian size of the blob
+    Header {
+        /// The size of the blob
+        size: ByteNum,
+    },
+    /// The 64 byte hash pair of a node
+    Parent {
+        /// The node
+        node: TreeNode,
+    },
+    /// Data of the blob
+    Data {
+        /// The byte range of the data within the blob
+        range: Range<ByteNum>,
+    },
+}
+
+impl WireItem {
+    /// The number of bytes of the item in the encoded response
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Header { .. } => 8,
+            Self::Parent { .. } => 64,
+            Self::Data { range } => (range.end - range.start).0,
+        }
+    }
+
+    /// True if the item takes no space, which is only the case for empty data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Compute the items of the encoding of `ranges` for a blob of size `size`
+pub fn expected_layout(
+    size: ByteNum,
+    block_size: BlockSize,
+    ranges: &ChunkRangesRef,
+) -> Vec<WireItem> {
+    let mut res = vec![WireItem::Header { size }];
+    let chunks = size.chunks().max(ChunkNum(1));
+    let last = chunks - 1;
+    let mut requested = ChunkRanges::new_unchecked(SmallVec::from_slice(ranges.boundaries()));
+    requested &= ChunkRanges::from(..chunks);
+    if ranges.intersects(&ChunkRanges::from(last..)) {
+        requested |= ChunkRanges::from(last..chunks);
+    }
+    layout_rec(ChunkNum(0), size.0, block_size, &requested, &mut res);
+    res
+}
+
+fn layout_rec(
+    start: ChunkNum,
+    size: u64,
+    block_size: BlockSize,
+    requested: &ChunkRanges,
+    res: &mut Vec<WireItem>,
+) {
+    const CHUNK_LEN: u64 = 1024;
+    let chunks = ByteNum(size).chunks().max(ChunkNum(1));
+    let covered = ChunkRanges::from(start..start + chunks);
+    if !requested.intersects(&covered) {
+        return;
+    }
+    let data = WireItem::Data {
+        range: start.to_bytes()..start.to_bytes() + size,
+    };
+    if size <= CHUNK_LEN {
+        res.push(data);
+        return;
+    }
+    let n = chunks.0.next_power_of_two();
+    let level = n.trailing_zeros() - 1;
+    if level < block_size.to_u32() && requested.is_superset(&covered) {
+        res.push(data);
+        return;
+    }
+    let mid = n / 2;
+    res.push(WireItem::Parent {
+        node: TreeNode(start.0 | ((1 << level) - 1)),
+    });
+    layout_rec(start, mid * CHUNK_LEN, block_size, requested, res);
+    layout_rec(
+        start + mid,
+        size - mid * CHUNK_LEN,
+        block_size,
+        requested,
+        res,
+    );
+}
@@ -16,19 +16,23 @@
 //! All this is then used in the [io] module to implement the actual io, both
 //! synchronous and asynchronous.
 #![deny(missing_docs)]
-use range_collections::RangeSetRef;
 use std::{
     fmt::{self, Debug},
     ops::Range,
 };
 #[macro_use]
 mod macros;
+pub mod hasher;
 pub mod iter;
+#[cfg(any(test, feature = "layout"))]
+pub mod layout;
+#[cfg(not(feature = "range-collections"))]
+mod range_set;
+pub mod ranges;
 mod rec;
 mod tree;
 use iter::*;
-use tree::BlockNum;
-pub use tree::{BlockSize, ByteNum, ChunkNum};
+pub use tree::{BlockNum, BlockSize, ByteNum, ChunkNum};
 pub mod io;
 pub use blake3;
 
@@ -38,15 +42,30 @@ mod tests;
 mod tests2;
 
 /// A set of chunk ranges
+#[cfg(feature = "range-collections")]
 pub type ChunkRanges = range_collections::RangeSet2<ChunkNum>;
 
 /// A referenceable set of chunk ranges
 ///
 /// [ChunkRanges] implements [AsRef<ChunkRangesRef>].
+#[cfg(feature = "range-collections")]
 pub type ChunkRangesRef = range_collections::RangeSetRef<ChunkNum>;
 
+#[cfg(not(feature = "range-collections"))]
+pub use range_set::{ChunkRanges, ChunkRangesRef};
+
+/// Hash a subtree of data, starting at chunk `start_chunk`
+///
+/// This hashes directly from the slice, without copying into a buffer. Callers that
+/// hash many chunk groups, like the outboard computation, reuse a single read buffer.
 fn hash_subtree(start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
-    if data.len().is_power_of_two() {
+    use blake3::guts::{ChunkState, CHUNK_LEN};
+    if data.len() <= CHUNK_LEN {
+        // fast path for a single chunk, e.g. small blobs and the last chunk
+        let mut hasher = ChunkState::new(start_chunk);
+        hasher.update(data);
+        hasher.finalize(is_root)
+    } else if data.len().is_power_of_two() {
         blake3::guts::hash_subtree(start_chunk, data, is_root)
     } else {
         recursive_hash_subtree(start_chunk, data, is_root)
@@ -110,8 +129,21 @@ impl PostOrderOffset {
 
 impl BaoTree {
     /// Create a new self contained BaoTree
+    ///
+    /// Panics if `block_size` is larger than [BlockSize::MAX]. Use [BaoTree::try_new]
+    /// if the block size comes from untrusted input.
     pub fn new(size: ByteNum, block_size: BlockSize) -> Self {
-        Self { size, block_size }
+        Self::try_new(size, block_size).expect("block size too large")
+    }
+
+    /// Create a new self contained BaoTree
+    ///
+    /// Returns None if `block_size` is larger than [BlockSize::MAX].
+    pub fn try_new(size: ByteNum, block_size: BlockSize) -> Option<Self> {
+        if block_size > BlockSize::MAX {
+            return None;
+        }
+        Some(Self { size, block_size })
     }
 
     /// The size of the blob from which this tree was constructed, in bytes
@@ -142,8 +174,115 @@ impl BaoTree {
 
     fn byte_range(&self, node: TreeNode) -> Range<ByteNum> {
         let start = node.chunk_range().start.to_bytes();
-        let end = node.chunk_range().end.to_bytes();
-        start..end.min(self.size)
+        // the end of the root of a tree of more than 2^63 bytes does not fit into an u64
+        let end = node.chunk_range().end.checked_to_bytes();
+        start..end.map_or(self.size, |end| end.min(self.size))
+    }
+
+    /// Compute the byte ranges of the two blocks of a leaf node
+    ///
+    /// A leaf node is a node at the level of the block size, see [BaoTree::is_leaf].
+    /// It covers two blocks, the left range is from the start of the leaf to the middle,
+    /// the right range is from the middle to the end. Both ranges are clamped to the
+    /// size of the tree.
+    ///
+    /// For the last leaf of the tree, the right range can be empty if the data ends
+    /// within the left block. This is the only leaf that is not stored in an outboard.
+    ///
+    /// Returns `None` if the node is not a leaf of this tree, or starts at or after
+    /// the end of the data. A tree with size 0 has a single leaf with two empty ranges.
+    pub fn leaf_byte_ranges(&self, leaf: TreeNode) -> Option<(Range<ByteNum>, Range<ByteNum>)> {
+        if !self.is_leaf(leaf) {
+            return None;
+        }
+        let start = leaf.byte_range().start;
+        if !(start < self.size || (start == 0 && self.size == 0)) {
+            return None;
+        }
+        let (start, mid, end) = self.leaf_byte_ranges3(leaf);
+        Some((start..mid, mid..end))
+    }
+
+    /// Compute the chunk ranges of the two blocks of a leaf node
+    ///
+    /// This is the same as [BaoTree::leaf_byte_ranges], but in chunks. A partial
+    /// last chunk is included, so for the last leaf the end of the non-empty range is
+    /// the number of chunks of the tree. An empty byte range gives an empty chunk range.
+    pub fn leaf_chunk_ranges(&self, leaf: TreeNode) -> Option<(Range<ChunkNum>, Range<ChunkNum>)> {
+        let (left, right) = self.leaf_byte_ranges(leaf)?;
+        let to_chunks = |r: Range<ByteNum>| {
+            if r.is_empty() {
+                r.end.chunks()..r.end.chunks()
+            } else {
+                r.start.full_chunks()..r.end.chunks()
+            }
+        };
+        Some((to_chunks(left), to_chunks(right)))
+    }
+
+    /// The leaf node that contains the byte at `offset`
+    ///
+    /// The byte is within one of the two ranges given by [BaoTree::leaf_byte_ranges]
+    /// for the returned leaf. Returns `None` if the offset is not within the data,
+    /// except for a tree with size 0, where offset 0 gives the single leaf.
+    pub fn leaf_containing(&self, offset: ByteNum) -> Option<TreeNode> {
+        if !(offset < self.size || (offset == 0 && self.size == 0)) {
+            return None;
+        }
+        let level = self.block_size.0;
+        let chunk = offset.full_chunks().0;
+        // clear the bits below the leaf level, then set the trailing `level` bits to 1
+        let start = chunk & !((2 << level) - 1);
+        Some(TreeNode(start | ((1 << level) - 1)))
+    }
+
+    /// The ancestors of `node` in this tree, from the parent up to the root
+    ///
+    /// The hash pairs of these nodes are what is needed to verify `node` against
+    /// the root hash. For a node within a leaf, the nodes up to the leaf are included,
+    /// even though they are not stored in an outboard. The root has no ancestors.
+    pub fn ancestors(&self, node: TreeNode) -> impl Iterator<Item = TreeNode> {
+        let shift = self.block_size.0;
+        let (_, len) = self.shifted();
+        std::iter::successors(Some(node), move |node| {
+            if node.level() < u32::from(shift) {
+                // within a leaf, the tree is complete
+                node.parent()
+            } else {
+                // use the shifted tree to skip parents that are not part of the tree
+                let shifted = node.add_block_size(shift)?;
+                let parent = shifted.restricted_parent(len)?;
+                Some(parent.subtract_block_size(shift))
+            }
+        })
+        .skip(1)
+    }
+
+    /// Split the tree into the subtrees at `level`
+    ///
+    /// Returns the nodes at `level` that contain data, see [TreeNode::level], from left
+    /// to right, together with the byte range of the data below each node. The hash of
+    /// a subtree only depends on its data and on the chunk number it starts at, so the
+    /// subtrees can be stored and hashed independently with [io::sync::subtree_hash],
+    /// and the root hash can be computed from their hashes with
+    /// [io::sync::combine_subtree_hashes].
+    ///
+    /// If the tree is not larger than a single node at `level`, the result is just the
+    /// root of the tree, with the entire data.
+    pub fn split_at_level(&self, level: u32) -> Vec<(TreeNode, Range<ByteNum>)> {
+        let root = self.root();
+        if level >= root.level() {
+            return vec![(root, ByteNum(0)..self.size)];
+        }
+        // number of chunks covered by a node at this level
+        let span = 2u64 << level;
+        let count = (self.chunks().0 + span - 1) / span;
+        (0..count)
+            .map(|i| {
+                let node = TreeNode((i * span) | ((1 << level) - 1));
+                (node, self.byte_range(node))
+            })
+            .collect()
     }
 
     /// Compute the byte ranges for a leaf node
@@ -172,14 +311,62 @@ impl BaoTree {
     ///
     /// This iterator is used by both the sync and async io code for encoding
     /// from an outboard and ranges as well as decoding an encoded stream.
+    ///
+    /// A traversal with a `min_level` above the block size skips the hash pairs below
+    /// `min_level` for fully requested subtrees. This is what the
+    /// encode_ranges_with_min_level functions in [io] use, and the stream can only be
+    /// decoded with the block size `max(block_size, min_level)`. All other io code uses
+    /// a `min_level` of 0.
     pub fn ranges_pre_order_chunks_iter_ref<'a>(
         &self,
-        ranges: &'a RangeSetRef<ChunkNum>,
+        ranges: &'a ChunkRangesRef,
         min_level: u8,
     ) -> PreOrderPartialChunkIterRef<'a> {
         PreOrderPartialChunkIterRef::new(*self, ranges, min_level)
     }
 
+    /// The exact size of the encoding of `ranges`, as produced by the encode_ranges functions
+    ///
+    /// This is the 8 byte size header, plus 64 bytes for each hash pair, plus the data
+    /// of the leaves that are part of the response. Ranges that are past the end of the
+    /// blob are handled the same way as when encoding, and chunk groups that are only
+    /// partially requested are counted as the individual chunks that are sent.
+    pub fn encoded_size(&self, ranges: &ChunkRangesRef) -> ByteNum {
+        let mut res = 8;
+        let ranges = rec::truncate_ranges(ranges, self.size);
+        for item in ResponseIterRef::new(*self, ranges) {
+            match item {
+                BaoChunk::Parent { .. } => res += 64,
+                BaoChunk::Leaf { size, .. } => res += size as u64,
+            }
+        }
+        ByteNum(res)
+    }
+
+    /// The number of hash pairs in the encoding of `ranges`
+    ///
+    /// Together with [BaoTree::encoded_size], this gives the split of the encoding into
+    /// 64 byte hash pairs and data, e.g. to check that a peer did not send more data
+    /// than requested.
+    pub fn encoded_hash_pairs(&self, ranges: &ChunkRangesRef) -> u64 {
+        let ranges = rec::truncate_ranges(ranges, self.size);
+        ResponseIterRef::new(*self, ranges)
+            .filter(|item| matches!(item, BaoChunk::Parent { .. }))
+            .count() as u64
+    }
+
+    /// The number of leaves in the encoding of `ranges`
+    ///
+    /// This is the number of [BaoChunk::Leaf] items of the response, and so the number
+    /// of leaves that a decoder yields for it. A chunk group that is only partially
+    /// requested is a single leaf.
+    pub fn encoded_leaves(&self, ranges: &ChunkRangesRef) -> u64 {
+        let ranges = rec::truncate_ranges(ranges, self.size);
+        ResponseIterRef::new(*self, ranges)
+            .filter(|item| matches!(item, BaoChunk::Leaf { .. }))
+            .count() as u64
+    }
+
     /// Traverse the entire tree in post order as [TreeNode]s,
     /// down to the level given by the block size.
     pub fn post_order_nodes_iter(&self) -> impl Iterator<Item = TreeNode> {
@@ -190,6 +377,11 @@ impl BaoTree {
 
     /// Traverse the entire tree in pre order as [TreeNode]s,
     /// down to the level given by the block size.
+    ///
+    /// This is the complete walk, root first and left to right, without any range
+    /// filtering. It includes the half leaf, if there is one. The nodes for which
+    /// [BaoTree::pre_order_offset] returns an offset are the hash pairs of a pre order
+    /// outboard, in the order they are stored.
     pub fn pre_order_nodes_iter(&self) -> impl Iterator<Item = TreeNode> {
         let (root, len) = self.shifted();
         let shift = self.block_size.0;
@@ -206,7 +398,7 @@ impl BaoTree {
     /// covered by the ranges.
     pub fn ranges_pre_order_nodes_iter<'a>(
         &self,
-        ranges: &'a RangeSetRef<ChunkNum>,
+        ranges: &'a ChunkRangesRef,
         min_level: u8,
     ) -> PreOrderPartialIterRef<'a> {
         PreOrderPartialIterRef::new(*self, ranges, min_level)
@@ -259,7 +451,7 @@ impl BaoTree {
     ///
     /// If a tree has a non-zero block size, this is different than the node
     /// being a leaf (level=0).
-    const fn is_leaf(&self, node: TreeNode) -> bool {
+    pub const fn is_leaf(&self, node: TreeNode) -> bool {
         node.level() == self.block_size.to_u32()
     }
 
@@ -288,6 +480,13 @@ impl BaoTree {
     }
 
     /// The offset of the given node in the pre order traversal
+    ///
+    /// This is the index of the hash pair of `node` in a pre order outboard, so the
+    /// pair is at byte `offset * 64` after the 8 byte size prefix. Returns `None` for
+    /// nodes below the block size, and for the last leaf if it has only one block,
+    /// since these have no hash pair in an outboard.
+    ///
+    /// `node` must be a node of this tree, otherwise the result is meaningless.
     pub fn pre_order_offset(&self, node: TreeNode) -> Option<u64> {
         // if the node has a level less than block_size, this will return None
         let shifted = node.add_block_size(self.block_size.0)?;
@@ -300,7 +499,53 @@ impl BaoTree {
         }
     }
 
+    /// The offset of the hash pair of `node` in the combined encoding
+    ///
+    /// The combined encoding is the 8 byte size, followed by all hash pairs and all
+    /// data in pre order, as produced by [io::sync::encode_combined]. Before the pair
+    /// of a node come the pairs before it in pre order, and all data to its left.
+    ///
+    /// Returns `None` if the node has no hash pair in this tree.
+    pub fn combined_pair_offset(&self, node: TreeNode) -> Option<u64> {
+        if node.level() < self.block_size.to_u32() || node.mid().to_bytes() >= self.size {
+            return None;
+        }
+        let pairs = self.pre_order_offset(node)?;
+        Some(8 + pairs * 64 + node.chunk_range().start.to_bytes().0)
+    }
+
+    /// The offset of the data byte at `offset` in the combined encoding
+    ///
+    /// The data of both blocks of a leaf directly follows the hash pair of the leaf.
+    /// The last leaf has no hash pair if it has only one block, so its data comes
+    /// after all pairs.
+    ///
+    /// Returns `None` if the offset is not within the data, except for a tree with
+    /// size 0, where offset 0 gives the end of the encoding.
+    pub fn combined_data_offset(&self, offset: ByteNum) -> Option<u64> {
+        let leaf = self.leaf_containing(offset)?;
+        let start = leaf.chunk_range().start.to_bytes();
+        let leaf_offset = match self.combined_pair_offset(leaf) {
+            Some(pair_offset) => pair_offset + 64,
+            None => 8 + self.outboard_hash_pairs() * 64 + start.0,
+        };
+        Some(leaf_offset + (offset.0 - start.0))
+    }
+
     /// The offset of the given node in the post order traversal
+    ///
+    /// This is the index of the hash pair of `node` in a post order outboard, so the
+    /// pair is at byte `offset.value() * 64`. Returns `None` for nodes below the block
+    /// size, and for the last leaf if it has only one block, since these have no hash
+    /// pair in an outboard.
+    ///
+    /// Nodes that are entirely within the data are [PostOrderOffset::Stable]. Their
+    /// offset does not depend on the size, so it stays the same when data is appended.
+    /// Nodes on the right edge of the tree that extend past the end of the data are
+    /// [PostOrderOffset::Unstable]. They come last in post order, and their offset
+    /// is computed from the total number of hash pairs.
+    ///
+    /// `node` must be a node of this tree, otherwise the result is meaningless.
     pub fn post_order_offset(&self, node: TreeNode) -> Option<PostOrderOffset> {
         // if the node has a level less than block_size, this will return None
         let shifted = node.add_block_size(self.block_size.0)?;
@@ -321,6 +566,100 @@ impl BaoTree {
         }
     }
 
+    /// The number of leading hash pairs of a post order outboard that are stable
+    ///
+    /// These are the pairs of the nodes that are entirely within the data, see
+    /// [PostOrderOffset::Stable]. They do not change when data is appended, so the
+    /// first `stable_pair_count() * 64` bytes of a post order outboard can be kept
+    /// across appends. The remaining pairs are for the nodes on the right edge of
+    /// the tree, and have to be recomputed.
+    pub fn stable_pair_count(&self) -> u64 {
+        // every complete subtree of full blocks has one pair less than it has blocks
+        let full_blocks = self.size.full_chunks().0 >> self.block_size.0;
+        full_blocks - u64::from(full_blocks.count_ones())
+    }
+
+    /// The node whose hash pair is at `offset` in a post order outboard
+    ///
+    /// This is the inverse of [BaoTree::post_order_offset], so a tool that reads a
+    /// post order outboard sequentially can find the node, and with it the byte range,
+    /// of each pair. Returns `None` if the offset is not less than the number of hash
+    /// pairs.
+    pub fn node_at_post_order_offset(&self, offset: u64) -> Option<TreeNode> {
+        if offset >= self.outboard_hash_pairs() {
+            return None;
+        }
+        let shift = self.block_size.0;
+        if offset < self.stable_pair_count() {
+            // stable offsets do not depend on the size, so search the smallest complete
+            // tree at the left edge that contains the offset
+            let level = (offset + 2).next_power_of_two().trailing_zeros() - 1;
+            let mut node = TreeNode((1 << level) - 1);
+            while node.post_order_offset() != offset {
+                let left = node.left_child()?;
+                node = if offset < left.post_order_range().end {
+                    left
+                } else {
+                    node.right_child()?
+                };
+            }
+            Some(node.subtract_block_size(shift))
+        } else {
+            // unstable nodes are on the path from the root to the last block
+            let last = ChunkNum(self.blocks().0 - 1);
+            let (mut node, _) = self.shifted();
+            loop {
+                let unshifted = node.subtract_block_size(shift);
+                if let Some(PostOrderOffset::Unstable(o)) = self.post_order_offset(unshifted) {
+                    if o == offset {
+                        return Some(unshifted);
+                    }
+                }
+                node = if last < node.mid() {
+                    node.left_child()?
+                } else {
+                    node.right_child()?
+                };
+            }
+        }
+    }
+
+    /// Render the entire tree for debugging
+    ///
+    /// There is one line per node of [BaoTree::pre_order_nodes_iter], indented by the
+    /// level of the node. A line shows the node, its level, the blocks and bytes it
+    /// covers, and its offset in a post order outboard, if it has a hash pair there.
+    pub fn format_tree(&self) -> String {
+        use std::fmt::Write;
+        let shift = self.block_size.0;
+        let (root, _) = self.shifted();
+        let root_level = root.level() + u32::from(shift);
+        let mut res = String::new();
+        for node in self.pre_order_nodes_iter() {
+            let indent = ((root_level - node.level()) * 2) as usize;
+            let bytes = self.byte_range(node);
+            let blocks = (node.chunk_range().start.0 >> shift)..bytes.end.blocks(self.block_size).0;
+            let offset = match self.post_order_offset(node) {
+                Some(PostOrderOffset::Stable(offset)) => format!("{offset}"),
+                Some(PostOrderOffset::Unstable(offset)) => format!("{offset} (unstable)"),
+                None => "none".to_string(),
+            };
+            writeln!(
+                res,
+                "{:indent$}{} level={} blocks={:?} bytes={}..{} post_order={}",
+                "",
+                node.0,
+                node.level(),
+                blocks,
+                bytes.start.0,
+                bytes.end.0,
+                offset,
+            )
+            .unwrap();
+        }
+        res
+    }
+
     const fn chunk_group_chunks(&self) -> ChunkNum {
         ChunkNum(1 << self.block_size.0)
     }
@@ -355,6 +694,17 @@ impl ByteNum {
         let open_block = ((size & block_mask) != 0) as u64;
         BlockNum(full_blocks + open_block)
     }
+
+    /// number of blocks that this number of bytes covers, given a block size
+    ///
+    /// Returns None if the block size is so large that a block does not fit into an
+    /// u64, in which case [ByteNum::blocks] would overflow.
+    pub const fn checked_blocks(&self, block_size: BlockSize) -> Option<BlockNum> {
+        if block_size.0 as u32 + 10 >= u64::BITS {
+            return None;
+        }
+        Some(self.blocks(block_size))
+    }
 }
 
 impl ChunkNum {
@@ -362,6 +712,18 @@ impl ChunkNum {
     pub const fn to_bytes(&self) -> ByteNum {
         ByteNum(self.0 << 10)
     }
+
+    /// number of bytes that this number of chunks covers, or None if it does not
+    /// fit into an u64
+    ///
+    /// [ChunkNum::to_bytes] silently drops the high bits for chunk numbers of 2^54
+    /// and above, which can come from a query.
+    pub const fn checked_to_bytes(&self) -> Option<ByteNum> {
+        match self.0.checked_mul(1 << 10) {
+            Some(bytes) => Some(ByteNum(bytes)),
+            None => None,
+        }
+    }
 }
 
 /// An u64 that defines a node in a bao tree.
@@ -649,18 +1011,15 @@ fn pre_order_offset_loop(node: u64, len: u64) -> u64 {
 
 /// Split and canonicalize a range set at a given chunk number
 ///
-/// Compared to [RangeSetRef::split], this function will canonicalize the second range
-pub(crate) fn split(
-    ranges: &RangeSetRef<ChunkNum>,
-    mid: ChunkNum,
-) -> (&RangeSetRef<ChunkNum>, &RangeSetRef<ChunkNum>) {
+/// Compared to [ChunkRangesRef::split], this function will canonicalize the second range
+pub(crate) fn split(ranges: &ChunkRangesRef, mid: ChunkNum) -> (&ChunkRangesRef, &ChunkRangesRef) {
     let (a, mut b) = ranges.split(mid);
     // check that a does not contain a redundant boundary at or after mid
     debug_assert!(a.boundaries().last() < Some(&mid));
     // Replace b with the canonicalized version if it starts at or before mid.
-    // This is necessary to be able to check it with RangeSetRef::is_all()
+    // This is necessary to be able to check it with ChunkRangesRef::is_all()
     if b.boundaries().len() == 1 && b.boundaries()[0] <= mid {
-        b = RangeSetRef::new(&[ChunkNum(0)]).unwrap();
+        b = ChunkRangesRef::new(&[ChunkNum(0)]).unwrap();
     }
     (a, b)
 }
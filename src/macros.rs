@@ -28,6 +28,7 @@ macro_rules! index_newtype {
             }
         }
 
+        #[cfg(feature = "range-collections")]
         impl RangeSetEntry for $name {
             fn min_value() -> Self {
                 $name(0)
@@ -107,9 +108,17 @@ macro_rules! index_newtype {
         impl $name {
 
             /// Convert to usize or panic if it doesn't fit.
+            ///
+            /// On 32 bit targets, this panics for values of 2^32 and above. Use
+            /// [Self::try_to_usize] for values that are not bounded by something in memory.
             pub fn to_usize(self) -> usize {
                 usize::try_from(self.0).expect("usize overflow")
             }
+
+            /// Convert to usize, or fail if it doesn't fit.
+            pub fn try_to_usize(self) -> Result<usize, std::num::TryFromIntError> {
+                usize::try_from(self.0)
+            }
         }
     }
 }
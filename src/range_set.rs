@@ -0,0 +1,231 @@
+//! A minimal range set of chunks, used when the `range-collections` feature is off
+//!
+//! This only implements the operations the encoders and decoders need, with the same
+//! names and semantics as the [range_collections](https://docs.rs/range-collections)
+//! types it replaces. A set is stored as a strictly increasing list of boundaries that
+//! alternate between the start and the end of a range, so `[1, 3, 5]` means the chunks
+//! `1..3` and `5..`.
+use smallvec::SmallVec;
+use std::{
+    fmt,
+    ops::{BitAndAssign, BitOrAssign, BitXorAssign, Deref, Range, RangeFrom, RangeTo},
+};
+
+use crate::ChunkNum;
+
+/// The boundaries of the set containing all chunks
+const ALL: &[ChunkNum] = &[ChunkNum(0)];
+
+/// A set of chunk ranges
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct ChunkRanges {
+    boundaries: SmallVec<[ChunkNum; 2]>,
+}
+
+/// A referenceable set of chunk ranges
+///
+/// [ChunkRanges] implements [AsRef<ChunkRangesRef>].
+#[derive(PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct ChunkRangesRef {
+    boundaries: [ChunkNum],
+}
+
+impl ChunkRanges {
+    /// The empty set
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// The set containing all chunks
+    pub fn all() -> Self {
+        Self {
+            boundaries: SmallVec::from_slice(ALL),
+        }
+    }
+
+    /// Create a set from boundaries, without checking that they are strictly increasing
+    pub fn new_unchecked(boundaries: SmallVec<[ChunkNum; 2]>) -> Self {
+        debug_assert!(is_strictly_increasing(&boundaries));
+        Self { boundaries }
+    }
+
+    /// The boundaries of the ranges
+    pub fn into_inner(self) -> SmallVec<[ChunkNum; 2]> {
+        self.boundaries
+    }
+}
+
+impl ChunkRangesRef {
+    /// Create a set from boundaries, or None if they are not strictly increasing
+    pub fn new(boundaries: &[ChunkNum]) -> Option<&Self> {
+        if is_strictly_increasing(boundaries) {
+            Some(Self::new_unchecked(boundaries))
+        } else {
+            None
+        }
+    }
+
+    /// Create a set from boundaries, without checking that they are strictly increasing
+    pub fn new_unchecked(boundaries: &[ChunkNum]) -> &Self {
+        debug_assert!(is_strictly_increasing(boundaries));
+        // SAFETY: ChunkRangesRef is a repr(transparent) wrapper around [ChunkNum]
+        unsafe { &*(boundaries as *const [ChunkNum] as *const ChunkRangesRef) }
+    }
+
+    /// The boundaries of the ranges
+    pub fn boundaries(&self) -> &[ChunkNum] {
+        &self.boundaries
+    }
+
+    /// True if the set contains no chunks
+    pub fn is_empty(&self) -> bool {
+        self.boundaries.is_empty()
+    }
+
+    /// True if the set contains all chunks
+    pub fn is_all(&self) -> bool {
+        &self.boundaries == ALL
+    }
+
+    /// Split the set into the part below `at` and the part at or above `at`
+    ///
+    /// Like `RangeSetRef::split`, the right part is not canonicalized. If `at` is inside
+    /// a range, the right part starts with the start of that range.
+    pub fn split(&self, at: ChunkNum) -> (&Self, &Self) {
+        let b = &self.boundaries;
+        let left = b.partition_point(|x| *x < at);
+        let right = b.partition_point(|x| *x <= at);
+        let right = if right % 2 == 0 { right } else { right - 1 };
+        (
+            Self::new_unchecked(&b[..left]),
+            Self::new_unchecked(&b[right..]),
+        )
+    }
+
+    /// True if every chunk in `other` is also in this set
+    pub fn is_superset(&self, other: &Self) -> bool {
+        combine(&other.boundaries, &self.boundaries, |a, b| a && !b).is_empty()
+    }
+
+    /// True if this set and `other` have at least one chunk in common
+    pub fn intersects(&self, other: &Self) -> bool {
+        !combine(&self.boundaries, &other.boundaries, |a, b| a && b).is_empty()
+    }
+}
+
+/// Merge the boundaries of two sets
+///
+/// `op` decides for each pair of memberships if a chunk is in the result. It must
+/// return false if the chunk is in neither set.
+fn combine(
+    a: &[ChunkNum],
+    b: &[ChunkNum],
+    op: impl Fn(bool, bool) -> bool,
+) -> SmallVec<[ChunkNum; 2]> {
+    let mut res = SmallVec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut state = false;
+    loop {
+        let x = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) => *x.min(y),
+            (Some(x), None) => *x,
+            (None, Some(y)) => *y,
+            (None, None) => break,
+        };
+        if a.get(i) == Some(&x) {
+            i += 1;
+        }
+        if b.get(j) == Some(&x) {
+            j += 1;
+        }
+        let next = op(i % 2 == 1, j % 2 == 1);
+        if next != state {
+            res.push(x);
+            state = next;
+        }
+    }
+    res
+}
+
+fn is_strictly_increasing(boundaries: &[ChunkNum]) -> bool {
+    boundaries.windows(2).all(|w| w[0] < w[1])
+}
+
+impl Deref for ChunkRanges {
+    type Target = ChunkRangesRef;
+
+    fn deref(&self) -> &ChunkRangesRef {
+        ChunkRangesRef::new_unchecked(&self.boundaries)
+    }
+}
+
+impl AsRef<ChunkRangesRef> for ChunkRanges {
+    fn as_ref(&self) -> &ChunkRangesRef {
+        self
+    }
+}
+
+impl From<Range<ChunkNum>> for ChunkRanges {
+    fn from(range: Range<ChunkNum>) -> Self {
+        if range.start < range.end {
+            Self {
+                boundaries: SmallVec::from_slice(&[range.start, range.end]),
+            }
+        } else {
+            Self::empty()
+        }
+    }
+}
+
+impl From<RangeFrom<ChunkNum>> for ChunkRanges {
+    fn from(range: RangeFrom<ChunkNum>) -> Self {
+        Self {
+            boundaries: SmallVec::from_slice(&[range.start]),
+        }
+    }
+}
+
+impl From<RangeTo<ChunkNum>> for ChunkRanges {
+    fn from(range: RangeTo<ChunkNum>) -> Self {
+        Self::from(ChunkNum(0)..range.end)
+    }
+}
+
+impl BitOrAssign for ChunkRanges {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.boundaries = combine(&self.boundaries, &rhs.boundaries, |a, b| a || b);
+    }
+}
+
+impl BitAndAssign for ChunkRanges {
+    fn bitand_assign(&mut self, rhs: Self) {
+        self.boundaries = combine(&self.boundaries, &rhs.boundaries, |a, b| a && b);
+    }
+}
+
+impl BitXorAssign for ChunkRanges {
+    fn bitxor_assign(&mut self, rhs: Self) {
+        self.boundaries = combine(&self.boundaries, &rhs.boundaries, |a, b| a != b);
+    }
+}
+
+impl fmt::Debug for ChunkRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Debug for ChunkRangesRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut set = f.debug_set();
+        for range in self.boundaries.chunks(2) {
+            match range {
+                [start, end] => set.entry(&(start.0..end.0)),
+                [start] => set.entry(&(start.0..)),
+                _ => unreachable!(),
+            };
+        }
+        set.finish()
+    }
+}
@@ -0,0 +1,321 @@
+//! A minimal set of chunk ranges
+//!
+//! The encoders and decoders only need the sorted boundaries of a query. The
+//! [ChunkRangeSet] trait captures just that, so they can take ranges from any of the
+//! range set types of this crate. The traversal itself works on a [ChunkRangesRef]
+//! view of the boundaries, which is created without copying.
+//!
+//! [SimpleChunkRanges] and [SimpleChunkRangesRef] are a small implementation of this
+//! trait that does not need any range set types from `range_collections`. Conversions
+//! to and from [ChunkRanges] and [ChunkRangesRef] are lossless.
+//!
+//! With the `range-collections` feature, which is on by default, [ChunkRanges] is the
+//! `RangeSet2` type of `range_collections`. Without it, [ChunkRanges] is a minimal
+//! internal range set, with just the operations the encoders and decoders need, and
+//! the functions that take `range_collections` byte ranges are not available.
+//!
+//! [all_chunks], [chunk_range] and [chunks_for_bytes] build [ChunkRanges] for the
+//! common queries, without having to know the range set API.
+//! [canonicalize_ranges] normalizes a query for a known size, the same way as the
+//! encoders and decoders do.
+use smallvec::SmallVec;
+use std::ops::{Range, RangeFrom};
+
+use self::sealed::Sealed;
+
+use crate::{rec::truncate_ranges_owned, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef};
+
+/// The query for an entire blob
+///
+/// This does not depend on the size, so it can be used before the size is known.
+///
+/// ```
+/// use bao_tree::{io::outboard::PostOrderMemOutboard, ranges, BlockSize};
+///
+/// let data = vec![1u8; 100000];
+/// let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+/// let mut encoded = Vec::new();
+/// bao_tree::io::sync::encode_ranges(&data, &outboard, &ranges::all_chunks(), &mut encoded)
+///     .unwrap();
+/// assert_eq!(encoded.len(), 8 + 6 * 64 + 100000);
+/// ```
+pub fn all_chunks() -> ChunkRanges {
+    ChunkRanges::all()
+}
+
+/// The query for the chunks from `start` to `end`
+///
+/// The result is empty if `start >= end`.
+///
+/// ```
+/// use bao_tree::{io::outboard::PostOrderMemOutboard, ranges, BlockSize, ChunkNum};
+///
+/// let data = vec![1u8; 100000];
+/// let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+/// let query = ranges::chunk_range(ChunkNum(16), ChunkNum(32));
+/// let mut encoded = Vec::new();
+/// bao_tree::io::sync::encode_ranges(&data, &outboard, &query, &mut encoded).unwrap();
+/// ```
+pub fn chunk_range(start: ChunkNum, end: ChunkNum) -> ChunkRanges {
+    if start < end {
+        ChunkRanges::from(start..end)
+    } else {
+        ChunkRanges::empty()
+    }
+}
+
+/// The query for the chunks that contain the bytes in `range`
+///
+/// The range is rounded outwards to full chunks, since data can only be verified in
+/// whole chunks. The result is empty if the range is empty.
+///
+/// ```
+/// use bao_tree::{io::outboard::PostOrderMemOutboard, ranges, BlockSize, ByteNum, ChunkNum};
+///
+/// let data = vec![1u8; 100000];
+/// let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+/// let query = ranges::chunks_for_bytes(ByteNum(1000)..ByteNum(3000));
+/// assert_eq!(query, ranges::chunk_range(ChunkNum(0), ChunkNum(3)));
+/// let mut encoded = Vec::new();
+/// bao_tree::io::sync::encode_ranges(&data, &outboard, &query, &mut encoded).unwrap();
+/// ```
+pub fn chunks_for_bytes(range: Range<ByteNum>) -> ChunkRanges {
+    if range.start < range.end {
+        chunk_range(range.start.full_chunks(), range.end.chunks())
+    } else {
+        ChunkRanges::empty()
+    }
+}
+
+/// Canonicalize a query for a blob of size `size`
+///
+/// This is the normalization that the encoders and decoders apply once the size is
+/// known, so two queries with the same canonical form produce the same response.
+///
+/// Everything behind the end of the blob is considered a request for the last chunk,
+/// so a query for chunks beyond the end can be used to get a size proof. The first
+/// range that includes the last chunk, or anything behind it, is turned into an open
+/// range, and everything after it is dropped. Otherwise the query does not change.
+///
+/// To know which chunk ranges a response will actually contain, e.g. for a partially
+/// requested chunk group, use [crate::io::covered_chunk_ranges].
+///
+/// ```
+/// use bao_tree::{ranges, ByteNum, ChunkNum, ChunkRanges};
+///
+/// // a blob of 7 chunks
+/// let size = ByteNum(7 * 1024);
+/// // a query that ends before the last chunk does not change
+/// let query = ranges::chunk_range(ChunkNum(0), ChunkNum(6));
+/// assert_eq!(ranges::canonicalize_ranges(query.clone(), size), query);
+/// // a query past the end includes the last chunk
+/// let query = ranges::chunk_range(ChunkNum(0), ChunkNum(10));
+/// assert_eq!(
+///     ranges::canonicalize_ranges(query, size),
+///     ChunkRanges::from(ChunkNum(0)..)
+/// );
+/// ```
+pub fn canonicalize_ranges(ranges: ChunkRanges, size: ByteNum) -> ChunkRanges {
+    truncate_ranges_owned(ranges, size)
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A set of chunk ranges, given as a sorted list of boundaries.
+///
+/// The boundaries alternate between the start and the end of a range, so `[1, 3, 5]`
+/// means the chunks `1..3` and `5..`.
+///
+/// This trait is sealed. It is implemented for [ChunkRanges], [ChunkRangesRef],
+/// [SimpleChunkRanges] and [SimpleChunkRangesRef], which can only be constructed with
+/// strictly increasing boundaries, so the encoders and decoders can rely on that.
+pub trait ChunkRangeSet: Sealed {
+    /// The boundaries of the ranges
+    fn boundaries(&self) -> &[ChunkNum];
+}
+
+impl Sealed for ChunkRangesRef {}
+impl Sealed for ChunkRanges {}
+impl Sealed for SimpleChunkRanges {}
+impl<'a> Sealed for SimpleChunkRangesRef<'a> {}
+impl<T: Sealed + ?Sized> Sealed for &T {}
+
+impl ChunkRangeSet for ChunkRangesRef {
+    fn boundaries(&self) -> &[ChunkNum] {
+        ChunkRangesRef::boundaries(self)
+    }
+}
+
+impl ChunkRangeSet for ChunkRanges {
+    fn boundaries(&self) -> &[ChunkNum] {
+        ChunkRangesRef::boundaries(self)
+    }
+}
+
+impl ChunkRangeSet for SimpleChunkRanges {
+    fn boundaries(&self) -> &[ChunkNum] {
+        &self.boundaries
+    }
+}
+
+impl<'a> ChunkRangeSet for SimpleChunkRangesRef<'a> {
+    fn boundaries(&self) -> &[ChunkNum] {
+        self.boundaries
+    }
+}
+
+impl<T: ChunkRangeSet + ?Sized> ChunkRangeSet for &T {
+    fn boundaries(&self) -> &[ChunkNum] {
+        (**self).boundaries()
+    }
+}
+
+/// View any [ChunkRangeSet] as a [ChunkRangesRef], without copying
+///
+/// All implementations of the sealed [ChunkRangeSet] trait have strictly increasing
+/// boundaries, so this does not need to check them.
+pub(crate) fn as_chunk_ranges_ref<R: ChunkRangeSet + ?Sized>(ranges: &R) -> &ChunkRangesRef {
+    let boundaries = ranges.boundaries();
+    debug_assert!(is_strictly_increasing(boundaries));
+    ChunkRangesRef::new_unchecked(boundaries)
+}
+
+fn is_strictly_increasing(boundaries: &[ChunkNum]) -> bool {
+    boundaries.windows(2).all(|w| w[0] < w[1])
+}
+
+/// The boundaries of the set containing all chunks
+const ALL: &[ChunkNum] = &[ChunkNum(0)];
+
+/// An owned set of chunk ranges
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimpleChunkRanges {
+    boundaries: SmallVec<[ChunkNum; 2]>,
+}
+
+impl SimpleChunkRanges {
+    /// Create a new set from boundaries, or None if they are not strictly increasing
+    pub fn new(boundaries: Vec<ChunkNum>) -> Option<Self> {
+        if is_strictly_increasing(&boundaries) {
+            Some(Self {
+                boundaries: SmallVec::from_vec(boundaries),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The empty set
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// The set containing all chunks
+    pub fn all() -> Self {
+        Self {
+            boundaries: SmallVec::from_slice(ALL),
+        }
+    }
+
+    /// Borrow the set
+    pub fn as_ranges_ref(&self) -> SimpleChunkRangesRef<'_> {
+        SimpleChunkRangesRef {
+            boundaries: &self.boundaries,
+        }
+    }
+
+    /// The boundaries of the ranges
+    pub fn boundaries(&self) -> &[ChunkNum] {
+        &self.boundaries
+    }
+}
+
+impl From<Range<ChunkNum>> for SimpleChunkRanges {
+    fn from(range: Range<ChunkNum>) -> Self {
+        if range.start < range.end {
+            Self {
+                boundaries: SmallVec::from_slice(&[range.start, range.end]),
+            }
+        } else {
+            Self::empty()
+        }
+    }
+}
+
+impl From<RangeFrom<ChunkNum>> for SimpleChunkRanges {
+    fn from(range: RangeFrom<ChunkNum>) -> Self {
+        Self {
+            boundaries: SmallVec::from_slice(&[range.start]),
+        }
+    }
+}
+
+impl From<&ChunkRangesRef> for SimpleChunkRanges {
+    fn from(ranges: &ChunkRangesRef) -> Self {
+        Self {
+            boundaries: SmallVec::from_slice(ranges.boundaries()),
+        }
+    }
+}
+
+impl From<ChunkRanges> for SimpleChunkRanges {
+    fn from(ranges: ChunkRanges) -> Self {
+        Self {
+            boundaries: ranges.into_inner(),
+        }
+    }
+}
+
+impl From<SimpleChunkRanges> for ChunkRanges {
+    fn from(ranges: SimpleChunkRanges) -> Self {
+        ChunkRanges::new_unchecked(ranges.boundaries)
+    }
+}
+
+/// A borrowed set of chunk ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimpleChunkRangesRef<'a> {
+    boundaries: &'a [ChunkNum],
+}
+
+impl<'a> SimpleChunkRangesRef<'a> {
+    /// Create a new set from boundaries, or None if they are not strictly increasing
+    pub fn new(boundaries: &'a [ChunkNum]) -> Option<Self> {
+        if is_strictly_increasing(boundaries) {
+            Some(Self { boundaries })
+        } else {
+            None
+        }
+    }
+
+    /// The boundaries of the ranges
+    pub fn boundaries(self) -> &'a [ChunkNum] {
+        self.boundaries
+    }
+
+    /// True if the set contains no chunks
+    pub fn is_empty(self) -> bool {
+        self.boundaries.is_empty()
+    }
+
+    /// True if the set contains all chunks
+    pub fn is_all(self) -> bool {
+        self.boundaries == ALL
+    }
+}
+
+impl<'a> From<&'a ChunkRangesRef> for SimpleChunkRangesRef<'a> {
+    fn from(ranges: &'a ChunkRangesRef) -> Self {
+        Self {
+            boundaries: ranges.boundaries(),
+        }
+    }
+}
+
+impl<'a> From<SimpleChunkRangesRef<'a>> for &'a ChunkRangesRef {
+    fn from(ranges: SimpleChunkRangesRef<'a>) -> Self {
+        ChunkRangesRef::new_unchecked(ranges.boundaries)
+    }
+}
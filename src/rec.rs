@@ -2,7 +2,11 @@
 //!
 //! Encocding is used to compute hashes, decoding is only used in tests as a
 //! reference implementation.
-use crate::{blake3, split, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef};
+use crate::{
+    blake3,
+    hasher::{Blake3Hasher, Hasher},
+    split, ByteNum, ChunkNum, ChunkRanges, ChunkRangesRef,
+};
 
 #[cfg(test)]
 use crate::{iter::BaoChunk, BaoTree, BlockSize, TreeNode};
@@ -31,7 +35,7 @@ pub fn truncate_ranges(ranges: &ChunkRangesRef, size: ByteNum) -> &ChunkRangesRe
     ChunkRangesRef::new_unchecked(&bs[..truncated_len(ranges, size)])
 }
 
-/// A version of [canonicalize_ranges] that takes and returns an owned [ChunkRanges].
+/// A version of [truncate_ranges] that takes and returns an owned [ChunkRanges].
 ///
 /// This is needed for the state machines that own their ranges.
 pub fn truncate_ranges_owned(ranges: ChunkRanges, size: ByteNum) -> ChunkRanges {
@@ -107,14 +111,36 @@ pub(crate) fn encode_selected_rec(
     emit_data: bool,
     res: &mut Vec<u8>,
 ) -> blake3::Hash {
-    use blake3::guts::{ChunkState, CHUNK_LEN};
+    encode_selected_rec_with_hasher(
+        &Blake3Hasher,
+        start_chunk,
+        data,
+        is_root,
+        query,
+        min_level,
+        emit_data,
+        res,
+    )
+}
+
+/// Same as [encode_selected_rec], but computes all hashes with `hasher`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encode_selected_rec_with_hasher(
+    hasher: &impl Hasher,
+    start_chunk: ChunkNum,
+    data: &[u8],
+    is_root: bool,
+    query: &ChunkRangesRef,
+    min_level: u32,
+    emit_data: bool,
+    res: &mut Vec<u8>,
+) -> blake3::Hash {
+    use blake3::guts::CHUNK_LEN;
     if data.len() <= CHUNK_LEN {
         if emit_data && !query.is_empty() {
             res.extend_from_slice(data);
         }
-        let mut hasher = ChunkState::new(start_chunk.0);
-        hasher.update(data);
-        hasher.finalize(is_root)
+        hasher.hash_chunk(start_chunk.0, data, is_root)
     } else {
         let chunks = data.len() / CHUNK_LEN + (data.len() % CHUNK_LEN != 0) as usize;
         let chunks = chunks.next_power_of_two();
@@ -138,7 +164,8 @@ pub(crate) fn encode_selected_rec(
             None
         };
         // recurse to the left and right to compute the hashes and emit data
-        let left = encode_selected_rec(
+        let left = encode_selected_rec_with_hasher(
+            hasher,
             start_chunk,
             &data[..mid_bytes],
             false,
@@ -147,7 +174,8 @@ pub(crate) fn encode_selected_rec(
             emit_data,
             res,
         );
-        let right = encode_selected_rec(
+        let right = encode_selected_rec_with_hasher(
+            hasher,
             mid_chunk,
             &data[mid_bytes..],
             false,
@@ -161,7 +189,7 @@ pub(crate) fn encode_selected_rec(
             res[o..o + 32].copy_from_slice(left.as_bytes());
             res[o + 32..o + 64].copy_from_slice(right.as_bytes());
         }
-        blake3::guts::parent_cv(&left, &right, is_root)
+        hasher.parent(&left, &right, is_root)
     }
 }
 
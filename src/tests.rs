@@ -76,7 +76,7 @@ fn post_order_outboard_reference(data: &[u8]) -> PostOrderMemOutboard {
     let tree = BaoTree::new(ByteNum(data.len() as u64), BlockSize::ZERO);
     outboard.splice(..8, []);
     let pre = PreOrderMemOutboard::new(hash, tree, outboard);
-    pre.unwrap().flip()
+    pre.unwrap().flip().unwrap()
 }
 
 fn encode_slice_reference(data: &[u8], chunk_range: Range<ChunkNum>) -> (Vec<u8>, blake3::Hash) {
@@ -144,6 +144,118 @@ fn bao_tree_decode_slice_iter_impl(data: Vec<u8>, range: Range<u64>) {
     }
 }
 
+/// Check interoperability with the bao crate at block size 0, in both directions.
+///
+/// `start` and `len` are a byte range as used by the bao slice functions. The range
+/// must be non empty and start within the data, otherwise bao and this crate differ.
+fn bao_interop_impl(size: usize, start: u64, len: u64) {
+    let data = make_test_data(size);
+    let (bao_outboard, bao_hash) = bao::encode::outboard(&data);
+    let root = blake3::Hash::from(*bao_hash.as_bytes());
+    // the chunks that bao includes for the byte range
+    let chunk_start = ByteNum(start).full_chunks();
+    let chunk_end = ByteNum(start + len).chunks().max(chunk_start + 1);
+    let ranges = ChunkRanges::from(chunk_start..chunk_end);
+    let mut extractor = bao::encode::SliceExtractor::new_outboard(
+        Cursor::new(&data),
+        Cursor::new(&bao_outboard),
+        start,
+        len,
+    );
+    let mut bao_encoded = Vec::new();
+    extractor.read_to_end(&mut bao_encoded).unwrap();
+
+    // encode with this crate, compare with bao and decode with bao
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize::ZERO);
+    assert_eq!(outboard.root(), root);
+    let mut encoded = Vec::new();
+    encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    assert_eq!(encoded, bao_encoded);
+    let mut decoder = bao::decode::SliceDecoder::new(Cursor::new(&encoded), &bao_hash, start, len);
+    let mut decoded = Vec::new();
+    decoder.read_to_end(&mut decoded).unwrap();
+    let end = (start + len).min(size as u64) as usize;
+    assert_eq!(decoded, data[start as usize..end]);
+
+    // decode the bao slice with this crate
+    let mut ec = Cursor::new(bao_encoded);
+    let mut decoded = Vec::new();
+    for item in decode_ranges_into_chunks(root, BlockSize::ZERO, &mut ec, &ranges) {
+        let (pos, slice) = item.unwrap();
+        if decoded.is_empty() {
+            assert_eq!(pos, chunk_start.to_bytes());
+        }
+        decoded.extend_from_slice(&slice);
+    }
+    let start = chunk_start.to_bytes().to_usize();
+    let end = chunk_end.to_bytes().to_usize().min(size);
+    assert_eq!(decoded, data[start..end]);
+}
+
+#[test]
+fn bao_interop_cases() {
+    let cases = [
+        (1, 0, 1),
+        (1023, 1000, 1),
+        (1024, 0, 1024),
+        (1025, 1024, 1),
+        (1025, 0, 2000),
+        (2048, 1023, 2),
+        (10000, 3000, 5000),
+        (24 * 1024 + 1, 24 * 1024, 1),
+        (100000, 0, 100000),
+        (100000, 50000, 1_000_000),
+    ];
+    for (size, start, len) in cases {
+        bao_interop_impl(size, start, len);
+    }
+}
+
+/// Check that the combined encoding at block size 0 is identical to the one of the
+/// bao crate, and that it can be decoded by both.
+fn bao_combined_impl(size: usize) {
+    let data = make_test_data(size);
+    let (expected, bao_hash) = bao::encode::encode(&data);
+    let (actual, root) = crate::io::encode(&data, BlockSize::ZERO);
+    assert_eq!(root.as_bytes(), bao_hash.as_bytes());
+    assert_eq!(actual, expected);
+    let mut actual2 = Vec::new();
+    let root2 = crate::io::sync::encode_combined(&data, BlockSize::ZERO, &mut actual2).unwrap();
+    assert_eq!(root2, root);
+    assert_eq!(actual2, expected);
+    // bao can decode our encoding
+    let decoded = bao::decode::decode(&actual, &bao_hash).unwrap();
+    assert_eq!(decoded, data);
+    // we can decode bao's encoding
+    let mut decoded = Vec::new();
+    let ranges = ChunkRanges::all();
+    for item in decode_ranges_into_chunks(root, BlockSize::ZERO, expected.as_slice(), &ranges) {
+        let (pos, slice) = item.unwrap();
+        assert_eq!(pos.to_usize(), decoded.len());
+        decoded.extend_from_slice(&slice);
+    }
+    assert_eq!(decoded, data);
+}
+
+#[test]
+fn bao_combined_cases() {
+    for size in [
+        0,
+        1,
+        1023,
+        1024,
+        1025,
+        2047,
+        2048,
+        2049,
+        10000,
+        24 * 1024 + 1,
+        100000,
+    ] {
+        bao_combined_impl(size);
+    }
+}
+
 #[cfg(feature = "tokio_fsm")]
 mod fsm_tests {
     use super::*;
@@ -183,6 +295,268 @@ mod fsm_tests {
         bao_tree_decode_slice_fsm_impl(td(1024 * 17), 0..18).await;
     }
 
+    /// decode with decode_ranges_stream, and check the ranges written to the target
+    ///
+    /// if `corrupt` is true, the last byte of the encoding is flipped, so decoding
+    /// must end with an error.
+    async fn decode_ranges_stream_impl(data: Vec<u8>, range: Range<u64>, corrupt: bool) {
+        use futures::StreamExt;
+        let range = ChunkNum(range.start)..ChunkNum(range.end);
+        let (mut encoded, root) = encode_slice_reference(&data, range.clone());
+        if corrupt {
+            *encoded.last_mut().unwrap() ^= 1;
+        }
+        let mut target = Cursor::new(Vec::new());
+        let ranges = ChunkRanges::from(range);
+        let items = decode_ranges_stream(
+            root,
+            BlockSize::ZERO,
+            ranges,
+            Cursor::new(encoded),
+            &mut target,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        let target = target.into_inner();
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Ok(range) => {
+                    let range = range.start.to_usize()..range.end.to_usize();
+                    assert_eq!(target[range.clone()], data[range]);
+                }
+                Err(_) => {
+                    assert!(corrupt);
+                    assert_eq!(i, items.len() - 1);
+                }
+            }
+        }
+        assert_eq!(corrupt, matches!(items.last(), Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn decode_ranges_stream_cases() {
+        use make_test_data as td;
+        for corrupt in [false, true] {
+            decode_ranges_stream_impl(td(1), 0..1, corrupt).await;
+            decode_ranges_stream_impl(td(1025), 0..2, corrupt).await;
+            decode_ranges_stream_impl(td(24 * 1024 + 1), 0..25, corrupt).await;
+            decode_ranges_stream_impl(td(24 * 1024 + 1), 3..7, corrupt).await;
+        }
+    }
+
+    /// decode with decode_ranges_tokio through a duplex pipe
+    ///
+    /// The writer is kept open until the stream is done, so this would hang if the
+    /// decoder tried to read past the end of the response. If `corrupt` is true, the
+    /// last byte of the encoding is flipped, so decoding must end with an error.
+    async fn decode_ranges_tokio_impl(
+        size: usize,
+        ranges: ChunkRanges,
+        block_size: BlockSize,
+        corrupt: bool,
+    ) {
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+        let data = make_test_data(size);
+        let outboard = PostOrderMemOutboard::create(&data, block_size);
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+        if corrupt {
+            *encoded.last_mut().unwrap() ^= 1;
+        }
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let write = async move {
+            writer.write_all(&encoded).await.unwrap();
+            writer
+        };
+        let items =
+            decode_ranges_tokio(outboard.root, block_size, ranges, reader).collect::<Vec<_>>();
+        let (_writer, items) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            tokio::join!(write, items)
+        })
+        .await
+        .unwrap();
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Ok((offset, leaf)) => {
+                    let start = offset.to_usize();
+                    assert_eq!(leaf[..], data[start..start + leaf.len()]);
+                }
+                Err(_) => {
+                    assert!(corrupt);
+                    assert_eq!(i, items.len() - 1);
+                }
+            }
+        }
+        assert_eq!(corrupt, matches!(items.last(), Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn decode_ranges_tokio_cases() {
+        let cases = [
+            (1, ChunkRanges::all(), 0),
+            (1024 * 16 + 1, ChunkRanges::all(), 0),
+            (
+                1024 * 16 + 1,
+                ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+                2,
+            ),
+            (100000, ChunkRanges::from(ChunkNum(50)..), 1),
+        ];
+        for (size, ranges, block_level) in cases {
+            for corrupt in [false, true] {
+                decode_ranges_tokio_impl(size, ranges.clone(), BlockSize(block_level), corrupt)
+                    .await;
+            }
+        }
+    }
+
+    /// decode with decode_ranges_stream directly into a tokio file
+    async fn decode_ranges_stream_file_impl(
+        size: usize,
+        ranges: ChunkRanges,
+        block_size: BlockSize,
+    ) {
+        use futures::StreamExt;
+        let data = make_test_data(size);
+        let outboard = PostOrderMemOutboard::create(&data, block_size);
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        let items = decode_ranges_stream(
+            outboard.root,
+            block_size,
+            ranges.clone(),
+            Cursor::new(encoded),
+            file,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        let written = std::fs::read(&path).unwrap();
+        let mut ranges_written = ChunkRanges::empty();
+        for item in items {
+            let range = item.unwrap();
+            let (start, end) = (range.start.to_usize(), range.end.to_usize());
+            assert_eq!(written[start..end], data[start..end]);
+            ranges_written |= ChunkRanges::from(range.start.full_chunks()..range.end.chunks());
+        }
+        let covered = crate::io::covered_chunk_ranges(size as u64, &ranges, block_size);
+        assert_eq!(ranges_written, covered);
+    }
+
+    #[tokio::test]
+    async fn decode_ranges_stream_file_cases() {
+        let cases = [
+            (1, ChunkRanges::all(), 0),
+            (100000, ChunkRanges::all(), 2),
+            (
+                100000,
+                {
+                    let mut ranges = ChunkRanges::from(ChunkNum(3)..ChunkNum(5));
+                    ranges |= ChunkRanges::from(ChunkNum(40)..);
+                    ranges
+                },
+                1,
+            ),
+        ];
+        for (size, ranges, block_level) in cases {
+            decode_ranges_stream_file_impl(size, ranges, BlockSize(block_level)).await;
+        }
+    }
+
+    /// encode with encode_ranges_tokio through a duplex pipe, and compare with the sync encoder
+    async fn encode_ranges_tokio_impl(size: usize, ranges: ChunkRanges, block_size: BlockSize) {
+        use tokio::io::AsyncReadExt;
+        let data = make_test_data(size);
+        let outboard = PostOrderMemOutboard::create(&data, block_size);
+        let mut expected = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut expected).unwrap();
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let mut outboard = outboard;
+        let encode = async move {
+            encode_ranges_tokio(Cursor::new(&data), &mut outboard, &ranges, writer).await
+        };
+        let mut actual = Vec::new();
+        let (res, read) = tokio::join!(encode, reader.read_to_end(&mut actual));
+        res.unwrap();
+        read.unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn encode_ranges_tokio_cases() {
+        let cases = [
+            (0, ChunkRanges::all(), 0),
+            (1024 * 16 + 1, ChunkRanges::all(), 0),
+            (
+                1024 * 16 + 1,
+                ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+                2,
+            ),
+            (1024 * 1024 * 3, ChunkRanges::from(ChunkNum(100)..), 1),
+        ];
+        for (size, ranges, block_level) in cases {
+            encode_ranges_tokio_impl(size, ranges, BlockSize(block_level)).await;
+        }
+    }
+
+    /// An outboard that has none of the hash pairs
+    struct NoPairsOutboard(BaoTree);
+
+    impl crate::io::fsm::Outboard for NoPairsOutboard {
+        fn root(&self) -> blake3::Hash {
+            blake3::Hash::from([0; 32])
+        }
+        fn tree(&self) -> BaoTree {
+            self.0
+        }
+        type LoadFuture<'a> =
+            futures::future::Ready<std::io::Result<Option<(blake3::Hash, blake3::Hash)>>>;
+        fn load(&mut self, _node: TreeNode) -> Self::LoadFuture<'_> {
+            futures::future::ok(None)
+        }
+    }
+
+    /// encode_ranges_tokio reports a wrong outboard size or a missing hash pair as an error
+    #[tokio::test]
+    async fn encode_ranges_tokio_errors() {
+        use crate::io::EncodeError;
+        let data = make_test_data(1024 * 64 + 17);
+        let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+        let expected = outboard.data.len() as u64 + 8;
+        let mut too_short = PostOrderMemOutboard {
+            root: outboard.root,
+            tree: outboard.tree,
+            data: outboard.data[..outboard.data.len() - 64].to_vec(),
+        };
+        let res = encode_ranges_tokio(
+            Cursor::new(&data),
+            &mut too_short,
+            &ChunkRanges::all(),
+            tokio::io::sink(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(EncodeError::OutboardSizeMismatch { expected: e, actual: a })
+                if e == expected && a == expected - 64
+        ));
+        let tree = outboard.tree;
+        let res = encode_ranges_tokio(
+            Cursor::new(&data),
+            NoPairsOutboard(tree),
+            &ChunkRanges::all(),
+            tokio::io::sink(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(EncodeError::MissingHashPair(node)) if node == tree.root()
+        ));
+    }
+
     proptest! {
         #[test]
         fn bao_tree_decode_slice_all_stream(len in 0..32768usize) {
@@ -727,7 +1101,7 @@ fn encode_single_chunk_large() {
     let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
 
     // encode the given ranges
-    let get_encoded = |ranges| {
+    let get_encoded = |ranges: &ChunkRanges| {
         let mut actual_encoded = Vec::new();
         crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut actual_encoded)
             .unwrap();
@@ -917,6 +1291,18 @@ fn encode_last_chunk_cases() {
 
 proptest! {
 
+    /// Check that encoding and decoding at block size 0 is interoperable with the bao crate
+    #[test]
+    fn bao_interop_proptest((size, start) in (1..100000usize).prop_flat_map(|size| (Just(size), 0..size as u64)), len in 1..10000u64) {
+        bao_interop_impl(size, start, len);
+    }
+
+    /// Check that the combined encoding at block size 0 is the same as for the bao crate
+    #[test]
+    fn bao_combined_proptest(size in 0..100000usize) {
+        bao_combined_impl(size);
+    }
+
     #[test]
     fn node_from_chunk_and_level(block in 0..100000u64, level in 0u8..8u8) {
         let chunk = block << (level + 1);
@@ -996,7 +1382,7 @@ proptest! {
         let post1 = post_order_outboard_reference(&data);
         let post2 = post_order_outboard_reference_2(&data);
         prop_assert_eq!(&post1, &post2);
-        prop_assert_eq!(&post1, &post1.flip().flip());
+        prop_assert_eq!(&post1, &post1.flip().unwrap().flip().unwrap());
     }
 
 
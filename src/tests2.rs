@@ -14,7 +14,8 @@ use smallvec::SmallVec;
 use std::ops::Range;
 use test_strategy::proptest;
 
-use crate::io::outboard::PreOrderMemOutboard;
+use crate::io::growable::GrowableBlob;
+use crate::io::outboard::{downsample_outboard, refine_outboard, PreOrderMemOutboard};
 use crate::rec::{
     get_leaf_ranges, make_test_data, partial_chunk_iter_reference, range_union,
     response_iter_reference, truncate_ranges, ReferencePreOrderPartialChunkIterRef,
@@ -24,13 +25,15 @@ use crate::{
     blake3, hash_subtree,
     io::{
         fsm::{BaoContentItem, ResponseDecoderReadingNext},
-        outboard::PostOrderMemOutboard,
+        outboard::{parse_hash_pair, PostOrderMemOutboard},
+        push::SliceDecoder,
         sync::{DecodeResponseItem, Outboard},
-        Header, Leaf, Parent,
+        AnyDecodeError, DecodeError, EncodeError, Header, Leaf, LeafMismatch, Parent,
+        ParentMismatch, StartDecodeError,
     },
     iter::{BaoChunk, PreOrderPartialChunkIterRef, ResponseIterRef},
     rec::{encode_selected_rec, select_nodes_rec},
-    BaoTree, BlockSize, ByteNum, ChunkNum, TreeNode,
+    BaoTree, BlockNum, BlockSize, ByteNum, ChunkNum, TreeNode,
 };
 
 fn tree() -> impl Strategy<Value = BaoTree> {
@@ -224,9 +227,9 @@ fn mem_outboard_flip_impl(tree: BaoTree) {
     let data = make_test_data(tree.size.to_usize());
     let post = PostOrderMemOutboard::create(&data, tree.block_size);
     let pre = PreOrderMemOutboard::create(data, tree.block_size);
-    assert_eq!(post, pre.flip());
-    assert_eq!(pre, post.flip());
-    assert_eq!(post, post.flip().flip());
+    assert_eq!(post, pre.flip().unwrap());
+    assert_eq!(pre, post.flip().unwrap());
+    assert_eq!(post, post.flip().unwrap().flip().unwrap());
 }
 
 #[proptest]
@@ -234,6 +237,127 @@ fn mem_outboard_flip_proptest(#[strategy(tree())] tree: BaoTree) {
     mem_outboard_flip_impl(tree);
 }
 
+/// The data of the in memory outboards is public, so it can have the wrong size.
+/// Accessing a missing pair must be an error, not a panic.
+#[test]
+fn mem_outboard_truncated() {
+    let data = make_test_data(100000);
+    let mut post = PostOrderMemOutboard::create(&data, BlockSize(4));
+    let mut pre = PreOrderMemOutboard::create(&data, BlockSize(4));
+    let root = post.tree().root();
+    post.data.truncate(post.data.len() - 64);
+    pre.data.truncate(pre.data.len() - 64);
+    let invalid = std::io::ErrorKind::InvalidData;
+    assert_eq!(post.flip().unwrap_err().kind(), invalid);
+    assert_eq!(post.pairs().unwrap_err().kind(), invalid);
+    assert_eq!(pre.flip().unwrap_err().kind(), invalid);
+    // the root pair is the last pair in post order
+    assert_eq!(post.load(root).unwrap_err().kind(), invalid);
+    let pair = (blake3::Hash::from([0; 32]), blake3::Hash::from([0; 32]));
+    let res = crate::io::sync::OutboardMut::save(&mut post, root, &pair);
+    assert_eq!(res.unwrap_err().kind(), invalid);
+}
+
+/// Check that downsampling a fine outboard gives the same result as computing
+/// the coarse outboard from the data.
+fn downsample_outboard_impl(tree: BaoTree, extra: u8) -> (Vec<u8>, Vec<u8>) {
+    let data = make_test_data(tree.size.to_usize());
+    let to = BlockSize(tree.block_size.0 + 1 + extra);
+    let fine = PostOrderMemOutboard::create(&data, tree.block_size);
+    let expected = PostOrderMemOutboard::create(&data, to).data;
+    let actual = downsample_outboard(&fine.data, tree.size.0, tree.block_size, to).unwrap();
+    (expected, actual)
+}
+
+#[test]
+fn downsample_outboard_cases() {
+    let cases = [(0, 0, 0), (1024, 0, 0), (0x4001, 0, 3), (0x10000, 0, 3)];
+    for (size, block_level, extra) in cases {
+        let tree = BaoTree::new(ByteNum(size), BlockSize(block_level));
+        assert_tuple_eq!(downsample_outboard_impl(tree, extra));
+    }
+    // downsampling to the same or a smaller block size is an error
+    assert!(downsample_outboard(&[], 0, BlockSize(1), BlockSize(1)).is_err());
+    assert!(downsample_outboard(&[], 0, BlockSize(1), BlockSize(0)).is_err());
+}
+
+#[proptest]
+fn downsample_outboard_proptest(#[strategy(tree())] tree: BaoTree, #[strategy(0u8..3)] extra: u8) {
+    prop_assert_tuple_eq!(downsample_outboard_impl(tree, extra));
+}
+
+/// Check that refining a coarse outboard gives the same result as computing
+/// the fine outboard from the data.
+fn refine_outboard_impl(tree: BaoTree, extra: u8) -> (Vec<u8>, Vec<u8>) {
+    let data = make_test_data(tree.size.to_usize());
+    let from = BlockSize(tree.block_size.0 + 1 + extra);
+    let coarse = PostOrderMemOutboard::create(&data, from);
+    let expected = PostOrderMemOutboard::create(&data, tree.block_size).data;
+    let actual = refine_outboard(
+        &coarse.data,
+        data.as_slice(),
+        tree.size.0,
+        from,
+        tree.block_size,
+    )
+    .unwrap();
+    (expected, actual)
+}
+
+/// Check that refining a corrupted coarse outboard fails with a data error.
+fn refine_outboard_neg_impl(tree: BaoTree, rand: u32) {
+    let data = make_test_data(tree.size.to_usize());
+    let from = BlockSize(tree.block_size.0 + 1);
+    let mut coarse = PostOrderMemOutboard::create(&data, from);
+    if !coarse.data.is_empty() {
+        flip_bit(&mut coarse.data, rand as usize);
+        let err = refine_outboard(
+            &coarse.data,
+            data.as_slice(),
+            tree.size.0,
+            from,
+            tree.block_size,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn refine_outboard_cases() {
+    let cases = [(0, 0, 0), (1024, 0, 0), (0x4001, 0, 3), (0x10000, 0, 3)];
+    for (size, block_level, extra) in cases {
+        let tree = BaoTree::new(ByteNum(size), BlockSize(block_level));
+        assert_tuple_eq!(refine_outboard_impl(tree, extra));
+        refine_outboard_neg_impl(tree, 12345);
+    }
+    // refining to the same or a larger block size is an error
+    assert!(refine_outboard(&[], std::io::empty(), 0, BlockSize(1), BlockSize(1)).is_err());
+    assert!(refine_outboard(&[], std::io::empty(), 0, BlockSize(0), BlockSize(1)).is_err());
+    // data that is longer than the size is an error
+    let data = make_test_data(5000);
+    let coarse = PostOrderMemOutboard::create(&data[..4999], BlockSize(1));
+    let err = refine_outboard(
+        &coarse.data,
+        data.as_slice(),
+        4999,
+        BlockSize(1),
+        BlockSize(0),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[proptest]
+fn refine_outboard_proptest(#[strategy(tree())] tree: BaoTree, #[strategy(0u8..3)] extra: u8) {
+    prop_assert_tuple_eq!(refine_outboard_impl(tree, extra));
+}
+
+#[proptest]
+fn refine_outboard_neg_proptest(#[strategy(tree())] tree: BaoTree, rand: u32) {
+    refine_outboard_neg_impl(tree, rand);
+}
+
 /// range is a range of chunks. Just using u64 for convenience in tests
 fn valid_ranges_sync(outboard: &PostOrderMemOutboard) -> ChunkRanges {
     crate::io::sync::valid_ranges(outboard).unwrap()
@@ -353,7 +477,7 @@ fn encode_decode_full_sync_impl(
         .unwrap();
     let mut encoded_read = std::io::Cursor::new(encoded);
     let mut decoded = Vec::new();
-    let ob_res_opt = crate::io::sync::decode_response_into(
+    let (size, ob_res_opt) = crate::io::sync::decode_response_into(
         outboard.root(),
         outboard.tree().block_size,
         &ranges,
@@ -366,6 +490,7 @@ fn encode_decode_full_sync_impl(
         &mut decoded,
     )
     .unwrap();
+    assert_eq!(size, outboard.tree().size());
     let ob_res = ob_res_opt.unwrap_or_else(|| {
         PostOrderMemOutboard::new(outboard.root(), outboard.tree(), vec![]).unwrap()
     });
@@ -396,7 +521,7 @@ async fn encode_decode_full_fsm_impl(
 
     let mut read_encoded = std::io::Cursor::new(encoded);
     let mut decoded = BytesMut::new();
-    let ob_res_opt = crate::io::fsm::decode_response_into(
+    let (size, ob_res_opt) = crate::io::fsm::decode_response_into(
         outboard.root(),
         outboard.tree().block_size,
         ranges,
@@ -410,6 +535,7 @@ async fn encode_decode_full_fsm_impl(
     )
     .await
     .unwrap();
+    assert_eq!(size, outboard.tree().size());
     let ob_res = ob_res_opt.unwrap_or_else(|| {
         PostOrderMemOutboard::new(outboard.root(), outboard.tree(), vec![]).unwrap()
     });
@@ -875,3 +1001,5071 @@ fn canonicalize_ranges_test() {
         assert_eq!(expected, actual);
     }
 }
+
+/// Append data in pieces of varying size on one thread, while taking snapshots and
+/// encoding from them on another thread.
+///
+/// Every snapshot must be identical to an outboard computed from scratch for the
+/// data it covers, even though the data keeps growing while it is being encoded.
+#[test]
+fn growable_blob_concurrent_append_snapshot() {
+    let data = make_test_data(1024 * 64 + 12345);
+    for block_size in [BlockSize(0), BlockSize(2)] {
+        let blob = GrowableBlob::new(block_size);
+        let written = std::sync::RwLock::new(Vec::new());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut rest = &data[..];
+                let mut n = 1;
+                while !rest.is_empty() {
+                    let (piece, tail) = rest.split_at(n.min(rest.len()));
+                    // the data must be available before it is appended to the blob
+                    written.write().unwrap().extend_from_slice(piece);
+                    blob.append(piece);
+                    rest = tail;
+                    n = n * 7 % 5000 + 1;
+                }
+            });
+            s.spawn(|| loop {
+                let snapshot = blob.snapshot();
+                let size = snapshot.tree().size.to_usize();
+                // this may already contain more data than the snapshot covers
+                let current = written.read().unwrap().clone();
+                let expected = PostOrderMemOutboard::create(&current[..size], block_size);
+                assert_eq!(snapshot.root(), expected.root());
+                let mut actual_encoded = Vec::new();
+                snapshot
+                    .encode_ranges(&current, &ChunkRanges::all(), &mut actual_encoded)
+                    .unwrap();
+                let mut expected_encoded = Vec::new();
+                crate::io::sync::encode_ranges_validated(
+                    &current[..size],
+                    &expected,
+                    &ChunkRanges::all(),
+                    &mut expected_encoded,
+                )
+                .unwrap();
+                assert_eq!(actual_encoded, expected_encoded);
+                if size == data.len() {
+                    break;
+                }
+            });
+        });
+    }
+}
+
+/// Check that verify_ranges accepts a valid encoding, and rejects it after
+/// flipping a random bit after the header.
+fn verify_ranges_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize, rand: u32) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut encoded).unwrap();
+    crate::io::sync::verify_ranges(outboard.root(), block_size, ranges, encoded.as_slice())
+        .unwrap();
+    if encoded.len() > 8 {
+        flip_bit(&mut encoded[8..], rand as usize);
+        let res =
+            crate::io::sync::verify_ranges(outboard.root(), block_size, ranges, encoded.as_slice());
+        assert!(res.is_err());
+    }
+}
+
+#[test]
+fn verify_ranges_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        verify_ranges_impl(size, &ranges, BlockSize(block_level), 12345);
+    }
+}
+
+#[proptest]
+fn verify_ranges_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    rand: u32,
+) {
+    let (size, ranges) = size_and_selection;
+    verify_ranges_impl(size, &ranges, block_size, rand);
+}
+
+/// Check that every block of a tree verifies against the outboard, both on its own
+/// and together with its sibling, and that a corrupted block is rejected.
+fn verify_block_impl(tree: BaoTree) {
+    use crate::io::{
+        sync::{verify_block, verify_block_with_sibling},
+        BlockVerifyError,
+    };
+    let data = make_test_data(tree.size.to_usize());
+    let outboard = PostOrderMemOutboard::create(&data, tree.block_size);
+    let block_data = |block: BlockNum| {
+        let start = block.to_bytes(tree.block_size).to_usize().min(data.len());
+        let end = (start + tree.block_size.bytes()).min(data.len());
+        &data[start..end]
+    };
+    let blocks = tree.blocks();
+    for block in (0..blocks.0).map(BlockNum) {
+        let sibling_block = BlockNum(block.0 ^ 1);
+        let sibling: &[u8] = if sibling_block < blocks {
+            block_data(sibling_block)
+        } else {
+            // the last block is the left half of an unpersisted leaf
+            &[]
+        };
+        let mut buf = block_data(block).to_vec();
+        verify_block(&tree, &outboard, block, &buf).unwrap();
+        verify_block_with_sibling(&tree, &outboard, block, &buf, sibling).unwrap();
+        if !buf.is_empty() {
+            flip_bit(&mut buf, block.to_usize());
+            assert!(matches!(
+                verify_block(&tree, &outboard, block, &buf),
+                Err(BlockVerifyError::HashMismatch(b)) if b == block
+            ));
+            assert!(matches!(
+                verify_block_with_sibling(&tree, &outboard, block, &buf, sibling),
+                Err(BlockVerifyError::HashMismatch(b)) if b == block
+            ));
+        }
+    }
+    assert!(matches!(
+        verify_block(&tree, &outboard, blocks, &[]),
+        Err(BlockVerifyError::BlockOutOfRange(_))
+    ));
+}
+
+#[test]
+fn verify_block_cases() {
+    for block_level in 0..3 {
+        let block_size = BlockSize(block_level);
+        let block_bytes = block_size.bytes() as u64;
+        // all block positions of small trees, with and without a half full last leaf
+        for blocks in 0..9 {
+            for extra in [0, 1, block_bytes - 1] {
+                let size = blocks * block_bytes + extra;
+                verify_block_impl(BaoTree::new(ByteNum(size), block_size));
+            }
+        }
+    }
+}
+
+#[proptest]
+fn verify_block_proptest(#[strategy(tree())] tree: BaoTree) {
+    verify_block_impl(tree);
+}
+
+/// Check that computing outboards into a reused buffer gives the same result as
+/// computing them into a fresh one.
+#[test]
+fn outboard_post_order_into_reuse() {
+    use crate::io::sync::{outboard_post_order, OutboardBuilder};
+    let mut out = Vec::new();
+    let mut buffer = Vec::new();
+    for block_size in [BlockSize(0), BlockSize(4)] {
+        // go from large to small, so there is stale data in the buffers
+        for size in [100000, 0x4001, 1024, 1, 0] {
+            let data = make_test_data(size);
+            let mut expected = Vec::new();
+            let expected_hash =
+                outboard_post_order(data.as_slice(), size as u64, block_size, &mut expected)
+                    .unwrap();
+            let hash = OutboardBuilder::new(block_size)
+                .buffer(&mut buffer)
+                .write_into(data.as_slice(), size as u64, &mut out)
+                .unwrap();
+            assert_eq!(hash, expected_hash);
+            assert_eq!(out, expected);
+            let hash = OutboardBuilder::new(block_size)
+                .write_into(data.as_slice(), size as u64, &mut out)
+                .unwrap();
+            assert_eq!(hash, expected_hash);
+            assert_eq!(out, expected);
+        }
+    }
+}
+
+/// Check that the pre and post order outboard sizes match the actual outboards.
+///
+/// The number of hash pairs is the same for both layouts, so the sizes must be equal.
+fn outboard_size_impl(tree: BaoTree) {
+    use crate::io::{outboard_size, outboard_size_pre_order};
+    let data = make_test_data(tree.size.to_usize());
+    let post = PostOrderMemOutboard::create(&data, tree.block_size).into_inner_with_suffix();
+    let pre = PreOrderMemOutboard::create(&data, tree.block_size).into_inner_with_prefix();
+    let post_size = outboard_size(tree.size.0, tree.block_size);
+    let pre_size = outboard_size_pre_order(tree.size.0, tree.block_size);
+    assert_eq!(post_size, post.len() as u64);
+    assert_eq!(pre_size, pre.len() as u64);
+    assert_eq!(pre_size, post_size);
+}
+
+#[test]
+fn outboard_size_cases() {
+    let cases = [(0, 0), (1024, 0), (1025, 0), (0x4001, 2), (0x10000, 4)];
+    for (size, block_level) in cases {
+        outboard_size_impl(BaoTree::new(ByteNum(size), BlockSize(block_level)));
+    }
+}
+
+#[proptest]
+fn outboard_size_proptest(#[strategy(tree())] tree: BaoTree) {
+    outboard_size_impl(tree);
+}
+
+/// Check that the streaming flip gives the same result as flipping in memory.
+fn flip_pre_order_outboard_impl(tree: BaoTree) -> (Vec<u8>, Vec<u8>) {
+    use crate::io::outboard::flip_pre_order_outboard;
+    let data = make_test_data(tree.size.to_usize());
+    let pre = PreOrderMemOutboard::create(&data, tree.block_size);
+    let expected = pre.flip().unwrap().into_inner_with_suffix();
+    let pre = pre.into_inner_with_prefix();
+    let mut actual = Vec::new();
+    let actual_tree = flip_pre_order_outboard(&pre, tree.block_size, &mut actual).unwrap();
+    assert_eq!(actual_tree, tree);
+    (expected, actual)
+}
+
+#[test]
+fn flip_pre_order_outboard_cases() {
+    let cases = [(0, 0), (1024, 0), (1025, 0), (0x4001, 2), (0x10000, 4)];
+    for (size, block_level) in cases {
+        let tree = BaoTree::new(ByteNum(size), BlockSize(block_level));
+        assert_tuple_eq!(flip_pre_order_outboard_impl(tree));
+    }
+}
+
+#[proptest]
+fn flip_pre_order_outboard_proptest(#[strategy(tree())] tree: BaoTree) {
+    prop_assert_tuple_eq!(flip_pre_order_outboard_impl(tree));
+}
+
+/// Migrate a few outboard files, with a wrong root for one of them, and check the
+/// files, the journal, and that an exhausted budget stops the migration.
+#[test]
+fn migrate_outboards_files() {
+    use crate::io::migrate::migrate_outboards;
+    use std::{collections::BTreeMap, path::Path, time::Duration};
+    let block_size = BlockSize(1);
+    let dir = tempfile::tempdir().unwrap();
+    let mut expected = BTreeMap::new();
+    let mut roots = BTreeMap::new();
+    for size in [0, 1024, 0x4001, 100000] {
+        let data = make_test_data(size);
+        let pre = PreOrderMemOutboard::create(&data, block_size);
+        let path = dir.path().join(format!("{size}.obao"));
+        std::fs::write(&path, pre.clone().into_inner_with_prefix()).unwrap();
+        expected.insert(path.clone(), pre.flip().unwrap().into_inner_with_suffix());
+        roots.insert(path, pre.root);
+    }
+    // a file with a wrong root must not be touched
+    let bad = dir.path().join(format!("{}.obao", 0x4001));
+    roots.insert(bad.clone(), blake3::hash(b"wrong"));
+    let bad_content = std::fs::read(&bad).unwrap();
+    let root = |path: &Path| roots.get(path).copied();
+
+    // no budget, so nothing must happen
+    let mut paths = expected.keys().cloned();
+    let mut journal = Vec::new();
+    let report = migrate_outboards(
+        paths.by_ref(),
+        block_size,
+        Duration::ZERO,
+        root,
+        &mut journal,
+    )
+    .unwrap();
+    assert!(report.budget_exhausted);
+    assert_eq!(report.migrated, 0);
+    assert!(journal.is_empty());
+
+    let report = migrate_outboards(paths, block_size, Duration::MAX, root, &mut journal).unwrap();
+    assert!(!report.budget_exhausted);
+    assert_eq!(report.migrated, 3);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, bad);
+    assert_eq!(std::fs::read(&bad).unwrap(), bad_content);
+    let journal = String::from_utf8(journal).unwrap();
+    for (path, expected) in &expected {
+        if path == &bad {
+            assert!(!journal.contains(&path.display().to_string()));
+        } else {
+            assert_eq!(&std::fs::read(path).unwrap(), expected);
+            assert!(journal.contains(&path.display().to_string()));
+        }
+    }
+    // no temporary files are left behind
+    assert_eq!(
+        std::fs::read_dir(dir.path()).unwrap().count(),
+        expected.len()
+    );
+}
+
+/// Check that the ranges computed by covered_chunk_ranges are exactly the ranges
+/// that are delivered when decoding a response.
+fn covered_chunk_ranges_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut delivered = ChunkRanges::empty();
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    for item in iter {
+        if let DecodeResponseItem::Leaf(Leaf { offset, data }) = item.unwrap() {
+            let end = offset + ByteNum(data.len() as u64);
+            delivered |= ChunkRanges::from(offset.full_chunks()..end.chunks());
+        }
+    }
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    assert_eq!(covered, delivered);
+}
+
+#[test]
+fn covered_chunk_ranges_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (0, ChunkRanges::empty(), 0),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (1024 * 16, ChunkRanges::from(ChunkNum(4)..ChunkNum(8)), 2),
+    ];
+    for (size, ranges, block_level) in cases {
+        covered_chunk_ranges_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn covered_chunk_ranges_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    covered_chunk_ranges_impl(size, &ranges, block_size);
+}
+
+/// Check that encoded_size and the count returned by encode_ranges_to are the
+/// exact size of the encoding
+fn encoded_size_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    let written =
+        crate::io::sync::encode_ranges_to(&data, &outboard, ranges, &mut encoded).unwrap();
+    assert_eq!(written, encoded.len() as u64);
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    assert_eq!(tree.encoded_size(ranges), ByteNum(encoded.len() as u64));
+    // the encoding is the header, the hash pairs and the data of the covered chunks
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    let mut data_bytes = 0;
+    for range in covered.iter() {
+        let range_collections::range_set::RangeSetRange::Range(range) = range else {
+            panic!("covered ranges are bounded");
+        };
+        let end = range.end.to_bytes().min(ByteNum(size as u64));
+        data_bytes += (end - range.start.to_bytes()).0;
+    }
+    assert_eq!(
+        8 + tree.encoded_hash_pairs(ranges) * 64 + data_bytes,
+        encoded.len() as u64
+    );
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    assert_eq!(encoded, expected);
+    // the number of leaves is the number of leaves the decoder yields
+    let leaves = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .filter(|item| matches!(item, Ok(DecodeResponseItem::Leaf(_))))
+    .count();
+    assert_eq!(tree.encoded_leaves(ranges), leaves as u64);
+}
+
+#[test]
+fn encoded_size_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (0, ChunkRanges::empty(), 0),
+        (1024 * 16 + 1, ChunkRanges::empty(), 2),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (
+            1024 * 16,
+            ChunkRanges::from(ChunkNum(100)..ChunkNum(200)),
+            0,
+        ),
+        (1000, ChunkRanges::from(ChunkNum(1)..), 0),
+    ];
+    for (size, ranges, block_level) in cases {
+        encoded_size_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encoded_size_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encoded_size_impl(size, &ranges, block_size);
+}
+
+/// Check that writing the items of an EncodeResponseIter gives the same bytes as
+/// encode_ranges
+fn encode_response_iter_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    use crate::io::sync::{EncodeResponseItem, EncodeResponseIter};
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    let items = EncodeResponseIter::new(outboard.tree(), ranges);
+    let mut encoded = Vec::new();
+    let mut out_buf = Vec::new();
+    for item in EncodeResponseIter::new(outboard.tree(), ranges) {
+        match item {
+            EncodeResponseItem::Header(size) => encoded.extend_from_slice(&size.0.to_le_bytes()),
+            EncodeResponseItem::Parent { node, .. } => {
+                let (l_hash, r_hash) = outboard.load(node).unwrap().unwrap();
+                encoded.extend_from_slice(l_hash.as_bytes());
+                encoded.extend_from_slice(r_hash.as_bytes());
+            }
+            EncodeResponseItem::Leaf {
+                start_chunk,
+                byte_range,
+                ranges,
+                ..
+            } => {
+                let leaf = &data[byte_range.start.to_usize()..byte_range.end.to_usize()];
+                encoded.extend_from_slice(items.leaf_bytes(
+                    start_chunk,
+                    leaf,
+                    ranges,
+                    &mut out_buf,
+                ));
+            }
+        }
+    }
+    assert_eq!(encoded, expected);
+    // without the header, this is the headerless encoding
+    let headerless = EncodeResponseIter::new(outboard.tree(), ranges).headerless();
+    assert_eq!(headerless.count() + 1, items.count());
+}
+
+#[test]
+fn encode_response_iter_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_response_iter_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_response_iter_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_response_iter_impl(size, &ranges, block_size);
+}
+
+/// Check that encode_ranges_checked produces the same output as encode_ranges for
+/// a consistent outboard, and detects an outboard in the wrong layout.
+fn encode_ranges_checked_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges_checked(&data, &outboard, ranges, &mut actual).unwrap();
+    assert_eq!(expected, actual);
+    // an outboard in pre order layout, read as post order
+    let pre = PreOrderMemOutboard::create(&data, block_size);
+    let wrong = PostOrderMemOutboard {
+        root: outboard.root,
+        tree: outboard.tree,
+        data: pre.data,
+    };
+    let mut encoded = Vec::new();
+    let res = crate::io::sync::encode_ranges_checked(&data, &wrong, ranges, &mut encoded);
+    if wrong.data == outboard.data {
+        // the layouts are the same for trees with less than 2 pairs
+        res.unwrap();
+    } else if !ranges.is_empty() {
+        // the root pair is always emitted, and is wrong
+        assert!(matches!(res, Err(EncodeError::InconsistentOutboard(_))));
+    }
+}
+
+#[test]
+fn encode_ranges_checked_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024 * 3, ChunkRanges::all(), 0),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            0,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (1024 * 64, ChunkRanges::from(ChunkNum(4)..ChunkNum(8)), 2),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_ranges_checked_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_checked_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_ranges_checked_impl(size, &ranges, block_size);
+}
+
+/// Check that the encode functions reject outboards of the wrong size with a typed
+/// error instead of panicking.
+#[test]
+fn encode_ranges_outboard_size_mismatch() {
+    let data = make_test_data(1024 * 64 + 17);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+    let expected = outboard.data.len() as u64 + 8;
+    let too_short = outboard.data[..outboard.data.len() - 64].to_vec();
+    let mut too_long = outboard.data.clone();
+    too_long.extend_from_slice(&[0u8; 64]);
+    // an outboard for block size 1 claiming to be for block size 2
+    let other = PostOrderMemOutboard::create(&data, BlockSize(1)).data;
+    for wrong in [too_short, too_long, other] {
+        let actual = wrong.len() as u64 + 8;
+        let wrong = PostOrderMemOutboard {
+            root: outboard.root,
+            tree: outboard.tree,
+            data: wrong,
+        };
+        let check = |res: std::result::Result<(), EncodeError>| {
+            assert!(matches!(
+                res,
+                Err(EncodeError::OutboardSizeMismatch { expected: e, actual: a })
+                    if e == expected && a == actual
+            ));
+        };
+        let ranges = ChunkRanges::all();
+        check(crate::io::sync::encode_ranges(
+            &data,
+            &wrong,
+            &ranges,
+            std::io::sink(),
+        ));
+        check(crate::io::sync::encode_ranges_checked(
+            &data,
+            &wrong,
+            &ranges,
+            std::io::sink(),
+        ));
+        check(crate::io::sync::encode_ranges_validated(
+            &data,
+            &wrong,
+            &ranges,
+            std::io::sink(),
+        ));
+    }
+}
+
+/// An outboard that has none of the hash pairs, like a partial outboard that does not
+/// cover the query
+struct NoPairsOutboard(PostOrderMemOutboard);
+
+impl Outboard for NoPairsOutboard {
+    fn root(&self) -> blake3::Hash {
+        self.0.root
+    }
+    fn tree(&self) -> BaoTree {
+        self.0.tree
+    }
+    fn load(&self, _node: TreeNode) -> std::io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+        Ok(None)
+    }
+}
+
+/// Check that the encode functions report a missing hash pair with a typed error
+/// instead of panicking.
+#[test]
+fn encode_ranges_missing_hash_pair() {
+    let data = make_test_data(1024 * 64 + 17);
+    let outboard = NoPairsOutboard(PostOrderMemOutboard::create(&data, BlockSize(2)));
+    let root = outboard.tree().root();
+    let check = |res: std::result::Result<(), EncodeError>| {
+        assert!(matches!(
+            res,
+            Err(EncodeError::MissingHashPair(node)) if node == root
+        ));
+    };
+    let ranges = ChunkRanges::all();
+    check(crate::io::sync::encode_ranges(
+        &data,
+        &outboard,
+        &ranges,
+        std::io::sink(),
+    ));
+    check(crate::io::sync::encode_ranges_io(
+        std::io::Cursor::new(&data),
+        &outboard,
+        &ranges,
+        std::io::sink(),
+    ));
+    check(crate::io::sync::encode_ranges_checked(
+        &data,
+        &outboard,
+        &ranges,
+        std::io::sink(),
+    ));
+    check(crate::io::sync::encode_ranges_validated(
+        &data,
+        &outboard,
+        &ranges,
+        std::io::sink(),
+    ));
+    let err = std::io::Error::from(EncodeError::MissingHashPair(root));
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+/// A reader that counts the number of seeks
+struct SeekCounter<R> {
+    inner: R,
+    seeks: usize,
+}
+
+impl<R: std::io::Read> std::io::Read for SeekCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: std::io::Seek> std::io::Seek for SeekCounter<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.seeks += 1;
+        self.inner.seek(pos)
+    }
+}
+
+/// Check that encode_ranges_io from a file gives the same result as encode_ranges,
+/// with at most one seek per leaf.
+#[test]
+fn encode_ranges_io_file() {
+    let size = 1024 * 1024 * 3 + 17;
+    let block_size = BlockSize(2);
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut file = tempfile::tempfile().unwrap();
+    std::io::Write::write_all(&mut file, &data).unwrap();
+    let mut scattered = ChunkRanges::from(ChunkNum(1)..ChunkNum(2));
+    scattered |= ChunkRanges::from(ChunkNum(100)..ChunkNum(107));
+    scattered |= ChunkRanges::from(ChunkNum(1000)..ChunkNum(1001));
+    scattered |= ChunkRanges::from(ChunkNum(3000)..);
+    let cases = [
+        ChunkRanges::all(),
+        ChunkRanges::empty(),
+        scattered,
+        ChunkRanges::from(ChunkNum(10000)..),
+    ];
+    for ranges in cases {
+        let mut expected = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut expected).unwrap();
+        let mut reader = SeekCounter {
+            inner: &mut file,
+            seeks: 0,
+        };
+        let mut actual = Vec::new();
+        crate::io::sync::encode_ranges_io(&mut reader, &outboard, &ranges, &mut actual).unwrap();
+        assert_eq!(expected, actual);
+        let leaves = outboard
+            .tree
+            .ranges_pre_order_chunks_iter_ref(&ranges, 0)
+            .filter(|item| matches!(item, BaoChunk::Leaf { .. }))
+            .count();
+        assert!(reader.seeks <= leaves);
+    }
+    // a file that is too short
+    file.set_len(size as u64 - 1).unwrap();
+    let res = crate::io::sync::encode_ranges_io(
+        &mut file,
+        &outboard,
+        &ChunkRanges::all(),
+        std::io::sink(),
+    );
+    assert!(
+        matches!(res, Err(EncodeError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+    );
+}
+
+/// Check that validate_outboard accepts a correct outboard, and finds the node of
+/// a corrupted hash pair.
+fn validate_outboard_impl(tree: BaoTree) {
+    let size = tree.size().to_usize();
+    let block_size = tree.block_size;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let root = outboard.root();
+    let mut ob = outboard.into_inner_with_suffix();
+    let res = crate::io::sync::validate_outboard(&data, &ob, block_size).unwrap();
+    assert_eq!(res, root);
+    // corrupt each pair in turn
+    for (i, node) in tree.post_order_nodes_iter().enumerate() {
+        let Some(offset) = tree.post_order_offset(node) else {
+            continue;
+        };
+        let byte = (offset.value() * 64) as usize + i % 64;
+        ob[byte] ^= 1;
+        let res = crate::io::sync::validate_outboard(&data, &ob, block_size);
+        assert!(
+            matches!(res, Err(DecodeError::ParentHashMismatch(m)) if m.node == node && m.expected != m.actual)
+        );
+        ob[byte] ^= 1;
+    }
+    // wrong size suffix
+    let n = ob.len();
+    ob[n - 8] ^= 1;
+    assert!(crate::io::sync::validate_outboard(&data, &ob, block_size).is_err());
+}
+
+#[test]
+fn validate_outboard_cases() {
+    let cases = [
+        (0, 0),
+        (1024, 0),
+        (1025, 0),
+        (1024 * 16 + 1, 2),
+        (100000, 1),
+    ];
+    for (size, block_level) in cases {
+        validate_outboard_impl(BaoTree::new(ByteNum(size), BlockSize(block_level)));
+    }
+}
+
+#[proptest]
+fn validate_outboard_proptest(#[strategy(tree())] tree: BaoTree) {
+    validate_outboard_impl(tree);
+}
+
+/// Encode with one block size and decode with another, configured independently.
+///
+/// If the block sizes are the same, decoding must succeed. Otherwise, decoding must
+/// either fail or produce exactly the requested data.
+fn block_size_interop_impl(
+    size: usize,
+    ranges: &ChunkRangesRef,
+    encode: BlockSize,
+    decode: BlockSize,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, encode);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut encoded).unwrap();
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        decode,
+        encoded.as_slice(),
+        ranges,
+    );
+    let mut failed = false;
+    for item in iter {
+        match item {
+            Ok(DecodeResponseItem::Leaf(Leaf { offset, data: leaf })) => {
+                let start = offset.to_usize();
+                assert_eq!(leaf[..], data[start..start + leaf.len()]);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                failed = true;
+                break;
+            }
+        }
+    }
+    if encode == decode {
+        assert!(!failed);
+    }
+}
+
+#[test]
+fn block_size_interop_cases() {
+    let cases = [
+        (1000, ChunkRanges::all()),
+        (1024 * 16 + 1, ChunkRanges::all()),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(3)..ChunkNum(5))),
+        (100000, ChunkRanges::from(ChunkNum(50)..)),
+    ];
+    for (size, ranges) in cases {
+        for encode in 0..4 {
+            for decode in 0..4 {
+                block_size_interop_impl(size, &ranges, BlockSize(encode), BlockSize(decode));
+            }
+        }
+    }
+}
+
+#[proptest]
+fn block_size_interop_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] encode: BlockSize,
+    #[strategy(block_size())] decode: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    block_size_interop_impl(size, &ranges, encode, decode);
+}
+
+/// Announce the block size in front of the stream, and decode with a decoder that is
+/// configured independently.
+///
+/// If the block sizes are the same, decoding must succeed. Otherwise, reading the
+/// block size must fail with an explicit mismatch, for both the sync and fsm api.
+fn block_size_header_impl(
+    size: usize,
+    ranges: &ChunkRangesRef,
+    encode: BlockSize,
+    decode: BlockSize,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, encode);
+    let mut encoded = Vec::new();
+    crate::io::sync::write_block_size(encode, &mut encoded).unwrap();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut reader = encoded.as_slice();
+    let res = crate::io::sync::read_block_size(&mut reader, decode);
+    let mut fsm_reader = std::io::Cursor::new(encoded.clone());
+    let fsm_res =
+        futures::executor::block_on(crate::io::fsm::read_block_size(&mut fsm_reader, decode));
+    let mut fsm_encoded = Vec::new();
+    futures::executor::block_on(crate::io::fsm::write_block_size(encode, &mut fsm_encoded))
+        .unwrap();
+    assert_eq!(fsm_encoded[..], encoded[..1]);
+    if encode == decode {
+        res.unwrap();
+        fsm_res.unwrap();
+        let iter =
+            crate::io::sync::DecodeResponseIter::new(outboard.root(), decode, reader, ranges);
+        for item in iter {
+            item.unwrap();
+        }
+    } else {
+        for err in [res.unwrap_err(), fsm_res.unwrap_err()] {
+            assert!(matches!(
+                err,
+                StartDecodeError::BlockSizeMismatch { expected, actual }
+                    if expected == decode && actual == encode
+            ));
+        }
+    }
+}
+
+#[test]
+fn block_size_header_cases() {
+    let cases = [
+        (0, ChunkRanges::all()),
+        (1000, ChunkRanges::all()),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(3)..ChunkNum(5))),
+        (100000, ChunkRanges::from(ChunkNum(50)..)),
+    ];
+    for (size, ranges) in cases {
+        for encode in 0..4 {
+            for decode in 0..4 {
+                block_size_header_impl(size, &ranges, BlockSize(encode), BlockSize(decode));
+            }
+        }
+    }
+    // an empty stream means that the remote does not have the blob
+    let err = crate::io::sync::read_block_size(&[0u8; 0][..], BlockSize(4)).unwrap_err();
+    assert!(matches!(err, StartDecodeError::NotFound));
+    // a block size that can not be used is not written
+    let mut encoded = Vec::new();
+    assert!(
+        crate::io::sync::write_block_size(BlockSize(BlockSize::MAX.0 + 1), &mut encoded).is_err()
+    );
+    assert!(encoded.is_empty());
+}
+
+/// Check that the leaf ranges of all leaves are contiguous and cover the data.
+fn leaf_byte_ranges_impl(tree: BaoTree) {
+    let group = tree.chunk_group_chunks().0;
+    let mut end = ByteNum(0);
+    let mut chunks = ChunkNum(0);
+    let mut start_chunk = 0;
+    loop {
+        let leaf = TreeNode::from_start_chunk_and_level(ChunkNum(start_chunk), tree.block_size);
+        let Some((left, right)) = tree.leaf_byte_ranges(leaf) else {
+            break;
+        };
+        assert_eq!(left.start, end);
+        assert_eq!(left.end, right.start);
+        assert_eq!(right.is_empty(), !tree.is_persisted(leaf));
+        end = right.end;
+        let (left_chunks, right_chunks) = tree.leaf_chunk_ranges(leaf).unwrap();
+        assert_eq!(left_chunks.start, chunks);
+        chunks = if right_chunks.is_empty() {
+            left_chunks.end
+        } else {
+            right_chunks.end
+        };
+        start_chunk += group * 2;
+    }
+    assert_eq!(end, tree.size());
+    assert_eq!(chunks, tree.chunks());
+    // branch nodes are not leaves
+    if tree.blocks() > BlockNum(2) {
+        assert!(tree.leaf_byte_ranges(tree.root()).is_none());
+    }
+}
+
+#[test]
+fn leaf_byte_ranges_cases() {
+    let cases = [
+        (0, 0),
+        (1, 0),
+        (1024, 0),
+        (1025, 0),
+        (2049, 1),
+        (1024 * 16 + 1, 2),
+    ];
+    for (size, block_level) in cases {
+        leaf_byte_ranges_impl(BaoTree::new(ByteNum(size), BlockSize(block_level)));
+    }
+}
+
+#[proptest]
+fn leaf_byte_ranges_proptest(#[strategy(tree())] tree: BaoTree) {
+    leaf_byte_ranges_impl(tree);
+}
+
+/// Check that conversions between SimpleChunkRanges and ChunkRanges are lossless,
+/// and that the constructors reject boundaries that are not strictly increasing.
+fn simple_chunk_ranges_impl(ranges: ChunkRanges) {
+    use crate::ranges::{SimpleChunkRanges, SimpleChunkRangesRef};
+    let ranges_ref: &ChunkRangesRef = &ranges;
+    // conversions
+    let simple = SimpleChunkRanges::from(ranges.clone());
+    assert_eq!(simple, SimpleChunkRanges::from(ranges_ref));
+    assert_eq!(ChunkRanges::from(simple.clone()), ranges);
+    let simple_ref = SimpleChunkRangesRef::from(ranges_ref);
+    assert_eq!(simple_ref, simple.as_ranges_ref());
+    let back: &ChunkRangesRef = simple_ref.into();
+    assert_eq!(back, ranges_ref);
+    assert_eq!(simple_ref.is_empty(), ranges.is_empty());
+    assert_eq!(simple_ref.is_all(), ranges.is_all());
+    // constructors
+    let boundaries = ranges.boundaries().to_vec();
+    assert_eq!(
+        SimpleChunkRangesRef::new(&boundaries),
+        Some(simple.as_ranges_ref())
+    );
+    assert_eq!(SimpleChunkRanges::new(boundaries.clone()), Some(simple));
+    if boundaries.len() >= 2 {
+        let mut reversed = boundaries;
+        reversed.reverse();
+        assert!(SimpleChunkRangesRef::new(&reversed).is_none());
+        assert!(SimpleChunkRanges::new(reversed).is_none());
+    }
+}
+
+#[test]
+fn simple_chunk_ranges_cases() {
+    let cases = [
+        ChunkRanges::empty(),
+        ChunkRanges::all(),
+        ChunkRanges::from(ChunkNum(2)..ChunkNum(5)),
+        ChunkRanges::from(ChunkNum(2)..),
+    ];
+    for ranges in cases {
+        simple_chunk_ranges_impl(ranges);
+    }
+    let duplicate = vec![ChunkNum(1), ChunkNum(1)];
+    assert!(crate::ranges::SimpleChunkRanges::new(duplicate).is_none());
+}
+
+#[proptest]
+fn simple_chunk_ranges_proptest(#[strategy(selection(100000, 3))] ranges: ChunkRanges) {
+    simple_chunk_ranges_impl(ranges);
+}
+
+/// Check that encoding with the minimal range set type gives the same result as
+/// encoding with a ChunkRanges.
+#[test]
+fn encode_ranges_simple_chunk_ranges() {
+    use crate::ranges::SimpleChunkRanges;
+    let data = make_test_data(1024 * 16 + 12345);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+    let mut ranges = ChunkRanges::from(ChunkNum(3)..ChunkNum(9));
+    ranges |= ChunkRanges::from(ChunkNum(20)..);
+    let simple = SimpleChunkRanges::from(ranges.clone());
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &ranges, &mut expected).unwrap();
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &simple, &mut actual).unwrap();
+    assert_eq!(expected, actual);
+    crate::io::sync::verify_ranges(outboard.root(), BlockSize(2), &simple, actual.as_slice())
+        .unwrap();
+}
+
+#[test]
+fn block_size_max() {
+    let size = ByteNum(u64::MAX);
+    let tree = BaoTree::try_new(size, BlockSize::MAX).unwrap();
+    assert_eq!(tree.chunk_group_bytes(), ByteNum(1 << 30));
+    assert!(tree.blocks() > BlockNum(0));
+    assert!(BaoTree::try_new(size, BlockSize(BlockSize::MAX.0 + 1)).is_none());
+    assert!(BaoTree::try_new(size, BlockSize(55)).is_none());
+    assert_eq!(BlockSize::from_bytes(1 << 30), Some(BlockSize::MAX));
+    assert_eq!(BlockSize::from_bytes(1 << 31), None);
+}
+
+/// Check that a post order outboard read from a file loads the same pairs as the
+/// in memory outboard. The length is a suffix, so the pairs start at offset 0.
+fn post_order_outboard_load_impl(size: usize, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let mem = PostOrderMemOutboard::create(&data, block_size);
+    let tree = mem.tree();
+    let file = mem.clone().into_inner_with_suffix();
+    let outboard =
+        crate::io::outboard::PostOrderOutboard::new(mem.root(), block_size, &file).unwrap();
+    assert_eq!(outboard.tree(), tree);
+    for node in tree.post_order_nodes_iter() {
+        assert_eq!(outboard.load(node).unwrap(), mem.load(node).unwrap());
+    }
+}
+
+#[test]
+fn post_order_outboard_load_cases() {
+    let cases = [
+        (0, 0),
+        (1024, 0),
+        (2048, 0),
+        (1024 * 16 + 1, 0),
+        (1024 * 16 + 1, 2),
+        (100000, 1),
+        (100000, 4),
+    ];
+    for (size, block_level) in cases {
+        post_order_outboard_load_impl(size, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn post_order_outboard_load_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    post_order_outboard_load_impl(size, block_size);
+}
+
+/// Loading an outboard with a block size above [BlockSize::MAX] must fail with an
+/// error instead of panicking, since the block size comes from outside.
+#[test]
+fn outboard_load_block_size_too_large() {
+    let data = make_test_data(100000);
+    let block_size = BlockSize(BlockSize::MAX.0 + 1);
+    let post = PostOrderMemOutboard::create(&data, BlockSize(4));
+    let pre = PreOrderMemOutboard::create(&data, BlockSize(4));
+    let post_file = post.clone().into_inner_with_suffix();
+    let pre_file = pre.clone().into_inner_with_prefix();
+    let kind = |res: std::io::Result<()>| res.unwrap_err().kind();
+    let invalid = std::io::ErrorKind::InvalidInput;
+    assert_eq!(
+        kind(PostOrderMemOutboard::load(post.root(), &post_file, block_size).map(drop)),
+        invalid
+    );
+    assert_eq!(
+        kind(PreOrderMemOutboard::load(pre.root(), &pre_file, block_size).map(drop)),
+        invalid
+    );
+    assert_eq!(
+        kind(
+            crate::io::outboard::PostOrderOutboard::new(post.root(), block_size, &post_file)
+                .map(drop)
+        ),
+        invalid
+    );
+    assert_eq!(
+        kind(
+            crate::io::outboard::PreOrderOutboard::new(pre.root(), block_size, &pre_file).map(drop)
+        ),
+        invalid
+    );
+    let fine = post.data;
+    assert_eq!(
+        kind(downsample_outboard(&fine, 100000, BlockSize(4), block_size).map(drop)),
+        invalid
+    );
+}
+
+/// A reader that pretends to contain `size` zero bytes, except for an 8 byte length
+/// at `len_offset`, and records the positions of all reads.
+struct MockReader {
+    size: u64,
+    len_offset: u64,
+    len: u64,
+    reads: std::cell::RefCell<Vec<u64>>,
+}
+
+impl MockReader {
+    fn new(size: u64, len_offset: u64, len: u64) -> Self {
+        Self {
+            size,
+            len_offset,
+            len,
+            reads: Default::default(),
+        }
+    }
+}
+
+impl crate::io::sync::ReadAt for MockReader {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads.borrow_mut().push(pos);
+        let remaining = self.size.saturating_sub(pos);
+        let n = usize::try_from(remaining)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        buf[..n].fill(0);
+        if pos == self.len_offset {
+            let k = n.min(8);
+            buf[..k].copy_from_slice(&self.len.to_le_bytes()[..k]);
+        }
+        Ok(n)
+    }
+}
+
+impl crate::io::sync::Size for MockReader {
+    fn size(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(self.size))
+    }
+}
+
+/// Check that file based outboards for a 16 TiB blob at block size 0 work, with
+/// pair offsets beyond 2^32.
+#[test]
+fn large_outboard_offsets() {
+    use crate::io::outboard::{PostOrderOutboard, PreOrderOutboard};
+    let size = 1u64 << 44;
+    let block_size = BlockSize::ZERO;
+    let tree = BaoTree::new(ByteNum(size), block_size);
+    let pairs = tree.outboard_hash_pairs();
+    assert!(pairs > 1 << 32);
+    let outboard_size = crate::io::outboard_size(size, block_size);
+    assert_eq!(outboard_size, pairs * 64 + 8);
+    let root = blake3::Hash::from([0; 32]);
+    // the parent of the last two chunks is the last pair in pre order
+    let last = TreeNode((size >> 10) * 2 - 3);
+    let pre_offset = tree.pre_order_offset(last).unwrap();
+    assert!(pre_offset * 64 > 1 << 32);
+    let reader = MockReader::new(outboard_size, 0, size);
+    let outboard = PreOrderOutboard::new(root, block_size, reader).unwrap();
+    assert_eq!(outboard.tree(), tree);
+    outboard.load(last).unwrap().unwrap();
+    assert_eq!(
+        outboard.data.reads.borrow().last(),
+        Some(&(pre_offset * 64 + 8))
+    );
+    // the root is the last pair in post order
+    let post_offset = tree.post_order_offset(tree.root()).unwrap().value();
+    assert_eq!(post_offset, pairs - 1);
+    let reader = MockReader::new(outboard_size, outboard_size - 8, size);
+    let outboard = PostOrderOutboard::new(root, block_size, reader).unwrap();
+    assert_eq!(outboard.tree(), tree);
+    outboard.load(tree.root()).unwrap().unwrap();
+    assert_eq!(
+        outboard.data.reads.borrow().last(),
+        Some(&(post_offset * 64))
+    );
+    // encode the last chunk, without any data or outboard in memory
+    let data = MockReader::new(size, u64::MAX, 0);
+    let ranges = ChunkRanges::from(ChunkNum((size >> 10) - 1)..);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    assert_eq!(tree.encoded_size(&ranges), ByteNum(encoded.len() as u64));
+    assert_eq!(data.reads.borrow().last(), Some(&(size - 1024)));
+}
+
+/// A hasher that counts invocations of the primitives
+#[derive(Debug, Default)]
+struct CountingHasher {
+    chunks: std::cell::Cell<u64>,
+    parents: std::cell::Cell<u64>,
+}
+
+impl crate::hasher::Hasher for CountingHasher {
+    fn hash_chunk(&self, chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+        self.chunks.set(self.chunks.get() + 1);
+        crate::hasher::Blake3Hasher.hash_chunk(chunk, data, is_root)
+    }
+
+    fn parent(&self, left: &blake3::Hash, right: &blake3::Hash, is_root: bool) -> blake3::Hash {
+        self.parents.set(self.parents.get() + 1);
+        crate::hasher::Blake3Hasher.parent(left, right, is_root)
+    }
+}
+
+/// Check that a custom hasher gives the same results as the default, and that every
+/// chunk is hashed once and every parent is computed once.
+fn custom_hasher_impl(size: usize, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let chunks = ByteNum(size as u64).chunks().0.max(1);
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    let hasher = CountingHasher::default();
+    let mut outboard = Vec::new();
+    let root = crate::io::sync::OutboardBuilder::new(block_size)
+        .hasher(&hasher)
+        .write(data.as_slice(), size as u64, &mut outboard)
+        .unwrap();
+    assert_eq!(root, expected.root());
+    assert_eq!(outboard, expected.clone().into_inner_with_suffix());
+    assert_eq!(hasher.chunks.get(), chunks);
+    assert_eq!(hasher.parents.get(), chunks - 1);
+    // decode everything
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &expected, &ranges, &mut encoded).unwrap();
+    let hasher = CountingHasher::default();
+    let iter = crate::io::sync::DecodeResponseIter::new_with_hasher(
+        root,
+        block_size,
+        encoded.as_slice(),
+        &ranges,
+        BytesMut::new(),
+        &hasher,
+    );
+    for item in iter {
+        item.unwrap();
+    }
+    assert_eq!(hasher.chunks.get(), chunks);
+    assert_eq!(hasher.parents.get(), chunks - 1);
+    // all other encoders and decoders that take a hasher do the same work with it
+    let check = |run: &dyn Fn(&CountingHasher)| {
+        let hasher = CountingHasher::default();
+        run(&hasher);
+        assert_eq!(hasher.chunks.get(), chunks);
+        assert_eq!(hasher.parents.get(), chunks - 1);
+    };
+    check(&|hasher| {
+        let mut res = Vec::new();
+        crate::io::sync::encode_ranges_validated_with_hasher(
+            &data, &expected, &ranges, &mut res, hasher,
+        )
+        .unwrap();
+        assert_eq!(res, encoded);
+    });
+    check(&|hasher| {
+        let mut res = Vec::new();
+        futures::executor::block_on(crate::io::fsm::encode_ranges_validated_with_hasher(
+            Bytes::from(data.clone()),
+            &mut expected.clone(),
+            &ranges,
+            &mut res,
+            hasher,
+        ))
+        .unwrap();
+        assert_eq!(res, encoded);
+    });
+    check(&|hasher| {
+        let verified = crate::io::sync::validate_slice_with_hasher(
+            root,
+            block_size,
+            &ranges,
+            encoded.as_slice(),
+            hasher,
+        )
+        .unwrap();
+        let covered = crate::io::covered_chunk_ranges(size as u64, &ranges, block_size);
+        assert_eq!(verified, covered);
+    });
+    check(&|hasher| {
+        let mut res = Vec::new();
+        crate::io::sync::decode_combined_with_hasher(
+            root,
+            block_size,
+            encoded.as_slice(),
+            &mut res,
+            hasher,
+        )
+        .unwrap();
+        assert_eq!(res, data);
+    });
+    check(&|hasher| {
+        let mut verifier = crate::io::sync::Verifier::new_with_hasher(root, block_size, hasher);
+        verifier.feed_slice(encoded.as_slice(), &ranges).unwrap();
+    });
+    check(&|hasher| {
+        let mut decoder = SliceDecoder::new_with_hasher(root, block_size, ranges.clone(), hasher);
+        decoder.push(&encoded).unwrap();
+        decoder.finish().unwrap();
+    });
+    check(&|hasher| {
+        let start = crate::io::fsm::ResponseDecoderStart::new_with_hasher(
+            root,
+            ranges.clone(),
+            block_size,
+            encoded.as_slice(),
+            hasher,
+        );
+        let (mut reading, _) = futures::executor::block_on(start.next()).unwrap();
+        while let ResponseDecoderReadingNext::More((next, item)) =
+            futures::executor::block_on(reading.next())
+        {
+            item.unwrap();
+            reading = next;
+        }
+    });
+}
+
+#[test]
+fn custom_hasher_cases() {
+    let cases = [
+        (0, 0),
+        (1, 0),
+        (1024, 0),
+        (1025, 1),
+        (1024 * 16 + 1, 2),
+        (100000, 3),
+    ];
+    for (size, block_level) in cases {
+        custom_hasher_impl(size, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn custom_hasher_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    custom_hasher_impl(size, block_size);
+}
+
+/// Decode with a different block size than the one used for encoding.
+///
+/// Unless the blob fits into a single block for both block sizes, this must fail at
+/// the first node where the trees differ, before yielding any data. A decoder with a
+/// smaller block size fails at a parent, one with a larger block size at a leaf.
+fn block_size_mismatch_impl(size: usize, encode: BlockSize, decode: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, encode);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        decode,
+        encoded.as_slice(),
+        &ranges,
+    );
+    let mut error = None;
+    let mut leaves = 0;
+    for item in iter {
+        match item {
+            Ok(DecodeResponseItem::Leaf(leaf)) => {
+                // any data we get must be correct
+                let start = leaf.offset.to_usize();
+                assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+                leaves += 1;
+            }
+            Ok(_) => {}
+            Err(cause) => {
+                error = Some(cause);
+                break;
+            }
+        }
+    }
+    let single_block = size <= encode.bytes().min(decode.bytes());
+    if encode == decode || single_block {
+        assert!(error.is_none());
+        return;
+    }
+    assert_eq!(leaves, 0);
+    if decode < encode {
+        assert!(matches!(error, Some(AnyDecodeError::ParentHashMismatch(_))));
+    } else {
+        assert!(matches!(error, Some(AnyDecodeError::LeafHashMismatch(_))));
+    }
+}
+
+#[test]
+fn block_size_mismatch_cases() {
+    let cases = [
+        (0, 0, 4),
+        (1024, 0, 4),
+        (1025, 0, 4),
+        (1025, 4, 0),
+        (1024 * 16, 2, 3),
+        (1024 * 16 + 1, 2, 3),
+        (1024 * 16 + 1, 3, 2),
+        (100000, 1, 0),
+    ];
+    for (size, encode, decode) in cases {
+        block_size_mismatch_impl(size, BlockSize(encode), BlockSize(decode));
+    }
+}
+
+#[proptest]
+fn block_size_mismatch_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] encode: BlockSize,
+    #[strategy(block_size())] decode: BlockSize,
+) {
+    block_size_mismatch_impl(size, encode, decode);
+}
+
+/// A decoder with an unsupported block size must fail before reading, not panic
+#[test]
+fn decode_invalid_block_size() {
+    let data = make_test_data(10000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let block_size = BlockSize(BlockSize::MAX.0 + 1);
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        &ranges,
+    );
+    match iter.next() {
+        Some(Err(AnyDecodeError::Io(e))) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected an io error, got {other:?}"),
+    }
+    let res =
+        crate::io::sync::verify_ranges(outboard.root(), block_size, &ranges, encoded.as_slice());
+    assert!(matches!(res, Err(AnyDecodeError::Io(_))));
+}
+
+/// Check that all sync encoders produce the same bytes, also for chunk groups that
+/// are only partially requested
+fn encode_ranges_partial_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut expected).unwrap();
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut actual).unwrap();
+    assert_eq!(actual, expected);
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges_checked(&data, &outboard, ranges, &mut actual).unwrap();
+    assert_eq!(actual, expected);
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges_io(std::io::Cursor::new(&data), &outboard, ranges, &mut actual)
+        .unwrap();
+    assert_eq!(actual, expected);
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    assert_eq!(tree.encoded_size(ranges), ByteNum(expected.len() as u64));
+}
+
+#[test]
+fn encode_ranges_partial_cases() {
+    let cases = [
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(1)..ChunkNum(2)),
+            2,
+        ),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(3)..), 3),
+        (1024 * 3, ChunkRanges::from(ChunkNum(1)..ChunkNum(2)), 4),
+        (100000, ChunkRanges::from(ChunkNum(7)..ChunkNum(50)), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_ranges_partial_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_partial_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_ranges_partial_impl(size, &ranges, block_size);
+}
+
+/// Check that encoding without an outboard gives the same result as encoding with
+/// one, and that the result can be decoded.
+fn encode_ranges_from_data_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let (encoded, root) = crate::io::sync::encode_ranges_from_data(&data, ranges, block_size);
+    assert_eq!(root, blake3::hash(&data));
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    assert_eq!(encoded, expected);
+    let iter =
+        crate::io::sync::DecodeResponseIter::new(root, block_size, encoded.as_slice(), ranges);
+    for item in iter {
+        if let DecodeResponseItem::Leaf(leaf) = item.unwrap() {
+            let start = leaf.offset.to_usize();
+            assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+        }
+    }
+}
+
+#[test]
+fn encode_ranges_from_data_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::empty(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (100000, ChunkRanges::from(ChunkNum(7)..ChunkNum(50)), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_ranges_from_data_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_from_data_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_ranges_from_data_impl(size, &ranges, block_size);
+}
+
+/// Decode an arbitrary byte range, encoded using the covering chunk ranges
+fn decode_exact_bytes_impl(size: usize, range: Range<u64>, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = crate::io::round_up_to_chunks(&RangeSet2::from(range.clone()));
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let byte_range = ByteNum(range.start)..ByteNum(range.end);
+    let mut actual = Vec::new();
+    let summary = crate::io::sync::decode_exact_bytes(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        byte_range.clone(),
+        false,
+        &mut actual,
+    )
+    .unwrap();
+    let end = (range.end as usize).min(size);
+    let start = (range.start as usize).min(end);
+    assert_eq!(summary.size, ByteNum(size as u64));
+    assert_eq!(summary.written, ByteNum(start as u64)..ByteNum(end as u64));
+    assert_eq!(actual, &data[start..end]);
+    // strict mode fails if the range is not within the blob, without writing anything
+    let mut actual = Vec::new();
+    let res = crate::io::sync::decode_exact_bytes(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        byte_range,
+        true,
+        &mut actual,
+    );
+    if range.end > size as u64 {
+        assert!(
+            matches!(res, Err(AnyDecodeError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput)
+        );
+        assert!(actual.is_empty());
+    } else {
+        assert_eq!(res.unwrap(), summary);
+        assert_eq!(actual, &data[start..end]);
+    }
+}
+
+#[test]
+fn decode_exact_bytes_cases() {
+    let cases = [
+        (0, 0..0, 0),
+        (0, 0..1, 0),
+        (1, 0..1, 0),
+        (1000, 10..20, 0),
+        (1025, 1023..1025, 0),
+        (1024 * 16 + 1, 1000..9000, 2),
+        (1024 * 16 + 1, 4095..4097, 2),
+        (1024 * 16 + 1, 10000..100000, 1),
+        (1024 * 16 + 1, 20000..30000, 1),
+        (100000, 5..5, 3),
+    ];
+    for (size, range, block_level) in cases {
+        decode_exact_bytes_impl(size, range, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_exact_bytes_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(0u64..110000)] start: u64,
+    #[strategy(0u64..20000)] len: u64,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    decode_exact_bytes_impl(size, start..start + len, block_size);
+}
+
+/// Check that encode_ranges_validated detects corrupted data and a corrupted outboard
+/// before writing the corrupted part.
+fn encode_ranges_validated_corruption_impl(size: usize, flip: usize, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    // flip a bit in the data
+    let mut corrupted = data.clone();
+    corrupted[flip] ^= 1;
+    let mut encoded = Vec::new();
+    let res =
+        crate::io::sync::encode_ranges_validated(&corrupted, &outboard, &ranges, &mut encoded);
+    let Err(EncodeError::LeafHashMismatch(m)) = res else {
+        panic!("expected a leaf hash mismatch, got {res:?}");
+    };
+    assert!(m.chunks.start.to_bytes() <= ByteNum(flip as u64));
+    assert!(ByteNum(flip as u64) < m.chunks.end.to_bytes());
+    // everything that was written is correct, so the corrupted byte was not written
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut expected).unwrap();
+    assert!(expected.starts_with(&encoded));
+    // flip a bit in the outboard
+    if !outboard.data.is_empty() {
+        let mut outboard = outboard.clone();
+        let i = flip % outboard.data.len();
+        outboard.data[i] ^= 1;
+        let mut encoded = Vec::new();
+        let res = crate::io::sync::encode_ranges_validated(&data, &outboard, &ranges, &mut encoded);
+        assert!(matches!(
+            res,
+            Err(EncodeError::ParentHashMismatch(_) | EncodeError::LeafHashMismatch(_))
+        ));
+    }
+}
+
+#[test]
+fn encode_ranges_validated_corruption_cases() {
+    let cases = [
+        (1, 0, 0),
+        (1025, 1024, 0),
+        (1024 * 16 + 1, 5000, 2),
+        (1024 * 16 + 1, 1024 * 16, 2),
+        (100000, 99999, 1),
+    ];
+    for (size, flip, block_level) in cases {
+        encode_ranges_validated_corruption_impl(size, flip, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_validated_corruption_proptest(
+    #[strategy((1usize..100000).prop_flat_map(|size| (Just(size), 0..size)))] size_and_flip: (
+        usize,
+        usize,
+    ),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, flip) = size_and_flip;
+    encode_ranges_validated_corruption_impl(size, flip, block_size);
+}
+
+/// Check leaf_containing and ancestors against a brute force search in the
+/// pre order traversal
+fn leaf_containing_impl(tree: BaoTree, offset: ByteNum) {
+    let Some(leaf) = tree.leaf_containing(offset) else {
+        assert!(offset >= tree.size() && tree.size() > 0);
+        return;
+    };
+    assert!(tree.is_leaf(leaf));
+    let (left, right) = tree.leaf_byte_ranges(leaf).unwrap();
+    assert!(left.contains(&offset) || right.contains(&offset) || tree.size() == 0);
+    let chunk = offset.full_chunks();
+    let contains = |node: &TreeNode| node.chunk_range().contains(&chunk);
+    let mut expected = tree
+        .pre_order_nodes_iter()
+        .filter(|node| contains(node) && node.level() > leaf.level())
+        .collect::<Vec<_>>();
+    expected.sort_by_key(|node| node.level());
+    let actual = tree.ancestors(leaf).collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+    // the chain ends at the root
+    let root = tree.pre_order_nodes_iter().next().unwrap();
+    assert_eq!(actual.last().copied().unwrap_or(leaf), root);
+    assert_eq!(tree.ancestors(root).count(), 0);
+}
+
+#[test]
+fn leaf_containing_cases() {
+    let cases = [
+        (0, 0, 0),
+        (1, 0, 0),
+        (1024, 1023, 0),
+        (1025, 1024, 0),
+        (1025, 1025, 0),
+        (2049, 2048, 1),
+        (1024 * 16 + 1, 1024 * 16, 2),
+        (100000, 5000, 1),
+    ];
+    for (size, offset, block_level) in cases {
+        let tree = BaoTree::new(ByteNum(size), BlockSize(block_level));
+        leaf_containing_impl(tree, ByteNum(offset));
+    }
+}
+
+#[proptest]
+fn leaf_containing_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..110000)] offset: u64,
+) {
+    leaf_containing_impl(tree, ByteNum(offset));
+}
+
+/// Check the iterator, the encoder and the decoder against the reference layout
+fn layout_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    use crate::layout::{expected_layout, WireItem};
+    let data = make_test_data(size);
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    let expected = expected_layout(tree.size(), block_size, ranges);
+    // the response iterator
+    let mut actual = vec![WireItem::Header { size: tree.size() }];
+    for item in ResponseIterRef::new(tree, truncate_ranges(ranges, tree.size())) {
+        actual.push(match item {
+            BaoChunk::Parent { node, .. } => WireItem::Parent { node },
+            BaoChunk::Leaf {
+                start_chunk, size, ..
+            } => {
+                let start = start_chunk.to_bytes();
+                WireItem::Data {
+                    range: start..start + size as u64,
+                }
+            }
+        });
+    }
+    assert_eq!(actual, expected);
+    // the encoder
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut offset = 0;
+    for item in &expected {
+        let bytes = &encoded[offset..offset + item.len() as usize];
+        match item {
+            WireItem::Header { size } => assert_eq!(bytes, &size.0.to_le_bytes()[..]),
+            WireItem::Parent { node } => {
+                // pairs below the block size are not stored in the outboard
+                if let Some((l, r)) = outboard.load(*node).unwrap() {
+                    assert_eq!(&bytes[..32], l.as_bytes());
+                    assert_eq!(&bytes[32..], r.as_bytes());
+                }
+            }
+            WireItem::Data { range } => {
+                assert_eq!(bytes, &data[range.start.to_usize()..range.end.to_usize()])
+            }
+        }
+        offset += item.len() as usize;
+    }
+    assert_eq!(offset, encoded.len());
+    // the decoder
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    let actual = iter
+        .map(|item| match item.unwrap() {
+            DecodeResponseItem::Header(Header { size }) => WireItem::Header { size },
+            DecodeResponseItem::Parent(Parent { node, .. }) => WireItem::Parent { node },
+            DecodeResponseItem::Leaf(Leaf { offset, data }) => WireItem::Data {
+                range: offset..offset + data.len() as u64,
+            },
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn layout_cases() {
+    let mut two_ranges = ChunkRanges::from(ChunkNum(0)..ChunkNum(5));
+    two_ranges |= ChunkRanges::from(ChunkNum(7)..ChunkNum(10));
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (0, ChunkRanges::empty(), 0),
+        (1, ChunkRanges::from(ChunkNum(5)..), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (1024 * 7, two_ranges, 0),
+        (100000, ChunkRanges::from(ChunkNum(7)..ChunkNum(50)), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        layout_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn layout_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    layout_impl(size, &ranges, block_size);
+}
+
+/// Decode a combined encoding, and check that corruption is detected and that all
+/// bytes written before the failure are correct.
+fn decode_combined_impl(size: usize, flip: Option<usize>, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let (mut encoded, root) = crate::io::encode(&data, block_size);
+    if let Some(flip) = flip {
+        let i = flip % encoded.len();
+        encoded[i] ^= 1;
+    }
+    let mut out = Vec::new();
+    let res = crate::io::sync::decode_combined(root, block_size, encoded.as_slice(), &mut out);
+    match res {
+        Ok(n) => {
+            assert_eq!(n, size as u64);
+            assert_eq!(out, data);
+        }
+        Err(e) => {
+            assert!(flip.is_some());
+            assert_eq!(e.written, out.len() as u64);
+            assert_eq!(out, &data[..out.len()]);
+        }
+    }
+}
+
+#[test]
+fn decode_combined_cases() {
+    let cases = [
+        (0, None, 0),
+        (1, None, 0),
+        (1024 * 16 + 1, None, 2),
+        (100000, None, 3),
+        (1, Some(3), 0),
+        (1, Some(8), 0),
+        (1024 * 16 + 1, Some(8), 2),
+        (1024 * 16 + 1, Some(10000), 2),
+        (100000, Some(99999), 1),
+    ];
+    for (size, flip, block_level) in cases {
+        decode_combined_impl(size, flip, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_combined_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    flip: Option<usize>,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    decode_combined_impl(size, flip, block_size);
+}
+
+/// A [crate::io::sync::ReadAt] for a slice that records all reads
+struct RecordingReader<'a> {
+    data: &'a [u8],
+    reads: std::cell::RefCell<Vec<Range<u64>>>,
+}
+
+impl<'a> crate::io::sync::ReadAt for RecordingReader<'a> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = crate::io::sync::ReadAt::read_at(&self.data, pos, buf)?;
+        self.reads.borrow_mut().push(pos..pos + n as u64);
+        Ok(n)
+    }
+}
+
+impl<'a> crate::io::sync::Size for RecordingReader<'a> {
+    fn size(&self) -> std::io::Result<Option<u64>> {
+        Ok(Some(self.data.len() as u64))
+    }
+}
+
+/// Check that encode_ranges only reads the data of the leaves in the response,
+/// each of them once
+fn encode_ranges_reads_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let reader = RecordingReader {
+        data: &data,
+        reads: Default::default(),
+    };
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&reader, &outboard, ranges, &mut encoded).unwrap();
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    let mut end = 0;
+    for read in reader.reads.borrow().iter() {
+        // reads are in order and do not overlap
+        assert!(read.start >= end);
+        end = read.end;
+        // a read is a single leaf that contains some data of the response
+        let chunks = ByteNum(read.start).full_chunks()..ByteNum(read.end).chunks();
+        assert!(read.end - read.start <= block_size.bytes() as u64);
+        assert!(read.is_empty() || covered.intersects(&ChunkRanges::from(chunks)));
+    }
+}
+
+#[test]
+fn encode_ranges_reads_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (
+            1024 * 1024,
+            ChunkRanges::from(ChunkNum(500)..ChunkNum(510)),
+            4,
+        ),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_ranges_reads_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_reads_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_ranges_reads_impl(size, &ranges, block_size);
+}
+
+/// The contract for a blob of size 0: the outboard is just the size, the encoding of
+/// any query is just the size, and decoding any query yields no data.
+fn empty_file_impl(ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data: Vec<u8> = Vec::new();
+    let hash = blake3::hash(&data);
+    let tree = BaoTree::new(ByteNum(0), block_size);
+    assert_eq!(tree.blocks(), BlockNum(1));
+    assert_eq!(tree.outboard_hash_pairs(), 0);
+    assert_eq!(tree.encoded_size(ranges), ByteNum(8));
+    // outboard
+    let mut outboard = Vec::new();
+    let root = crate::io::sync::outboard_post_order(data.as_slice(), 0, block_size, &mut outboard)
+        .unwrap();
+    assert_eq!(root, hash);
+    assert_eq!(outboard, 0u64.to_le_bytes());
+    assert_eq!(crate::io::outboard(&data, block_size), (outboard, hash));
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    assert_eq!(outboard.root(), hash);
+    assert!(outboard.data.is_empty());
+    // encoding
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    assert_eq!(encoded, 0u64.to_le_bytes());
+    let mut validated = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut validated).unwrap();
+    assert_eq!(validated, encoded);
+    // decoding
+    let iter =
+        crate::io::sync::DecodeResponseIter::new(hash, block_size, encoded.as_slice(), ranges);
+    for item in iter {
+        match item.unwrap() {
+            DecodeResponseItem::Header(header) => assert_eq!(header.size, ByteNum(0)),
+            DecodeResponseItem::Parent(parent) => panic!("unexpected parent {parent:?}"),
+            DecodeResponseItem::Leaf(leaf) => assert!(leaf.data.is_empty()),
+        }
+    }
+    crate::io::sync::verify_ranges(hash, block_size, ranges, encoded.as_slice()).unwrap();
+    let mut out = Vec::new();
+    let written =
+        crate::io::sync::decode_combined(hash, block_size, encoded.as_slice(), &mut out).unwrap();
+    assert_eq!(written, 0);
+    assert!(out.is_empty());
+}
+
+#[test]
+fn empty_file_cases() {
+    let cases = [
+        (ChunkRanges::empty(), 0),
+        (ChunkRanges::all(), 0),
+        (ChunkRanges::all(), 4),
+        (ChunkRanges::from(ChunkNum(0)..ChunkNum(1)), 1),
+        (ChunkRanges::from(ChunkNum(1)..ChunkNum(2)), 2),
+        (ChunkRanges::from(ChunkNum(u64::MAX)..), 3),
+    ];
+    for (ranges, block_level) in cases {
+        empty_file_impl(&ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn empty_file_proptest(
+    #[strategy(size_and_selection(0..1, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (_, ranges) = size_and_selection;
+    empty_file_impl(&ranges, block_size);
+}
+
+/// Check the combined offsets against a combined encoding, and extracting ranges
+/// from it against encode_ranges
+fn extract_ranges_from_combined_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let mut combined = Vec::new();
+    crate::io::sync::encode_combined(&data, block_size, &mut combined).unwrap();
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let tree = outboard.tree();
+    for node in tree.pre_order_nodes_iter() {
+        match (
+            tree.combined_pair_offset(node),
+            outboard.load(node).unwrap(),
+        ) {
+            (Some(offset), Some((l, r))) => {
+                let offset = offset as usize;
+                assert_eq!(&combined[offset..offset + 32], l.as_bytes());
+                assert_eq!(&combined[offset + 32..offset + 64], r.as_bytes());
+            }
+            (None, None) => {}
+            other => panic!("inconsistent pair for {node:?}: {other:?}"),
+        }
+    }
+    for offset in (0..size).step_by(997).chain(size.checked_sub(1)) {
+        let actual = tree.combined_data_offset(ByteNum(offset as u64)).unwrap();
+        assert_eq!(combined[actual as usize], data[offset]);
+    }
+    assert_eq!(tree.combined_data_offset(ByteNum(size as u64 + 1)), None);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    let mut actual = Vec::new();
+    crate::io::sync::extract_ranges_from_combined(
+        std::io::Cursor::new(&combined),
+        ranges,
+        block_size,
+        &mut actual,
+    )
+    .unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn extract_ranges_from_combined_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::empty(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (1024 * 16 + 1, ChunkRanges::from(ChunkNum(100)..), 1),
+        (100000, ChunkRanges::from(ChunkNum(7)..ChunkNum(50)), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        extract_ranges_from_combined_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn extract_ranges_from_combined_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    extract_ranges_from_combined_impl(size, &ranges, block_size);
+}
+
+/// Check that a size proof verifies, and that proofs with a wrong size do not
+fn size_proof_impl(size: u64, block_size: BlockSize) {
+    let data = make_test_data(size as usize);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let root = outboard.root();
+    let proof = crate::io::sync::encode_size_proof(&data, &outboard).unwrap();
+    let mut expected = Vec::new();
+    let last_chunk = ChunkRanges::from(ChunkNum(ByteNum(size).chunks().0.saturating_sub(1))..);
+    crate::io::sync::encode_ranges(&data, &outboard, &last_chunk, &mut expected).unwrap();
+    assert_eq!(proof, expected);
+    let actual = crate::io::sync::verify_size(root, proof.as_slice(), block_size).unwrap();
+    assert_eq!(actual, ByteNum(size));
+    // a proof with an inflated or deflated size header
+    let wrong_sizes = [
+        size + 1,
+        size + 1024,
+        size * 2 + 1,
+        u64::MAX,
+        size.saturating_sub(1),
+    ];
+    for wrong in wrong_sizes.into_iter().filter(|wrong| *wrong != size) {
+        let mut malicious = proof.clone();
+        malicious[..8].copy_from_slice(&wrong.to_le_bytes());
+        assert!(crate::io::sync::verify_size(root, malicious.as_slice(), block_size).is_err());
+    }
+    // a valid proof for a larger blob with the same prefix
+    let larger = make_test_data(size as usize + 1024);
+    let larger_outboard = PostOrderMemOutboard::create(&larger, block_size);
+    let proof = crate::io::sync::encode_size_proof(&larger, &larger_outboard).unwrap();
+    assert!(crate::io::sync::verify_size(root, proof.as_slice(), block_size).is_err());
+}
+
+#[test]
+fn size_proof_cases() {
+    let cases = [
+        (0, 0),
+        (1, 0),
+        (1024, 0),
+        (1025, 1),
+        (1024 * 16 + 1, 2),
+        (100000, 3),
+    ];
+    for (size, block_level) in cases {
+        size_proof_impl(size, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn size_proof_proptest(
+    #[strategy(0u64..100000)] size: u64,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    size_proof_impl(size, block_size);
+}
+
+/// Check that encoding everything except `have` sends exactly the missing chunks
+fn encode_ranges_excluding_impl(size: usize, have: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let (encoded, sent) = crate::io::sync::encode_ranges_excluding(&data, &outboard, have).unwrap();
+    let all = crate::ranges::chunk_range(ChunkNum(0), ByteNum(size as u64).chunks());
+    // sent and have are disjoint, and together cover the blob
+    assert!(all.is_superset(&sent));
+    assert!(!sent.intersects(have));
+    let mut union = sent.clone();
+    union |= ChunkRanges::new_unchecked(SmallVec::from_slice(have.boundaries()));
+    assert!(union.is_superset(&all));
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &sent, &mut expected).unwrap();
+    assert_eq!(encoded, expected);
+    if sent.is_empty() {
+        assert_eq!(encoded, (size as u64).to_le_bytes());
+    }
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        &sent,
+    );
+    for item in iter {
+        if let DecodeResponseItem::Leaf(leaf) = item.unwrap() {
+            let start = leaf.offset.to_usize();
+            assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+        }
+    }
+}
+
+#[test]
+fn encode_ranges_excluding_cases() {
+    let cases = [
+        (0, ChunkRanges::empty(), 0),
+        (1, ChunkRanges::empty(), 0),
+        (1, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::from(..ChunkNum(17)), 2),
+        (1024 * 16 + 1, ChunkRanges::from(..ChunkNum(16)), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (100000, ChunkRanges::from(ChunkNum(7)..), 3),
+    ];
+    for (size, have, block_level) in cases {
+        encode_ranges_excluding_impl(size, &have, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_ranges_excluding_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, have) = size_and_selection;
+    encode_ranges_excluding_impl(size, &have, block_size);
+}
+
+/// Check that the pre and post order offsets locate the hash pairs in raw outboards
+fn outboard_offsets_impl(tree: BaoTree) {
+    let data = make_test_data(tree.size().to_usize());
+    let post = PostOrderMemOutboard::create(&data, tree.block_size);
+    let pre = post.flip().unwrap();
+    for node in tree.pre_order_nodes_iter() {
+        let expected = post.load(node).unwrap();
+        let post_offset = tree.post_order_offset(node);
+        let pre_offset = tree.pre_order_offset(node);
+        assert_eq!(post_offset.is_some(), expected.is_some());
+        assert_eq!(pre_offset.is_some(), expected.is_some());
+        let (Some(post_offset), Some(pre_offset), Some((l, r))) =
+            (post_offset, pre_offset, expected)
+        else {
+            continue;
+        };
+        let stable = node.byte_range().end <= tree.size();
+        assert_eq!(
+            matches!(post_offset, crate::PostOrderOffset::Stable(_)),
+            stable
+        );
+        for (offset, outboard) in [(post_offset.value(), &post.data), (pre_offset, &pre.data)] {
+            let offset = offset as usize * 64;
+            assert_eq!(&outboard[offset..offset + 32], l.as_bytes());
+            assert_eq!(&outboard[offset + 32..offset + 64], r.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn outboard_offsets_cases() {
+    let cases = [
+        (0, 0),
+        (1025, 0),
+        (1024 * 16 + 1, 2),
+        (100000, 1),
+        (100000, 3),
+    ];
+    for (size, block_level) in cases {
+        outboard_offsets_impl(BaoTree::new(ByteNum(size), BlockSize(block_level)));
+    }
+}
+
+#[proptest]
+fn outboard_offsets_proptest(#[strategy(tree())] tree: BaoTree) {
+    outboard_offsets_impl(tree);
+}
+
+/// Check that encode_all and decode_all round trip, and that decode_all rejects
+/// corrupted encodings
+fn encode_all_impl(size: usize, flip: Option<usize>, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = crate::io::sync::encode_all(&data, &outboard).unwrap();
+    let mut combined = Vec::new();
+    crate::io::sync::encode_combined(&data, block_size, &mut combined).unwrap();
+    assert_eq!(encoded, combined);
+    let root = outboard.root();
+    match flip {
+        Some(flip) if !encoded.is_empty() => {
+            let flip = flip % encoded.len();
+            encoded[flip] ^= 1;
+            assert!(crate::io::sync::decode_all(root, block_size, encoded.as_slice()).is_err());
+        }
+        _ => {
+            let actual = crate::io::sync::decode_all(root, block_size, encoded.as_slice()).unwrap();
+            assert_eq!(actual, data);
+        }
+    }
+}
+
+#[test]
+fn encode_all_cases() {
+    let cases = [
+        (0, None, 0),
+        (1, None, 0),
+        (1024 * 16 + 1, None, 2),
+        (100000, None, 3),
+        (1, Some(8), 0),
+        (1024 * 16 + 1, Some(0), 2),
+        (1024 * 16 + 1, Some(10000), 2),
+    ];
+    for (size, flip, block_level) in cases {
+        encode_all_impl(size, flip, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_all_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    flip: Option<usize>,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    encode_all_impl(size, flip, block_size);
+}
+
+/// Check that multiple queries are encoded as independent segments, and decoded
+/// segment by segment
+fn encode_ranges_multi_impl(size: usize, queries: &[ChunkRanges], block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_multi(&data, &outboard, queries, &mut encoded).unwrap();
+    let mut expected = Vec::new();
+    for query in queries {
+        crate::io::sync::encode_ranges(&data, &outboard, query, &mut expected).unwrap();
+    }
+    assert_eq!(encoded, expected);
+    let iter = crate::io::sync::DecodeMultiIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        queries,
+    );
+    let mut headers = 0;
+    let mut last = 0;
+    for (index, item) in iter {
+        assert!(index >= last);
+        last = index;
+        match item.unwrap() {
+            DecodeResponseItem::Header(header) => {
+                assert_eq!(index, headers);
+                assert_eq!(header.size, ByteNum(size as u64));
+                headers += 1;
+            }
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(leaf) => {
+                let start = leaf.offset.to_usize();
+                assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+            }
+        }
+    }
+    assert_eq!(headers, queries.len());
+}
+
+#[test]
+fn encode_ranges_multi_cases() {
+    let header = ChunkRanges::from(..ChunkNum(1));
+    let window = ChunkRanges::from(ChunkNum(30)..ChunkNum(40));
+    let cases = [
+        (0, vec![ChunkRanges::all(), ChunkRanges::all()], 0),
+        (100000, vec![], 2),
+        (
+            100000,
+            vec![header.clone(), window.clone(), ChunkRanges::all()],
+            2,
+        ),
+        (100000, vec![window, ChunkRanges::empty(), header], 0),
+    ];
+    for (size, queries, block_level) in cases {
+        encode_ranges_multi_impl(size, &queries, BlockSize(block_level));
+    }
+}
+
+/// A segment with a different size than the first one is an error, but the
+/// segments before it are still decoded
+#[test]
+fn decode_multi_size_mismatch() {
+    let data = make_test_data(10000);
+    let block_size = BlockSize(1);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let queries = [ChunkRanges::all(), ChunkRanges::empty()];
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &queries[0], &mut encoded).unwrap();
+    // the second segment is just a header, since the query is empty
+    encoded.extend_from_slice(&20000u64.to_le_bytes());
+    let items = crate::io::sync::DecodeMultiIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        &queries,
+    )
+    .collect::<Vec<_>>();
+    let (last, rest) = items.split_last().unwrap();
+    assert!(rest.iter().all(|(index, item)| *index == 0 && item.is_ok()));
+    assert!(matches!(
+        last,
+        (1, Err(AnyDecodeError::Io(e))) if e.kind() == std::io::ErrorKind::InvalidData
+    ));
+}
+
+#[proptest]
+fn encode_ranges_multi_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(proptest::collection::vec(selection(100000, 2), 0..4))] queries: Vec<ChunkRanges>,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    encode_ranges_multi_impl(size, &queries, block_size);
+}
+
+/// Check that headerless encoding is the encoding without the size, that it can be
+/// decoded with the size from out of band, and that the decoder does not accept a
+/// stream with a size header
+fn headerless_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let root = outboard.root();
+    let size = ByteNum(size as u64);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_headerless(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut expected).unwrap();
+    assert_eq!(encoded, expected[8..]);
+    let iter = crate::io::sync::decode_ranges_headerless(
+        root,
+        size,
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .unwrap();
+    for item in iter {
+        match item.unwrap() {
+            DecodeResponseItem::Header(_) => panic!("unexpected header"),
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(leaf) => {
+                let start = leaf.offset.to_usize();
+                assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+            }
+        }
+    }
+    // the same with the fsm decoder
+    let tree = BaoTree::new(size, block_size);
+    let mut reading = crate::io::fsm::ResponseDecoderReading::new(
+        root,
+        ChunkRanges::new_unchecked(SmallVec::from_slice(ranges.boundaries())),
+        tree,
+        std::io::Cursor::new(encoded.as_slice()),
+    );
+    while let ResponseDecoderReadingNext::More((next, item)) =
+        futures::executor::block_on(reading.next())
+    {
+        if let BaoContentItem::Leaf(leaf) = item.unwrap() {
+            let start = leaf.offset.to_usize();
+            assert_eq!(leaf.data, data[start..start + leaf.data.len()]);
+        }
+        // the root hash is still known after it has been verified
+        assert_eq!(next.hash(), &root);
+        reading = next;
+    }
+    // a stream with a size header in front
+    if !encoded.is_empty() {
+        let mut iter = crate::io::sync::decode_ranges_headerless(
+            root,
+            size,
+            block_size,
+            expected.as_slice(),
+            ranges,
+        )
+        .unwrap();
+        assert!(iter.any(|item| item.is_err()));
+    }
+}
+
+#[test]
+fn headerless_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (100000, ChunkRanges::from(ChunkNum(100)..), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        headerless_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn headerless_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    headerless_impl(size, &ranges, block_size);
+}
+
+/// Check that format_tree has one line per node, in pre order, indented by level
+fn format_tree_impl(tree: BaoTree) {
+    let text = tree.format_tree();
+    let lines = text.lines().collect::<Vec<_>>();
+    let nodes = tree.pre_order_nodes_iter().collect::<Vec<_>>();
+    assert_eq!(lines.len(), nodes.len());
+    let root_level = nodes[0].level();
+    for (line, node) in lines.iter().zip(nodes) {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        assert_eq!(indent, (root_level - node.level()) as usize * 2);
+        assert!(trimmed.starts_with(&format!("{} ", node.0)));
+        assert!(trimmed.contains(&format!("level={}", node.level())));
+        let bytes = tree.byte_range(node);
+        assert!(trimmed.contains(&format!("bytes={}..{}", bytes.start.0, bytes.end.0)));
+    }
+}
+
+#[test]
+fn format_tree_cases() {
+    let cases = [
+        (0, 0),
+        (1025, 0),
+        (1024 * 16 + 1, 2),
+        (100000, 1),
+        (100000, 3),
+    ];
+    for (size, block_level) in cases {
+        format_tree_impl(BaoTree::new(ByteNum(size), BlockSize(block_level)));
+    }
+}
+
+#[proptest]
+fn format_tree_proptest(#[strategy(tree())] tree: BaoTree) {
+    format_tree_impl(tree);
+}
+
+/// Check that extracting a narrower slice from an encoded slice gives the same bytes
+/// as encoding the narrower slice directly
+fn extract_slice_impl(
+    size: usize,
+    outer: &ChunkRanges,
+    inner: &ChunkRanges,
+    block_size: BlockSize,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut slice = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, outer, &mut slice).unwrap();
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, inner, &mut expected).unwrap();
+    let actual =
+        crate::io::sync::extract_slice(slice.as_slice(), outer, inner, block_size).unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn extract_slice_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), ChunkRanges::empty(), 2),
+        (
+            100000,
+            ChunkRanges::from(..ChunkNum(64)),
+            ChunkRanges::from(ChunkNum(5)..ChunkNum(7)),
+            4,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(1)..ChunkNum(3)),
+            ChunkRanges::from(ChunkNum(1)..ChunkNum(2)),
+            4,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(50)..),
+            ChunkRanges::from(ChunkNum(1000)..),
+            3,
+        ),
+        // only subsets after canonicalizing for the size, both mean the last chunk
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(1000)..),
+            ChunkRanges::from(ChunkNum(97)..ChunkNum(98)),
+            2,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(97)..ChunkNum(98)),
+            ChunkRanges::from(ChunkNum(2000)..),
+            0,
+        ),
+    ];
+    for (size, outer, inner, block_level) in cases {
+        extract_slice_impl(size, &outer, &inner, BlockSize(block_level));
+    }
+}
+
+#[test]
+fn extract_slice_not_subset() {
+    let data = make_test_data(10000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(1));
+    let outer = ChunkRanges::from(..ChunkNum(1));
+    let inner = ChunkRanges::from(..ChunkNum(2));
+    let mut slice = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &outer, &mut slice).unwrap();
+    let err =
+        crate::io::sync::extract_slice(slice.as_slice(), &outer, &inner, BlockSize(1)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[proptest]
+fn extract_slice_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(selection(100000, 2))] inner: ChunkRanges,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, outer) = size_and_selection;
+    let mut inner = inner;
+    inner &= outer.clone();
+    extract_slice_impl(size, &outer, &inner, block_size);
+}
+
+/// Check that decode errors name the node or chunk of the corrupted or missing item,
+/// and convert to io errors of the expected kind
+fn decode_error_location_impl(
+    size: usize,
+    ranges: &ChunkRanges,
+    block_size: BlockSize,
+    pick: usize,
+    truncate: bool,
+) {
+    use crate::layout::{expected_layout, WireItem};
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    // the items after the header that take space, with their offsets
+    let mut items = Vec::new();
+    let mut offset = 0;
+    for item in expected_layout(ByteNum(size as u64), block_size, ranges) {
+        let len = item.len() as usize;
+        if !item.is_empty() && !matches!(item, WireItem::Header { .. }) {
+            items.push((offset, item));
+        }
+        offset += len;
+    }
+    if items.is_empty() {
+        return;
+    }
+    let (offset, item) = &items[pick % items.len()];
+    if truncate {
+        encoded.truncate(*offset);
+    } else {
+        encoded[*offset] ^= 1;
+    }
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    let error = iter.filter_map(|item| item.err()).next().unwrap();
+    match (item, &error, truncate) {
+        (
+            WireItem::Parent { node },
+            AnyDecodeError::ParentHashMismatch(ParentMismatch { node: actual, .. }),
+            false,
+        )
+        | (WireItem::Parent { node }, AnyDecodeError::ParentNotFound(actual), true) => {
+            assert_eq!(actual, node);
+        }
+        (
+            WireItem::Data { range },
+            AnyDecodeError::LeafHashMismatch(LeafMismatch {
+                chunks: Range { start: actual, .. },
+                ..
+            }),
+            false,
+        )
+        | (WireItem::Data { range }, AnyDecodeError::LeafNotFound(actual), true) => {
+            assert_eq!(*actual, range.start.full_chunks());
+        }
+        _ => panic!("unexpected error {error:?} for {item:?}"),
+    }
+    let expected_kind = if truncate {
+        std::io::ErrorKind::UnexpectedEof
+    } else {
+        std::io::ErrorKind::InvalidData
+    };
+    assert_eq!(std::io::Error::from(error).kind(), expected_kind);
+}
+
+#[test]
+fn decode_error_location_cases() {
+    let cases = [
+        (1, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 4),
+        (100000, ChunkRanges::from(ChunkNum(50)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        for pick in 0..8 {
+            for truncate in [false, true] {
+                decode_error_location_impl(size, &ranges, BlockSize(block_level), pick, truncate);
+            }
+        }
+    }
+}
+
+#[proptest]
+fn decode_error_location_proptest(
+    #[strategy(size_and_selection(1..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    pick: usize,
+    truncate: bool,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_error_location_impl(size, &ranges, block_size, pick, truncate);
+}
+
+/// Encode random ranges, decode them again, and check that exactly the requested
+/// data comes back
+fn encode_decode_ranges_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut encoded = encoded.as_slice();
+    let iter =
+        crate::io::sync::DecodeResponseIter::new(outboard.root(), block_size, &mut encoded, ranges);
+    let mut decoded = ChunkRanges::empty();
+    let mut header = None;
+    for item in iter {
+        match item.unwrap() {
+            DecodeResponseItem::Header(Header { size }) => header = Some(size),
+            DecodeResponseItem::Parent(_) => {}
+            DecodeResponseItem::Leaf(Leaf { offset, data: leaf }) => {
+                let start = offset.to_usize();
+                assert_eq!(leaf, data[start..start + leaf.len()]);
+                let end = offset + ByteNum(leaf.len() as u64);
+                let leaf_ranges = crate::ranges::chunk_range(offset.full_chunks(), end.chunks());
+                assert!(!decoded.intersects(&leaf_ranges));
+                decoded |= leaf_ranges;
+            }
+        }
+    }
+    // the size prefix is read, and the entire stream is consumed
+    assert_eq!(header, Some(ByteNum(size as u64)));
+    assert!(encoded.is_empty());
+    // all requested chunks within the data are decoded
+    let chunks = ByteNum(size as u64).chunks();
+    let mut requested = ranges.clone();
+    requested &= crate::ranges::chunk_range(ChunkNum(0), chunks);
+    assert!(decoded.is_superset(&requested));
+    assert_eq!(
+        decoded,
+        crate::io::covered_chunk_ranges(size as u64, ranges, block_size)
+    );
+}
+
+#[test]
+fn encode_decode_ranges_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::from(ChunkNum(1)..), 0),
+        (
+            1024 * 16 + 1,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            2,
+        ),
+        (100000, ChunkRanges::from(ChunkNum(10)..ChunkNum(40)), 4),
+        (100000, ChunkRanges::from(ChunkNum(u64::MAX)..), 3),
+    ];
+    for (size, ranges, block_level) in cases {
+        encode_decode_ranges_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn encode_decode_ranges_proptest(
+    #[strategy(size_and_selection(0..100000, 3))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_decode_ranges_impl(size, &ranges, block_size);
+}
+
+/// A reader that counts the bytes that were read from it
+struct CountingRead<'a> {
+    data: &'a [u8],
+    count: &'a std::cell::Cell<usize>,
+}
+
+impl<'a> std::io::Read for CountingRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = std::io::Read::read(&mut self.data, buf)?;
+        self.count.set(self.count.get() + n);
+        Ok(n)
+    }
+}
+
+/// Check that DecodeResponseIter reads exactly one item from the reader per call
+/// to next, and not more
+fn decode_lazy_impl(size: usize, ranges: &ChunkRangesRef, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let count = std::cell::Cell::new(0);
+    let reader = CountingRead {
+        data: &encoded,
+        count: &count,
+    };
+    let iter =
+        crate::io::sync::DecodeResponseIter::new(outboard.root(), block_size, reader, ranges);
+    let mut expected = 0;
+    for item in iter {
+        expected += match item.unwrap() {
+            DecodeResponseItem::Header(_) => 8,
+            DecodeResponseItem::Parent(_) => 64,
+            DecodeResponseItem::Leaf(leaf) => leaf.data.len(),
+        };
+        assert_eq!(count.get(), expected);
+    }
+    assert_eq!(expected, encoded.len());
+}
+
+#[test]
+fn decode_lazy_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 4),
+    ];
+    for (size, ranges, block_level) in cases {
+        decode_lazy_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_lazy_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_lazy_impl(size, &ranges, block_size);
+}
+
+/// Check that decode_response_into can decode into a cursor using SeekWriter
+fn decode_into_cursor_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut target = crate::io::sync::SeekWriter(std::io::Cursor::new(Vec::new()));
+    let (decoded_size, _) = crate::io::sync::decode_response_into(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(crate::io::outboard::EmptyOutboard::new(tree, root)),
+        &mut target,
+    )
+    .unwrap();
+    assert_eq!(decoded_size, ByteNum(size as u64));
+    let decoded = target.0.into_inner();
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    for range in covered.iter() {
+        let range_collections::range_set::RangeSetRange::Range(range) = range else {
+            panic!("covered ranges are bounded");
+        };
+        let start = range.start.to_bytes().to_usize();
+        let end = range.end.to_bytes().to_usize().min(size);
+        assert_eq!(decoded[start..end], data[start..end]);
+    }
+}
+
+#[test]
+fn decode_into_cursor_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 4),
+        (100000, ChunkRanges::from(ChunkNum(50)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        decode_into_cursor_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_into_cursor_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_into_cursor_impl(size, &ranges, block_size);
+}
+
+/// Check that encoding with a min_level, with the sync and the fsm api, is the same as
+/// encoding with the larger block size, needs no more hash pairs, and decodes to the
+/// same data
+fn encode_min_level_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize, min_level: u8) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    let written = crate::io::sync::encode_ranges_with_min_level(
+        &data,
+        &outboard,
+        ranges,
+        min_level,
+        &mut encoded,
+    )
+    .unwrap();
+    assert_eq!(written, encoded.len() as u64);
+    let coarse_block_size = BlockSize(block_size.0.max(min_level));
+    let coarse = PostOrderMemOutboard::create(&data, coarse_block_size);
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &coarse, ranges, &mut expected).unwrap();
+    assert_eq!(encoded, expected);
+    let mut fsm_outboard = outboard.clone();
+    let mut fsm_encoded = Vec::new();
+    futures::executor::block_on(crate::io::fsm::encode_ranges_with_min_level(
+        Bytes::from(data.clone()),
+        &mut fsm_outboard,
+        ranges,
+        min_level,
+        &mut fsm_encoded,
+    ))
+    .unwrap();
+    assert_eq!(fsm_encoded, expected);
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    let coarse_tree = BaoTree::new(ByteNum(size as u64), coarse_block_size);
+    assert!(coarse_tree.encoded_hash_pairs(ranges) <= tree.encoded_hash_pairs(ranges));
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        coarse_block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    for item in iter {
+        if let DecodeResponseItem::Leaf(Leaf { offset, data: leaf }) = item.unwrap() {
+            let start = offset.to_usize();
+            assert_eq!(leaf, data[start..start + leaf.len()]);
+        }
+    }
+}
+
+#[test]
+fn encode_min_level_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0, 4),
+        (100000, ChunkRanges::all(), 0, 4),
+        (100000, ChunkRanges::all(), 4, 2),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(70)), 1, 3),
+        (100000, ChunkRanges::from(ChunkNum(50)..), 2, 6),
+    ];
+    for (size, ranges, block_level, min_level) in cases {
+        encode_min_level_impl(size, &ranges, BlockSize(block_level), min_level);
+    }
+}
+
+#[proptest]
+fn encode_min_level_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    #[strategy(0u8..8)] min_level: u8,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_min_level_impl(size, &ranges, block_size, min_level);
+}
+
+/// Check that the push decoder yields the same items as DecodeResponseIter, no matter
+/// how the response is split into frames
+fn push_decoder_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize, frame: usize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .map(|item| format!("{:?}", item.unwrap()))
+    .collect::<Vec<_>>();
+    let mut decoder = SliceDecoder::new(outboard.root(), block_size, ranges.clone());
+    let mut actual = Vec::new();
+    for bytes in encoded.chunks(frame) {
+        assert!(decoder.needed().unwrap() > 0);
+        let items = decoder.push(bytes).unwrap();
+        actual.extend(items.iter().map(|item| format!("{:?}", item)));
+    }
+    assert!(decoder.is_done());
+    assert_eq!(decoder.needed().unwrap(), 0);
+    decoder.finish().unwrap();
+    assert_eq!(actual, expected);
+
+    // a truncated response is not complete
+    let mut decoder = SliceDecoder::new(outboard.root(), block_size, ranges.clone());
+    decoder.push(&encoded[..encoded.len() - 1]).unwrap();
+    assert_eq!(decoder.needed().unwrap(), 1);
+    assert!(decoder.finish().is_err());
+
+    // bytes after the end of the response are an error
+    let mut decoder = SliceDecoder::new(outboard.root(), block_size, ranges.clone());
+    encoded.push(0);
+    assert!(decoder.push(&encoded).is_err());
+    assert!(decoder.is_done());
+    // a failed decoder can not be used or resumed, and does not finish successfully
+    assert!(decoder.is_failed());
+    assert!(decoder.needed().is_err());
+    assert!(decoder.state().is_err());
+    assert!(decoder.push(&[]).is_err());
+    assert!(decoder.finish().is_err());
+}
+
+#[test]
+fn push_decoder_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0, 1),
+        (1024, ChunkRanges::all(), 0, 7),
+        (100000, ChunkRanges::all(), 4, 1),
+        (100000, ChunkRanges::all(), 4, 100),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2, 64),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1, 1000),
+    ];
+    for (size, ranges, block_level, frame) in cases {
+        push_decoder_impl(size, &ranges, BlockSize(block_level), frame);
+    }
+}
+
+#[proptest]
+fn push_decoder_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    #[strategy(1usize..20000)] frame: usize,
+) {
+    let (size, ranges) = size_and_selection;
+    push_decoder_impl(size, &ranges, block_size, frame);
+}
+
+/// Check that the push decoder detects corrupted data at the same item as
+/// DecodeResponseIter
+#[test]
+fn push_decoder_corrupt() {
+    let block_size = BlockSize(2);
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    for i in [8, 8 + 64, encoded.len() - 1] {
+        let mut corrupted = encoded.clone();
+        corrupted[i] ^= 1;
+        let expected = crate::io::sync::DecodeResponseIter::new(
+            outboard.root(),
+            block_size,
+            corrupted.as_slice(),
+            &ranges,
+        )
+        .find_map(|item| item.err())
+        .unwrap();
+        let mut decoder = SliceDecoder::new(outboard.root(), block_size, ranges.clone());
+        let actual = decoder.push(&corrupted).unwrap_err();
+        assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+    }
+}
+
+/// A partial outboard that contains just the hash pairs that a decoder of a response
+/// to `ranges` would have saved
+fn partial_outboard(full: &PostOrderMemOutboard, ranges: &ChunkRangesRef) -> PostOrderMemOutboard {
+    let tree = full.tree();
+    let mut res = PostOrderMemOutboard::new(full.root(), tree, vec![0; full.data.len()]).unwrap();
+    let ranges = crate::rec::truncate_ranges(ranges, tree.size);
+    for item in ResponseIterRef::new(tree, ranges) {
+        if let BaoChunk::Parent { node, .. } = item {
+            if let Some(offset) = tree.post_order_offset(node) {
+                let offset = usize::try_from(offset.value() * 64).unwrap();
+                res.data[offset..offset + 64].copy_from_slice(&full.data[offset..offset + 64]);
+            }
+        }
+    }
+    res
+}
+
+/// Check that merging two partial outboards gives an outboard that is valid for the
+/// union of their valid ranges
+fn merge_outboards_impl(size: usize, a: &ChunkRanges, b: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let full = PostOrderMemOutboard::create(&data, block_size);
+    let a = partial_outboard(&full, a);
+    let b = partial_outboard(&full, b);
+    let merged = crate::io::outboard::merge_outboards(&a, &b).unwrap();
+    let mut expected = crate::io::sync::valid_ranges(&a).unwrap();
+    expected |= crate::io::sync::valid_ranges(&b).unwrap();
+    assert_eq!(crate::io::sync::valid_ranges(&merged).unwrap(), expected);
+    // merging is idempotent, and merging with the full outboard gives the full outboard
+    assert_eq!(crate::io::outboard::merge_outboards(&a, &a).unwrap(), a);
+    assert_eq!(
+        crate::io::outboard::merge_outboards(&a, &full).unwrap(),
+        full
+    );
+}
+
+#[test]
+fn merge_outboards_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), ChunkRanges::empty(), 0),
+        (100000, ChunkRanges::empty(), ChunkRanges::empty(), 2),
+        (
+            100000,
+            ChunkRanges::from(..ChunkNum(16)),
+            ChunkRanges::from(ChunkNum(16)..ChunkNum(32)),
+            2,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            ChunkRanges::from(ChunkNum(50)..),
+            0,
+        ),
+        (100000, ChunkRanges::all(), ChunkRanges::empty(), 4),
+    ];
+    for (size, a, b, block_level) in cases {
+        merge_outboards_impl(size, &a, &b, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn merge_outboards_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] a: (usize, ChunkRanges),
+    #[strategy(selection(100000, 2))] b: ChunkRanges,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, a) = a;
+    merge_outboards_impl(size, &a, &b, block_size);
+}
+
+#[test]
+fn merge_outboards_errors() {
+    let block_size = BlockSize(1);
+    let data = make_test_data(100000);
+    let full = PostOrderMemOutboard::create(&data, block_size);
+    // a pair that does not agree with a verified pair
+    let mut corrupted = full.clone();
+    corrupted.data[0] ^= 1;
+    let err = crate::io::outboard::merge_outboards(&full, &corrupted).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    // a different blob
+    let other = PostOrderMemOutboard::create(&data[..50000], block_size);
+    let err = crate::io::outboard::merge_outboards(&full, &other).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// Check that a push decoder that is interrupted after `cut` bytes can be resumed from
+/// its state, given the rest of the response from the offset of the state
+fn push_decoder_resume_impl(
+    encoded: &[u8],
+    root: blake3::Hash,
+    ranges: &ChunkRanges,
+    block_size: BlockSize,
+    cut: usize,
+) -> Vec<String> {
+    let mut decoder = SliceDecoder::new(root, block_size, ranges.clone());
+    let mut items = decoder.push(&encoded[..cut]).unwrap();
+    let state = decoder.state().unwrap();
+    #[cfg(feature = "serde")]
+    let state: crate::io::push::DecoderState =
+        postcard::from_bytes(&postcard::to_stdvec(&state).unwrap()).unwrap();
+    let offset = usize::try_from(state.offset).unwrap();
+    assert!(offset <= cut);
+    let mut decoder = SliceDecoder::from_state(state).unwrap();
+    items.extend(decoder.push(&encoded[offset..]).unwrap());
+    decoder.finish().unwrap();
+    items.iter().map(|item| format!("{:?}", item)).collect()
+}
+
+/// Interrupt the decoder at each of `cuts`, or at every byte boundary if `cuts` is None
+fn push_decoder_resume_all_impl(
+    size: usize,
+    ranges: &ChunkRanges,
+    block_size: BlockSize,
+    cuts: Option<&[usize]>,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .map(|item| format!("{:?}", item.unwrap()))
+    .collect::<Vec<_>>();
+    let cuts = match cuts {
+        Some(cuts) => cuts.iter().map(|cut| (*cut).min(encoded.len())).collect(),
+        None => (0..=encoded.len()).collect::<Vec<_>>(),
+    };
+    for cut in cuts {
+        let actual = push_decoder_resume_impl(&encoded, outboard.root(), ranges, block_size, cut);
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn push_decoder_resume_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024, ChunkRanges::all(), 0),
+        (5000, ChunkRanges::all(), 0),
+        (5000, ChunkRanges::all(), 1),
+        (6000, ChunkRanges::from(ChunkNum(3)..ChunkNum(5)), 0),
+        (6000, ChunkRanges::from(ChunkNum(4)..), 2),
+    ];
+    for (size, ranges, block_level) in cases {
+        push_decoder_resume_all_impl(size, &ranges, BlockSize(block_level), None);
+    }
+}
+
+#[proptest]
+fn push_decoder_resume_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    cuts: [usize; 4],
+) {
+    let (size, ranges) = size_and_selection;
+    let cuts = cuts.map(|cut| cut % (size + size / 16 + 100));
+    push_decoder_resume_all_impl(size, &ranges, block_size, Some(&cuts));
+}
+
+#[test]
+fn push_decoder_resume_invalid() {
+    let root = blake3::hash(&[]);
+    let mut state = SliceDecoder::new(root, BlockSize(0), ChunkRanges::all())
+        .state()
+        .unwrap();
+    assert!(SliceDecoder::from_state(state.clone()).is_ok());
+    state.ranges = vec![2, 1];
+    assert!(SliceDecoder::from_state(state.clone()).is_err());
+    state.ranges = vec![0];
+    state.size = Some(1024);
+    state.items = 2;
+    assert!(SliceDecoder::from_state(state).is_err());
+}
+
+/// A state with fewer or more hashes than the completed items leave on the stack must
+/// be rejected, instead of making the decoder panic when it runs out of hashes
+#[test]
+fn push_decoder_resume_stack_depth() {
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ChunkRanges::all(), &mut encoded).unwrap();
+    let mut decoder = SliceDecoder::new(outboard.root(), BlockSize(0), ChunkRanges::all());
+    // the header and the first two hash pairs
+    decoder.push(&encoded[..8 + 2 * 64]).unwrap();
+    let state = decoder.state().unwrap();
+    assert_eq!(state.stack.len(), 3);
+    assert!(SliceDecoder::from_state(state.clone()).is_ok());
+    for len in 0..state.stack.len() {
+        let mut truncated = state.clone();
+        truncated.stack.truncate(len);
+        let err = SliceDecoder::from_state(truncated).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+    let mut extended = state;
+    extended.stack.push([0; 32]);
+    let err = SliceDecoder::from_state(extended).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// Check that a verifier that is fed multiple slices verifies the same data as
+/// decoding each slice on its own, and does not accept a corrupted known pair
+fn verifier_impl(size: usize, a: &ChunkRanges, b: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut verifier = crate::io::sync::Verifier::new(outboard.root(), block_size);
+    for ranges in [a, b, a] {
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+        let expected = crate::io::sync::DecodeResponseIter::new(
+            outboard.root(),
+            block_size,
+            encoded.as_slice(),
+            ranges,
+        )
+        .filter_map(|item| match item.unwrap() {
+            DecodeResponseItem::Leaf(leaf) => {
+                Some(leaf.offset..leaf.offset + leaf.data.len() as u64)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+        let actual = verifier.feed_slice(encoded.as_slice(), ranges).unwrap();
+        assert_eq!(actual, expected);
+        // corrupt the first item, which is usually the root pair that is known by now
+        if encoded.len() > 8 {
+            encoded[8] ^= 1;
+            assert!(verifier.feed_slice(encoded.as_slice(), ranges).is_err());
+        }
+    }
+    assert_eq!(verifier.tree(), Some(outboard.tree()));
+}
+
+#[test]
+fn verifier_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), ChunkRanges::empty(), 0),
+        (
+            100000,
+            ChunkRanges::from(..ChunkNum(16)),
+            ChunkRanges::from(ChunkNum(16)..ChunkNum(32)),
+            2,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            ChunkRanges::from(ChunkNum(50)..),
+            0,
+        ),
+        (100000, ChunkRanges::all(), ChunkRanges::all(), 4),
+    ];
+    for (size, a, b, block_level) in cases {
+        verifier_impl(size, &a, &b, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn verifier_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] a: (usize, ChunkRanges),
+    #[strategy(selection(100000, 2))] b: ChunkRanges,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, a) = a;
+    verifier_impl(size, &a, &b, block_size);
+}
+
+#[test]
+fn verifier_size_mismatch() {
+    let block_size = BlockSize(1);
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut verifier = crate::io::sync::Verifier::new(outboard.root(), block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ChunkRanges::all(), &mut encoded).unwrap();
+    verifier
+        .feed_slice(encoded.as_slice(), &ChunkRanges::all())
+        .unwrap();
+    // a slice that claims a different size
+    encoded[0] ^= 1;
+    let err = verifier
+        .feed_slice(encoded.as_slice(), &ChunkRanges::all())
+        .unwrap_err();
+    assert!(matches!(err, AnyDecodeError::Io(_)));
+}
+
+/// Check that decoding two responses into the same outboard saves exactly the hash
+/// pairs that are needed for the two responses
+fn decode_with_outboard_impl(size: usize, a: &ChunkRanges, b: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let full = PostOrderMemOutboard::create(&data, block_size);
+    let mut outboard =
+        PostOrderMemOutboard::new(full.root(), full.tree(), vec![0; full.data.len()]).unwrap();
+    let mut target = Vec::new();
+    for ranges in [a, b] {
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges(&data, &full, ranges, &mut encoded).unwrap();
+        let decoded_size = crate::io::sync::decode_ranges_with_outboard(
+            full.root(),
+            block_size,
+            ranges,
+            encoded.as_slice(),
+            &mut outboard,
+            &mut target,
+        )
+        .unwrap();
+        assert_eq!(decoded_size, ByteNum(size as u64));
+    }
+    let expected = crate::io::outboard::merge_outboards(
+        &partial_outboard(&full, a),
+        &partial_outboard(&full, b),
+    )
+    .unwrap();
+    assert_eq!(outboard, expected);
+}
+
+#[test]
+fn decode_with_outboard_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), ChunkRanges::empty(), 0),
+        (
+            100000,
+            ChunkRanges::from(..ChunkNum(16)),
+            ChunkRanges::from(ChunkNum(16)..ChunkNum(32)),
+            2,
+        ),
+        (
+            100000,
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(5)),
+            ChunkRanges::from(ChunkNum(50)..),
+            0,
+        ),
+        (100000, ChunkRanges::all(), ChunkRanges::empty(), 4),
+    ];
+    for (size, a, b, block_level) in cases {
+        decode_with_outboard_impl(size, &a, &b, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_with_outboard_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] a: (usize, ChunkRanges),
+    #[strategy(selection(100000, 2))] b: ChunkRanges,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, a) = a;
+    decode_with_outboard_impl(size, &a, &b, block_size);
+}
+
+/// Check that a hash pair that does not verify is not saved
+#[test]
+fn decode_with_outboard_corrupt() {
+    let block_size = BlockSize(1);
+    let data = make_test_data(100000);
+    let full = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &full, &ranges, &mut encoded).unwrap();
+    // the root pair
+    encoded[8] ^= 1;
+    let mut outboard =
+        PostOrderMemOutboard::new(full.root(), full.tree(), vec![0; full.data.len()]).unwrap();
+    let res = crate::io::sync::decode_ranges_with_outboard(
+        full.root(),
+        block_size,
+        &ranges,
+        encoded.as_slice(),
+        &mut outboard,
+        Vec::new(),
+    );
+    assert!(res.is_err());
+    assert!(outboard.data.iter().all(|x| *x == 0));
+}
+
+/// Check that a canonicalized query produces the same response as the original query
+fn canonicalize_ranges_impl(size: usize, ranges: ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let canonical = crate::ranges::canonicalize_ranges(ranges.clone(), ByteNum(size as u64));
+    let mut expected = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut expected).unwrap();
+    let mut actual = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &canonical, &mut actual).unwrap();
+    assert_eq!(actual, expected);
+    // canonicalization is idempotent
+    assert_eq!(
+        crate::ranges::canonicalize_ranges(canonical.clone(), ByteNum(size as u64)),
+        canonical
+    );
+}
+
+#[test]
+fn canonicalize_ranges_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (7 * 1024, ChunkRanges::from(ChunkNum(0)..ChunkNum(10)), 0),
+        (7 * 1024, ChunkRanges::from(ChunkNum(100)..), 1),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+    ];
+    for (size, ranges, block_level) in cases {
+        canonicalize_ranges_impl(size, ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn canonicalize_ranges_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    canonicalize_ranges_impl(size, ranges, block_size);
+}
+
+/// Check that validate_slice proves the ranges that the response covers
+fn validate_slice_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let proven =
+        crate::io::sync::validate_slice(outboard.root(), block_size, ranges, encoded.as_slice())
+            .unwrap();
+    let expected = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    assert_eq!(proven, expected);
+    if encoded.len() > 8 {
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let res = crate::io::sync::validate_slice(
+            outboard.root(),
+            block_size,
+            ranges,
+            encoded.as_slice(),
+        );
+        assert!(res.is_err());
+    }
+}
+
+#[test]
+fn validate_slice_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        validate_slice_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn validate_slice_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    validate_slice_impl(size, &ranges, block_size);
+}
+
+/// Check that validate_passthrough forwards a valid response unchanged, and that it
+/// stops forwarding before a corrupted item
+fn validate_passthrough_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    use crate::io::sync::validate_passthrough;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut output = Vec::new();
+    let stats = validate_passthrough(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        &mut output,
+    )
+    .unwrap();
+    assert_eq!(output, encoded);
+    assert_eq!(stats.wire_bytes, encoded.len() as u64);
+    if encoded.len() > 8 {
+        let pos = 8 + (encoded.len() - 8) / 2;
+        let mut corrupted = encoded.clone();
+        corrupted[pos] ^= 1;
+        let mut output = Vec::new();
+        let res = validate_passthrough(
+            outboard.root(),
+            block_size,
+            ranges,
+            corrupted.as_slice(),
+            &mut output,
+        );
+        assert!(res.is_err());
+        assert!(output.len() <= pos);
+        assert_eq!(output.as_slice(), &encoded[..output.len()]);
+    }
+}
+
+#[test]
+fn validate_passthrough_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (0, ChunkRanges::empty(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        validate_passthrough_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn validate_passthrough_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    validate_passthrough_impl(size, &ranges, block_size);
+}
+
+/// Check that the outboard computed from a file is the same as the in memory outboard
+fn outboard_post_order_file_impl(size: usize, block_size: BlockSize, buffer_size: usize) {
+    use std::io::{Read, Seek, Write};
+    let data = make_test_data(size);
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    let dir = tempfile::tempdir().unwrap();
+    let mut data_file = std::fs::File::options()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(dir.path().join("data"))
+        .unwrap();
+    data_file.write_all(&data).unwrap();
+    let mut outboard_file = std::fs::File::options()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(dir.path().join("outboard"))
+        .unwrap();
+    // garbage from a previous, larger outboard
+    outboard_file.write_all(&vec![0xff; 100000]).unwrap();
+    let mut progress = Vec::new();
+    let hash = crate::io::sync::OutboardBuilder::new(block_size)
+        .progress(|done, _| {
+            progress.push(done);
+            std::ops::ControlFlow::Continue(())
+        })
+        .write_file(&data_file, &mut outboard_file, buffer_size)
+        .unwrap();
+    assert_eq!(hash, expected.root());
+    let mut outboard = Vec::new();
+    outboard_file.rewind().unwrap();
+    outboard_file.read_to_end(&mut outboard).unwrap();
+    assert_eq!(outboard, expected.into_inner_with_suffix());
+    assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(
+        progress.last().copied().unwrap_or_default(),
+        ByteNum(size as u64)
+    );
+}
+
+#[test]
+fn outboard_post_order_file_cases() {
+    let cases = [
+        (0, 0, 1024),
+        (1024, 0, 1),
+        (100000, 2, 4096),
+        (100000, 4, 1 << 20),
+    ];
+    for (size, block_level, buffer_size) in cases {
+        outboard_post_order_file_impl(size, BlockSize(block_level), buffer_size);
+    }
+}
+
+/// Check that hash mismatches are displayed with the location of the failing item
+#[test]
+fn decode_error_display() {
+    let block_size = BlockSize(2);
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let decode = |encoded: &[u8]| {
+        crate::io::sync::verify_ranges(outboard.root(), block_size, &ranges, encoded).unwrap_err()
+    };
+    // the root pair
+    let mut corrupted = encoded.clone();
+    corrupted[8] ^= 1;
+    let err = decode(&corrupted);
+    let AnyDecodeError::ParentHashMismatch(m) = &err else {
+        panic!("expected a parent hash mismatch, got {err:?}");
+    };
+    let (l_hash, r_hash) = parse_hash_pair(corrupted[8..72].try_into().unwrap());
+    assert_eq!(m.expected, outboard.root());
+    assert_eq!(m.actual, blake3::guts::parent_cv(&l_hash, &r_hash, true));
+    let chunks = m.node.chunk_range();
+    let expected = format!(
+        "parent hash mismatch at node {} (level {}, blocks {}..{}): expected {}, actual {}",
+        m.node,
+        m.node.level(),
+        chunks.start.0 >> 2,
+        (chunks.end.0 + 3) >> 2,
+        m.expected.to_hex(),
+        m.actual.to_hex()
+    );
+    assert_eq!(err.to_string(), expected);
+    assert_eq!(std::io::Error::from(err).to_string(), expected);
+    // the last leaf, which is a right child without a sibling
+    let mut corrupted = encoded;
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 1;
+    let err = decode(&corrupted);
+    let AnyDecodeError::LeafHashMismatch(m) = &err else {
+        panic!("expected a leaf hash mismatch, got {err:?}");
+    };
+    assert_eq!(m.chunks, ChunkNum(96)..ChunkNum(98));
+    assert!(!m.left);
+    let start = m.chunks.start.to_bytes().to_usize();
+    let mut leaf = data[start..].to_vec();
+    assert_eq!(m.expected, hash_subtree(96, &leaf, false));
+    *leaf.last_mut().unwrap() ^= 1;
+    assert_eq!(m.actual, hash_subtree(96, &leaf, false));
+    let expected = format!(
+        "leaf hash mismatch at chunks 96..98 (blocks 24..25, right child): expected {}, actual {}",
+        m.expected.to_hex(),
+        m.actual.to_hex()
+    );
+    assert_eq!(err.to_string(), expected);
+    // the first leaf is a left child
+    let mut corrupted = data.clone();
+    corrupted[0] ^= 1;
+    let res =
+        crate::io::sync::encode_ranges_validated(&corrupted, &outboard, &ranges, &mut Vec::new());
+    let Err(EncodeError::LeafHashMismatch(m)) = &res else {
+        panic!("expected a leaf hash mismatch, got {res:?}");
+    };
+    assert_eq!(m.chunks, ChunkNum(0)..ChunkNum(4));
+    assert!(m.left);
+    assert!(res
+        .unwrap_err()
+        .to_string()
+        .contains("blocks 0..1, left child"));
+}
+
+/// Check that progress is reported after each leaf, and ends at the total number of
+/// bytes, both when computing an outboard and when decoding
+fn progress_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    let mut progress = Vec::new();
+    let mut outboard = Vec::new();
+    let hash = crate::io::sync::OutboardBuilder::new(block_size)
+        .progress(|n, total| {
+            assert_eq!(total, ByteNum(size as u64));
+            progress.push(n);
+            std::ops::ControlFlow::Continue(())
+        })
+        .write(data.as_slice(), size as u64, &mut outboard)
+        .unwrap();
+    assert_eq!(hash, expected.root());
+    assert_eq!(outboard, expected.clone().into_inner_with_suffix());
+    let leaves = expected
+        .tree()
+        .post_order_chunks_iter()
+        .filter(|item| matches!(item, BaoChunk::Leaf { .. }))
+        .count();
+    assert_eq!(progress.len(), leaves);
+    assert!(progress.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(progress.last().copied(), Some(ByteNum(size as u64)));
+
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &expected, ranges, &mut encoded).unwrap();
+    let mut progress = Vec::new();
+    let mut totals = Vec::new();
+    crate::io::sync::decode_response_into_with_progress(
+        expected.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(crate::io::outboard::EmptyOutboard::new(tree, root)),
+        Vec::new(),
+        |n, total| {
+            progress.push(n);
+            totals.push(total);
+            std::ops::ControlFlow::Continue(())
+        },
+    )
+    .unwrap();
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    let total = covered
+        .iter()
+        .map(|range| {
+            let range_collections::range_set::RangeSetRange::Range(range) = range else {
+                panic!("covered ranges are bounded");
+            };
+            range.end.to_bytes().min(ByteNum(size as u64)) - range.start.to_bytes()
+        })
+        .fold(ByteNum(0), |a, b| a + b);
+    assert_eq!(progress.last().copied().unwrap_or_default(), total);
+    assert!(totals.iter().all(|t| *t == total));
+}
+
+#[test]
+fn progress_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(50)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        progress_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+/// Returning break from a progress callback cancels the operation
+#[test]
+fn progress_cancel() {
+    use crate::io::Cancelled;
+    use std::ops::ControlFlow;
+    let data = make_test_data(100000);
+    let block_size = BlockSize(2);
+    let mut calls = 0;
+    let err = crate::io::sync::OutboardBuilder::new(block_size)
+        .progress(|_, _| {
+            calls += 1;
+            ControlFlow::Break(())
+        })
+        .write(data.as_slice(), data.len() as u64, Vec::new())
+        .unwrap_err();
+    assert!(Cancelled::is_cancelled(&err));
+    assert_eq!(calls, 1);
+
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ChunkRanges::all(), &mut encoded).unwrap();
+    let mut target = crate::io::sync::SeekWriter(std::io::Cursor::new(Vec::new()));
+    let err = crate::io::sync::decode_response_into_with_progress(
+        outboard.root(),
+        block_size,
+        &ChunkRanges::all(),
+        encoded.as_slice(),
+        |tree, root| Ok(crate::io::outboard::EmptyOutboard::new(tree, root)),
+        &mut target,
+        |done, _| {
+            if done >= ByteNum(10000) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        },
+    )
+    .unwrap_err();
+    assert!(Cancelled::is_cancelled(&err));
+    assert!(target.0.get_ref().len() < data.len());
+    assert!(!Cancelled::is_cancelled(&std::io::ErrorKind::Other.into()));
+}
+
+#[proptest]
+fn progress_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    progress_impl(size, &ranges, block_size);
+}
+
+/// Check that after decoding a truncated response, bytes_read is the position where
+/// the response was cut off
+fn bytes_read_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    for end in 0..=encoded.len() {
+        let mut iter = crate::io::sync::DecodeResponseIter::new(
+            outboard.root(),
+            block_size,
+            &encoded[..end],
+            ranges,
+        );
+        let res = iter.by_ref().collect::<Result<Vec<_>, _>>();
+        assert_eq!(res.is_ok(), end == encoded.len());
+        assert_eq!(iter.bytes_read(), end as u64);
+    }
+}
+
+#[test]
+fn bytes_read_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1024, ChunkRanges::all(), 0),
+        (10000, ChunkRanges::all(), 0),
+        (10000, ChunkRanges::all(), 2),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        bytes_read_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+/// Check the number of hash pairs against the traversals, for small trees of 1 to 64
+/// blocks, with a full or a partial last block
+#[test]
+fn hash_pairs_small_trees() {
+    for block_level in 0..4 {
+        let block_size = BlockSize(block_level);
+        let block_bytes = block_size.bytes() as u64;
+        for blocks in 1..=64u64 {
+            for size in [blocks * block_bytes, (blocks - 1) * block_bytes + 1] {
+                let tree = BaoTree::new(ByteNum(size), block_size);
+                assert_eq!(tree.blocks(), BlockNum(blocks));
+                let pairs = tree.outboard_hash_pairs();
+                assert_eq!(pairs, blocks - 1);
+                let parents = tree
+                    .post_order_chunks_iter()
+                    .filter(|item| matches!(item, BaoChunk::Parent { .. }))
+                    .count() as u64;
+                assert_eq!(parents, pairs);
+                let post_order = tree
+                    .post_order_nodes_iter()
+                    .filter(|node| tree.post_order_offset(*node).is_some())
+                    .count() as u64;
+                assert_eq!(post_order, pairs);
+                let pre_order = tree
+                    .pre_order_nodes_iter()
+                    .filter(|node| tree.pre_order_offset(*node).is_some())
+                    .count() as u64;
+                assert_eq!(pre_order, pairs);
+            }
+        }
+    }
+}
+
+/// Check that the decode into functions return an error instead of panicking when the
+/// response ends early, at the start, in the middle, or at the end of any item
+fn decode_into_truncated_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    use crate::layout::expected_layout;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut cuts = Vec::new();
+    let mut offset = 0;
+    for item in expected_layout(ByteNum(size as u64), block_size, ranges) {
+        let len = item.len() as usize;
+        if len > 0 {
+            cuts.extend([offset, offset + len / 2, offset + len - 1]);
+        }
+        offset += len;
+    }
+    assert_eq!(offset, encoded.len());
+    cuts.dedup();
+    for cut in cuts {
+        let truncated = &encoded[..cut];
+        let res = crate::io::sync::decode_response_into(
+            outboard.root(),
+            block_size,
+            ranges,
+            truncated,
+            |tree, root| {
+                let outboard_data =
+                    vec![0; usize::try_from(tree.outboard_hash_pairs() * 64).unwrap()];
+                Ok(PostOrderMemOutboard::new(root, tree, outboard_data).unwrap())
+            },
+            Vec::new(),
+        );
+        let err = res.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let mut partial = PostOrderMemOutboard::new(
+            outboard.root(),
+            outboard.tree(),
+            vec![0; outboard.data.len()],
+        )
+        .unwrap();
+        let res = crate::io::sync::decode_ranges_with_outboard(
+            outboard.root(),
+            block_size,
+            ranges,
+            truncated,
+            &mut partial,
+            Vec::new(),
+        );
+        let err = res.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let mut decoded = BytesMut::new();
+        let res = futures::executor::block_on(crate::io::fsm::decode_response_into(
+            outboard.root(),
+            block_size,
+            ranges.clone(),
+            std::io::Cursor::new(truncated),
+            |root, tree| async move {
+                let outboard_data =
+                    vec![0; usize::try_from(tree.outboard_hash_pairs() * 64).unwrap()];
+                Ok(PostOrderMemOutboard::new(root, tree, outboard_data).unwrap())
+            },
+            &mut decoded,
+        ));
+        let err = res.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
+
+#[test]
+fn decode_into_truncated_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::all(), 0),
+        (1024 * 16 + 1, ChunkRanges::all(), 2),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 4),
+        (100000, ChunkRanges::from(ChunkNum(50)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        decode_into_truncated_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_into_truncated_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_into_truncated_impl(size, &ranges, block_size);
+}
+
+/// Split a tree at a level, hash the subtrees independently, and check that they
+/// cover the data and combine to the root hash
+fn split_at_level_impl(size: usize, level: u32) {
+    let data = make_test_data(size);
+    let tree = BaoTree::new(ByteNum(size as u64), BlockSize::ZERO);
+    let subtrees = tree.split_at_level(level);
+    let mut end = ByteNum(0);
+    let mut hashes = Vec::new();
+    for (node, range) in &subtrees {
+        if subtrees.len() > 1 {
+            assert_eq!(node.level(), level);
+        }
+        assert_eq!(range.start, end);
+        end = range.end;
+        let data = &data[range.start.to_usize()..range.end.to_usize()];
+        hashes.push(crate::io::sync::subtree_hash(&tree, *node, data).unwrap());
+    }
+    assert_eq!(end, tree.size());
+    let root = crate::io::sync::combine_subtree_hashes(&hashes).unwrap();
+    assert_eq!(root, blake3::hash(&data));
+}
+
+#[test]
+fn split_at_level_cases() {
+    for size in [0, 1, 1024, 1025, 2048, 2049, 1024 * 16 + 1, 100000] {
+        for level in 0..8 {
+            split_at_level_impl(size, level);
+        }
+    }
+}
+
+#[proptest]
+fn split_at_level_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(0u32..10)] level: u32,
+) {
+    split_at_level_impl(size, level);
+}
+
+#[test]
+fn subtree_hash_size_mismatch() {
+    let tree = BaoTree::new(ByteNum(100000), BlockSize::ZERO);
+    let (node, _) = tree.split_at_level(2)[0];
+    let err = crate::io::sync::subtree_hash(&tree, node, &[0; 10]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// Check that decoders reject a size above the maximum before decoding anything
+#[test]
+fn decode_size_too_large() {
+    let data = make_test_data(100000);
+    let block_size = BlockSize(2);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let decode = |encoded: &[u8], max_size: ByteNum| {
+        crate::io::sync::DecodeResponseIter::new(outboard.root(), block_size, encoded, &ranges)
+            .with_max_size(max_size)
+            .collect::<Result<Vec<_>, _>>()
+    };
+    // a custom limit
+    assert!(decode(&encoded, ByteNum(100000)).is_ok());
+    let err = decode(&encoded, ByteNum(99999)).unwrap_err();
+    assert!(matches!(err, AnyDecodeError::SizeTooLarge(ByteNum(100000))));
+    assert_eq!(
+        std::io::Error::from(err).kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    // the default limit
+    let mut absurd = encoded.clone();
+    absurd[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    let err = decode(&absurd, ByteNum(u64::MAX)).unwrap_err();
+    assert!(matches!(
+        err,
+        AnyDecodeError::SizeTooLarge(ByteNum(u64::MAX))
+    ));
+    let err = crate::io::sync::validate_slice(outboard.root(), block_size, &ranges, &absurd[..])
+        .unwrap_err();
+    assert!(matches!(err, AnyDecodeError::SizeTooLarge(_)));
+    let mut decoder = SliceDecoder::new(outboard.root(), block_size, ranges.clone());
+    let err = decoder.push(&absurd).unwrap_err();
+    assert!(matches!(err, AnyDecodeError::SizeTooLarge(_)));
+    // the fsm decoder
+    let start = |encoded: Vec<u8>, max_size: ByteNum| {
+        let start = crate::io::fsm::ResponseDecoderStart::new(
+            outboard.root(),
+            ranges.clone(),
+            block_size,
+            std::io::Cursor::new(encoded),
+        )
+        .with_max_size(max_size);
+        futures::executor::block_on(start.next()).map(|(_, size)| size)
+    };
+    assert_eq!(start(encoded.clone(), ByteNum(100000)).unwrap(), 100000);
+    let err = start(encoded, ByteNum(99999)).unwrap_err();
+    assert!(matches!(
+        err,
+        StartDecodeError::SizeTooLarge(ByteNum(100000))
+    ));
+    let err = start(absurd, ByteNum(u64::MAX)).unwrap_err();
+    assert!(matches!(
+        err,
+        StartDecodeError::SizeTooLarge(ByteNum(u64::MAX))
+    ));
+}
+
+/// A target for decoding that records the offsets of all writes
+struct RecordingTarget<'a> {
+    data: &'a mut Vec<u8>,
+    writes: &'a mut Vec<u64>,
+}
+
+impl<'a> crate::io::sync::ReadAt for RecordingTarget<'a> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data: &[u8] = self.data;
+        crate::io::sync::ReadAt::read_at(&data, pos, buf)
+    }
+}
+
+impl<'a> crate::io::sync::WriteAt for RecordingTarget<'a> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let start = pos as usize;
+        let end = start + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(buf);
+        self.writes.push(pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decode a blob over a partial and partially wrong copy of it, and check that only
+/// the leaves that differ are written
+fn decode_into_existing_impl(size: usize, block_size: BlockSize, changed: usize) {
+    use crate::io::{outboard::EmptyOutboard, sync::decode_response_into_existing};
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let covered = crate::io::covered_chunk_ranges(size as u64, &ranges, block_size);
+    let mut existing = data[..size / 2].to_vec();
+    if changed < existing.len() {
+        existing[changed] ^= 1;
+    }
+    let mut writes = Vec::new();
+    let target = RecordingTarget {
+        data: &mut existing,
+        writes: &mut writes,
+    };
+    let (decoded_size, _, res) = decode_response_into_existing(
+        outboard.root(),
+        block_size,
+        &ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(EmptyOutboard::new(tree, root)),
+        target,
+    )
+    .unwrap();
+    assert_eq!(decoded_size, ByteNum(size as u64));
+    assert_eq!(existing, data);
+    let mut both = res.present.clone();
+    both &= res.written.clone();
+    assert!(both.is_empty());
+    let mut all = res.present.clone();
+    all |= res.written.clone();
+    assert_eq!(all, covered);
+    if changed < size / 2 {
+        let mut touched = res.written.clone();
+        touched &= ChunkRanges::from(ByteNum(changed as u64).full_chunks()..);
+        assert!(!touched.is_empty());
+    }
+    // now everything is present, so nothing is written
+    let mut writes = Vec::new();
+    let target = RecordingTarget {
+        data: &mut existing,
+        writes: &mut writes,
+    };
+    let (_, _, res) = decode_response_into_existing(
+        outboard.root(),
+        block_size,
+        &ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(EmptyOutboard::new(tree, root)),
+        target,
+    )
+    .unwrap();
+    assert!(writes.is_empty());
+    assert!(res.written.is_empty());
+    assert_eq!(res.present, covered);
+}
+
+/// A target that can be written, but fails to read
+struct UnreadableTarget(Vec<u8>);
+
+impl crate::io::sync::ReadAt for UnreadableTarget {
+    fn read_at(&self, _pos: u64, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+}
+
+impl crate::io::sync::WriteAt for UnreadableTarget {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let start = pos as usize;
+        let end = start + buf.len();
+        if self.0.len() < end {
+            self.0.resize(end, 0);
+        }
+        self.0[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that a read error other than the end of the target is returned, instead of
+/// treating the leaf as not present
+#[test]
+fn decode_into_existing_read_error() {
+    use crate::io::{outboard::EmptyOutboard, sync::decode_response_into_existing};
+    let data = make_test_data(100000);
+    let block_size = BlockSize(2);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let err = decode_response_into_existing(
+        outboard.root(),
+        block_size,
+        &ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(EmptyOutboard::new(tree, root)),
+        UnreadableTarget(Vec::new()),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn decode_into_existing_cases() {
+    let cases = [
+        (0, 0, 0),
+        (100000, 0, 5000),
+        (100000, 4, 0),
+        (100000, 2, 100000),
+    ];
+    for (size, block_level, changed) in cases {
+        decode_into_existing_impl(size, BlockSize(block_level), changed);
+    }
+}
+
+#[proptest]
+fn decode_into_existing_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] block_size: BlockSize,
+    #[strategy(0usize..100000)] changed: usize,
+) {
+    decode_into_existing_impl(size, block_size, changed);
+}
+
+/// Decode a part of a blob into a file with decode_response_into_file, and check the
+/// length of the file and the data
+fn decode_into_file_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize, set_len: bool) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut file = tempfile::tempfile().unwrap();
+    let (decoded_size, _) = crate::io::sync::decode_response_into_file(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        |tree, root| Ok(crate::io::outboard::EmptyOutboard::new(tree, root)),
+        &mut file,
+        set_len,
+    )
+    .unwrap();
+    assert_eq!(decoded_size, ByteNum(size as u64));
+    let mut written = Vec::new();
+    std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(0)).unwrap();
+    std::io::Read::read_to_end(&mut file, &mut written).unwrap();
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    // without set_len, the file ends after the last range that was written
+    let end = match covered.boundaries().last() {
+        Some(end) if !set_len => end.to_bytes().to_usize().min(size),
+        _ => size,
+    };
+    assert_eq!(written.len(), end);
+    for range in covered.iter() {
+        let range_collections::range_set::RangeSetRange::Range(range) = range else {
+            panic!("covered ranges are finite");
+        };
+        let start = range.start.to_bytes().to_usize();
+        let end = range.end.to_bytes().to_usize().min(size);
+        assert_eq!(written[start..end], data[start..end]);
+    }
+}
+
+#[test]
+fn decode_into_file_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::from(ChunkNum(97)..), 2),
+        (100000, ChunkRanges::from(ChunkNum(0)..ChunkNum(1)), 2),
+        (100000, ChunkRanges::from(ChunkNum(30)..ChunkNum(40)), 0),
+    ];
+    for (size, ranges, block_level) in cases {
+        for set_len in [false, true] {
+            decode_into_file_impl(size, &ranges, BlockSize(block_level), set_len);
+        }
+    }
+}
+
+/// Check that decode_response_into_file with set_len set to false leaves a longer
+/// file alone
+#[test]
+fn decode_into_file_keep_len() {
+    let data = make_test_data(100000);
+    let block_size = BlockSize(2);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::from(ChunkNum(97)..);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    for (set_len, expected) in [(false, 200000), (true, 100000)] {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(200000).unwrap();
+        crate::io::sync::decode_response_into_file(
+            outboard.root(),
+            block_size,
+            &ranges,
+            encoded.as_slice(),
+            |tree, root| Ok(crate::io::outboard::EmptyOutboard::new(tree, root)),
+            &mut file,
+            set_len,
+        )
+        .unwrap();
+        assert_eq!(file.metadata().unwrap().len(), expected);
+    }
+}
+
+/// Check that a sparse query only visits the nodes on the paths to the requested
+/// chunks, not all nodes of the tree, and only keeps nodes that intersect the query
+/// on the stack
+#[test]
+fn sparse_query_node_count() {
+    // the most nodes on the stack of the pre order iterator at any time
+    let max_pending = |tree: BaoTree, ranges: &ChunkRanges| {
+        let mut iter = tree.ranges_pre_order_nodes_iter(ranges, 0);
+        let mut max = iter.pending();
+        while iter.next().is_some() {
+            max = max.max(iter.pending());
+        }
+        max
+    };
+    // 2^20 chunks
+    let size = ByteNum(1 << 30);
+    for block_level in 0..4 {
+        let tree = BaoTree::new(size, BlockSize(block_level));
+        let depth = tree.shifted().0.level() as usize + 1;
+        let last = size.chunks() - 1;
+        let single = [ChunkNum(0), ChunkNum(12345), last];
+        for chunk in single {
+            let ranges = ChunkRanges::from(chunk..chunk + 1);
+            let nodes = tree.ranges_pre_order_nodes_iter(&ranges, 0).count();
+            assert!(nodes <= depth, "{nodes} nodes for chunk {chunk}");
+            // children outside of the query are not pushed, so there is never more
+            // than one pending node
+            assert_eq!(max_pending(tree, &ranges), 1);
+            let items = ResponseIterRef::new(tree, &ranges).count();
+            // the leaf is an extra item, and a partial leaf has the nodes below the block size
+            assert!(items <= depth + 1 + block_level as usize);
+        }
+        let mut ranges = ChunkRanges::empty();
+        for chunk in single {
+            ranges |= ChunkRanges::from(chunk..chunk + 1);
+        }
+        let nodes = tree.ranges_pre_order_nodes_iter(&ranges, 0).count();
+        assert!(nodes <= single.len() * depth);
+        // each pending node contains at least one of the requested chunks
+        assert!(max_pending(tree, &ranges) <= single.len());
+    }
+}
+
+/// Decode into a slice, and check the written ranges and the data in them
+fn decode_into_slice_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut target = vec![0u8; size];
+    let written = crate::io::sync::decode_ranges_into_slice(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        &mut target,
+    )
+    .unwrap();
+    let expected = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    assert_eq!(written, expected);
+    for range in written.iter() {
+        let range_collections::range_set::RangeSetRange::Range(range) = range else {
+            panic!("written ranges are finite");
+        };
+        let start = range.start.to_bytes().to_usize();
+        let end = range.end.to_bytes().to_usize().min(size);
+        assert_eq!(target[start..end], data[start..end]);
+    }
+    // a target that is too small
+    if size > 0 {
+        let mut target = vec![0u8; size - 1];
+        let err = crate::io::sync::decode_ranges_into_slice(
+            outboard.root(),
+            block_size,
+            ranges,
+            encoded.as_slice(),
+            &mut target,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, AnyDecodeError::SizeTooLarge(actual) if actual == ByteNum(size as u64))
+        );
+        assert!(target.iter().all(|x| *x == 0));
+    }
+}
+
+#[test]
+fn decode_into_slice_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        decode_into_slice_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_into_slice_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_into_slice_impl(size, &ranges, block_size);
+}
+
+/// Read an encoded response item by item with EncodedSlice, and check that the items
+/// are the same as those of the decoder
+fn encoded_slice_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    use crate::io::sync::EncodedSlice;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    let mut reader = EncodedSlice::new(encoded.as_slice());
+    let size = reader.read_header().unwrap();
+    let Some(Ok(DecodeResponseItem::Header(header))) = expected.next() else {
+        panic!("expected a header");
+    };
+    assert_eq!(size, header.size);
+    let tree = BaoTree::new(size, block_size);
+    let ranges = crate::ranges::canonicalize_ranges(ranges.clone(), size);
+    for item in ResponseIterRef::new(tree, &ranges) {
+        let expected = expected.next().unwrap().unwrap();
+        match (item, expected) {
+            (BaoChunk::Parent { node, .. }, DecodeResponseItem::Parent(parent)) => {
+                assert_eq!(node, parent.node);
+                assert_eq!(reader.read_parent().unwrap(), parent.pair);
+            }
+            (BaoChunk::Leaf { size, .. }, DecodeResponseItem::Leaf(leaf)) => {
+                let mut buf = vec![0u8; size];
+                reader.read_leaf(&mut buf).unwrap();
+                assert_eq!(buf, leaf.data);
+            }
+            (item, expected) => panic!("{item:?} does not match {expected:?}"),
+        }
+    }
+    assert!(expected.next().is_none());
+    assert!(reader.into_inner().is_empty());
+}
+
+#[test]
+fn encoded_slice_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        encoded_slice_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+/// Check that reading past the end of the stream is an unexpected eof
+#[test]
+fn encoded_slice_eof() {
+    let mut reader = crate::io::sync::EncodedSlice::new(&[0u8; 4][..]);
+    let err = reader.read_header().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    let mut reader = crate::io::sync::EncodedSlice::new(&[0u8; 32][..]);
+    let err = reader.read_parent().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+/// Check that the decoders reuse the allocation of their buffer for all leaves, as
+/// long as the data of each leaf is dropped before the next one is read
+#[test]
+fn decode_reuses_buffer() {
+    let data = make_test_data(100000);
+    let block_size = BlockSize(2);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+    let mut ptrs = std::collections::BTreeSet::new();
+    let iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        &ranges,
+    );
+    for item in iter {
+        if let DecodeResponseItem::Leaf(leaf) = item.unwrap() {
+            ptrs.insert(leaf.data.as_ptr() as usize);
+        }
+    }
+    assert_eq!(ptrs.len(), 1);
+    let mut ptrs = std::collections::BTreeSet::new();
+    let start = crate::io::fsm::ResponseDecoderStart::new(
+        outboard.root(),
+        ranges,
+        block_size,
+        std::io::Cursor::new(encoded.as_slice()),
+    );
+    let (mut reading, _) = futures::executor::block_on(start.next()).unwrap();
+    while let ResponseDecoderReadingNext::More((next, item)) =
+        futures::executor::block_on(reading.next())
+    {
+        if let BaoContentItem::Leaf(leaf) = item.unwrap() {
+            ptrs.insert(leaf.data.as_ptr() as usize);
+        }
+        reading = next;
+    }
+    assert_eq!(ptrs.len(), 1);
+}
+
+/// Check the checked conversions of the number newtypes at the overflow boundaries
+#[test]
+fn checked_conversions() {
+    let max_chunk = ChunkNum(u64::MAX >> 10);
+    assert_eq!(max_chunk.checked_to_bytes(), Some(max_chunk.to_bytes()));
+    assert_eq!((max_chunk + 1).checked_to_bytes(), None);
+    let bs = BlockSize(4);
+    let max_block = BlockNum(u64::MAX >> 14);
+    assert_eq!(
+        max_block.checked_to_chunks(bs),
+        Some(max_block.to_chunks(bs))
+    );
+    assert_eq!(max_block.checked_to_bytes(bs), Some(max_block.to_bytes(bs)));
+    assert_eq!((max_block + 1).checked_to_bytes(bs), None);
+    assert_eq!(
+        (max_block + 1).checked_to_chunks(bs),
+        Some((max_block + 1).to_chunks(bs))
+    );
+    assert_eq!(
+        ByteNum(u64::MAX).checked_blocks(BlockSize::MAX),
+        Some(ByteNum(u64::MAX).blocks(BlockSize::MAX))
+    );
+    assert_eq!(ByteNum(1).checked_blocks(BlockSize(54)), None);
+    assert_eq!(
+        ByteNum(u64::MAX).try_to_usize().ok(),
+        usize::try_from(u64::MAX).ok()
+    );
+    assert_eq!(ByteNum(1000).try_to_usize(), Ok(1000));
+    // the byte range of the root of a tree that is larger than 2^63 bytes
+    for size in [(1 << 63) + 1, u64::MAX] {
+        let tree = BaoTree::new(ByteNum(size), BlockSize::ZERO);
+        assert_eq!(tree.byte_range(tree.root()), ByteNum(0)..ByteNum(size));
+    }
+}
+
+/// Decode with a callback, and compare the leaves with the ones of the iterator
+fn decode_with_callback_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let mut leaves = Vec::new();
+    let actual_size = crate::io::sync::decode_ranges_with(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        |offset, data| {
+            leaves.push((offset, data.to_vec()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(actual_size, ByteNum(size as u64));
+    let expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .filter_map(|item| match item.unwrap() {
+        DecodeResponseItem::Leaf(Leaf { offset, data }) => Some((offset, data.to_vec())),
+        _ => None,
+    })
+    .collect::<Vec<_>>();
+    assert_eq!(leaves, expected);
+    // an error from the callback aborts decoding
+    let mut calls = 0;
+    let res = crate::io::sync::decode_ranges_with(
+        outboard.root(),
+        block_size,
+        ranges,
+        encoded.as_slice(),
+        |_, _| {
+            calls += 1;
+            Err(std::io::ErrorKind::WriteZero.into())
+        },
+    );
+    if expected.is_empty() {
+        assert!(res.is_ok());
+    } else {
+        assert!(
+            matches!(res, Err(AnyDecodeError::Io(e)) if e.kind() == std::io::ErrorKind::WriteZero)
+        );
+    }
+    assert_eq!(calls, expected.len().min(1));
+}
+
+#[test]
+fn decode_with_callback_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::all(), 4),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+    ];
+    for (size, ranges, block_level) in cases {
+        decode_with_callback_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn decode_with_callback_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    decode_with_callback_impl(size, &ranges, block_size);
+}
+
+/// Check that the decoders report the canonicalized ranges that were delivered
+fn verified_ranges_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    let expected = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    );
+    for item in iter.by_ref() {
+        item.unwrap();
+    }
+    assert_eq!(iter.verified_ranges(), &expected);
+    assert_eq!(iter.tree().unwrap().size(), ByteNum(size as u64));
+    let start = crate::io::fsm::ResponseDecoderStart::new(
+        outboard.root(),
+        ranges.clone(),
+        block_size,
+        std::io::Cursor::new(encoded.as_slice()),
+    );
+    let (mut reading, _) = futures::executor::block_on(start.next()).unwrap();
+    let mut verified = ChunkRanges::empty();
+    while let ResponseDecoderReadingNext::More((next, item)) =
+        futures::executor::block_on(reading.next())
+    {
+        item.unwrap();
+        verified = next.verified_ranges().clone();
+        reading = next;
+    }
+    assert_eq!(verified, expected);
+    // a truncated response only reports the leaves before the end
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        &encoded[..encoded.len() - 1],
+        ranges,
+    );
+    for item in iter.by_ref() {
+        if item.is_err() {
+            break;
+        }
+    }
+    let mut intersection = iter.verified_ranges().clone();
+    intersection &= expected.clone();
+    assert_eq!(&intersection, iter.verified_ranges());
+    assert!(expected.is_empty() || iter.verified_ranges() != &expected);
+}
+
+#[test]
+fn verified_ranges_cases() {
+    let cases = [
+        (0, ChunkRanges::all(), 0),
+        (1, ChunkRanges::all(), 0),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(7)), 2),
+        (100000, ChunkRanges::from(ChunkNum(500)..), 1),
+        (100000, ChunkRanges::from(ChunkNum(90)..ChunkNum(200)), 0),
+    ];
+    for (size, ranges, block_level) in cases {
+        verified_ranges_impl(size, &ranges, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn verified_ranges_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    verified_ranges_impl(size, &ranges, block_size);
+}
+
+/// The single chunk fast path of hash_subtree gives the same hashes as blake3
+#[test]
+fn hash_subtree_single_chunk() {
+    for size in [0, 1, 2, 100, 512, 1023, 1024] {
+        let data = make_test_data(size);
+        assert_eq!(hash_subtree(0, &data, true), blake3::hash(&data));
+        if size.is_power_of_two() {
+            for start_chunk in [0, 1, 7] {
+                assert_eq!(
+                    hash_subtree(start_chunk, &data, false),
+                    blake3::guts::hash_subtree(start_chunk, &data, false)
+                );
+            }
+        }
+    }
+}
+
+/// Check the decode statistics for small trees, where they are easy to compute
+/// Corrupt a byte of a response, and check that decoding with continue_on_mismatch
+/// recovers everything that is not below the corrupted item
+fn continue_on_mismatch_impl(size: usize, ranges: &ChunkRanges, block_size: BlockSize, pos: usize) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data, &outboard, ranges, &mut encoded).unwrap();
+    if encoded.len() <= 8 {
+        return;
+    }
+    let pos = 8 + pos % (encoded.len() - 8);
+    encoded[pos] ^= 1;
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root(),
+        block_size,
+        encoded.as_slice(),
+        ranges,
+    )
+    .continue_on_mismatch();
+    let mut errors = 0;
+    for item in iter.by_ref() {
+        match item {
+            Ok(DecodeResponseItem::Leaf(Leaf { offset, data: leaf })) => {
+                let start = offset.to_usize();
+                assert_eq!(leaf, &data[start..start + leaf.len()]);
+            }
+            Ok(_) => {}
+            Err(AnyDecodeError::LeafHashMismatch(_) | AnyDecodeError::ParentHashMismatch(_)) => {
+                errors += 1
+            }
+            Err(cause) => panic!("unexpected error {cause}"),
+        }
+    }
+    assert!(errors > 0);
+    // the entire response was read, so the stream stayed in sync
+    assert_eq!(iter.bytes_read(), encoded.len() as u64);
+    let covered = crate::io::covered_chunk_ranges(size as u64, ranges, block_size);
+    assert!(!iter.failed_ranges().is_empty());
+    let mut both = iter.verified_ranges().clone();
+    both &= iter.failed_ranges().clone();
+    assert!(both.is_empty());
+    let mut all = iter.verified_ranges().clone();
+    all |= iter.failed_ranges().clone();
+    assert_eq!(all, covered);
+}
+
+#[test]
+fn continue_on_mismatch_cases() {
+    let cases = [
+        (100000, ChunkRanges::all(), 0, 0),
+        (100000, ChunkRanges::all(), 0, 50000),
+        (100000, ChunkRanges::all(), 2, 100000),
+        (100000, ChunkRanges::from(ChunkNum(5)..ChunkNum(30)), 2, 500),
+    ];
+    for (size, ranges, block_level, pos) in cases {
+        continue_on_mismatch_impl(size, &ranges, BlockSize(block_level), pos);
+    }
+}
+
+#[proptest]
+fn continue_on_mismatch_proptest(
+    #[strategy(size_and_selection(1..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    pos: usize,
+) {
+    let (size, ranges) = size_and_selection;
+    continue_on_mismatch_impl(size, &ranges, block_size, pos);
+}
+
+#[test]
+fn decode_stats() {
+    use crate::io::sync::DecodeStats;
+    // (size, ranges, block level, expected stats)
+    let cases = [
+        (0, ChunkRanges::all(), 0, (0, 0, 8, 1)),
+        (1000, ChunkRanges::all(), 0, (1000, 0, 1008, 1)),
+        // 3 chunks, 2 hash pairs
+        (3000, ChunkRanges::all(), 0, (3000, 2, 3136, 3)),
+        // the second chunk only needs the hash pair of the root and the left subtree
+        (
+            3000,
+            ChunkRanges::from(ChunkNum(1)..ChunkNum(2)),
+            0,
+            (1024, 2, 1160, 1),
+        ),
+        // a single block of 4 chunks
+        (3000, ChunkRanges::all(), 2, (3000, 0, 3008, 1)),
+    ];
+    for (size, ranges, block_level, (payload_bytes, parent_pairs, wire_bytes, leaf_hashes)) in cases
+    {
+        let block_size = BlockSize(block_level);
+        let data = make_test_data(size);
+        let outboard = PostOrderMemOutboard::create(&data, block_size);
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges(&data, &outboard, &ranges, &mut encoded).unwrap();
+        let mut iter = crate::io::sync::DecodeResponseIter::new(
+            outboard.root(),
+            block_size,
+            encoded.as_slice(),
+            &ranges,
+        );
+        for item in iter.by_ref() {
+            item.unwrap();
+        }
+        let expected = DecodeStats {
+            payload_bytes,
+            parent_pairs,
+            wire_bytes,
+            leaf_hashes,
+        };
+        assert_eq!(iter.stats(), expected);
+        assert_eq!(iter.stats().wire_bytes, encoded.len() as u64);
+    }
+}
+
+/// Check that the stable pairs are exactly the leading pairs of a post order outboard
+/// of nodes that have a stable offset
+#[proptest]
+fn stable_pair_count_proptest(#[strategy(tree())] tree: BaoTree) {
+    let mut stable = Vec::new();
+    for node in tree.post_order_nodes_iter() {
+        if let Some(crate::PostOrderOffset::Stable(offset)) = tree.post_order_offset(node) {
+            stable.push(offset);
+        }
+    }
+    stable.sort();
+    let expected = (0..tree.stable_pair_count()).collect::<Vec<_>>();
+    prop_assert_eq!(stable, expected);
+    prop_assert!(tree.stable_pair_count() <= tree.outboard_hash_pairs());
+}
+
+/// Check that OutboardBuilder::on_subtree reports every node with a hash pair
+/// in post order, with the hash of its data
+fn outboard_subtrees_impl(size: usize, block_size: BlockSize) {
+    let data = make_test_data(size);
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    let mut subtrees = Vec::new();
+    let mut outboard = Vec::new();
+    let hash = crate::io::sync::OutboardBuilder::new(block_size)
+        .on_subtree(|node, hash| subtrees.push((node, hash)))
+        .write(data.as_slice(), size as u64, &mut outboard)
+        .unwrap();
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    assert_eq!(hash, expected.root);
+    assert_eq!(outboard, expected.into_inner_with_suffix());
+    let nodes = subtrees.iter().map(|(node, _)| *node).collect::<Vec<_>>();
+    let expected_nodes = tree
+        .post_order_nodes_iter()
+        .filter(|node| tree.post_order_offset(*node).is_some())
+        .collect::<Vec<_>>();
+    assert_eq!(nodes, expected_nodes);
+    for (node, hash) in subtrees {
+        let range = tree.byte_range(node);
+        let node_data = &data[range.start.to_usize()..range.end.to_usize()];
+        assert_eq!(
+            hash,
+            crate::io::sync::subtree_hash(&tree, node, node_data).unwrap()
+        );
+    }
+}
+
+#[test]
+fn outboard_subtrees_cases() {
+    let cases = [(0, 0), (1024, 0), (2048, 0), (100000, 0), (100000, 2)];
+    for (size, block_level) in cases {
+        outboard_subtrees_impl(size, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn outboard_subtrees_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    outboard_subtrees_impl(size, block_size);
+}
+
+/// Check the convenience methods of NodeInfo against its fields
+#[proptest]
+fn node_info_touches(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    let tree = BaoTree::new(ByteNum(size as u64), block_size);
+    for info in tree.ranges_pre_order_nodes_iter(&ranges, 0) {
+        prop_assert_eq!(info.touches_left(), !info.l_ranges.is_empty());
+        prop_assert_eq!(info.touches_right(), !info.r_ranges.is_empty());
+        prop_assert_eq!(info.is_included(), info.full);
+        // a node is only visited if the query touches it
+        prop_assert!(info.touches_left() || info.touches_right());
+    }
+}
+
+/// Check that node_at_post_order_offset is the inverse of post_order_offset
+#[proptest]
+fn node_at_post_order_offset_proptest(#[strategy(tree())] tree: BaoTree) {
+    for node in tree.post_order_nodes_iter() {
+        if let Some(offset) = tree.post_order_offset(node) {
+            prop_assert_eq!(tree.node_at_post_order_offset(offset.value()), Some(node));
+        }
+    }
+    prop_assert_eq!(
+        tree.node_at_post_order_offset(tree.outboard_hash_pairs()),
+        None
+    );
+}
+
+#[test]
+fn stable_pair_count_cases() {
+    // (size, block level, stable pairs)
+    let cases = [
+        (0, 0, 0),
+        (1024, 0, 0),
+        (2048, 0, 1),
+        (3000, 0, 1),
+        (4096, 0, 3),
+        (7 * 1024, 0, 4),
+        (8 * 1024, 1, 3),
+        (8 * 1024 - 1, 1, 1),
+    ];
+    for (size, block_level, expected) in cases {
+        let tree = BaoTree::new(ByteNum(size), BlockSize(block_level));
+        assert_eq!(tree.stable_pair_count(), expected);
+    }
+}
+
+/// Computing an outboard from a chain of segments gives the same result as from the
+/// contiguous data
+fn outboard_from_segments_impl(size: usize, splits: &[usize], block_size: BlockSize) {
+    use std::io::Read;
+    let data = make_test_data(size);
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    let mut bounds = splits.iter().map(|x| x % (size + 1)).collect::<Vec<_>>();
+    bounds.push(0);
+    bounds.push(size);
+    bounds.sort();
+    let mut reader: Box<dyn Read + '_> = Box::new(std::io::empty());
+    for w in bounds.windows(2) {
+        reader = Box::new(reader.chain(&data[w[0]..w[1]]));
+    }
+    let mut outboard = Vec::new();
+    let hash = crate::io::sync::outboard_post_order(reader, size as u64, block_size, &mut outboard)
+        .unwrap();
+    assert_eq!(hash, expected.root());
+    assert_eq!(outboard, expected.into_inner_with_suffix());
+}
+
+#[test]
+fn outboard_from_segments_cases() {
+    let cases = [
+        (0, vec![], 0),
+        (100000, vec![1], 0),
+        (100000, vec![1023, 1024, 1025, 50000], 4),
+        (100000, vec![99999, 5, 5], 2),
+    ];
+    for (size, splits, block_level) in cases {
+        outboard_from_segments_impl(size, &splits, BlockSize(block_level));
+    }
+}
+
+#[proptest]
+fn outboard_from_segments_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(proptest::collection::vec(any::<usize>(), 0..8))] splits: Vec<usize>,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    outboard_from_segments_impl(size, &splits, block_size);
+}
+
+/// Check that the typed pairs of an outboard flatten back to the outboard data, and
+/// that each pair is the pair of its node
+#[proptest]
+fn outboard_pairs_proptest(#[strategy(tree())] tree: BaoTree) {
+    let data = make_test_data(tree.size().to_usize());
+    let outboard = PostOrderMemOutboard::create(&data, tree.block_size);
+    let pairs = outboard.pairs().unwrap();
+    prop_assert_eq!(pairs.to_post_order_bytes(), outboard.outboard().to_vec());
+    prop_assert_eq!(pairs.stable.len() as u64, tree.stable_pair_count());
+    prop_assert_eq!(pairs.len() as u64, tree.outboard_hash_pairs());
+    for Parent { node, pair } in pairs.stable.iter() {
+        prop_assert!(matches!(
+            tree.post_order_offset(*node),
+            Some(crate::PostOrderOffset::Stable(_))
+        ));
+        prop_assert_eq!(outboard.load(*node).unwrap(), Some(*pair));
+    }
+    for Parent { node, pair } in pairs.unstable.iter() {
+        prop_assert!(matches!(
+            tree.post_order_offset(*node),
+            Some(crate::PostOrderOffset::Unstable(_))
+        ));
+        prop_assert_eq!(outboard.load(*node).unwrap(), Some(*pair));
+    }
+}
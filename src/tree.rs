@@ -6,6 +6,7 @@ use std::{
     ops::{Add, Div, Mul, Sub},
 };
 
+#[cfg(feature = "range-collections")]
 use range_collections::range_set::RangeSetEntry;
 
 index_newtype! {
@@ -26,13 +27,26 @@ index_newtype! {
 }
 
 impl BlockNum {
+    /// The first chunk of this block, given a block size
     pub fn to_chunks(self, block_level: BlockSize) -> ChunkNum {
         ChunkNum(self.0 << block_level.0)
     }
 
+    /// The first byte of this block, given a block size
     pub fn to_bytes(self, block_level: BlockSize) -> ByteNum {
         ByteNum(self.0 << (block_level.0 + 10))
     }
+
+    /// The first chunk of this block, or None if it does not fit into an u64
+    pub fn checked_to_chunks(self, block_level: BlockSize) -> Option<ChunkNum> {
+        let factor = 1u64.checked_shl(block_level.0 as u32)?;
+        self.0.checked_mul(factor).map(ChunkNum)
+    }
+
+    /// The first byte of this block, or None if it does not fit into an u64
+    pub fn checked_to_bytes(self, block_level: BlockSize) -> Option<ByteNum> {
+        self.checked_to_chunks(block_level)?.checked_to_bytes()
+    }
 }
 
 index_newtype! {
@@ -68,6 +82,12 @@ impl BlockSize {
     /// This means that blocks and blake3 chunks are the same size.
     pub const ZERO: BlockSize = BlockSize(0);
 
+    /// The largest supported block size, 2^20 chunks or 1 GiB
+    ///
+    /// All byte offset computations shift by `10 + block_size`, so this keeps the
+    /// shifts well below 64 bits. [crate::BaoTree::new] enforces this limit.
+    pub const MAX: BlockSize = BlockSize(20);
+
     /// Number of bytes in a block at this level
     pub const fn bytes(self) -> usize {
         BLAKE3_CHUNK_SIZE << self.0
@@ -83,6 +103,10 @@ impl BlockSize {
             // must be at least 1024 bytes
             return None;
         }
+        if bytes > (BLAKE3_CHUNK_SIZE as u64) << Self::MAX.0 {
+            // must be at most the max block size
+            return None;
+        }
         Some(Self((bytes.trailing_zeros() - 10) as u8))
     }
 